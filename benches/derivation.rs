@@ -0,0 +1,58 @@
+//! signing many addresses under one account with and without the derived-node cache
+//!
+//! cargo bench --bench derivation
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crypto_key_master::{Curve, KeyMaster, MemoryKeystore};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+const SEED: &str = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
+
+fn key_master(cache: usize) -> (KeyMaster<MemoryKeystore>, String) {
+    let key_master = KeyMaster::new(MemoryKeystore::new()).with_derivation_cache(cache);
+    let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+    key_master
+        .unlock(&key_id, "123", Duration::from_secs(3600))
+        .unwrap();
+    (key_master, key_id)
+}
+
+fn bench_sign(c: &mut Criterion) {
+    let digest = Sha256::digest(b"hello");
+    let mut group = c.benchmark_group("sign m/84'/0'/0'/0/i");
+    for cache in [0, 64] {
+        let (key_master, key_id) = key_master(cache);
+        let mut index = 0u32;
+        group.bench_function(BenchmarkId::new("cache", cache), |b| {
+            b.iter(|| {
+                index = (index + 1) % 10_000;
+                let path = format!("m/84'/0'/0'/0/{}", index);
+                key_master
+                    .sign_digest(&key_id, &path, Curve::Secp256k1, &digest, "")
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_public_key(c: &mut Criterion) {
+    let mut group = c.benchmark_group("public key m/84'/0'/0'/0/i");
+    for cache in [0, 64] {
+        let (key_master, key_id) = key_master(cache);
+        let mut index = 0u32;
+        group.bench_function(BenchmarkId::new("cache", cache), |b| {
+            b.iter(|| {
+                index = (index + 1) % 10_000;
+                let path = format!("m/84'/0'/0'/0/{}", index);
+                key_master
+                    .get_public_key(&key_id, &path, Curve::Secp256k1, "")
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sign, bench_public_key);
+criterion_main!(benches);
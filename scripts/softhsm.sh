@@ -0,0 +1,36 @@
+#!/bin/sh
+# Initialize a SoftHSMv2 token for the PKCS#11 tests in a private directory and print the
+# environment they need:
+#
+#   eval "$(scripts/softhsm.sh)"
+#   cargo test --features pkcs11 keystore::pkcs11 -- --ignored
+#
+# CKM_SOFTHSM_DIR overrides the token directory, which is recreated on every run.
+set -eu
+
+dir="${CKM_SOFTHSM_DIR:-${TMPDIR:-/tmp}/ckm-softhsm}"
+module=""
+for candidate in \
+    /usr/lib/softhsm/libsofthsm2.so \
+    /usr/lib/x86_64-linux-gnu/softhsm/libsofthsm2.so \
+    /usr/lib/aarch64-linux-gnu/softhsm/libsofthsm2.so \
+    /usr/local/lib/softhsm/libsofthsm2.so \
+    /opt/homebrew/lib/softhsm/libsofthsm2.so; do
+    if [ -f "$candidate" ]; then
+        module="$candidate"
+        break
+    fi
+done
+if [ -z "$module" ]; then
+    echo "libsofthsm2.so not found, install SoftHSMv2 (softhsm2 package)" >&2
+    exit 1
+fi
+
+rm -rf "$dir"
+mkdir -p "$dir/tokens"
+printf 'directories.tokendir = %s/tokens\nobjectstore.backend = file\n' "$dir" >"$dir/softhsm2.conf"
+SOFTHSM2_CONF="$dir/softhsm2.conf" softhsm2-util --init-token --free --label ckm \
+    --pin 1234 --so-pin 0000 >&2
+
+echo "export SOFTHSM2_CONF='$dir/softhsm2.conf'"
+echo "export CKM_PKCS11_MODULE='$module'"
@@ -0,0 +1,353 @@
+//! async keystores and key master, KDF, derivation and blocking backend calls run on the tokio
+//! blocking pool instead of the executor threads
+use crate::keystore::{decode_seed, encode_seed, KeySource};
+use crate::{
+    public_key, sign_with_keys, signed_input, CKMError, Curve, Keystore, SignRequest,
+    SigningSignature,
+};
+use std::future::Future;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// async variant of `Keystore`, see there for the meaning of each method
+pub trait AsyncKeystore: Send + Sync {
+    /// generate the entropy to provide to outside world
+    fn generate_entropy(
+        &self,
+        length: u32,
+    ) -> impl Future<Output = Result<Vec<u8>, CKMError>> + Send;
+
+    /// get the key by id
+    fn get_key(
+        &self,
+        password: &str,
+        key_id: String,
+    ) -> impl Future<Output = Result<Vec<u8>, CKMError>> + Send;
+
+    /// write key to store
+    fn write_key(
+        &self,
+        password: &str,
+        key: String,
+    ) -> impl Future<Output = Result<String, CKMError>> + Send;
+
+    /// ids of the stored keys
+    fn list_keys(&self) -> impl Future<Output = Result<Vec<String>, CKMError>> + Send {
+        async { Err(CKMError::NotFound("key listing".to_string())) }
+    }
+
+    /// whether the store signs with its keys itself, like `Keystore::signs_internally`
+    fn signs_internally(&self) -> bool {
+        false
+    }
+
+    /// sign inside the store, like `Keystore::sign_digest`
+    fn sign_digest(
+        &self,
+        _password: &str,
+        _key_id: &str,
+        _path: &str,
+        _curve: &Curve,
+        _digest: &[u8],
+    ) -> impl Future<Output = Result<SigningSignature, CKMError>> + Send {
+        async { Err(CKMError::InternalSigningUnsupported) }
+    }
+
+    /// public key of a key held by a store that signs internally, like `Keystore::get_public_key`
+    fn get_public_key(
+        &self,
+        _password: &str,
+        _key_id: &str,
+        _path: &str,
+        _curve: &Curve,
+    ) -> impl Future<Output = Result<Vec<u8>, CKMError>> + Send {
+        async { Err(CKMError::InternalSigningUnsupported) }
+    }
+}
+
+/// run a closure on the blocking pool, panics are passed on to the caller
+async fn blocking<T, F>(f: F) -> Result<T, CKMError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, CKMError> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_e) => Err(CKMError::Unknown),
+    }
+}
+
+/// adapter running a sync `Keystore` on the blocking pool, every call is one blocking task
+pub struct BlockingKeystore<Store> {
+    store: Arc<Store>,
+}
+
+impl<Store> Clone for BlockingKeystore<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<Store: Keystore + 'static> BlockingKeystore<Store> {
+    pub fn new(store: Store) -> Self {
+        Self::from_arc(Arc::new(store))
+    }
+
+    /// share a store that is also used through a sync `KeyMaster`
+    pub fn from_arc(store: Arc<Store>) -> Self {
+        Self { store }
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &Arc<Store> {
+        &self.store
+    }
+}
+
+impl<Store: Keystore + 'static> AsyncKeystore for BlockingKeystore<Store> {
+    async fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        let store = self.store.clone();
+        blocking(move || store.generate_entropy(length)).await
+    }
+
+    async fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
+        let store = self.store.clone();
+        let password = Zeroizing::new(password.to_string());
+        blocking(move || store.get_key(&password, key_id)).await
+    }
+
+    async fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let store = self.store.clone();
+        let password = Zeroizing::new(password.to_string());
+        let key = Zeroizing::new(key);
+        blocking(move || store.write_key(&password, key.to_string())).await
+    }
+
+    async fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let store = self.store.clone();
+        blocking(move || store.list_keys()).await
+    }
+
+    fn signs_internally(&self) -> bool {
+        self.store.signs_internally()
+    }
+
+    async fn sign_digest(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+        curve: &Curve,
+        digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        let store = self.store.clone();
+        let password = Zeroizing::new(password.to_string());
+        let (key_id, path, curve, digest) = (
+            key_id.to_string(),
+            path.to_string(),
+            *curve,
+            digest.to_vec(),
+        );
+        blocking(move || store.sign_digest(&password, &key_id, &path, &curve, &digest)).await
+    }
+
+    async fn get_public_key(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+        curve: &Curve,
+    ) -> Result<Vec<u8>, CKMError> {
+        let store = self.store.clone();
+        let password = Zeroizing::new(password.to_string());
+        let (key_id, path, curve) = (key_id.to_string(), path.to_string(), *curve);
+        blocking(move || store.get_public_key(&password, &key_id, &path, &curve)).await
+    }
+}
+
+/// the seed of one key, read from the async store before moving to the blocking pool
+struct SeedKeys {
+    key_id: String,
+    seed: Zeroizing<Vec<u8>>,
+}
+
+impl KeySource for SeedKeys {
+    fn read_seed(&self, _password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        if key_id != self.key_id {
+            return Err(CKMError::NotExist);
+        }
+        Ok(self.seed.clone())
+    }
+}
+
+/// async KeyMaster, wrap sync stores in `BlockingKeystore`
+pub struct AsyncKeyMaster<Store> {
+    store: Store,
+}
+
+impl<Store: AsyncKeystore> AsyncKeyMaster<Store> {
+    pub fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    /// sign the request, see `KeyMaster::sign`
+    pub async fn sign(
+        &self,
+        sign_request: SignRequest<'_>,
+        password: &str,
+    ) -> Result<SigningSignature, CKMError> {
+        if self.store.signs_internally() {
+            return self
+                .store
+                .sign_digest(
+                    password,
+                    sign_request.key_id,
+                    sign_request.path,
+                    &sign_request.curve,
+                    &signed_input(&sign_request),
+                )
+                .await;
+        }
+        let keys = self._seed_keys(password, sign_request.key_id).await?;
+        let path = sign_request.path.to_string();
+        let (data, curve) = (sign_request.unsigend_data, sign_request.curve);
+        blocking(move || {
+            let request = SignRequest {
+                path: &path,
+                unsigend_data: data,
+                key_id: &keys.key_id,
+                curve,
+            };
+            sign_with_keys(request, "", &keys)
+        })
+        .await
+    }
+
+    /// public key of the path, see `KeyMaster::get_public_key`
+    pub async fn get_public_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<Vec<u8>, CKMError> {
+        if self.store.signs_internally() {
+            return self
+                .store
+                .get_public_key(password, key_id, path, &curve)
+                .await;
+        }
+        let keys = self._seed_keys(password, key_id).await?;
+        let path = path.to_string();
+        blocking(move || public_key(&keys, "", &keys.key_id, &path, curve)).await
+    }
+
+    /// ids of the keys in the store
+    pub async fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        self.store.list_keys().await
+    }
+
+    /// generate entropy for seed
+    pub async fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        self.store.generate_entropy(length).await
+    }
+
+    /// write a hex encoded seed to storage, tagged like `KeyMaster::write_seed`
+    pub async fn write_seed(&self, password: &str, seed: String) -> Result<String, CKMError> {
+        let stored = Zeroizing::new(encode_seed(&seed)?);
+        self.store.write_key(password, stored.to_string()).await
+    }
+
+    async fn _seed_keys(&self, password: &str, key_id: &str) -> Result<SeedKeys, CKMError> {
+        let key = Zeroizing::new(self.store.get_key(password, key_id.to_string()).await?);
+        Ok(SeedKeys {
+            key_id: key_id.to_string(),
+            seed: decode_seed(key)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::fake::{FakeKeystore, FakeSigner};
+    use crate::KeyMaster;
+
+    fn request(curve: Curve) -> SignRequest<'static> {
+        SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: "123456",
+            curve,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_key_master() {
+        let key_master = AsyncKeyMaster::new(BlockingKeystore::new(FakeKeystore {}));
+        assert_eq!(
+            key_master.generate_entropy(32).await.unwrap(),
+            vec![0u8; 32]
+        );
+        let key_id = key_master.write_seed("123", "00".repeat(64)).await.unwrap();
+        assert_eq!(key_id, "123456");
+
+        let sync_master = KeyMaster::new(FakeKeystore {});
+        let sig = key_master
+            .sign(request(Curve::Secp256k1), "123")
+            .await
+            .unwrap();
+        let expected = sync_master.sign(request(Curve::Secp256k1), "123").unwrap();
+        assert_eq!((sig.r, sig.s, sig.v), (expected.r, expected.s, expected.v));
+
+        // schnorr signatures use fresh auxiliary randomness, verify instead
+        let sig = key_master
+            .sign(request(Curve::Secp256k1Schnorr), "123")
+            .await
+            .unwrap();
+        let x_only = key_master
+            .get_public_key("123456", "m/44'/0'/0'/0/0", Curve::Secp256k1Schnorr, "123")
+            .await
+            .unwrap();
+        assert!(crate::schnorr_verify(&x_only, b"hello", &sig.to_compact()));
+
+        for curve in [Curve::Secp256k1, Curve::Secp256R1, Curve::Ed25519] {
+            // SLIP-10 Ed25519 only derives hardened children
+            let public_key = key_master
+                .get_public_key("123456", "m/44'/0'/0'", curve, "123")
+                .await
+                .unwrap();
+            let expected = sync_master
+                .get_public_key("123456", "m/44'/0'/0'", curve, "123")
+                .unwrap();
+            assert_eq!(public_key, expected);
+        }
+        // SLIP-10 Ed25519 keys have no non-hardened children
+        assert!(matches!(
+            key_master.sign(request(Curve::Ed25519), "123").await,
+            Err(CKMError::InvalidPath { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_async_internal_signing() {
+        let key_master = Arc::new(AsyncKeyMaster::new(BlockingKeystore::new(FakeSigner {})));
+        let tasks: Vec<_> = (0..4)
+            .map(|_i| {
+                let key_master = key_master.clone();
+                tokio::spawn(async move { key_master.sign(request(Curve::Secp256k1), "123").await })
+            })
+            .collect();
+        for task in tasks {
+            let sig = task.await.unwrap().unwrap();
+            // same signature as `internal_signing_usage`
+            assert_eq!(
+                hex::encode(sig.r),
+                "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+            );
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! batch signing, every key is decrypted once and shared BIP32 parents are derived once
+use crate::curve::k1::{derive_from_seed, master_node};
+use crate::curve::parse_path;
+use crate::keystore::KeySource;
+use crate::{dispatch, CKMError, Curve, Keystore, SignRequest, SigningSignature};
+use bip32::{ChildNumber, DerivationPath, ExtendedKey, Prefix, XPrv};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use zeroize::Zeroizing;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// the seeds and parent nodes of one batch, later reads are served from memory
+struct BatchKeys<'a, Keys> {
+    keys: &'a Keys,
+    seeds: HashMap<String, Result<Zeroizing<Vec<u8>>, CKMError>>,
+    /// parents of the requested paths, `ExtendedKey` wipes them when the batch ends
+    parents: BTreeMap<(String, Vec<ChildNumber>), ExtendedKey>,
+}
+
+impl<'a, Keys: KeySource> BatchKeys<'a, Keys> {
+    fn new(keys: &'a Keys, requests: &[SignRequest], password: &str) -> Self {
+        let mut seeds = HashMap::new();
+        let mut paths: BTreeMap<String, Vec<Vec<ChildNumber>>> = BTreeMap::new();
+        for request in requests {
+            // every curve signs from the seed, only the BIP32 curves share parents
+            seeds
+                .entry(request.key_id.to_string())
+                .or_insert_with(|| keys.read_seed(password, request.key_id));
+            if !matches!(request.curve, Curve::Secp256k1 | Curve::Secp256k1Schnorr) {
+                continue;
+            }
+            // unparsable paths fail on their own request
+            if let Ok(path) = request.path.parse::<DerivationPath>() {
+                let children: Vec<ChildNumber> = path.iter().collect();
+                let parent = children[..children.len().saturating_sub(1)].to_vec();
+                paths
+                    .entry(request.key_id.to_string())
+                    .or_default()
+                    .push(parent);
+            }
+        }
+
+        let mut parents = BTreeMap::new();
+        for (key_id, mut key_paths) in paths {
+            let seed = match seeds.get(&key_id) {
+                Some(Ok(seed)) => seed,
+                _ => continue,
+            };
+            let master = match master_node(seed) {
+                Ok(master) => master,
+                Err(_e) => continue,
+            };
+            key_paths.sort();
+            key_paths.dedup();
+            // every prefix is derived once, sorted paths reuse the nodes of their prefixes
+            let mut nodes: BTreeMap<Vec<ChildNumber>, XPrv> = BTreeMap::new();
+            nodes.insert(Vec::new(), master);
+            for parent in key_paths {
+                if _derive_prefixes(&mut nodes, &parent).is_none() {
+                    continue;
+                }
+                if let Some(node) = nodes.get(&parent) {
+                    parents.insert((key_id.clone(), parent), node.to_extended_key(Prefix::XPRV));
+                }
+            }
+        }
+        Self {
+            keys,
+            seeds,
+            parents,
+        }
+    }
+}
+
+/// derive the missing prefixes of the path from the deepest one already derived
+fn _derive_prefixes(
+    nodes: &mut BTreeMap<Vec<ChildNumber>, XPrv>,
+    path: &[ChildNumber],
+) -> Option<()> {
+    let depth = (0..=path.len())
+        .rev()
+        .find(|depth| nodes.contains_key(&path[..*depth]))?;
+    let mut node = nodes.get(&path[..depth])?.clone();
+    for (i, child) in path.iter().enumerate().skip(depth) {
+        node = node.derive_child(*child).ok()?;
+        nodes.insert(path[..=i].to_vec(), node.clone());
+    }
+    Some(())
+}
+
+impl<'a, Keys: KeySource> KeySource for BatchKeys<'a, Keys> {
+    fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        match self.seeds.get(key_id) {
+            Some(seed) => seed.clone(),
+            None => self.keys.read_seed(password, key_id),
+        }
+    }
+
+    fn derive_k1(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        let parsed = parse_path(path)?;
+        let children: Vec<ChildNumber> = parsed.iter().collect();
+        if let Some((last, parent)) = children.split_last() {
+            if let Some(node) = self.parents.get(&(key_id.to_string(), parent.to_vec())) {
+                let node = XPrv::try_from(node.clone())
+                    .map_err(|e| CKMError::crypto("bip32 node failed", e))?;
+                let child = node
+                    .derive_child(*last)
+                    .map_err(|e| CKMError::crypto("bip32 derivation failed", e))?;
+                return Ok(Zeroizing::new(child.private_key().to_bytes().to_vec()));
+            }
+        }
+        let seed = self.read_seed(password, key_id)?;
+        Ok(Zeroizing::new(derive_from_seed(&seed, path)?))
+    }
+}
+
+/// sign every request, the results are in the order of the requests
+#[cfg(feature = "rayon")]
+pub(crate) fn sign_batch<Store: Keystore>(
+    requests: Vec<SignRequest>,
+    password: &str,
+    store: &Store,
+    keys: &(impl KeySource + Sync),
+) -> Vec<Result<SigningSignature, CKMError>> {
+    if store.signs_internally() {
+        return requests
+            .into_par_iter()
+            .map(|request| dispatch(request, password, store, keys))
+            .collect();
+    }
+    let batch = BatchKeys::new(keys, &requests, password);
+    requests
+        .into_par_iter()
+        .map(|request| dispatch(request, password, store, &batch))
+        .collect()
+}
+
+/// sign every request, the results are in the order of the requests
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn sign_batch<Store: Keystore>(
+    requests: Vec<SignRequest>,
+    password: &str,
+    store: &Store,
+    keys: &impl KeySource,
+) -> Vec<Result<SigningSignature, CKMError>> {
+    if store.signs_internally() {
+        return requests
+            .into_iter()
+            .map(|request| dispatch(request, password, store, keys))
+            .collect();
+    }
+    let batch = BatchKeys::new(keys, &requests, password);
+    requests
+        .into_iter()
+        .map(|request| dispatch(request, password, store, &batch))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::fake::FakeKeystore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// counts the reads of the fake seed
+    struct CountingKeys {
+        reads: AtomicUsize,
+    }
+
+    impl KeySource for CountingKeys {
+        fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            FakeKeystore {}.read_seed(password, key_id)
+        }
+    }
+
+    #[test]
+    fn test_batch_keys() {
+        let keys = CountingKeys {
+            reads: AtomicUsize::new(0),
+        };
+        let paths: Vec<String> = (0..20)
+            .map(|i| format!("m/84'/0'/0'/{}/{}", i % 2, i))
+            .chain(vec!["m".to_string(), "m/0".to_string()])
+            .collect();
+        let requests: Vec<SignRequest> = paths
+            .iter()
+            .map(|path| SignRequest {
+                path,
+                unsigend_data: Vec::new(),
+                key_id: "123456",
+                curve: Curve::Secp256k1,
+            })
+            .collect();
+        let batch = BatchKeys::new(&keys, &requests, "pass");
+        // the seed is read once and the parents are `m/84'/0'/0'/0`, `m/84'/0'/0'/1` and `m`
+        assert_eq!(keys.reads.load(Ordering::SeqCst), 1);
+        assert_eq!(batch.parents.len(), 3);
+
+        let seed = FakeKeystore {}.read_seed("pass", "123456").unwrap();
+        for path in paths.iter() {
+            let key = batch.derive_k1("pass", "123456", path).unwrap();
+            assert_eq!(*key, derive_from_seed(&seed, path).unwrap(), "{}", path);
+        }
+        assert_eq!(keys.reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_batch_keys_curves() {
+        let keys = CountingKeys {
+            reads: AtomicUsize::new(0),
+        };
+        let curves = [
+            Curve::Secp256k1,
+            Curve::Secp256k1Schnorr,
+            Curve::Secp256R1,
+            Curve::Ed25519,
+        ];
+        let requests: Vec<SignRequest> = curves
+            .iter()
+            .chain(curves.iter())
+            .map(|curve| SignRequest {
+                path: "m/44'/0'/0'",
+                unsigend_data: b"data".to_vec(),
+                key_id: "123456",
+                curve: *curve,
+            })
+            .collect();
+        let results = sign_batch(requests, "pass", &FakeKeystore {}, &keys);
+        // the seed is read once for the requests of every curve
+        assert_eq!(keys.reads.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(Result::is_ok));
+    }
+}
@@ -0,0 +1,80 @@
+//! local signing daemon serving a `LocalKeystore` directory over JSON-RPC on a Unix socket
+//!
+//! ckm-daemon --socket <path> [--dir keys] [--allow-uid <uid>]... [--mode <octal>]
+//!   [--max-connections 64]
+use crypto_key_master::{DaemonServer, KeyMaster, LocalKeystore};
+use std::process::exit;
+
+const USAGE: &str =
+    "usage: ckm-daemon --socket <path> [--dir <keystore dir>] [--allow-uid <uid>]... [--mode <octal>] [--max-connections <n>]";
+
+fn main() {
+    let (mut socket, mut dir, mut mode) = (None, None, None);
+    let mut max_connections = None;
+    // the user running the daemon is always allowed
+    let mut allowed_uids = vec![unsafe { libc::geteuid() }];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => args.next().unwrap_or_else(|| fail(USAGE)),
+        };
+        match arg.as_str() {
+            "--socket" => socket = Some(value),
+            "--dir" => dir = Some(value),
+            "--allow-uid" => allowed_uids.push(value.parse().unwrap_or_else(|_e| fail(USAGE))),
+            "--mode" => {
+                mode = Some(u32::from_str_radix(&value, 8).unwrap_or_else(|_e| fail(USAGE)))
+            }
+            "--max-connections" => match value.parse::<usize>() {
+                Ok(max) if max > 0 => max_connections = Some(max),
+                _ => fail("--max-connections must be a positive number"),
+            },
+            _ => fail(USAGE),
+        }
+    }
+    let socket = socket.unwrap_or_else(|| fail(USAGE));
+    // a relative socket path is taken from where the daemon was started, not from `--dir`
+    let socket = std::env::current_dir()
+        .map(|cwd| cwd.join(&socket))
+        .unwrap_or_else(|e| fail(&e.to_string()));
+
+    // `LocalKeystore` keeps its files in the working directory
+    if let Some(dir) = dir {
+        std::env::set_current_dir(&dir).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+    }
+    let mut server = DaemonServer::bind(&socket, KeyMaster::new(LocalKeystore::new()))
+        .unwrap_or_else(|e| fail(&error_message(&e)))
+        .allowed_uids(&allowed_uids);
+    if let Some(max) = max_connections {
+        server = server.with_max_connections(max);
+    }
+    if let Some(mode) = mode {
+        server
+            .set_mode(mode)
+            .unwrap_or_else(|e| fail(&error_message(&e)));
+    }
+    eprintln!("ckm-daemon listening on {}", server.path().display());
+    if let Err(e) = server.serve() {
+        fail(&error_message(&e));
+    }
+}
+
+/// the error with its sources
+fn error_message(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(2)
+}
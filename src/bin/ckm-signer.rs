@@ -0,0 +1,64 @@
+//! reference remote signer serving a `LocalKeystore` directory over mutually authenticated TLS
+//!
+//! ckm-signer --ca ca.pem --cert server.pem --key server.key [--listen 127.0.0.1:7443] [--dir keys]
+//!   [--max-connections 64]
+use crypto_key_master::{LocalKeystore, RemoteSignerServer, RemoteTlsConfig};
+use std::process::exit;
+
+const USAGE: &str =
+    "usage: ckm-signer --ca <ca.pem> --cert <cert.pem> --key <key.pem> [--listen <addr>] [--dir <keystore dir>] [--max-connections <n>]";
+
+fn main() {
+    let mut listen = "127.0.0.1:7443".to_string();
+    let (mut ca, mut cert, mut key, mut dir) = (None, None, None, None);
+    let mut max_connections = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => args.next().unwrap_or_else(|| fail(USAGE)),
+        };
+        match arg.as_str() {
+            "--listen" => listen = value,
+            "--ca" => ca = Some(value),
+            "--cert" => cert = Some(value),
+            "--key" => key = Some(value),
+            "--dir" => dir = Some(value),
+            "--max-connections" => match value.parse::<usize>() {
+                Ok(max) if max > 0 => max_connections = Some(max),
+                _ => fail("--max-connections must be a positive number"),
+            },
+            _ => fail(USAGE),
+        }
+    }
+    let (ca, cert, key) = match (ca, cert, key) {
+        (Some(ca), Some(cert), Some(key)) => (ca, cert, key),
+        _ => fail(USAGE),
+    };
+
+    let tls = RemoteTlsConfig::from_files(ca, cert, key).unwrap_or_else(|e| fail(&e.to_string()));
+    // `LocalKeystore` keeps its files in the working directory
+    if let Some(dir) = dir {
+        std::env::set_current_dir(&dir).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+    }
+    let mut server = RemoteSignerServer::bind(listen.as_str(), tls, LocalKeystore::new())
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    if let Some(max) = max_connections {
+        server = server.with_max_connections(max);
+    }
+    match server.local_addr() {
+        Ok(addr) => eprintln!("ckm-signer listening on {}", addr),
+        Err(e) => fail(&e.to_string()),
+    }
+    if let Err(e) = server.serve() {
+        fail(&e.to_string());
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(2)
+}
@@ -0,0 +1,138 @@
+//! ssh-agent serving Ed25519 and P-256 keys of a `LocalKeystore` directory
+//!
+//! ckm-ssh-agent --socket <path> [--dir keys] --identity <key_id>:<curve>:<path>...
+//!     [--password-file <file>] [--ttl <seconds>] [--max-connections 64]
+use crypto_key_master::{Curve, KeyMaster, LocalKeystore, SshAgent, Zeroizing};
+use std::io::BufRead;
+use std::process::exit;
+use std::time::Duration;
+
+const USAGE: &str = "usage: ckm-ssh-agent --socket <path> [--dir <keystore dir>] \
+    --identity <key_id>:<ed25519|p256>:<path>... [--password-file <file>] [--ttl <seconds>] \
+    [--max-connections <n>]";
+
+fn main() {
+    let (mut socket, mut dir, mut password_file) = (None, None, None);
+    let mut identities = Vec::new();
+    let mut ttl = 8 * 60 * 60;
+    let mut max_connections = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => args.next().unwrap_or_else(|| fail(USAGE)),
+        };
+        match arg.as_str() {
+            "--socket" => socket = Some(value),
+            "--dir" => dir = Some(value),
+            "--identity" => identities.push(identity(&value).unwrap_or_else(|| fail(USAGE))),
+            "--password-file" => password_file = Some(value),
+            "--ttl" => ttl = value.parse().unwrap_or_else(|_e| fail(USAGE)),
+            "--max-connections" => match value.parse::<usize>() {
+                Ok(max) if max > 0 => max_connections = Some(max),
+                _ => fail("--max-connections must be a positive number"),
+            },
+            _ => fail(USAGE),
+        }
+    }
+    let socket = socket.unwrap_or_else(|| fail(USAGE));
+    if identities.is_empty() {
+        fail(USAGE);
+    }
+    let socket = std::env::current_dir()
+        .map(|cwd| cwd.join(&socket))
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    // read before `--dir` changes the working directory
+    let password = read_password(password_file.as_deref());
+
+    // `LocalKeystore` keeps its files in the working directory
+    if let Some(dir) = dir {
+        std::env::set_current_dir(&dir).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+    }
+    let mut agent = SshAgent::bind(&socket, KeyMaster::new(LocalKeystore::new()))
+        .unwrap_or_else(|e| fail(&error_message(&e)));
+    let mut key_ids: Vec<&String> = identities.iter().map(|(key_id, _, _)| key_id).collect();
+    key_ids.sort();
+    key_ids.dedup();
+    // the agent signs through unlock sessions, the password is not kept around
+    let added = identities
+        .iter()
+        .try_for_each(|(key_id, curve, path)| {
+            agent
+                .add_identity(key_id, path, *curve, &password)
+                .map_err(|e| format!("{}: {}", key_id, error_message(&e)))
+        })
+        .and_then(|_| {
+            key_ids.iter().try_for_each(|key_id| {
+                agent
+                    .key_master()
+                    .unlock(key_id, &password, Duration::from_secs(ttl))
+                    .map(|_session| ())
+                    .map_err(|e| format!("{}: {}", key_id, error_message(&e)))
+            })
+        });
+    if let Err(message) = added {
+        // `exit` skips the drop removing the socket
+        drop(agent);
+        fail(&message);
+    }
+    drop(password);
+    if let Some(max) = max_connections {
+        agent = agent.with_max_connections(max);
+    }
+
+    println!(
+        "SSH_AUTH_SOCK={}; export SSH_AUTH_SOCK;",
+        agent.path().display()
+    );
+    eprintln!("ckm-ssh-agent listening on {}", agent.path().display());
+    if let Err(e) = agent.serve() {
+        fail(&error_message(&e));
+    }
+}
+
+/// `<key_id>:<curve>:<path>`
+fn identity(value: &str) -> Option<(String, Curve, String)> {
+    let mut parts = value.splitn(3, ':');
+    let key_id = parts.next()?.to_string();
+    let curve = parts.next()?.parse().ok()?;
+    let path = parts.next()?.to_string();
+    Some((key_id, curve, path))
+}
+
+/// first line of the password file, or of stdin without one
+fn read_password(file: Option<&str>) -> Zeroizing<String> {
+    let mut text = Zeroizing::new(String::new());
+    match file {
+        Some(file) => {
+            *text =
+                std::fs::read_to_string(file).unwrap_or_else(|e| fail(&format!("{}: {}", file, e)))
+        }
+        None => {
+            std::io::stdin()
+                .lock()
+                .read_line(&mut text)
+                .unwrap_or_else(|e| fail(&e.to_string()));
+        }
+    }
+    Zeroizing::new(text.lines().next().unwrap_or_default().to_string())
+}
+
+/// the error with its sources
+fn error_message(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(2)
+}
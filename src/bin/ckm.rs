@@ -0,0 +1,638 @@
+//! key management and signing on a `LocalKeystore` directory
+//!
+//! ckm [--dir <keystore dir>] [--json] <command> [options], see `USAGE`
+use bip39::Mnemonic;
+use crypto_key_master::{
+    verify_digest, verify_signature, CKMError, Curve, KeyMaster, Keystore, LocalKeystore,
+    SignRequest, SigningSignature, Zeroizing,
+};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::exit;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+const USAGE: &str = "usage: ckm [--dir <keystore dir>] [--json] <command> [options]
+
+commands:
+  generate [--bits 128|256] [--mnemonic] [--save]
+      new entropy, or its BIP39 mnemonic. --save stores the BIP39 seed and prints the key id
+  import --seed | --mnemonic | --keystore <file> [--input <file>]
+      store a hex seed or a mnemonic read from --input or stdin, or the key of a LocalKeystore
+      or Web3 Secret Storage V3 file, whose private key is the secp256k1 key of path m
+  list
+      ids of the stored keys
+  show <key id> [--path <path>] [--metadata-only]
+      keystore file metadata, master fingerprint and the xpub of the path (default m)
+  sign <key id> --path <path> [--curve <curve>] [--input <file>] [--hex] [--prehashed]
+      sign the data of --input or stdin, --prehashed signs a 32 bytes digest
+  verify --public-key <hex> --signature <hex> [--curve <curve>] [--input <file>] [--hex] [--prehashed]
+      exits with 1 when the signature is not valid
+  change-password <key id>
+  delete <key id> [--force]
+      --force deletes without checking the password
+
+secrets are never taken from the command line, each is read from the first line of a file
+descriptor or file, or prompted on the terminal:
+  --password-fd <fd> | --password-file <file>            password of the key
+  --new-password-fd <fd> | --new-password-file <file>    new password of change-password
+  --from-password-fd <fd> | --from-password-file <file>  password of an imported keystore file
+  --passphrase-fd <fd> | --passphrase-file <file>        BIP39 passphrase, empty when not given
+
+--curve is secp256k1 (default), secp256k1-schnorr, secp256r1 or ed25519, Ed25519 paths are
+hardened and --prehashed Ed25519 signs the data as it is. --json prints one JSON object, errors
+included. The keystore directory is --dir, $CKM_DIR or the working directory";
+
+/// options taking a value
+const OPTIONS: &[&str] = &[
+    "--dir",
+    "--bits",
+    "--keystore",
+    "--input",
+    "--path",
+    "--curve",
+    "--public-key",
+    "--signature",
+    "--password-fd",
+    "--password-file",
+    "--new-password-fd",
+    "--new-password-file",
+    "--from-password-fd",
+    "--from-password-file",
+    "--passphrase-fd",
+    "--passphrase-file",
+];
+
+/// options without a value
+const FLAGS: &[&str] = &[
+    "-h",
+    "--help",
+    "--json",
+    "--mnemonic",
+    "--save",
+    "--seed",
+    "--metadata-only",
+    "--hex",
+    "--prehashed",
+    "--force",
+];
+
+/// how long a key stays unlocked to serve the calls of one command
+const COMMAND_TTL: Duration = Duration::from_secs(60);
+
+type Fields = Vec<(&'static str, Value)>;
+
+enum Failure {
+    Usage(String),
+    Key(CKMError),
+    Other(String),
+}
+
+impl From<CKMError> for Failure {
+    fn from(e: CKMError) -> Self {
+        Failure::Key(e)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: HashSet<String>,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Failure> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if FLAGS.contains(&arg.as_str()) {
+                parsed.flags.insert(arg);
+            } else if OPTIONS.contains(&arg.as_str()) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| Failure::Usage(format!("{} needs a value", arg)))?;
+                parsed.options.insert(arg, value);
+            } else if arg.starts_with('-') {
+                return Err(Failure::Usage(format!("unknown option {}", arg)));
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|value| value.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, Failure> {
+        self.value(name)
+            .ok_or_else(|| Failure::Usage(format!("{} is required", name)))
+    }
+
+    /// the key id of commands on one key
+    fn key_id(&self) -> Result<&str, Failure> {
+        match self.positional.get(1) {
+            Some(key_id) => Ok(key_id),
+            None => Err(Failure::Usage(format!(
+                "{} needs a key id",
+                self.positional[0]
+            ))),
+        }
+    }
+
+    fn curve(&self) -> Result<Curve, Failure> {
+        Ok(Curve::from_str(
+            self.value("--curve").unwrap_or("secp256k1"),
+        )?)
+    }
+
+    /// data of `--input` or stdin, hex decoded with `--hex`
+    fn input(&self) -> Result<Zeroizing<Vec<u8>>, Failure> {
+        let mut data = Zeroizing::new(Vec::new());
+        match self.value("--input") {
+            Some(path) => File::open(path).and_then(|mut file| file.read_to_end(&mut data)),
+            None => std::io::stdin().read_to_end(&mut data),
+        }
+        .map_err(|e| Failure::Other(format!("reading the input: {}", e)))?;
+        if !self.flag("--hex") {
+            return Ok(data);
+        }
+        let text = std::str::from_utf8(&data).map_err(|_e| _not_hex("input"))?;
+        Ok(Zeroizing::new(
+            hex::decode(text.trim()).map_err(|_e| _not_hex("input"))?,
+        ))
+    }
+
+    /// secret of `--<name>-fd` or `--<name>-file`, `None` when neither is given
+    fn secret(&self, name: &str) -> Result<Option<Zeroizing<String>>, Failure> {
+        if let Some(fd) = self.value(&format!("--{}-fd", name)) {
+            let fd = fd
+                .parse()
+                .map_err(|_e| Failure::Usage(format!("--{}-fd takes a number", name)))?;
+            return _read_fd(fd).map(Some);
+        }
+        if let Some(path) = self.value(&format!("--{}-file", name)) {
+            let file = File::open(path).map_err(|e| Failure::Other(format!("{}: {}", path, e)))?;
+            return _first_line(file).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// secret of the options or the terminal, a new secret is prompted twice
+    fn password(&self, name: &str, prompt: &str, new: bool) -> Result<Zeroizing<String>, Failure> {
+        if let Some(secret) = self.secret(name)? {
+            return Ok(secret);
+        }
+        let password = _prompt(prompt)?;
+        if new && *password != *_prompt(&format!("repeat {}", prompt))? {
+            return Err(Failure::Other("the passwords do not match".to_string()));
+        }
+        Ok(password)
+    }
+}
+
+fn main() {
+    let args = Args::parse(std::env::args().skip(1));
+    let json = args
+        .as_ref()
+        .map(|args| args.flag("--json"))
+        .unwrap_or(false);
+    let result = args.and_then(|args| {
+        if args.positional.is_empty() || args.flag("-h") || args.flag("--help") {
+            return Err(Failure::Usage(String::new()));
+        }
+        let dir = args
+            .value("--dir")
+            .map(|dir| dir.to_string())
+            .or_else(|| std::env::var("CKM_DIR").ok());
+        // `LocalKeystore` keeps its files in the working directory
+        if let Some(dir) = dir {
+            std::env::set_current_dir(&dir)
+                .map_err(|e| Failure::Other(format!("{}: {}", dir, e)))?;
+        }
+        run(&args)
+    });
+    match result {
+        Ok((fields, code)) => {
+            _print(json, fields);
+            exit(code)
+        }
+        Err(Failure::Usage(message)) => {
+            if !message.is_empty() {
+                eprintln!("{}", message);
+            }
+            eprintln!("{}", USAGE);
+            exit(2)
+        }
+        Err(failure) => {
+            let (message, code) = match failure {
+                Failure::Key(e) => (e.to_string(), Some(e.code())),
+                Failure::Other(message) => (message, None),
+                Failure::Usage(_) => unreachable!(),
+            };
+            if json {
+                println!("{}", json!({ "error": message, "code": code }));
+            } else {
+                eprintln!("{}", message);
+            }
+            exit(2)
+        }
+    }
+}
+
+/// run the command, returns the output and the exit code
+fn run(args: &Args) -> Result<(Fields, i32), Failure> {
+    let store = LocalKeystore::new();
+    let key_master = KeyMaster::new(store.clone());
+    let fields = match args.positional[0].as_str() {
+        "generate" => generate(args, &key_master)?,
+        "import" => import(args, &store, &key_master)?,
+        "list" => vec![("keys", json!(key_master.list_keys()?))],
+        "show" => show(args, &key_master)?,
+        "sign" => sign(args, &key_master)?,
+        "verify" => {
+            let fields = verify(args)?;
+            let code = if fields[0].1 == json!(true) { 0 } else { 1 };
+            return Ok((fields, code));
+        }
+        "change-password" => {
+            let key_id = args.key_id()?;
+            let password = args.password("password", "password", false)?;
+            let new_password = args.password("new-password", "new password", true)?;
+            store.change_password(key_id, &password, &new_password)?;
+            vec![("key_id", json!(key_id))]
+        }
+        "delete" => {
+            let key_id = args.key_id()?;
+            if !args.flag("--force") {
+                let password = args.password("password", "password", false)?;
+                store.get_key(&password, key_id.to_string())?;
+            }
+            store.delete_key(key_id)?;
+            vec![("deleted", json!(key_id))]
+        }
+        command => return Err(Failure::Usage(format!("unknown command {}", command))),
+    };
+    Ok((fields, 0))
+}
+
+fn generate(args: &Args, key_master: &KeyMaster) -> Result<Fields, Failure> {
+    let bits = match args.value("--bits").unwrap_or("256") {
+        "128" => 128,
+        "256" => 256,
+        _ => return Err(Failure::Usage("--bits is 128 or 256".to_string())),
+    };
+    let entropy = Zeroizing::new(key_master.generate_entropy(bits)?);
+    let mnemonic = Mnemonic::from_entropy(&entropy).map_err(_mnemonic_error)?;
+    let mut fields = Fields::new();
+    if args.flag("--mnemonic") {
+        fields.push(("mnemonic", json!(mnemonic.to_string())));
+    } else {
+        fields.push(("entropy", json!(hex::encode(&*entropy))));
+    }
+    if args.flag("--save") {
+        let key_id = _save_mnemonic(args, key_master, &mnemonic)?;
+        fields.push(("key_id", json!(key_id)));
+    }
+    Ok(fields)
+}
+
+fn import(args: &Args, store: &LocalKeystore, key_master: &KeyMaster) -> Result<Fields, Failure> {
+    let sources = ["--seed", "--mnemonic"]
+        .iter()
+        .filter(|flag| args.flag(flag))
+        .count()
+        + args.value("--keystore").map_or(0, |_| 1);
+    if sources != 1 {
+        return Err(Failure::Usage(
+            "import takes one of --seed, --mnemonic or --keystore".to_string(),
+        ));
+    }
+    let key_id = if let Some(path) = args.value("--keystore") {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Failure::Other(format!("{}: {}", path, e)))?;
+        let password = args.password("from-password", "keystore file password", false)?;
+        let new_password = args.password("password", "new password", true)?;
+        store.import_keystore(&content, &password, &new_password)?
+    } else {
+        let input = args.input()?;
+        let text = std::str::from_utf8(&input)
+            .map_err(|_e| Failure::Other("the input is not text".to_string()))?
+            .trim();
+        if args.flag("--mnemonic") {
+            let mnemonic = Mnemonic::parse(text).map_err(_mnemonic_error)?;
+            _save_mnemonic(args, key_master, &mnemonic)?
+        } else {
+            let seed = Zeroizing::new(hex::decode(text).map_err(|_e| _not_hex("seed"))?);
+            if seed.len() != 64 {
+                return Err(Failure::Other("the seed must be 64 bytes".to_string()));
+            }
+            let password = args.password("password", "new password", true)?;
+            key_master.write_seed(&password, hex::encode(&*seed))?
+        }
+    };
+    Ok(vec![("key_id", json!(key_id))])
+}
+
+fn show(args: &Args, key_master: &KeyMaster) -> Result<Fields, Failure> {
+    let key_id = args.key_id()?;
+    if !key_master.list_keys()?.iter().any(|id| id == key_id) {
+        return Err(Failure::Key(CKMError::NotExist));
+    }
+    let metadata = std::fs::metadata(key_id).map_err(|e| CKMError::Io {
+        path: key_id.into(),
+        source: e.into(),
+    })?;
+    let content = std::fs::read_to_string(key_id).map_err(|e| CKMError::Io {
+        path: key_id.into(),
+        source: e.into(),
+    })?;
+    let keystore: Value = serde_json::from_str(&content).unwrap_or(Value::Null);
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs());
+    let mut fields = vec![
+        ("key_id", json!(key_id)),
+        ("size", json!(metadata.len())),
+        ("modified", json!(modified)),
+        ("cipher", keystore["cipher"].clone()),
+        ("kdf", keystore["kdf"].clone()),
+        ("kdfparams", keystore["kdfparams"].clone()),
+    ];
+    if args.flag("--metadata-only") {
+        return Ok(fields);
+    }
+    let path = args.value("--path").unwrap_or("m");
+    let password = args.password("password", "password", false)?;
+    key_master.unlock(key_id, &password, COMMAND_TTL)?;
+    let result = key_master
+        .get_master_fingerprint(key_id, "")
+        .and_then(|fingerprint| Ok((fingerprint, key_master.get_xpub(key_id, path, "")?)));
+    key_master.lock(key_id);
+    let (fingerprint, xpub) = result?;
+    fields.push(("fingerprint", json!(hex::encode(fingerprint))));
+    fields.push(("path", json!(path)));
+    fields.push(("xpub", json!(xpub)));
+    Ok(fields)
+}
+
+fn sign(args: &Args, key_master: &KeyMaster) -> Result<Fields, Failure> {
+    let key_id = args.key_id()?;
+    let path = args.required("--path")?;
+    let curve = args.curve()?;
+    let data = args.input()?;
+    let password = args.password("password", "password", false)?;
+    // one decryption serves the signature and the public key
+    key_master.unlock(key_id, &password, COMMAND_TTL)?;
+    let result = if args.flag("--prehashed") {
+        key_master.sign_digest(key_id, path, curve, &data, "")
+    } else {
+        let request = SignRequest {
+            path,
+            unsigend_data: data.to_vec(),
+            key_id,
+            curve,
+        };
+        key_master.sign(request, "")
+    }
+    .and_then(|sig| Ok((sig, key_master.get_public_key(key_id, path, curve, "")?)));
+    key_master.lock(key_id);
+    let (sig, public_key) = result?;
+    Ok(vec![
+        ("key_id", json!(key_id)),
+        ("path", json!(path)),
+        ("curve", json!(curve.as_str())),
+        ("public_key", json!(hex::encode(public_key))),
+        ("signature", json!(hex::encode(sig.to_compact()))),
+        ("recovery_id", json!(sig.v)),
+        // DER only encodes ECDSA signatures
+        (
+            "der",
+            match curve {
+                Curve::Secp256k1 | Curve::Secp256R1 => json!(hex::encode(sig.to_der())),
+                _ => serde_json::Value::Null,
+            },
+        ),
+    ])
+}
+
+fn verify(args: &Args) -> Result<Fields, Failure> {
+    let curve = args.curve()?;
+    let public_key =
+        hex::decode(args.required("--public-key")?).map_err(|_e| _not_hex("--public-key"))?;
+    let signature =
+        hex::decode(args.required("--signature")?).map_err(|_e| _not_hex("--signature"))?;
+    let signature = _parse_signature(&signature)?;
+    let data = args.input()?;
+    let valid = if args.flag("--prehashed") {
+        verify_digest(curve, &public_key, &data, &signature)?
+    } else {
+        verify_signature(curve, &public_key, &data, &signature)?
+    };
+    Ok(vec![("valid", json!(valid))])
+}
+
+/// store the BIP39 seed of the mnemonic with the optional passphrase
+fn _save_mnemonic(
+    args: &Args,
+    key_master: &KeyMaster,
+    mnemonic: &Mnemonic,
+) -> Result<String, Failure> {
+    let passphrase = args.secret("passphrase")?.unwrap_or_default();
+    let seed = Zeroizing::new(mnemonic.to_seed(passphrase.as_str()));
+    let password = args.password("password", "new password", true)?;
+    Ok(key_master.write_seed(&password, hex::encode(&seed[..]))?)
+}
+
+/// compact `r || s`, `r || s || v` or DER signature
+fn _parse_signature(bytes: &[u8]) -> Result<SigningSignature, CKMError> {
+    match bytes.len() {
+        64 => SigningSignature::from_compact(bytes),
+        65 => SigningSignature::from_rsv(bytes),
+        _ => SigningSignature::from_der(bytes),
+    }
+}
+
+fn _print(json: bool, fields: Fields) {
+    if json {
+        let object: Map<String, Value> = fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        println!("{}", Value::Object(object));
+        return;
+    }
+    for (name, value) in fields {
+        match value {
+            Value::Null => {}
+            Value::String(value) => println!("{}: {}", name, value),
+            // lists print one item per line
+            Value::Array(items) => {
+                for item in items {
+                    match item {
+                        Value::String(item) => println!("{}", item),
+                        item => println!("{}", item),
+                    }
+                }
+            }
+            value => println!("{}: {}", name, value),
+        }
+    }
+}
+
+fn _not_hex(name: &str) -> Failure {
+    Failure::Other(format!("{} is not hex", name))
+}
+
+fn _mnemonic_error(e: bip39::Error) -> Failure {
+    Failure::Other(format!("mnemonic error {}", e))
+}
+
+/// first line of the reader without the line break
+fn _first_line(reader: impl Read) -> Result<Zeroizing<String>, Failure> {
+    let mut line = Zeroizing::new(String::new());
+    BufReader::new(reader)
+        .read_line(&mut line)
+        .map_err(|e| Failure::Other(format!("reading a secret: {}", e)))?;
+    let end = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(end);
+    Ok(line)
+}
+
+#[cfg(unix)]
+fn _read_fd(fd: i32) -> Result<Zeroizing<String>, Failure> {
+    use std::os::unix::io::FromRawFd;
+    if fd < 3 {
+        return Err(Failure::Usage(
+            "secrets are not read from stdio".to_string(),
+        ));
+    }
+    // the descriptor was handed to this process for this read, it is closed afterwards
+    _first_line(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn _read_fd(_fd: i32) -> Result<Zeroizing<String>, Failure> {
+    Err(Failure::Usage(
+        "file descriptors are only supported on unix".to_string(),
+    ))
+}
+
+/// prompt on the terminal without echo
+#[cfg(unix)]
+fn _prompt(prompt: &str) -> Result<Zeroizing<String>, Failure> {
+    use std::os::unix::io::AsRawFd;
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| Failure::Other(format!("no terminal to prompt for the {}: {}", prompt, e)))?;
+    write!(tty, "{}: ", prompt)
+        .and_then(|_| tty.flush())
+        .map_err(|e| Failure::Other(e.to_string()))?;
+    let fd = tty.as_raw_fd();
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut term) } != 0 {
+        return Err(Failure::Other(
+            "the terminal can not hide the input".to_string(),
+        ));
+    }
+    let echo = term;
+    term.c_lflag &= !libc::ECHO;
+    term.c_lflag |= libc::ECHONL;
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+    let line = _first_line(&tty);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &echo) };
+    line
+}
+
+#[cfg(not(unix))]
+fn _prompt(prompt: &str) -> Result<Zeroizing<String>, Failure> {
+    eprint!("{}: ", prompt);
+    _first_line(std::io::stdin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        match Args::parse(line.split_whitespace().map(|arg| arg.to_string())) {
+            Ok(args) => args,
+            Err(_e) => panic!("{} does not parse", line),
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        let parsed = args("sign abc --path m/0 --json --password-file pw --hex");
+        assert_eq!(parsed.positional, vec!["sign", "abc"]);
+        assert_eq!(parsed.key_id().ok(), Some("abc"));
+        assert_eq!(parsed.value("--path"), Some("m/0"));
+        assert!(parsed.flag("--json") && parsed.flag("--hex"));
+        assert!(matches!(parsed.curve(), Ok(Curve::Secp256k1)));
+        assert!(matches!(
+            args("sign abc --curve ed25519").curve(),
+            Ok(Curve::Ed25519)
+        ));
+        assert!(matches!(
+            args("sign abc --curve p256").curve(),
+            Ok(Curve::Secp256R1)
+        ));
+        assert!(matches!(
+            args("sign abc --curve ed448").curve(),
+            Err(Failure::Key(CKMError::UnsupportedCurve))
+        ));
+        assert!(matches!(
+            Args::parse(vec!["--path".to_string()]),
+            Err(Failure::Usage(_))
+        ));
+        // a mistyped option is not taken as the key id or a value
+        for line in ["sign abc --pth m/0", "list -j", "delete abc --froce"].iter() {
+            assert!(matches!(
+                Args::parse(line.split_whitespace().map(|arg| arg.to_string())),
+                Err(Failure::Usage(_))
+            ));
+        }
+        assert!(matches!(args("show").key_id(), Err(Failure::Usage(_))));
+    }
+
+    #[test]
+    fn test_secrets() {
+        let path = std::env::temp_dir().join(format!("ckm-test-{}", std::process::id()));
+        std::fs::write(&path, "secret\r\nsecond line").unwrap();
+        let parsed = args(&format!("list --password-file {}", path.display()));
+        let password = parsed.password("password", "password", true).ok().unwrap();
+        assert_eq!(password.as_str(), "secret");
+        assert!(parsed.secret("passphrase").ok().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            args("list --password-fd 0").secret("password"),
+            Err(Failure::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_signature() {
+        let sig = SigningSignature {
+            r: [1u8; 32],
+            s: [2u8; 32],
+            v: Some(1),
+        };
+        let compact = _parse_signature(&sig.to_compact()).unwrap();
+        assert_eq!((compact.r, compact.s, compact.v), (sig.r, sig.s, None));
+        assert_eq!(_parse_signature(&sig.to_rsv().unwrap()).unwrap(), sig);
+        assert_eq!(_parse_signature(&sig.to_der()).unwrap().r, sig.r);
+    }
+}
@@ -0,0 +1,424 @@
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use crate::curve::{
+    k1::{k1_public_key, k1_recover_public_key, k1_sign_digest, k1_verify_digest},
+    schnorr::{schnorr_sign, schnorr_verify, tagged_hash, tweak_secret_key, x_only_public_key},
+};
+use crate::CKMError;
+
+use bitcoin::base64::{prelude::BASE64_STANDARD, Engine};
+use bitcoin::blockdata::opcodes::all::{OP_PUSHBYTES_0, OP_RETURN};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::key::TapTweak as _;
+use bitcoin::script::Builder;
+use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+use bitcoin::{
+    absolute, ecdsa, secp256k1, transaction, Address, Amount, CompressedPublicKey, Network,
+    OutPoint, PubkeyHash, ScriptBuf, ScriptHash, Sequence, Transaction, TxIn, TxOut, Txid,
+    WPubkeyHash, Witness, XOnlyPublicKey,
+};
+
+const MESSAGE_PREFIX: &[u8] = b"\x18Bitcoin Signed Message:\n";
+
+/// bitcoin address types a message can be signed for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+/// bitcoin signed message formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    /// BIP137 compact signature with the header byte, for P2PKH, P2SH-P2WPKH and P2WPKH
+    Bip137,
+    /// BIP322 simple signature, for P2WPKH and P2TR
+    Bip322Simple,
+}
+
+/// sign the message for the address of the private key, returns the base64 signature
+pub(crate) fn sign_message(
+    key: &[u8],
+    message: &[u8],
+    address_type: AddressType,
+    format: MessageFormat,
+) -> Result<String, CKMError> {
+    let signature = match format {
+        MessageFormat::Bip137 => _bip137_sign(key, message, address_type)?,
+        MessageFormat::Bip322Simple => _bip322_sign(key, message, address_type)?,
+    };
+    Ok(BASE64_STANDARD.encode(signature))
+}
+
+/// bitcoin address of the private key
+pub(crate) fn address(
+    key: &[u8],
+    address_type: AddressType,
+    network: Network,
+) -> Result<String, CKMError> {
+    let script = _script_pubkey(key, address_type)?;
+    let address = Address::from_script(&script, network)
+        .map_err(|e| CKMError::MessageError(e.to_string()))?;
+    Ok(address.to_string())
+}
+
+/// verify a BIP137 or BIP322 simple base64 signature of the message for the address
+pub fn verify_message(address: &str, message: &[u8], signature: &str) -> Result<bool, CKMError> {
+    let address = Address::from_str(address)
+        .map_err(|e| CKMError::MessageError(e.to_string()))?
+        .assume_checked();
+    let script = address.script_pubkey();
+    let signature = BASE64_STANDARD
+        .decode(signature.trim())
+        .map_err(|_e| CKMError::MessageError("invalid base64 signature".to_string()))?;
+    if signature.len() == 65 && (27..=42).contains(&signature[0]) {
+        return _bip137_verify(&script, message, &signature);
+    }
+    _bip322_verify(&script, message, &signature)
+}
+
+fn _bip137_sign(
+    key: &[u8],
+    message: &[u8],
+    address_type: AddressType,
+) -> Result<Vec<u8>, CKMError> {
+    let flag = match address_type {
+        AddressType::P2pkh => 31,
+        AddressType::P2shP2wpkh => 35,
+        AddressType::P2wpkh => 39,
+        AddressType::P2tr => {
+            return Err(CKMError::MessageError(
+                "BIP137 does not support taproot addresses".to_string(),
+            ))
+        }
+    };
+    let digest = _signed_message_hash(message);
+    let (sig, recovery_id) = k1_sign_digest(key, &digest)?;
+    let mut signature = vec![flag + recovery_id];
+    signature.extend_from_slice(&sig);
+    Ok(signature)
+}
+
+fn _bip137_verify(script: &ScriptBuf, message: &[u8], signature: &[u8]) -> Result<bool, CKMError> {
+    let header = signature[0];
+    let recovery_id = (header - 27) % 4;
+    let sig: [u8; 64] = signature[1..].try_into().unwrap();
+    let digest = _signed_message_hash(message);
+    let public_key = match k1_recover_public_key(&digest, &sig, recovery_id, header >= 31) {
+        Ok(public_key) => public_key,
+        Err(_e) => return Ok(false),
+    };
+    let candidates = match header {
+        27..=30 => vec![ScriptBuf::new_p2pkh(&PubkeyHash::hash(&public_key))],
+        // compressed P2PKH flags are also used by wallets signing for segwit addresses
+        31..=34 => {
+            let key = _compressed_key(&public_key)?;
+            vec![
+                ScriptBuf::new_p2pkh(&key.pubkey_hash()),
+                ScriptBuf::new_p2sh(&ScriptHash::hash(
+                    ScriptBuf::new_p2wpkh(&key.wpubkey_hash()).as_bytes(),
+                )),
+                ScriptBuf::new_p2wpkh(&key.wpubkey_hash()),
+            ]
+        }
+        35..=38 => {
+            let key = _compressed_key(&public_key)?;
+            vec![ScriptBuf::new_p2sh(&ScriptHash::hash(
+                ScriptBuf::new_p2wpkh(&key.wpubkey_hash()).as_bytes(),
+            ))]
+        }
+        _ => vec![ScriptBuf::new_p2wpkh(
+            &_compressed_key(&public_key)?.wpubkey_hash(),
+        )],
+    };
+    Ok(candidates.contains(script))
+}
+
+fn _bip322_sign(
+    key: &[u8],
+    message: &[u8],
+    address_type: AddressType,
+) -> Result<Vec<u8>, CKMError> {
+    let script = _script_pubkey(key, address_type)?;
+    let to_spend = _to_spend(&script, message);
+    let to_sign = _to_sign(&to_spend);
+    let mut cache = SighashCache::new(&to_sign);
+    let witness = match address_type {
+        AddressType::P2wpkh => {
+            let sighash = cache
+                .p2wpkh_signature_hash(0, &script, Amount::ZERO, EcdsaSighashType::All)
+                .map_err(|e| CKMError::MessageError(e.to_string()))?;
+            let (sig, _) = k1_sign_digest(key, sighash.as_byte_array())?;
+            let signature = ecdsa::Signature {
+                signature: secp256k1::ecdsa::Signature::from_compact(&sig)
+                    .map_err(|_e| CKMError::SigningError)?,
+                sighash_type: EcdsaSighashType::All,
+            };
+            let public_key = _compressed_key(&k1_public_key(key)?)?;
+            Witness::p2wpkh(&signature, &public_key.0)
+        }
+        AddressType::P2tr => {
+            let sighash = cache
+                .taproot_key_spend_signature_hash(
+                    0,
+                    &Prevouts::All(&to_spend.output),
+                    TapSighashType::Default,
+                )
+                .map_err(|e| CKMError::MessageError(e.to_string()))?;
+            let tweaked = tweak_secret_key(key, None)?;
+            let sig = schnorr_sign(&tweaked, sighash.as_byte_array(), None)?;
+            Witness::from_slice(&[sig.to_vec()])
+        }
+        _ => {
+            return Err(CKMError::MessageError(
+                "BIP322 simple signatures support P2WPKH and P2TR addresses".to_string(),
+            ))
+        }
+    };
+    Ok(bitcoin::consensus::serialize(&witness))
+}
+
+fn _bip322_verify(script: &ScriptBuf, message: &[u8], signature: &[u8]) -> Result<bool, CKMError> {
+    let witness: Witness = match bitcoin::consensus::deserialize(signature) {
+        Ok(witness) => witness,
+        Err(_e) => return Ok(false),
+    };
+    let to_spend = _to_spend(script, message);
+    let to_sign = _to_sign(&to_spend);
+    let mut cache = SighashCache::new(&to_sign);
+    if script.is_p2wpkh() {
+        if witness.len() != 2 {
+            return Ok(false);
+        }
+        let public_key = witness.nth(1).unwrap();
+        if public_key.len() != 33
+            || WPubkeyHash::hash(public_key).as_byte_array()[..] != script.as_bytes()[2..]
+        {
+            return Ok(false);
+        }
+        let signature = match ecdsa::Signature::from_slice(witness.nth(0).unwrap()) {
+            Ok(signature) => signature,
+            Err(_e) => return Ok(false),
+        };
+        let sighash = cache
+            .p2wpkh_signature_hash(0, script, Amount::ZERO, signature.sighash_type)
+            .map_err(|e| CKMError::MessageError(e.to_string()))?;
+        let sig = signature.signature.serialize_compact();
+        Ok(k1_verify_digest(public_key, sighash.as_byte_array(), &sig))
+    } else if script.is_p2tr() {
+        if witness.len() != 1 {
+            return Ok(false);
+        }
+        let signature = match bitcoin::taproot::Signature::from_slice(witness.nth(0).unwrap()) {
+            Ok(signature) => signature,
+            Err(_e) => return Ok(false),
+        };
+        let sighash = cache
+            .taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&to_spend.output),
+                signature.sighash_type,
+            )
+            .map_err(|e| CKMError::MessageError(e.to_string()))?;
+        let output_key = &script.as_bytes()[2..];
+        Ok(schnorr_verify(
+            output_key,
+            sighash.as_byte_array(),
+            signature.signature.as_ref(),
+        ))
+    } else {
+        Err(CKMError::MessageError(
+            "BIP322 simple signatures support P2WPKH and P2TR addresses".to_string(),
+        ))
+    }
+}
+
+fn _signed_message_hash(message: &[u8]) -> [u8; 32] {
+    let mut data = MESSAGE_PREFIX.to_vec();
+    data.extend(bitcoin::consensus::serialize(&bitcoin::VarInt(
+        message.len() as u64,
+    )));
+    data.extend_from_slice(message);
+    sha256d::Hash::hash(&data).to_byte_array()
+}
+
+fn _to_spend(script: &ScriptBuf, message: &[u8]) -> Transaction {
+    let message_hash = tagged_hash("BIP0322-signed-message", message);
+    let script_sig = Builder::new()
+        .push_opcode(OP_PUSHBYTES_0)
+        .push_slice(message_hash)
+        .into_script();
+    Transaction {
+        version: transaction::Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0xffff_ffff,
+            },
+            script_sig,
+            sequence: Sequence(0),
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script.clone(),
+        }],
+    }
+}
+
+fn _to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: transaction::Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.compute_txid(),
+                vout: 0,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence(0),
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+fn _script_pubkey(key: &[u8], address_type: AddressType) -> Result<ScriptBuf, CKMError> {
+    let script = match address_type {
+        AddressType::P2pkh => {
+            ScriptBuf::new_p2pkh(&_compressed_key(&k1_public_key(key)?)?.pubkey_hash())
+        }
+        AddressType::P2shP2wpkh => {
+            let witness_program =
+                ScriptBuf::new_p2wpkh(&_compressed_key(&k1_public_key(key)?)?.wpubkey_hash());
+            ScriptBuf::new_p2sh(&ScriptHash::hash(witness_program.as_bytes()))
+        }
+        AddressType::P2wpkh => {
+            ScriptBuf::new_p2wpkh(&_compressed_key(&k1_public_key(key)?)?.wpubkey_hash())
+        }
+        AddressType::P2tr => {
+            let output_key = x_only_public_key(&tweak_secret_key(key, None)?)?;
+            let output_key =
+                XOnlyPublicKey::from_slice(&output_key).map_err(|_e| CKMError::SigningError)?;
+            ScriptBuf::new_p2tr_tweaked(output_key.dangerous_assume_tweaked())
+        }
+    };
+    Ok(script)
+}
+
+fn _compressed_key(public_key: &[u8]) -> Result<CompressedPublicKey, CKMError> {
+    CompressedPublicKey::from_slice(public_key).map_err(|_e| CKMError::SigningError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::k1::derive_from_seed;
+    use crate::keystore::fake::FakeKeystore;
+    use crate::Keystore;
+
+    // BIP322 test vector key
+    fn bip322_key() -> Vec<u8> {
+        bitcoin::PrivateKey::from_wif("L3VFeEujGtevx9w18HD1fhRbCH67Az2dpCymeRE1SoPK6XQtaN2k")
+            .unwrap()
+            .inner
+            .secret_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_bip322_message_hash() {
+        assert_eq!(
+            hex::encode(tagged_hash("BIP0322-signed-message", b"")),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            hex::encode(tagged_hash("BIP0322-signed-message", b"Hello World")),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn test_bip322_p2wpkh_vectors() {
+        let key = bip322_key();
+        let address = address(&key, AddressType::P2wpkh, Network::Bitcoin).unwrap();
+        assert_eq!(address, "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l");
+
+        let vectors = [
+            ("", "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI="),
+            ("Hello World", "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI="),
+        ];
+        // the reference signatures use a grinded nonce, so check them by verification
+        for (message, signature) in vectors.iter() {
+            let sig = sign_message(
+                &key,
+                message.as_bytes(),
+                AddressType::P2wpkh,
+                MessageFormat::Bip322Simple,
+            )
+            .unwrap();
+            assert!(verify_message(&address, message.as_bytes(), &sig).unwrap());
+            assert!(verify_message(&address, message.as_bytes(), signature).unwrap());
+        }
+        assert!(!verify_message(&address, b"Hello", vectors[1].1).unwrap());
+    }
+
+    #[test]
+    fn test_bip322_p2tr() {
+        let key = bip322_key();
+        let address = address(&key, AddressType::P2tr, Network::Bitcoin).unwrap();
+        assert_eq!(
+            address,
+            "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3"
+        );
+        let vector = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        assert!(verify_message(&address, b"Hello World", vector).unwrap());
+
+        let sig = sign_message(
+            &key,
+            b"Hello World",
+            AddressType::P2tr,
+            MessageFormat::Bip322Simple,
+        )
+        .unwrap();
+        assert!(verify_message(&address, b"Hello World", &sig).unwrap());
+        assert!(!verify_message(&address, b"Hello", &sig).unwrap());
+    }
+
+    #[test]
+    fn test_bip137() {
+        // legacy uncompressed key signature
+        assert!(verify_message(
+            "1HZwkjkeaoZfTSaJxDw6aKkxp45agDiEzN",
+            b"This is an example of a signed message.",
+            "G9L5yLFjti0QTHhPyFrZCT1V/MMnBtXKmoiKDZ78NDBjERki6ZTQZdSMCtkgoNmp17By9ItJr8o7ChX0XxY91nk="
+        )
+        .unwrap());
+
+        let seed = FakeKeystore {}
+            .get_key("pass", "123456".to_string())
+            .unwrap();
+        let key = derive_from_seed(&seed, "m/84'/0'/0'/0/0").unwrap();
+        for address_type in [
+            AddressType::P2pkh,
+            AddressType::P2shP2wpkh,
+            AddressType::P2wpkh,
+        ] {
+            let address = address(&key, address_type, Network::Bitcoin).unwrap();
+            let sig = sign_message(&key, b"hello", address_type, MessageFormat::Bip137).unwrap();
+            assert!(verify_message(&address, b"hello", &sig).unwrap());
+            assert!(!verify_message(&address, b"hello!", &sig).unwrap());
+        }
+        assert_eq!(
+            address(&key, AddressType::P2wpkh, Network::Bitcoin).unwrap(),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+    }
+}
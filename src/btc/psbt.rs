@@ -0,0 +1,687 @@
+use std::convert::TryInto;
+
+use crate::curve::{
+    k1::{derive_from_seed, k1_public_key, k1_sign_digest, master_fingerprint},
+    schnorr::{schnorr_sign, tweak_secret_key, x_only_public_key},
+};
+use crate::CKMError;
+
+use bitcoin::bip32::{DerivationPath, KeySource};
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::{Psbt, PsbtSighashType};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::{ecdsa, secp256k1, taproot, PublicKey, TapLeafHash, TxOut, XOnlyPublicKey};
+use hex::encode;
+
+const PSBT_MAGIC: &[u8; 5] = b"psbt\xff";
+
+// PSBT_GLOBAL_UNSIGNED_TX and the PSBTv2 global, input and output fields
+const GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const GLOBAL_TX_VERSION: u8 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const GLOBAL_INPUT_COUNT: u8 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const GLOBAL_VERSION: u8 = 0xfb;
+const IN_PREVIOUS_TXID: u8 = 0x0e;
+const IN_OUTPUT_INDEX: u8 = 0x0f;
+const IN_SEQUENCE: u8 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const OUT_AMOUNT: u8 = 0x03;
+const OUT_SCRIPT: u8 = 0x04;
+
+type Map = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// key-value maps of a PSBT, without interpreting them
+#[derive(Debug, Clone, Default, PartialEq)]
+struct RawPsbt {
+    global: Map,
+    inputs: Vec<Map>,
+    outputs: Vec<Map>,
+}
+
+/// sign the inputs of a serialized PSBT (BIP174 version 0 or BIP370 version 2) that have a key
+/// of the seed, and return the PSBT in the same version. Inputs of other signers are left
+/// untouched, a PSBT without any input of the seed is an error
+pub(crate) fn sign_psbt(psbt: &[u8], seed: &[u8]) -> Result<Vec<u8>, CKMError> {
+    let raw = _parse_raw(psbt)?;
+    match _version(&raw)? {
+        0 => {
+            let mut psbt = _deserialize(psbt)?;
+            _sign(&mut psbt, seed)?;
+            Ok(psbt.serialize())
+        }
+        2 => {
+            let v0 = _v2_to_v0(&raw)?;
+            let mut psbt = _deserialize(&_serialize_raw(&v0))?;
+            _sign(&mut psbt, seed)?;
+            let signed = _parse_raw(&psbt.serialize())?;
+            let mut result = raw;
+            for (index, input) in result.inputs.iter_mut().enumerate() {
+                for entry in signed.inputs[index].iter() {
+                    if !v0.inputs[index].contains(entry) {
+                        input.push(entry.clone());
+                    }
+                }
+            }
+            Ok(_serialize_raw(&result))
+        }
+        version => Err(CKMError::PsbtError(format!(
+            "unsupported psbt version {}",
+            version
+        ))),
+    }
+}
+
+fn _sign(psbt: &mut Psbt, seed: &[u8]) -> Result<(), CKMError> {
+    let fingerprint = master_fingerprint(seed)?;
+    let mut cache = SighashCache::new(psbt.unsigned_tx.clone());
+    let mut owned = false;
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+        let ecdsa_keys: Vec<(secp256k1::PublicKey, DerivationPath)> = input
+            .bip32_derivation
+            .iter()
+            .filter(|(_, source)| _owned(source, &fingerprint))
+            .map(|(key, (_, path))| (*key, path.clone()))
+            .collect();
+        let taproot_keys: Vec<(XOnlyPublicKey, Vec<TapLeafHash>, DerivationPath)> = input
+            .tap_key_origins
+            .iter()
+            .filter(|(_, (_, source))| _owned(source, &fingerprint))
+            .map(|(key, (leaves, (_, path)))| (*key, leaves.clone(), path.clone()))
+            .collect();
+        if ecdsa_keys.is_empty() && taproot_keys.is_empty() {
+            // an input of another signer of the transaction
+            continue;
+        }
+        owned = true;
+
+        for (public_key, path) in ecdsa_keys {
+            let key = derive_from_seed(seed, &_path_string(&path))?;
+            if k1_public_key(&key)? != public_key.serialize() {
+                return Err(CKMError::PsbtError(format!(
+                    "input {} key does not match the derivation path",
+                    index
+                )));
+            }
+            let (message, sighash_type) = psbt
+                .sighash_ecdsa(index, &mut cache)
+                .map_err(|e| CKMError::PsbtError(e.to_string()))?;
+            let (sig, _) = k1_sign_digest(&key, message.as_ref())?;
+            let signature = secp256k1::ecdsa::Signature::from_compact(&sig)
+                .map_err(|_e| CKMError::SigningError)?;
+            psbt.inputs[index].partial_sigs.insert(
+                PublicKey::new(public_key),
+                ecdsa::Signature {
+                    signature,
+                    sighash_type,
+                },
+            );
+        }
+
+        for (public_key, leaves, path) in taproot_keys {
+            let key = derive_from_seed(seed, &_path_string(&path))?;
+            if x_only_public_key(&key)? != public_key.serialize() {
+                return Err(CKMError::PsbtError(format!(
+                    "input {} key does not match the derivation path",
+                    index
+                )));
+            }
+            if leaves.is_empty() {
+                if psbt.inputs[index].tap_internal_key != Some(public_key) {
+                    continue;
+                }
+                let merkle_root = psbt.inputs[index]
+                    .tap_merkle_root
+                    .map(|root| root.to_byte_array());
+                let key = tweak_secret_key(&key, merkle_root.as_ref())?;
+                let (sighash, sighash_type) = _taproot_sighash(psbt, index, &mut cache, None)?;
+                let signature = _taproot_signature(&key, &sighash, sighash_type)?;
+                psbt.inputs[index].tap_key_sig = Some(signature);
+            } else {
+                for leaf in leaves {
+                    let (sighash, sighash_type) =
+                        _taproot_sighash(psbt, index, &mut cache, Some(leaf))?;
+                    let signature = _taproot_signature(&key, &sighash, sighash_type)?;
+                    psbt.inputs[index]
+                        .tap_script_sigs
+                        .insert((public_key, leaf), signature);
+                }
+            }
+        }
+    }
+    if !owned {
+        return Err(CKMError::PsbtError(format!(
+            "no input has a key with fingerprint {}",
+            encode(fingerprint)
+        )));
+    }
+    Ok(())
+}
+
+fn _owned(source: &KeySource, fingerprint: &[u8; 4]) -> bool {
+    source.0.as_bytes() == fingerprint
+}
+
+fn _path_string(path: &DerivationPath) -> String {
+    let mut result = "m".to_string();
+    for child in path {
+        result.push_str(&format!("/{}", child));
+    }
+    result
+}
+
+fn _taproot_signature(
+    key: &[u8],
+    sighash: &[u8; 32],
+    sighash_type: TapSighashType,
+) -> Result<taproot::Signature, CKMError> {
+    let sig = schnorr_sign(key, sighash, None)?;
+    let signature =
+        secp256k1::schnorr::Signature::from_slice(&sig).map_err(|_e| CKMError::SigningError)?;
+    Ok(taproot::Signature {
+        signature,
+        sighash_type,
+    })
+}
+
+fn _taproot_sighash(
+    psbt: &Psbt,
+    index: usize,
+    cache: &mut SighashCache<bitcoin::Transaction>,
+    leaf: Option<TapLeafHash>,
+) -> Result<([u8; 32], TapSighashType), CKMError> {
+    let sighash_type = psbt.inputs[index]
+        .sighash_type
+        .unwrap_or_else(|| PsbtSighashType::from(TapSighashType::Default))
+        .taproot_hash_ty()
+        .map_err(|e| CKMError::PsbtError(e.to_string()))?;
+    let spend_utxo = |i| {
+        psbt.spend_utxo(i)
+            .cloned()
+            .map_err(|e| CKMError::PsbtError(e.to_string()))
+    };
+    let utxos: Vec<TxOut>;
+    let prevouts = match sighash_type {
+        TapSighashType::AllPlusAnyoneCanPay
+        | TapSighashType::NonePlusAnyoneCanPay
+        | TapSighashType::SinglePlusAnyoneCanPay => Prevouts::One(index, spend_utxo(index)?),
+        _ => {
+            utxos = (0..psbt.inputs.len())
+                .map(spend_utxo)
+                .collect::<Result<_, _>>()?;
+            Prevouts::All(&utxos)
+        }
+    };
+    let sighash = match leaf {
+        Some(leaf) => cache
+            .taproot_script_spend_signature_hash(index, &prevouts, leaf, sighash_type)
+            .map(|hash| hash.to_byte_array()),
+        None => cache
+            .taproot_key_spend_signature_hash(index, &prevouts, sighash_type)
+            .map(|hash| hash.to_byte_array()),
+    }
+    .map_err(|e| CKMError::PsbtError(e.to_string()))?;
+    Ok((sighash, sighash_type))
+}
+
+fn _deserialize(psbt: &[u8]) -> Result<Psbt, CKMError> {
+    Psbt::deserialize(psbt).map_err(|e| CKMError::PsbtError(e.to_string()))
+}
+
+fn _version(raw: &RawPsbt) -> Result<u32, CKMError> {
+    match _get(&raw.global, GLOBAL_VERSION) {
+        Some(value) => _read_u32(value),
+        None => Ok(0),
+    }
+}
+
+/// rebuild the unsigned transaction of a PSBTv2 and drop the version 2 only fields
+fn _v2_to_v0(raw: &RawPsbt) -> Result<RawPsbt, CKMError> {
+    let missing = |field: &str| CKMError::PsbtError(format!("psbt v2 is missing {}", field));
+
+    let version = _get(&raw.global, GLOBAL_TX_VERSION).ok_or_else(|| missing("tx version"))?;
+    let mut tx = _read_u32(version)?.to_le_bytes().to_vec();
+    _write_compact_size(&mut tx, raw.inputs.len() as u64);
+    for input in raw.inputs.iter() {
+        let txid = _get(input, IN_PREVIOUS_TXID).ok_or_else(|| missing("previous txid"))?;
+        if txid.len() != 32 {
+            return Err(CKMError::PsbtError("invalid previous txid".to_string()));
+        }
+        let vout = _get(input, IN_OUTPUT_INDEX).ok_or_else(|| missing("output index"))?;
+        let sequence = match _get(input, IN_SEQUENCE) {
+            Some(sequence) => _read_u32(sequence)?,
+            None => 0xffff_ffff,
+        };
+        tx.extend_from_slice(txid);
+        tx.extend_from_slice(&_read_u32(vout)?.to_le_bytes());
+        tx.push(0x00);
+        tx.extend_from_slice(&sequence.to_le_bytes());
+    }
+    _write_compact_size(&mut tx, raw.outputs.len() as u64);
+    for output in raw.outputs.iter() {
+        let amount = _get(output, OUT_AMOUNT).ok_or_else(|| missing("output amount"))?;
+        if amount.len() != 8 {
+            return Err(CKMError::PsbtError("invalid output amount".to_string()));
+        }
+        let script = _get(output, OUT_SCRIPT).ok_or_else(|| missing("output script"))?;
+        tx.extend_from_slice(amount);
+        _write_compact_size(&mut tx, script.len() as u64);
+        tx.extend_from_slice(script);
+    }
+    tx.extend_from_slice(&_locktime(raw)?.to_le_bytes());
+
+    let mut global = vec![(vec![GLOBAL_UNSIGNED_TX], tx)];
+    global.extend(
+        raw.global
+            .iter()
+            .filter(|(key, _)| {
+                ![
+                    GLOBAL_TX_VERSION,
+                    GLOBAL_FALLBACK_LOCKTIME,
+                    GLOBAL_INPUT_COUNT,
+                    GLOBAL_OUTPUT_COUNT,
+                    GLOBAL_TX_MODIFIABLE,
+                    GLOBAL_VERSION,
+                ]
+                .contains(&key[0])
+            })
+            .cloned(),
+    );
+    let inputs = raw
+        .inputs
+        .iter()
+        .map(|input| {
+            input
+                .iter()
+                .filter(|(key, _)| {
+                    !(IN_PREVIOUS_TXID..=IN_REQUIRED_HEIGHT_LOCKTIME).contains(&key[0])
+                })
+                .cloned()
+                .collect()
+        })
+        .collect();
+    let outputs = raw
+        .outputs
+        .iter()
+        .map(|output| {
+            output
+                .iter()
+                .filter(|(key, _)| ![OUT_AMOUNT, OUT_SCRIPT].contains(&key[0]))
+                .cloned()
+                .collect()
+        })
+        .collect();
+    Ok(RawPsbt {
+        global,
+        inputs,
+        outputs,
+    })
+}
+
+/// BIP370 locktime determination
+fn _locktime(raw: &RawPsbt) -> Result<u32, CKMError> {
+    let mut heights = Vec::new();
+    let mut times = Vec::new();
+    let mut can_use_height = true;
+    let mut can_use_time = true;
+    for input in raw.inputs.iter() {
+        let height = _get(input, IN_REQUIRED_HEIGHT_LOCKTIME);
+        let time = _get(input, IN_REQUIRED_TIME_LOCKTIME);
+        if height.is_none() && time.is_none() {
+            continue;
+        }
+        match height {
+            Some(height) => heights.push(_read_u32(height)?),
+            None => can_use_height = false,
+        }
+        match time {
+            Some(time) => times.push(_read_u32(time)?),
+            None => can_use_time = false,
+        }
+    }
+    if heights.is_empty() && times.is_empty() {
+        return match _get(&raw.global, GLOBAL_FALLBACK_LOCKTIME) {
+            Some(locktime) => _read_u32(locktime),
+            None => Ok(0),
+        };
+    }
+    if can_use_height {
+        Ok(heights.into_iter().max().unwrap_or(0))
+    } else if can_use_time {
+        Ok(times.into_iter().max().unwrap_or(0))
+    } else {
+        Err(CKMError::PsbtError(
+            "inputs require incompatible locktimes".to_string(),
+        ))
+    }
+}
+
+fn _get(map: &[(Vec<u8>, Vec<u8>)], key_type: u8) -> Option<&[u8]> {
+    map.iter()
+        .find(|(key, _)| key.len() == 1 && key[0] == key_type)
+        .map(|(_, value)| value.as_slice())
+}
+
+fn _read_u32(value: &[u8]) -> Result<u32, CKMError> {
+    let bytes: [u8; 4] = value
+        .try_into()
+        .map_err(|_e| CKMError::PsbtError("invalid 32 bits value".to_string()))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn _parse_raw(bytes: &[u8]) -> Result<RawPsbt, CKMError> {
+    if bytes.len() < PSBT_MAGIC.len() || &bytes[..PSBT_MAGIC.len()] != PSBT_MAGIC {
+        return Err(CKMError::PsbtError("invalid psbt magic".to_string()));
+    }
+    let mut cursor = &bytes[PSBT_MAGIC.len()..];
+    let global = _read_map(&mut cursor)?;
+    let (input_count, output_count) = match _get(&global, GLOBAL_UNSIGNED_TX) {
+        Some(tx) => {
+            let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(tx)
+                .map_err(|e| CKMError::PsbtError(e.to_string()))?;
+            (tx.input.len() as u64, tx.output.len() as u64)
+        }
+        None => {
+            let count = |key_type| -> Result<u64, CKMError> {
+                let mut value = _get(&global, key_type)
+                    .ok_or_else(|| CKMError::PsbtError("psbt is missing counts".to_string()))?;
+                _read_compact_size(&mut value)
+            };
+            (count(GLOBAL_INPUT_COUNT)?, count(GLOBAL_OUTPUT_COUNT)?)
+        }
+    };
+    let mut inputs = Vec::new();
+    for _ in 0..input_count {
+        inputs.push(_read_map(&mut cursor)?);
+    }
+    let mut outputs = Vec::new();
+    for _ in 0..output_count {
+        outputs.push(_read_map(&mut cursor)?);
+    }
+    if !cursor.is_empty() {
+        return Err(CKMError::PsbtError("trailing psbt data".to_string()));
+    }
+    Ok(RawPsbt {
+        global,
+        inputs,
+        outputs,
+    })
+}
+
+fn _serialize_raw(raw: &RawPsbt) -> Vec<u8> {
+    let mut bytes = PSBT_MAGIC.to_vec();
+    for map in std::iter::once(&raw.global)
+        .chain(raw.inputs.iter())
+        .chain(raw.outputs.iter())
+    {
+        for (key, value) in map {
+            _write_compact_size(&mut bytes, key.len() as u64);
+            bytes.extend_from_slice(key);
+            _write_compact_size(&mut bytes, value.len() as u64);
+            bytes.extend_from_slice(value);
+        }
+        bytes.push(0x00);
+    }
+    bytes
+}
+
+fn _read_map(cursor: &mut &[u8]) -> Result<Map, CKMError> {
+    let mut map = Vec::new();
+    loop {
+        let key_len = _read_compact_size(cursor)? as usize;
+        if key_len == 0 {
+            return Ok(map);
+        }
+        let key = _take(cursor, key_len)?.to_vec();
+        let value_len = _read_compact_size(cursor)? as usize;
+        let value = _take(cursor, value_len)?.to_vec();
+        map.push((key, value));
+    }
+}
+
+fn _take<'a>(cursor: &mut &'a [u8], len: usize) -> Result<&'a [u8], CKMError> {
+    if cursor.len() < len {
+        return Err(CKMError::PsbtError("unexpected end of psbt".to_string()));
+    }
+    let (head, tail) = cursor.split_at(len);
+    *cursor = tail;
+    Ok(head)
+}
+
+fn _read_compact_size(cursor: &mut &[u8]) -> Result<u64, CKMError> {
+    let first = _take(cursor, 1)?[0];
+    let size = match first {
+        0xfd => u16::from_le_bytes(_take(cursor, 2)?.try_into().unwrap()) as u64,
+        0xfe => u32::from_le_bytes(_take(cursor, 4)?.try_into().unwrap()) as u64,
+        0xff => u64::from_le_bytes(_take(cursor, 8)?.try_into().unwrap()),
+        size => size as u64,
+    };
+    Ok(size)
+}
+
+fn _write_compact_size(bytes: &mut Vec<u8>, size: u64) {
+    match size {
+        0..=0xfc => bytes.push(size as u8),
+        0xfd..=0xffff => {
+            bytes.push(0xfd);
+            bytes.extend_from_slice(&(size as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            bytes.push(0xfe);
+            bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        _ => {
+            bytes.push(0xff);
+            bytes.extend_from_slice(&size.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::schnorr::schnorr_verify;
+    use crate::keystore::fake::FakeKeystore;
+    use crate::Keystore;
+
+    use bitcoin::bip32::Fingerprint;
+    use bitcoin::{
+        absolute, transaction, Amount, CompressedPublicKey, OutPoint, ScriptBuf, Sequence,
+        Transaction, TxIn, Witness,
+    };
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    fn seed() -> Vec<u8> {
+        FakeKeystore {}
+            .get_key("pass", "123456".to_string())
+            .unwrap()
+    }
+
+    fn source(path: &str) -> KeySource {
+        (
+            Fingerprint::from_str("73c5da0a").unwrap(),
+            DerivationPath::from_str(path).unwrap(),
+        )
+    }
+
+    fn public_key(path: &str) -> secp256k1::PublicKey {
+        let key = derive_from_seed(&seed(), path).unwrap();
+        secp256k1::PublicKey::from_slice(&k1_public_key(&key).unwrap()).unwrap()
+    }
+
+    fn x_only(path: &str) -> XOnlyPublicKey {
+        let key = derive_from_seed(&seed(), path).unwrap();
+        XOnlyPublicKey::from_slice(&x_only_public_key(&key).unwrap()).unwrap()
+    }
+
+    fn tx_in(txid_byte: u8, vout: u32) -> TxIn {
+        let txid = bitcoin::Txid::from_byte_array([txid_byte; 32]);
+        TxIn {
+            previous_output: OutPoint { txid, vout },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }
+    }
+
+    /// PSBT spending a P2PKH, a P2WPKH and a P2TR key path output of the fake seed
+    fn unsigned_psbt() -> Psbt {
+        let legacy_key = CompressedPublicKey(public_key("m/44'/0'/0'/0/0"));
+        let segwit_key = CompressedPublicKey(public_key("m/84'/0'/0'/0/0"));
+        let internal_key = x_only("m/86'/0'/0'/0/0");
+        let secp = secp256k1::Secp256k1::verification_only();
+
+        let previous_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![tx_in(1, 0)],
+            output: vec![TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::new_p2pkh(&bitcoin::PubkeyHash::from(legacy_key)),
+            }],
+        };
+        let mut legacy_in = tx_in(0, 0);
+        legacy_in.previous_output.txid = previous_tx.compute_txid();
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![legacy_in, tx_in(2, 1), tx_in(3, 0)],
+            output: vec![TxOut {
+                value: Amount::from_sat(120_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&segwit_key.wpubkey_hash()),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+
+        psbt.inputs[0].non_witness_utxo = Some(previous_tx);
+        psbt.inputs[0].bip32_derivation =
+            BTreeMap::from([(legacy_key.0, source("m/44'/0'/0'/0/0"))]);
+
+        psbt.inputs[1].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(40_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&segwit_key.wpubkey_hash()),
+        });
+        psbt.inputs[1].bip32_derivation =
+            BTreeMap::from([(segwit_key.0, source("m/84'/0'/0'/0/0"))]);
+
+        psbt.inputs[2].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(40_000),
+            script_pubkey: ScriptBuf::new_p2tr(&secp, internal_key, None),
+        });
+        psbt.inputs[2].tap_internal_key = Some(internal_key);
+        psbt.inputs[2].tap_key_origins =
+            BTreeMap::from([(internal_key, (vec![], source("m/86'/0'/0'/0/0")))]);
+        psbt
+    }
+
+    fn verify_signed(psbt: &Psbt) {
+        let secp = secp256k1::Secp256k1::verification_only();
+        let mut cache = SighashCache::new(psbt.unsigned_tx.clone());
+        for index in 0..2 {
+            let (message, _) = psbt.sighash_ecdsa(index, &mut cache).unwrap();
+            let (public_key, sig) = psbt.inputs[index].partial_sigs.iter().next().unwrap();
+            secp.verify_ecdsa(&message, &sig.signature, &public_key.inner)
+                .unwrap();
+        }
+        let (sighash, _) = _taproot_sighash(psbt, 2, &mut cache, None).unwrap();
+        let output_key = decode("a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c");
+        let sig = psbt.inputs[2].tap_key_sig.unwrap().signature;
+        assert!(schnorr_verify(&output_key, &sighash, sig.as_ref()));
+    }
+
+    fn decode(data: &str) -> Vec<u8> {
+        hex::decode(data).unwrap()
+    }
+
+    #[test]
+    fn test_sign_psbt() {
+        let psbt = unsigned_psbt();
+        let signed = sign_psbt(&psbt.serialize(), &seed()).unwrap();
+        verify_signed(&Psbt::deserialize(&signed).unwrap());
+    }
+
+    #[test]
+    fn test_sign_psbt_v2() {
+        let psbt = unsigned_psbt();
+        let v0 = _parse_raw(&psbt.serialize()).unwrap();
+
+        // move the unsigned transaction into the PSBTv2 fields
+        let tx = &psbt.unsigned_tx;
+        let mut global = vec![
+            (vec![GLOBAL_TX_VERSION], 2u32.to_le_bytes().to_vec()),
+            (vec![GLOBAL_INPUT_COUNT], vec![3]),
+            (vec![GLOBAL_OUTPUT_COUNT], vec![1]),
+            (vec![GLOBAL_VERSION], 2u32.to_le_bytes().to_vec()),
+        ];
+        global.extend(v0.global.iter().skip(1).cloned());
+        let inputs = v0
+            .inputs
+            .iter()
+            .zip(tx.input.iter())
+            .map(|(map, input)| {
+                let mut map = map.clone();
+                map.push((
+                    vec![IN_PREVIOUS_TXID],
+                    input.previous_output.txid.to_byte_array().to_vec(),
+                ));
+                map.push((
+                    vec![IN_OUTPUT_INDEX],
+                    input.previous_output.vout.to_le_bytes().to_vec(),
+                ));
+                map.push((vec![IN_SEQUENCE], input.sequence.0.to_le_bytes().to_vec()));
+                map
+            })
+            .collect();
+        let outputs = vec![vec![
+            (vec![OUT_AMOUNT], 120_000u64.to_le_bytes().to_vec()),
+            (
+                vec![OUT_SCRIPT],
+                tx.output[0].script_pubkey.as_bytes().to_vec(),
+            ),
+        ]];
+        let v2 = RawPsbt {
+            global,
+            inputs,
+            outputs,
+        };
+
+        let signed = _parse_raw(&sign_psbt(&_serialize_raw(&v2), &seed()).unwrap()).unwrap();
+        assert_eq!(_version(&signed).unwrap(), 2);
+        assert_eq!(signed.global, v2.global);
+        let signed_v0 = _serialize_raw(&_v2_to_v0(&signed).unwrap());
+        verify_signed(&Psbt::deserialize(&signed_v0).unwrap());
+    }
+
+    #[test]
+    fn test_skip_foreign_input() {
+        let mut psbt = unsigned_psbt();
+        let mut foreign = source("m/84'/0'/0'/0/0");
+        foreign.0 = Fingerprint::from_str("deadbeef").unwrap();
+        let (key, _) = psbt.inputs[1].bip32_derivation.pop_first().unwrap();
+        psbt.inputs[1].bip32_derivation.insert(key, foreign.clone());
+
+        // the input of the other signer is left unsigned
+        let signed = sign_psbt(&psbt.serialize(), &seed()).unwrap();
+        let signed = Psbt::deserialize(&signed).unwrap();
+        assert_eq!(signed.inputs[0].partial_sigs.len(), 1);
+        assert!(signed.inputs[1].partial_sigs.is_empty());
+        assert!(signed.inputs[2].tap_key_sig.is_some());
+
+        // a PSBT without any input of the seed is refused
+        let (key, _) = psbt.inputs[0].bip32_derivation.pop_first().unwrap();
+        psbt.inputs[0].bip32_derivation.insert(key, foreign.clone());
+        let (key, (leaves, _)) = psbt.inputs[2].tap_key_origins.pop_first().unwrap();
+        psbt.inputs[2]
+            .tap_key_origins
+            .insert(key, (leaves, foreign));
+        assert!(matches!(
+            sign_psbt(&psbt.serialize(), &seed()),
+            Err(CKMError::PsbtError(_))
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+//! bound on the connections a server handles at once
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// connections a server handles at once by default, further clients wait in the listen backlog
+pub(crate) const MAX_CONNECTIONS: usize = 64;
+
+/// pause after a failed `accept`, so running out of file descriptors does not spin the server
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// counting semaphore of the connection threads of a server
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionLimit {
+    state: Arc<(Mutex<usize>, Condvar)>,
+    max: usize,
+}
+
+/// slot of one connection, released when the connection thread drops it
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl ConnectionLimit {
+    /// at most `max` connections at once, at least one
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            state: Arc::new((Mutex::new(0), Condvar::new())),
+            max: max.max(1),
+        }
+    }
+
+    /// wait until a slot is free and take it
+    pub(crate) fn acquire(&self) -> ConnectionPermit {
+        let (count, released) = &*self.state;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count >= self.max {
+            count = released.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+        *count += 1;
+        ConnectionPermit {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let (count, released) = &*self.state;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        released.notify_one();
+    }
+}
+
+/// whether the server keeps listening after the `accept` error, the client aborted or the
+/// process lacks file descriptors or memory for a while. Those are logged to stderr and the
+/// server pauses before the next `accept`, other errors are of the listener itself
+pub(crate) fn retry_accept(server: &str, e: &io::Error) -> bool {
+    if !_is_transient(e) {
+        return false;
+    }
+    eprintln!("{}: accept failed, {}", server, e);
+    thread::sleep(ACCEPT_BACKOFF);
+    true
+}
+
+fn _is_transient(e: &io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO)
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.acquire();
+        let second = limit.acquire();
+
+        let (sender, receiver) = mpsc::channel();
+        let waiting = {
+            let limit = limit.clone();
+            thread::spawn(move || {
+                let permit = limit.acquire();
+                sender.send(()).unwrap();
+                permit
+            })
+        };
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(first);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let third = waiting.join().unwrap();
+        drop((second, third));
+
+        // a zero limit still lets one connection through
+        let _permit = ConnectionLimit::new(0).acquire();
+    }
+
+    #[test]
+    fn test_retry_accept() {
+        let aborted = io::Error::from(ErrorKind::ConnectionAborted);
+        assert!(retry_accept("test", &aborted));
+        assert!(!retry_accept(
+            "test",
+            &io::Error::from(ErrorKind::InvalidInput)
+        ));
+        #[cfg(unix)]
+        {
+            assert!(retry_accept(
+                "test",
+                &io::Error::from_raw_os_error(libc::EMFILE)
+            ));
+            assert!(!retry_accept(
+                "test",
+                &io::Error::from_raw_os_error(libc::EBADF)
+            ));
+        }
+    }
+}
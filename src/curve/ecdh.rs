@@ -0,0 +1,112 @@
+use crate::{CKMError, Curve};
+
+use super::{ed25519, k1, r1};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// derive the private key of the path for the curve from a seed
+pub(crate) fn derive_from_seed(
+    seed: &[u8],
+    path: &str,
+    curve: &Curve,
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let key = match curve {
+        Curve::Secp256k1 | Curve::Secp256k1Schnorr => k1::derive_from_seed(seed, path)?,
+        Curve::Secp256R1 => r1::derive_from_seed(seed, path)?,
+        Curve::Ed25519 => ed25519::derive_from_seed(seed, path)?,
+    };
+    Ok(Zeroizing::new(key))
+}
+
+/// public key used for key agreement, compressed SEC1 for secp256k1 and P-256,
+/// X25519 for Ed25519
+pub(crate) fn public_key(key: &[u8], curve: &Curve) -> Result<Vec<u8>, CKMError> {
+    match curve {
+        Curve::Secp256k1 | Curve::Secp256k1Schnorr => Ok(k1::k1_public_key(key)?.to_vec()),
+        Curve::Secp256R1 => Ok(r1::r1_public_key(key)?.to_vec()),
+        Curve::Ed25519 => Ok(ed25519::x25519_public_key(key)?.to_vec()),
+    }
+}
+
+/// uncompressed SEC1 shared point for secp256k1 and P-256, the X25519 shared secret for Ed25519
+pub(crate) fn shared_point(
+    key: &[u8],
+    curve: &Curve,
+    peer_public_key: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let shared = match curve {
+        Curve::Secp256k1 | Curve::Secp256k1Schnorr => k1::k1_shared_point(key, peer_public_key)?,
+        Curve::Secp256R1 => r1::r1_shared_point(key, peer_public_key)?,
+        Curve::Ed25519 => ed25519::x25519_shared_secret(key, peer_public_key)?.to_vec(),
+    };
+    Ok(Zeroizing::new(shared))
+}
+
+/// raw ECDH shared secret, the x coordinate of the shared point or the X25519 output
+pub(crate) fn shared_secret(
+    key: &[u8],
+    curve: &Curve,
+    peer_public_key: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let shared = shared_point(key, curve, peer_public_key)?;
+    match curve {
+        Curve::Ed25519 => Ok(shared),
+        _ => Ok(Zeroizing::new(shared[1..33].to_vec())),
+    }
+}
+
+/// HKDF-SHA256 (RFC 5869) of the input key material into `length` bytes
+pub fn hkdf_sha256(
+    ikm: &[u8],
+    salt: Option<&[u8]>,
+    info: &[u8],
+    length: usize,
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let mut okm = Zeroizing::new(vec![0u8; length]);
+    Hkdf::<Sha256>::new(salt, ikm)
+        .expand(info, &mut okm)
+        .map_err(|_e| CKMError::EcdhError("invalid HKDF output length".to_string()))?;
+    Ok(okm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::fake::FakeKeystore;
+    use crate::Keystore;
+    use hex::{decode, encode};
+
+    #[test]
+    fn test_shared_secret() {
+        let seed = FakeKeystore {}
+            .get_key("pass", "123456".to_string())
+            .unwrap();
+        for (curve, path_a, path_b) in [
+            (Curve::Secp256k1, "m/44'/0'/0'/0/0", "m/44'/0'/0'/0/1"),
+            (Curve::Secp256R1, "m/44'/0'/0'/0/0", "m/44'/0'/0'/0/1"),
+            (Curve::Ed25519, "m/44'/501'/0'/0'", "m/44'/501'/1'/0'"),
+        ] {
+            let a = derive_from_seed(&seed, path_a, &curve).unwrap();
+            let b = derive_from_seed(&seed, path_b, &curve).unwrap();
+            let ab = shared_secret(&a, &curve, &public_key(&b, &curve).unwrap()).unwrap();
+            let ba = shared_secret(&b, &curve, &public_key(&a, &curve).unwrap()).unwrap();
+            assert_eq!(ab.len(), 32);
+            assert_eq!(ab, ba);
+            assert!(shared_secret(&a, &curve, &[2u8; 5]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_hkdf_sha256() {
+        // RFC 5869 test case 1
+        let ikm = [0x0bu8; 22];
+        let salt = decode("000102030405060708090a0b0c").unwrap();
+        let info = decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        let okm = hkdf_sha256(&ikm, Some(&salt), &info, 42).unwrap();
+        assert_eq!(
+            encode(&*okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+    }
+}
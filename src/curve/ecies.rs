@@ -0,0 +1,270 @@
+use std::convert::TryInto;
+
+use crate::CKMError;
+
+use super::ecdh::hkdf_sha256;
+use super::k1::{k1_public_key, k1_shared_point};
+use aes_gcm::{
+    aead::{generic_array::typenum::U16, AeadInPlace, NewAead},
+    aes::Aes256,
+    Aes256Gcm, AesGcm,
+};
+use k256::{
+    elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint},
+    AffinePoint, EncodedPoint,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+/// version byte of the crypto_key_master ECIES format
+const ECIES_V1: u8 = 0x01;
+/// first byte of the uncompressed ephemeral key in the eciespy format
+const ECIESPY_PREFIX: u8 = 0x04;
+const ECIES_V1_INFO: &[u8] = b"crypto_key_master ecies v1";
+const TAG_LENGTH: usize = 16;
+
+/// wire format of ECIES ciphertexts on secp256k1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EciesFormat {
+    /// `0x01 || ephemeral compressed key (33) || nonce (12) || ciphertext || tag (16)`, the AES-256-GCM
+    /// key is HKDF-SHA256 of the shared x coordinate salted with both public keys, and the version
+    /// byte and ephemeral key are authenticated
+    V1,
+    /// eciespy / eciesjs default format `ephemeral uncompressed key (65) || nonce (16) || tag (16) || ciphertext`,
+    /// the AES-256-GCM key is HKDF-SHA256 of the ephemeral key and the shared point, both uncompressed
+    Eciespy,
+}
+
+/// encrypt the plaintext to a SEC1 encoded secp256k1 public key with a fresh ephemeral key
+pub fn ecies_encrypt(
+    public_key: &[u8],
+    plaintext: &[u8],
+    format: EciesFormat,
+) -> Result<Vec<u8>, CKMError> {
+    let ephemeral_key = _ephemeral_key()?;
+    let nonce = match format {
+        EciesFormat::V1 => _random_bytes(12)?,
+        EciesFormat::Eciespy => _random_bytes(16)?,
+    };
+    _encrypt(&ephemeral_key, &nonce, public_key, plaintext, format)
+}
+
+/// decrypt an ECIES ciphertext of either format with the secp256k1 private key, the format is
+/// told apart by the first byte
+pub(crate) fn ecies_decrypt(key: &[u8], ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    match ciphertext.first() {
+        Some(&ECIES_V1) => {
+            if ciphertext.len() < 1 + 33 + 12 + TAG_LENGTH {
+                return Err(_too_short());
+            }
+            let (header, rest) = ciphertext.split_at(1 + 33);
+            let (nonce, rest) = rest.split_at(12);
+            let (body, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+            let ephemeral = &header[1..];
+            let recipient = k1_public_key(key)?;
+            let aes_key = _v1_key(key, ephemeral, ephemeral, &recipient)?;
+            let mut plaintext = Zeroizing::new(body.to_vec());
+            Aes256Gcm::new_from_slice(&aes_key)
+                .map_err(|_e| _decryption_failed())?
+                .decrypt_in_place_detached(
+                    &_array(nonce)?.into(),
+                    header,
+                    &mut plaintext,
+                    &_array::<16>(tag)?.into(),
+                )
+                .map_err(|_e| _decryption_failed())?;
+            Ok(plaintext)
+        }
+        Some(&ECIESPY_PREFIX) => {
+            if ciphertext.len() < 65 + 16 + TAG_LENGTH {
+                return Err(_too_short());
+            }
+            let (ephemeral, rest) = ciphertext.split_at(65);
+            let (nonce, rest) = rest.split_at(16);
+            let (tag, body) = rest.split_at(TAG_LENGTH);
+            let aes_key = _eciespy_key(key, ephemeral, ephemeral)?;
+            let mut plaintext = Zeroizing::new(body.to_vec());
+            AesGcm::<Aes256, U16>::new_from_slice(&aes_key)
+                .map_err(|_e| _decryption_failed())?
+                .decrypt_in_place_detached(
+                    &_array(nonce)?.into(),
+                    b"",
+                    &mut plaintext,
+                    &_array::<16>(tag)?.into(),
+                )
+                .map_err(|_e| _decryption_failed())?;
+            Ok(plaintext)
+        }
+        _ => Err(CKMError::EciesError(
+            "unknown ECIES format version".to_string(),
+        )),
+    }
+}
+
+fn _encrypt(
+    ephemeral_key: &[u8],
+    nonce: &[u8],
+    public_key: &[u8],
+    plaintext: &[u8],
+    format: EciesFormat,
+) -> Result<Vec<u8>, CKMError> {
+    let mut body = plaintext.to_vec();
+    match format {
+        EciesFormat::V1 => {
+            let ephemeral = k1_public_key(ephemeral_key)?;
+            let recipient = _sec1(public_key, true)?;
+            let aes_key = _v1_key(ephemeral_key, &recipient, &ephemeral, &recipient)?;
+            let mut output = vec![ECIES_V1];
+            output.extend_from_slice(&ephemeral);
+            let tag = Aes256Gcm::new_from_slice(&aes_key)
+                .map_err(|_e| CKMError::EciesError("encryption failed".to_string()))?
+                .encrypt_in_place_detached(&_array(nonce)?.into(), &output, &mut body)
+                .map_err(|_e| CKMError::EciesError("encryption failed".to_string()))?;
+            output.extend_from_slice(nonce);
+            output.extend(body);
+            output.extend_from_slice(&tag);
+            Ok(output)
+        }
+        EciesFormat::Eciespy => {
+            let ephemeral = _sec1(&k1_public_key(ephemeral_key)?, false)?;
+            let aes_key = _eciespy_key(ephemeral_key, public_key, &ephemeral)?;
+            let tag = AesGcm::<Aes256, U16>::new_from_slice(&aes_key)
+                .map_err(|_e| CKMError::EciesError("encryption failed".to_string()))?
+                .encrypt_in_place_detached(&_array(nonce)?.into(), b"", &mut body)
+                .map_err(|_e| CKMError::EciesError("encryption failed".to_string()))?;
+            let mut output = ephemeral;
+            output.extend_from_slice(nonce);
+            output.extend_from_slice(&tag);
+            output.extend(body);
+            Ok(output)
+        }
+    }
+}
+
+/// V1 AES key from the agreement of the private key with the peer public key
+fn _v1_key(
+    key: &[u8],
+    peer_public_key: &[u8],
+    ephemeral: &[u8],
+    recipient: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let shared = Zeroizing::new(k1_shared_point(key, peer_public_key)?);
+    let mut salt = ephemeral.to_vec();
+    salt.extend_from_slice(recipient);
+    hkdf_sha256(&shared[1..33], Some(&salt), ECIES_V1_INFO, 32)
+}
+
+/// eciespy AES key from the agreement of the private key with the peer public key
+fn _eciespy_key(
+    key: &[u8],
+    peer_public_key: &[u8],
+    ephemeral: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let shared = Zeroizing::new(k1_shared_point(key, peer_public_key)?);
+    let mut master = Zeroizing::new(ephemeral.to_vec());
+    master.extend_from_slice(&shared);
+    hkdf_sha256(&master, None, b"", 32)
+}
+
+fn _sec1(public_key: &[u8], compress: bool) -> Result<Vec<u8>, CKMError> {
+    let point = EncodedPoint::from_bytes(public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+        .ok_or_else(|| CKMError::EciesError("invalid secp256k1 public key".to_string()))?;
+    Ok(point.to_encoded_point(compress).as_bytes().to_vec())
+}
+
+fn _ephemeral_key() -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    loop {
+        let key = Zeroizing::new(_random_bytes(32)?);
+        // retry the negligible chance of a value outside the curve order
+        if k1_public_key(&key).is_ok() {
+            return Ok(key);
+        }
+    }
+}
+
+fn _random_bytes(length: usize) -> Result<Vec<u8>, CKMError> {
+    let mut bytes = vec![0u8; length];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_e| CKMError::RandomError)?;
+    Ok(bytes)
+}
+
+fn _array<const N: usize>(bytes: &[u8]) -> Result<[u8; N], CKMError> {
+    bytes.try_into().map_err(|_e| _too_short())
+}
+
+fn _too_short() -> CKMError {
+    CKMError::EciesError("ciphertext is too short".to_string())
+}
+
+fn _decryption_failed() -> CKMError {
+    CKMError::EciesError("decryption failed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::decode;
+
+    const KEY: &str = "e284129cc0922579a535bbf4d1a3b25773090d28c909bc0fed73b5e0222cc372";
+
+    #[test]
+    fn test_round_trip() {
+        let key = decode(KEY).unwrap();
+        let public_key = k1_public_key(&key).unwrap();
+        for format in [EciesFormat::V1, EciesFormat::Eciespy] {
+            let ciphertext = ecies_encrypt(&public_key, b"hello ecies", format).unwrap();
+            let plaintext = ecies_decrypt(&key, &ciphertext).unwrap();
+            assert_eq!(&plaintext[..], b"hello ecies");
+
+            let mut tampered = ciphertext.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 1;
+            assert!(ecies_decrypt(&key, &tampered).is_err());
+            assert!(ecies_decrypt(&[7u8; 32], &ciphertext).is_err());
+        }
+        let uncompressed = _sec1(&public_key, false).unwrap();
+        let ciphertext = ecies_encrypt(&uncompressed, b"", EciesFormat::V1).unwrap();
+        assert_eq!(ciphertext.len(), 1 + 33 + 12 + 16);
+        assert!(ecies_decrypt(&key, &ciphertext).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_eciespy_vector() {
+        // `ecies::encrypt` of the `ecies` crate 0.2.11, the Rust implementation of the
+        // eciespy / eciesjs format, with its default configuration
+        let key = decode(KEY).unwrap();
+        let ciphertext = decode(
+            "0489b9f068e9674cd783cad85e7e919aac644544a728bf4d6d543ad4116cc08f1ca23eaf26e03de194ab9bd72b8\
+             23e0c2fed886655285205c1847a9654740d09dc1c2d4e014342525790996a32f4d4df1db099b733fecd3b5df5f0\
+             25bbee192b022bff3bf1ff162ff413dc1d",
+        )
+        .unwrap();
+        assert_eq!(
+            &ecies_decrypt(&key, &ciphertext).unwrap()[..],
+            b"hello ecies"
+        );
+
+        // an encryption with a fixed ephemeral key and nonce, `ecies::decrypt` of the same crate
+        // returns the plaintext
+        let public_key = k1_public_key(&key).unwrap();
+        let nonce: Vec<u8> = (0..16).collect();
+        let encrypted = _encrypt(
+            &[0x11u8; 32],
+            &nonce,
+            &public_key,
+            b"hello ecies",
+            EciesFormat::Eciespy,
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(encrypted),
+            "044f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa385b6b1b8ead809ca67454d9683fcf2ba0\
+             3456d6fe2c4abe2b07f0fbdbb2f1c1000102030405060708090a0b0c0d0e0fce3a22f2cccc4ad4628ea7bc3de82d47b8\
+             c12e7556caa0a17eb45b"
+        );
+    }
+}
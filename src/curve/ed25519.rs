@@ -0,0 +1,170 @@
+use std::convert::TryInto;
+
+use crate::keystore::{imported_key, read_seed, KeySource};
+use crate::{CKMError, CurveSign, SignRequest, SigningSignature};
+
+use super::parse_path;
+use super::r1::hmac_sha512;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use sha2::{Digest, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+pub(crate) struct Ed25519 {}
+
+impl CurveSign for Ed25519 {
+    fn derive_key(
+        &self,
+        request: &SignRequest,
+        password: &str,
+        store: &impl KeySource,
+    ) -> Result<Vec<u8>, CKMError> {
+        let seed = read_seed(store, password, request.key_id)?;
+        derive_from_seed(&seed, request.path)
+    }
+
+    fn sign(
+        &self,
+        request: &SignRequest,
+        password: &str,
+        store: &impl KeySource,
+    ) -> Result<SigningSignature, CKMError> {
+        let key = Zeroizing::new(self.derive_key(request, password, store)?);
+        let sig = ed25519_sign(&key, &request.unsigend_data)?;
+        SigningSignature::from_compact(&sig)
+    }
+}
+
+/// derive the 32 bytes Ed25519 private key of the path from a seed with SLIP-10,
+/// Ed25519 only supports hardened derivation
+pub(crate) fn derive_from_seed(seed: &[u8], path: &str) -> Result<Vec<u8>, CKMError> {
+    // imported keys are secp256k1 keys
+    if imported_key(seed).is_some() {
+        return Err(CKMError::UnsupportedCurve);
+    }
+    let parsed = parse_path(path)?;
+    let i = hmac_sha512(b"ed25519 seed", seed);
+    let (mut key, mut chain_code): ([u8; 32], [u8; 32]) =
+        (i[..32].try_into().unwrap(), i[32..].try_into().unwrap());
+    for child in parsed.iter() {
+        if !child.is_hardened() {
+            return Err(CKMError::InvalidPath {
+                path: path.to_string(),
+                source: None,
+            });
+        }
+        let mut data = vec![0u8];
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&child.to_bytes());
+        let i = hmac_sha512(&chain_code, &data);
+        key = i[..32].try_into().unwrap();
+        chain_code = i[32..].try_into().unwrap();
+    }
+    Ok(key.to_vec())
+}
+
+/// 32 bytes Ed25519 public key of a private key
+pub(crate) fn ed25519_public_key(key_bytes: &[u8]) -> Result<[u8; 32], CKMError> {
+    let key_pair =
+        Ed25519KeyPair::from_seed_unchecked(key_bytes).map_err(|_e| CKMError::SigningError)?;
+    let public_key = key_pair.public_key().as_ref();
+    public_key.try_into().map_err(|_e| CKMError::SigningError)
+}
+
+/// 64 bytes Ed25519 signature of the message
+pub(crate) fn ed25519_sign(key_bytes: &[u8], message: &[u8]) -> Result<[u8; 64], CKMError> {
+    let key_pair =
+        Ed25519KeyPair::from_seed_unchecked(key_bytes).map_err(|_e| CKMError::SigningError)?;
+    let sig = key_pair.sign(message);
+    sig.as_ref().try_into().map_err(|_e| CKMError::SigningError)
+}
+
+/// verify an Ed25519 signature of the message against a 32 bytes public key
+pub(crate) fn ed25519_verify(public_key: &[u8], message: &[u8], sig: &[u8; 64]) -> bool {
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(message, sig)
+        .is_ok()
+}
+
+/// X25519 secret of an Ed25519 private key, the clamped first half of `sha512(key)`
+fn _x25519_secret(key_bytes: &[u8]) -> Result<StaticSecret, CKMError> {
+    if key_bytes.len() != 32 {
+        return Err(CKMError::SigningError);
+    }
+    let hash = Sha512::digest(key_bytes);
+    let scalar: [u8; 32] = hash[..32].try_into().unwrap();
+    Ok(StaticSecret::from(scalar))
+}
+
+/// X25519 public key of an Ed25519 private key
+pub(crate) fn x25519_public_key(key_bytes: &[u8]) -> Result<[u8; 32], CKMError> {
+    let secret = _x25519_secret(key_bytes)?;
+    Ok(PublicKey::from(&secret).to_bytes())
+}
+
+/// X25519 key agreement with the secret of an Ed25519 private key
+pub(crate) fn x25519_shared_secret(
+    key_bytes: &[u8],
+    peer_public_key: &[u8],
+) -> Result<[u8; 32], CKMError> {
+    let peer: [u8; 32] = peer_public_key
+        .try_into()
+        .map_err(|_e| CKMError::EcdhError("X25519 public key must be 32 bytes".to_string()))?;
+    let secret = _x25519_secret(key_bytes)?;
+    let shared = secret.diffie_hellman(&PublicKey::from(peer));
+    if !shared.was_contributory() {
+        return Err(CKMError::EcdhError(
+            "X25519 public key has a low order".to_string(),
+        ));
+    }
+    Ok(shared.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::{decode, encode};
+
+    #[test]
+    fn test_slip10_vectors() {
+        let seed = decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!(
+            encode(derive_from_seed(&seed, "m").unwrap()),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        assert_eq!(
+            encode(derive_from_seed(&seed, "m/0'").unwrap()),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+        assert!(derive_from_seed(&seed, "m/0").is_err());
+    }
+
+    #[test]
+    fn test_sign_vector() {
+        // RFC 8032 7.1 test 1, the empty message
+        let key =
+            decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60").unwrap();
+        let sig = ed25519_sign(&key, b"").unwrap();
+        assert_eq!(
+            encode(sig),
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+        );
+        assert!(ed25519_verify(
+            &ed25519_public_key(&key).unwrap(),
+            b"",
+            &sig
+        ));
+    }
+
+    #[test]
+    fn test_x25519() {
+        let alice = [1u8; 32];
+        let bob = [2u8; 32];
+        let shared = x25519_shared_secret(&alice, &x25519_public_key(&bob).unwrap()).unwrap();
+        assert_eq!(
+            shared,
+            x25519_shared_secret(&bob, &x25519_public_key(&alice).unwrap()).unwrap()
+        );
+        assert!(x25519_shared_secret(&alice, &[0u8; 32]).is_err());
+    }
+}
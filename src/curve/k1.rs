@@ -1,14 +1,27 @@
 use std::convert::TryInto;
 
-use crate::{CKMError, CurveSign, Keystore, SignRequest, SigningSignature};
+use crate::{
+    keystore::{imported_key, KeySource},
+    CKMError, CurveSign, SignRequest, SigningSignature,
+};
+
+use super::{parse_path, r1::hmac_sha512, rfc6979_nonce};
 
-use bip32::{Seed, XPrv};
+use bip32::{ChildNumber, ExtendedKey, ExtendedKeyAttrs, Prefix, Seed, XPrv};
 use ecdsa::{
-    signature::{Signature, Signer},
-    SigningKey,
+    hazmat::{RecoverableSignPrimitive, VerifyPrimitive},
+    signature::Signature,
 };
-use hex::*;
-use k256::Secp256k1;
+use k256::{
+    ecdsa::recoverable,
+    elliptic_curve::{
+        group::ff::PrimeField,
+        sec1::{FromEncodedPoint, ToEncodedPoint},
+    },
+    AffinePoint, EncodedPoint, ProjectivePoint, Scalar,
+};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
 
 pub(crate) struct K1 {}
 
@@ -17,24 +30,17 @@ impl CurveSign for K1 {
         &self,
         request: &SignRequest,
         password: &str,
-        store: &impl Keystore,
+        store: &impl KeySource,
     ) -> Result<Vec<u8>, CKMError> {
-        let key = store.get_key(password, request.key_id.to_string())?;
-        let seed_bytes: [u8; 64] = key.try_into().map_err(|_e| CKMError::SigningError)?;
-        let seed = Seed::new(seed_bytes);
-        let path = request.path;
-        let child_xprv =
-            XPrv::derive_from_path(&seed, &path.parse().map_err(|_e| CKMError::SigningError)?)
-                .map_err(|_e| CKMError::FileReadError)?;
-        let priv_key = child_xprv.private_key();
-        Ok(priv_key.to_bytes().to_vec())
+        let key = store.derive_k1(password, request.key_id, request.path)?;
+        Ok(key.to_vec())
     }
 
     fn sign(
         &self,
         request: &SignRequest,
         password: &str,
-        store: &impl Keystore,
+        store: &impl KeySource,
     ) -> Result<SigningSignature, CKMError> {
         let key = self.derive_key(request, password, store)?;
         let message = &request.unsigend_data;
@@ -42,25 +48,231 @@ impl CurveSign for K1 {
     }
 }
 
+/// derive the private key of the BIP32 path from a 64 bytes seed
+pub(crate) fn derive_from_seed(seed: &[u8], path: &str) -> Result<Vec<u8>, CKMError> {
+    let child_xprv = derive_xprv(seed, path)?;
+    let priv_key = child_xprv.private_key();
+    Ok(priv_key.to_bytes().to_vec())
+}
+
+/// BIP32 extended private key of the path from a 64 bytes seed
+pub(crate) fn derive_xprv(seed: &[u8], path: &str) -> Result<XPrv, CKMError> {
+    let path = parse_path(path)?;
+    if imported_key(seed).is_some() {
+        return path.iter().try_fold(master_node(seed)?, |node, child| {
+            node.derive_child(child)
+                .map_err(|e| CKMError::crypto("bip32 derivation failed", e))
+        });
+    }
+    let seed = Seed::new(_seed_bytes(seed)?);
+    XPrv::derive_from_path(&seed, &path).map_err(|e| CKMError::crypto("bip32 derivation failed", e))
+}
+
+/// BIP32 master node of a 64 bytes seed. An imported key is the master private key itself,
+/// its chain code is derived from the key so it stays as secret as the one of a seed
+pub(crate) fn master_node(seed: &[u8]) -> Result<XPrv, CKMError> {
+    let key = match imported_key(seed) {
+        Some(key) => key?,
+        None => {
+            return XPrv::new(Seed::new(_seed_bytes(seed)?))
+                .map_err(|e| CKMError::crypto("bip32 master key failed", e))
+        }
+    };
+    let mut key_bytes = [0u8; 33];
+    key_bytes[1..].copy_from_slice(&_secret_scalar(&key)?.to_bytes());
+    let chain_code = hmac_sha512(b"ckm imported key", &key);
+    let node = ExtendedKey {
+        prefix: Prefix::XPRV,
+        attrs: ExtendedKeyAttrs {
+            depth: 0,
+            parent_fingerprint: [0u8; 4],
+            child_number: ChildNumber(0),
+            chain_code: chain_code[32..].try_into().unwrap(),
+        },
+        key_bytes,
+    };
+    XPrv::try_from(node).map_err(|e| CKMError::crypto("bip32 master key failed", e))
+}
+
+/// BIP32 seeds of the key master are 64 bytes
+fn _seed_bytes(seed: &[u8]) -> Result<[u8; 64], CKMError> {
+    seed.try_into()
+        .map_err(|e| CKMError::crypto("seed is not 64 bytes", e))
+}
+
+/// BIP32 fingerprint of the master key of a 64 bytes seed
+pub(crate) fn master_fingerprint(seed: &[u8]) -> Result<[u8; 4], CKMError> {
+    Ok(master_node(seed)?.public_key().fingerprint())
+}
+
+/// 33 bytes compressed public key of a private key
+pub(crate) fn k1_public_key(key_bytes: &[u8]) -> Result<[u8; 33], CKMError> {
+    let d = _secret_scalar(key_bytes)?;
+    let point = (ProjectivePoint::generator() * d).to_affine();
+    let encoded = point.to_encoded_point(true);
+    encoded
+        .as_bytes()
+        .try_into()
+        .map_err(|e| CKMError::crypto("secp256k1 public key is not 33 bytes", e))
+}
+
+/// sign a 32 bytes digest with a RFC6979 nonce, returns the low-S `r || s`
+/// signature and the recovery id
+pub(crate) fn k1_sign_digest(
+    key_bytes: &[u8],
+    digest: &[u8; 32],
+) -> Result<([u8; 64], u8), CKMError> {
+    let d = _secret_scalar(key_bytes)?;
+    let z = Scalar::from_bytes_reduced(&(*digest).into());
+    let k = rfc6979_nonce(&d.to_bytes(), &z.to_bytes(), |v| {
+        Scalar::from_repr(v.into()).filter(|k| !bool::from(k.is_zero()))
+    });
+    let (sig, recovery_id) = d
+        .try_sign_recoverable_prehashed(&k, &z)
+        .map_err(|e| CKMError::crypto("secp256k1 signing failed", e))?;
+    let sig_bytes: [u8; 64] = sig
+        .as_bytes()
+        .try_into()
+        .map_err(|e| CKMError::crypto("secp256k1 signature is not 64 bytes", e))?;
+    Ok((sig_bytes, recovery_id as u8))
+}
+
+/// recover the SEC1 encoded public key from a digest signature and its recovery id
+pub(crate) fn k1_recover_public_key(
+    digest: &[u8; 32],
+    sig: &[u8; 64],
+    recovery_id: u8,
+    compressed: bool,
+) -> Result<Vec<u8>, CKMError> {
+    let sig = k256::ecdsa::Signature::from_bytes(sig)
+        .map_err(|e| CKMError::crypto("invalid secp256k1 signature", e))?;
+    let recovery_id = recoverable::Id::new(recovery_id)
+        .map_err(|e| CKMError::crypto("invalid recovery id", e))?;
+    let sig = recoverable::Signature::new(&sig, recovery_id)
+        .map_err(|e| CKMError::crypto("invalid secp256k1 signature", e))?;
+    let public_key = sig
+        .recover_verify_key_from_digest_bytes(&(*digest).into())
+        .map_err(|e| CKMError::crypto("public key recovery failed", e))?;
+    Ok(public_key.to_encoded_point(compressed).as_bytes().to_vec())
+}
+
+/// low-S normalize a signature made outside the key master and set the recovery id that
+/// recovers the SEC1 encoded public key
+#[cfg(any(feature = "pkcs11", feature = "aws-kms"))]
+pub(crate) fn k1_recoverable_signature(
+    digest: &[u8; 32],
+    mut sig: SigningSignature,
+    public_key: &[u8],
+) -> Result<SigningSignature, CKMError> {
+    sig.v = None;
+    sig.normalize_s()?;
+    let compact = sig.to_compact();
+    let compressed = public_key.len() == 33;
+    sig.v = (0..2).find(|v| {
+        k1_recover_public_key(digest, &compact, *v, compressed)
+            .map(|recovered| recovered == public_key)
+            .unwrap_or(false)
+    });
+    match sig.v {
+        Some(_) => Ok(sig),
+        None => Err(CKMError::SigningError),
+    }
+}
+
+/// verify a low-S `r || s` signature of a 32 bytes digest against a SEC1 encoded public key
+pub(crate) fn k1_verify_digest(public_key: &[u8], digest: &[u8; 32], sig: &[u8; 64]) -> bool {
+    let point = match EncodedPoint::from_bytes(public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+    {
+        Some(point) => point,
+        None => return false,
+    };
+    let sig = match k256::ecdsa::Signature::from_bytes(sig) {
+        Ok(sig) => sig,
+        Err(_e) => return false,
+    };
+    let z = Scalar::from_bytes_reduced(&(*digest).into());
+    point.verify_prehashed(&z, &sig).is_ok()
+}
+
+/// uncompressed SEC1 encoding of a secp256k1 public key, `None` for an invalid key
+#[cfg(feature = "jose")]
+pub(crate) fn k1_uncompressed(public_key: &[u8]) -> Option<Vec<u8>> {
+    EncodedPoint::from_bytes(public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+        .map(|point| point.to_encoded_point(false).as_bytes().to_vec())
+}
+
+/// ECDH on secp256k1, returns the uncompressed SEC1 shared point
+pub(crate) fn k1_shared_point(
+    key_bytes: &[u8],
+    peer_public_key: &[u8],
+) -> Result<Vec<u8>, CKMError> {
+    let d = _secret_scalar(key_bytes)?;
+    let peer = EncodedPoint::from_bytes(peer_public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+        .ok_or_else(|| CKMError::EcdhError("invalid secp256k1 public key".to_string()))?;
+    let shared = (ProjectivePoint::from(peer) * d).to_affine();
+    let encoded = shared.to_encoded_point(false);
+    if encoded.as_bytes().len() != 65 {
+        return Err(CKMError::EcdhError(
+            "shared point is the identity".to_string(),
+        ));
+    }
+    Ok(encoded.as_bytes().to_vec())
+}
+
+fn _secret_scalar(key_bytes: &[u8]) -> Result<Scalar, CKMError> {
+    let key: [u8; 32] = key_bytes
+        .try_into()
+        .map_err(|e| CKMError::crypto("secp256k1 key is not 32 bytes", e))?;
+    let d = Scalar::from_repr(key.into()).ok_or(CKMError::SigningError)?;
+    if bool::from(d.is_zero()) {
+        return Err(CKMError::SigningError);
+    }
+    Ok(d)
+}
+
 fn _k1_sign_message(key_bytes: &[u8], message_bytes: &[u8]) -> Result<SigningSignature, CKMError> {
-    let key: SigningKey<Secp256k1> =
-        SigningKey::from_bytes(key_bytes).map_err(|e| CKMError::SigningError)?;
-    let sig = key
-        .try_sign(message_bytes)
-        .map_err(|_e| CKMError::SigningError)?;
-    // https://www.reddit.com/r/rust/comments/kd7s60/i_dont_understand_this_temporary_value_is_freed/
-    let sig_bytes = sig.as_bytes();
-    let r = encode(&sig_bytes[0..32]);
-    let s = encode(&sig_bytes[32..]);
-    Ok(SigningSignature { r, s, v: None })
+    let digest: [u8; 32] = Sha256::digest(message_bytes).into();
+    k1_sign_recoverable(key_bytes, &digest)
+}
+
+/// sign a digest into a low-S signature with the recovery id
+pub(crate) fn k1_sign_recoverable(
+    key_bytes: &[u8],
+    digest: &[u8; 32],
+) -> Result<SigningSignature, CKMError> {
+    let (sig_bytes, recovery_id) = k1_sign_digest(key_bytes, digest)?;
+    let mut sig = SigningSignature::from_compact(&sig_bytes)?;
+    sig.v = Some(recovery_id);
+    // low-S is enforced for secp256k1 signatures
+    sig.normalize_s()?;
+    Ok(sig)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{keystore::fake::FakeKeystore, Curve};
+    use crate::{keystore::fake::FakeKeystore, Curve, Keystore};
 
     use super::*;
-    use hex::encode;
+
+    #[test]
+    fn test_derive_errors() {
+        use std::error::Error;
+        let seed = FakeKeystore {}.read_seed("", "").unwrap();
+        let e = derive_from_seed(&seed, "m/44'/x").unwrap_err();
+        assert!(matches!(&e, CKMError::InvalidPath { path, .. } if path == "m/44'/x"));
+        assert!(e.source().is_some());
+
+        let e = derive_from_seed(&seed[..32], "m/0").unwrap_err();
+        assert_eq!(e.to_string(), "seed is not 64 bytes");
+        assert_eq!(e.code(), 305);
+    }
+    use hex::{decode, encode};
 
     #[test]
     fn test_derive() {
@@ -102,11 +314,75 @@ mod tests {
 
         let sig = k1.sign(&request, password, &fake_store).unwrap();
 
-        let sig_expect = SigningSignature {
-            r: "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d".to_string(),
-            s: "0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d".to_string(),
-            v: None,
+        assert_eq!(
+            encode(sig.r),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+        );
+        assert_eq!(
+            encode(sig.s),
+            "0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d"
+        );
+        assert!(sig.v.is_some());
+    }
+
+    #[test]
+    fn test_sign_digest() {
+        // RFC6979 deterministic signatures match the message signing path
+        let fake_store = FakeKeystore {};
+        let request = SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: "123456",
+            curve: Curve::Secp256k1,
         };
-        assert_eq!(sig_expect, sig);
+        let key = K1 {}.derive_key(&request, "pass", &fake_store).unwrap();
+        let digest = decode("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let (sig, recovery_id) = k1_sign_digest(&key, &digest).unwrap();
+        assert_eq!(
+            encode(sig),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d\
+             0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d"
+        );
+        assert!(recovery_id < 2);
+        let public_key = k1_public_key(&key).unwrap();
+        assert!(k1_verify_digest(&public_key, &digest, &sig));
+        let recovered = k1_recover_public_key(&digest, &sig, recovery_id, true).unwrap();
+        assert_eq!(recovered, public_key.to_vec());
+    }
+
+    #[test]
+    fn test_master_fingerprint() {
+        let seed = FakeKeystore {}
+            .get_key("pass", "123456".to_string())
+            .unwrap();
+        assert_eq!(encode(master_fingerprint(&seed).unwrap()), "73c5da0a");
+    }
+
+    #[test]
+    fn test_imported_key() {
+        let key = [7u8; 32];
+        let stored = crate::keystore::encode_key(&key);
+        let stored = stored.as_bytes();
+        assert_eq!(derive_from_seed(stored, "m").unwrap(), key);
+        let master = master_node(stored).unwrap();
+        assert_eq!(master.public_key().to_bytes(), k1_public_key(&key).unwrap());
+        // children derive from the master node like the ones of a seed
+        let child = master.derive_child(ChildNumber(1 << 31)).unwrap();
+        assert_eq!(
+            derive_from_seed(stored, "m/0'").unwrap(),
+            child.private_key().to_bytes().to_vec()
+        );
+        assert!(matches!(
+            crate::curve::r1::derive_from_seed(stored, "m"),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(matches!(
+            crate::curve::ed25519::derive_from_seed(stored, "m"),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(master_node(b"ckm-key:secp256k1:hex:zz").is_err());
     }
 }
@@ -1,12 +1,269 @@
-use crate::{CKMError, Keystore, SignRequest};
+use std::convert::TryInto;
 
+use crate::{keystore::KeySource, CKMError, Curve, SignRequest};
+
+use bip32::DerivationPath;
+use hmac::{Hmac, Mac, NewMac};
+use k256::{elliptic_curve::group::ff::PrimeField, Scalar};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub(crate) mod ecdh;
+pub(crate) mod ecies;
+pub(crate) mod ed25519;
 pub(crate) mod k1;
+pub(crate) mod r1;
+pub(crate) mod schnorr;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// verify a signature of `KeyMaster::sign` over the data, ECDSA signatures are checked
+/// against the SHA-256 digest of the data
+pub fn verify_signature(
+    curve: Curve,
+    public_key: &[u8],
+    data: &[u8],
+    signature: &SigningSignature,
+) -> Result<bool, CKMError> {
+    match curve {
+        Curve::Secp256k1 | Curve::Secp256R1 => {
+            let digest: [u8; 32] = Sha256::digest(data).into();
+            verify_digest(curve, public_key, &digest, signature)
+        }
+        Curve::Secp256k1Schnorr | Curve::Ed25519 => {
+            verify_digest(curve, public_key, data, signature)
+        }
+    }
+}
+
+/// verify a signature of `KeyMaster::sign_digest`, the digest is 32 bytes for ECDSA and the
+/// message itself for schnorr and Ed25519. ECDSA signatures must be low-S on Secp256k1
+pub fn verify_digest(
+    curve: Curve,
+    public_key: &[u8],
+    digest: &[u8],
+    signature: &SigningSignature,
+) -> Result<bool, CKMError> {
+    let sig = signature.to_compact();
+    match curve {
+        Curve::Secp256k1 | Curve::Secp256R1 => {
+            let digest: [u8; 32] = digest.try_into().map_err(|_e| {
+                CKMError::SignatureFormatError("ECDSA digest must be 32 bytes".to_string())
+            })?;
+            match curve {
+                Curve::Secp256k1 => Ok(k1::k1_verify_digest(public_key, &digest, &sig)),
+                _ => Ok(r1::r1_verify_digest(public_key, &digest, &sig)),
+            }
+        }
+        Curve::Secp256k1Schnorr => Ok(schnorr::schnorr_verify(public_key, digest, &sig)),
+        Curve::Ed25519 => Ok(ed25519::ed25519_verify(public_key, digest, &sig)),
+    }
+}
+
+/// RFC 6979 nonce for a 256 bits curve and SHA-256, `x` is the private key and `h1` the
+/// reduced digest. `candidate` turns the generated bytes into a nonzero scalar below the order
+pub(crate) fn rfc6979_nonce<S>(
+    x: &[u8],
+    h1: &[u8],
+    candidate: impl Fn([u8; 32]) -> Option<S>,
+) -> S {
+    let hmac = |key: &[u8], parts: &[&[u8]]| -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    };
+    let mut v = [0x01u8; 32];
+    let mut k = [0x00u8; 32];
+    k = hmac(&k, &[&v, &[0x00], x, h1]);
+    v = hmac(&k, &[&v]);
+    k = hmac(&k, &[&v, &[0x01], x, h1]);
+    v = hmac(&k, &[&v]);
+    loop {
+        v = hmac(&k, &[&v]);
+        if let Some(nonce) = candidate(v) {
+            return nonce;
+        }
+        k = hmac(&k, &[&v, &[0x00]]);
+        v = hmac(&k, &[&v]);
+    }
+}
+
+/// parse a BIP32 style derivation path like `m/44'/0'/0'/0/0`
+pub(crate) fn parse_path(path: &str) -> Result<DerivationPath, CKMError> {
+    path.parse().map_err(|e| CKMError::InvalidPath {
+        path: path.to_string(),
+        source: Some(std::sync::Arc::new(e)),
+    })
+}
+
+/// signature with 32 bytes big endian `r` and `s`, and the recovery id `v` when the curve has one,
+/// serialized with hex encoded `r` and `s`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
 pub struct SigningSignature {
-    pub r: String,
-    pub s: String,
-    pub v: Option<String>,
+    #[serde(with = "hex::serde")]
+    pub r: [u8; 32],
+    #[serde(with = "hex::serde")]
+    pub s: [u8; 32],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u8>,
+}
+
+impl SigningSignature {
+    /// 64 bytes compact `r || s` encoding
+    pub fn to_compact(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.r);
+        bytes[32..].copy_from_slice(&self.s);
+        bytes
+    }
+
+    /// parse a 64 bytes compact `r || s` signature
+    pub fn from_compact(bytes: &[u8]) -> Result<Self, CKMError> {
+        if bytes.len() != 64 {
+            return Err(CKMError::SignatureFormatError(
+                "compact signature must be 64 bytes".to_string(),
+            ));
+        }
+        Ok(SigningSignature {
+            r: bytes[..32].try_into().unwrap(),
+            s: bytes[32..].try_into().unwrap(),
+            v: None,
+        })
+    }
+
+    /// 65 bytes recoverable `r || s || v` encoding, as used by Ethereum
+    pub fn to_rsv(&self) -> Result<[u8; 65], CKMError> {
+        let v = self.recovery_id()?;
+        let mut bytes = [0u8; 65];
+        bytes[..64].copy_from_slice(&self.to_compact());
+        bytes[64] = v;
+        Ok(bytes)
+    }
+
+    /// parse a 65 bytes recoverable `r || s || v` signature
+    pub fn from_rsv(bytes: &[u8]) -> Result<Self, CKMError> {
+        if bytes.len() != 65 {
+            return Err(CKMError::SignatureFormatError(
+                "recoverable signature must be 65 bytes".to_string(),
+            ));
+        }
+        let mut sig = Self::from_compact(&bytes[..64])?;
+        sig.v = Some(bytes[64]);
+        Ok(sig)
+    }
+
+    /// 65 bytes recoverable `v || r || s` encoding
+    pub fn to_vrs(&self) -> Result<[u8; 65], CKMError> {
+        let v = self.recovery_id()?;
+        let mut bytes = [0u8; 65];
+        bytes[0] = v;
+        bytes[1..].copy_from_slice(&self.to_compact());
+        Ok(bytes)
+    }
+
+    /// parse a 65 bytes recoverable `v || r || s` signature
+    pub fn from_vrs(bytes: &[u8]) -> Result<Self, CKMError> {
+        if bytes.len() != 65 {
+            return Err(CKMError::SignatureFormatError(
+                "recoverable signature must be 65 bytes".to_string(),
+            ));
+        }
+        let mut sig = Self::from_compact(&bytes[1..])?;
+        sig.v = Some(bytes[0]);
+        Ok(sig)
+    }
+
+    /// ASN.1 DER `SEQUENCE { r INTEGER, s INTEGER }` encoding
+    pub fn to_der(&self) -> Vec<u8> {
+        let r = _der_integer(&self.r);
+        let s = _der_integer(&self.s);
+        let mut bytes = vec![0x30, (r.len() + s.len()) as u8];
+        bytes.extend(r);
+        bytes.extend(s);
+        bytes
+    }
+
+    /// parse a strict ASN.1 DER signature
+    pub fn from_der(bytes: &[u8]) -> Result<Self, CKMError> {
+        let invalid = || CKMError::SignatureFormatError("invalid DER signature".to_string());
+        if bytes.len() < 8 || bytes[0] != 0x30 || bytes[1] as usize != bytes.len() - 2 {
+            return Err(invalid());
+        }
+        let (r, rest) = _parse_der_integer(&bytes[2..]).ok_or_else(invalid)?;
+        let (s, rest) = _parse_der_integer(rest).ok_or_else(invalid)?;
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SigningSignature { r, s, v: None })
+    }
+
+    /// whether `s` is in the lower half of the secp256k1 order
+    pub fn is_low_s(&self) -> bool {
+        match Scalar::from_repr(self.s.into()) {
+            Some(s) => !bool::from(s.is_high()),
+            None => false,
+        }
+    }
+
+    /// normalize a secp256k1 signature to low-S, flipping the recovery id parity when `s` is negated
+    pub fn normalize_s(&mut self) -> Result<(), CKMError> {
+        let s = Scalar::from_repr(self.s.into()).ok_or_else(|| {
+            CKMError::SignatureFormatError("s is not a secp256k1 scalar".to_string())
+        })?;
+        if bool::from(s.is_high()) {
+            self.s = (-s).to_bytes().into();
+            self.v = self.v.map(|v| v ^ 1);
+        }
+        Ok(())
+    }
+
+    fn recovery_id(&self) -> Result<u8, CKMError> {
+        self.v.ok_or_else(|| {
+            CKMError::SignatureFormatError("signature has no recovery id".to_string())
+        })
+    }
+}
+
+/// minimal DER integer of a big endian unsigned value
+fn _der_integer(value: &[u8; 32]) -> Vec<u8> {
+    let start = value.iter().position(|b| *b != 0).unwrap_or(31);
+    let value = &value[start..];
+    let mut bytes = vec![0x02];
+    if value[0] & 0x80 != 0 {
+        bytes.push(value.len() as u8 + 1);
+        bytes.push(0x00);
+    } else {
+        bytes.push(value.len() as u8);
+    }
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// parse a minimal positive DER integer of at most 32 bytes, returns the value and the remaining bytes
+fn _parse_der_integer(bytes: &[u8]) -> Option<([u8; 32], &[u8])> {
+    if bytes.len() < 2 || bytes[0] != 0x02 {
+        return None;
+    }
+    let len = bytes[1] as usize;
+    let rest = bytes.get(2..)?;
+    if len == 0 || len > rest.len() {
+        return None;
+    }
+    let (value, rest) = rest.split_at(len);
+    if value[0] & 0x80 != 0 || (len > 1 && value[0] == 0 && value[1] & 0x80 == 0) {
+        return None;
+    }
+    let value = if value[0] == 0 && len > 1 {
+        &value[1..]
+    } else {
+        value
+    };
+    if value.len() > 32 {
+        return None;
+    }
+    let mut integer = [0u8; 32];
+    integer[32 - value.len()..].copy_from_slice(value);
+    Some((integer, rest))
 }
 
 pub trait CurveSign {
@@ -14,12 +271,99 @@ pub trait CurveSign {
         &self,
         request: &SignRequest,
         password: &str,
-        store: &impl Keystore,
+        store: &impl KeySource,
     ) -> Result<Vec<u8>, CKMError>;
     fn sign(
         &self,
         request: &SignRequest,
         password: &str,
-        store: &impl Keystore,
+        store: &impl KeySource,
     ) -> Result<SigningSignature, CKMError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::decode;
+
+    fn sample() -> SigningSignature {
+        SigningSignature {
+            r: decode("38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            s: decode("0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            v: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_encodings() {
+        let sig = sample();
+        let der = sig.to_der();
+        assert_eq!(
+            hex::encode(&der),
+            "3044022038a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d\
+             02200a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d"
+        );
+        assert_eq!(
+            SigningSignature::from_der(&der).unwrap(),
+            SigningSignature { v: None, ..sig }
+        );
+        // padded high bit and short integers
+        let small = SigningSignature {
+            r: [0xffu8; 32],
+            s: {
+                let mut s = [0u8; 32];
+                s[31] = 1;
+                s
+            },
+            v: None,
+        };
+        let der = small.to_der();
+        assert_eq!(der.len(), 2 + 35 + 3);
+        assert_eq!(SigningSignature::from_der(&der).unwrap(), small);
+        assert!(SigningSignature::from_der(&der[..der.len() - 1]).is_err());
+
+        let compact = sig.to_compact();
+        assert_eq!(SigningSignature::from_compact(&compact).unwrap().r, sig.r);
+
+        let rsv = sig.to_rsv().unwrap();
+        assert_eq!(rsv[64], 1);
+        assert_eq!(SigningSignature::from_rsv(&rsv).unwrap(), sig);
+        let vrs = sig.to_vrs().unwrap();
+        assert_eq!(vrs[0], 1);
+        assert_eq!(SigningSignature::from_vrs(&vrs).unwrap(), sig);
+        assert!(SigningSignature { v: None, ..sig }.to_rsv().is_err());
+    }
+
+    #[test]
+    fn test_normalize_s() {
+        let sig = sample();
+        assert!(sig.is_low_s());
+        let mut high = sig;
+        high.s = (-Scalar::from_repr(sig.s.into()).unwrap())
+            .to_bytes()
+            .into();
+        high.v = Some(0);
+        assert!(!high.is_low_s());
+        high.normalize_s().unwrap();
+        assert_eq!(high, sig);
+    }
+
+    #[test]
+    fn test_serde() {
+        let sig = sample();
+        let json = serde_json::to_string(&sig).unwrap();
+        assert_eq!(
+            json,
+            "{\"r\":\"38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d\",\
+             \"s\":\"0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d\",\"v\":1}"
+        );
+        let parsed: SigningSignature = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, sig);
+    }
+}
@@ -0,0 +1,229 @@
+use std::convert::TryInto;
+
+use crate::keystore::{imported_key, read_seed, KeySource};
+use crate::{CKMError, CurveSign, SignRequest, SigningSignature};
+
+use super::{parse_path, rfc6979_nonce};
+use ecdsa::{
+    hazmat::{SignPrimitive, VerifyPrimitive},
+    signature::Signature,
+};
+use hmac::{Hmac, Mac, NewMac};
+use p256::{
+    elliptic_curve::{
+        group::ff::PrimeField,
+        sec1::{FromEncodedPoint, ToEncodedPoint},
+    },
+    AffinePoint, EncodedPoint, ProjectivePoint, Scalar,
+};
+use sha2::{Digest, Sha256, Sha512};
+
+pub(crate) struct R1 {}
+
+impl CurveSign for R1 {
+    fn derive_key(
+        &self,
+        request: &SignRequest,
+        password: &str,
+        store: &impl KeySource,
+    ) -> Result<Vec<u8>, CKMError> {
+        let seed = read_seed(store, password, request.key_id)?;
+        derive_from_seed(&seed, request.path)
+    }
+
+    fn sign(
+        &self,
+        request: &SignRequest,
+        password: &str,
+        store: &impl KeySource,
+    ) -> Result<SigningSignature, CKMError> {
+        let key = zeroize::Zeroizing::new(self.derive_key(request, password, store)?);
+        let digest: [u8; 32] = Sha256::digest(&request.unsigend_data).into();
+        r1_sign_digest(&key, &digest)
+    }
+}
+
+/// derive the P-256 private key of the path from a seed with SLIP-10
+pub(crate) fn derive_from_seed(seed: &[u8], path: &str) -> Result<Vec<u8>, CKMError> {
+    // imported keys are secp256k1 keys
+    if imported_key(seed).is_some() {
+        return Err(CKMError::UnsupportedCurve);
+    }
+    let path = parse_path(path)?;
+    let (mut key, mut chain_code) = _slip10_key(b"Nist256p1 seed", seed);
+    for child in path.iter() {
+        let mut data = if child.is_hardened() {
+            let mut data = vec![0u8];
+            data.extend_from_slice(&key.to_bytes());
+            data
+        } else {
+            _point_bytes(&(ProjectivePoint::generator() * key).to_affine(), true)
+        };
+        data.extend_from_slice(&child.to_bytes());
+        loop {
+            let i = hmac_sha512(&chain_code, &data);
+            let tweak = Scalar::from_repr(_field_bytes(&i));
+            let child_key = tweak.map(|tweak| tweak + key);
+            match child_key {
+                Some(child_key) if !bool::from(child_key.is_zero()) => {
+                    key = child_key;
+                    chain_code = i[32..].try_into().unwrap();
+                    break;
+                }
+                // SLIP-10 retries with `0x01 || I_R || index` for an invalid child key
+                _ => {
+                    data = vec![0x01];
+                    data.extend_from_slice(&i[32..]);
+                    data.extend_from_slice(&child.to_bytes());
+                }
+            }
+        }
+    }
+    Ok(key.to_bytes().to_vec())
+}
+
+/// 33 bytes compressed public key of a P-256 private key
+pub(crate) fn r1_public_key(key_bytes: &[u8]) -> Result<[u8; 33], CKMError> {
+    let d = secret_scalar(key_bytes)?;
+    _point_bytes(&(ProjectivePoint::generator() * d).to_affine(), true)
+        .try_into()
+        .map_err(|_e| CKMError::SigningError)
+}
+
+/// sign a 32 bytes digest with an RFC 6979 nonce, P-256 signatures are not normalized to low-S
+pub(crate) fn r1_sign_digest(
+    key_bytes: &[u8],
+    digest: &[u8; 32],
+) -> Result<SigningSignature, CKMError> {
+    let d = secret_scalar(key_bytes)?;
+    let z = Scalar::from_bytes_reduced(&(*digest).into());
+    let k = rfc6979_nonce(&d.to_bytes(), &z.to_bytes(), |v| {
+        Scalar::from_repr(v.into()).filter(|k| !bool::from(k.is_zero()))
+    });
+    let sig = d
+        .try_sign_prehashed(&k, &z)
+        .map_err(|e| CKMError::crypto("secp256r1 signing failed", e))?;
+    SigningSignature::from_compact(sig.as_bytes())
+}
+
+/// verify a `r || s` signature of a 32 bytes digest against a SEC1 encoded P-256 public key
+pub(crate) fn r1_verify_digest(public_key: &[u8], digest: &[u8; 32], sig: &[u8; 64]) -> bool {
+    let point = match EncodedPoint::from_bytes(public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+    {
+        Some(point) => point,
+        None => return false,
+    };
+    let sig = match p256::ecdsa::Signature::from_bytes(sig) {
+        Ok(sig) => sig,
+        Err(_e) => return false,
+    };
+    let z = Scalar::from_bytes_reduced(&(*digest).into());
+    point.verify_prehashed(&z, &sig).is_ok()
+}
+
+/// uncompressed SEC1 encoding of a P-256 public key, `None` for an invalid key
+#[cfg(any(feature = "ssh", feature = "jose"))]
+pub(crate) fn r1_uncompressed(public_key: &[u8]) -> Option<Vec<u8>> {
+    EncodedPoint::from_bytes(public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+        .map(|point| _point_bytes(&point, false))
+}
+
+/// ECDH on P-256, returns the uncompressed SEC1 shared point
+pub(crate) fn r1_shared_point(
+    key_bytes: &[u8],
+    peer_public_key: &[u8],
+) -> Result<Vec<u8>, CKMError> {
+    let d = secret_scalar(key_bytes)?;
+    let peer = EncodedPoint::from_bytes(peer_public_key)
+        .ok()
+        .and_then(|encoded| AffinePoint::from_encoded_point(&encoded))
+        .ok_or_else(|| CKMError::EcdhError("invalid P-256 public key".to_string()))?;
+    let shared = _point_bytes(&(ProjectivePoint::from(peer) * d).to_affine(), false);
+    if shared.len() != 65 {
+        return Err(CKMError::EcdhError(
+            "shared point is the identity".to_string(),
+        ));
+    }
+    Ok(shared)
+}
+
+pub(crate) fn secret_scalar(key_bytes: &[u8]) -> Result<Scalar, CKMError> {
+    let key: [u8; 32] = key_bytes.try_into().map_err(|_e| CKMError::SigningError)?;
+    let d = Scalar::from_repr(key.into()).ok_or(CKMError::SigningError)?;
+    if bool::from(d.is_zero()) {
+        return Err(CKMError::SigningError);
+    }
+    Ok(d)
+}
+
+fn _slip10_key(curve_key: &[u8], seed: &[u8]) -> (Scalar, [u8; 32]) {
+    let mut i = hmac_sha512(curve_key, seed);
+    loop {
+        if let Some(key) = Scalar::from_repr(_field_bytes(&i)) {
+            if !bool::from(key.is_zero()) {
+                return (key, i[32..].try_into().unwrap());
+            }
+        }
+        i = hmac_sha512(curve_key, &i);
+    }
+}
+
+fn _field_bytes(i: &[u8; 64]) -> p256::FieldBytes {
+    let bytes: [u8; 32] = i[..32].try_into().unwrap();
+    bytes.into()
+}
+
+fn _point_bytes(point: &AffinePoint, compress: bool) -> Vec<u8> {
+    point.to_encoded_point(compress).as_bytes().to_vec()
+}
+
+/// HMAC-SHA512 used by the SLIP-10 derivations
+pub(crate) fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+    mac.update(data);
+    let mut bytes = [0u8; 64];
+    bytes.copy_from_slice(&mac.finalize().into_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::{decode, encode};
+
+    #[test]
+    fn test_slip10_vectors() {
+        let seed = decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!(
+            encode(derive_from_seed(&seed, "m").unwrap()),
+            "612091aaa12e22dd2abef664f8a01a82cae99ad7441b7ef8110424915c268bc2"
+        );
+        assert_eq!(
+            encode(derive_from_seed(&seed, "m/0'").unwrap()),
+            "6939694369114c67917a182c59ddb8cafc3004e63ca5d3b84403ba8613debc0c"
+        );
+    }
+
+    #[test]
+    fn test_rfc6979_vector() {
+        // RFC 6979 A.2.5, P-256 with SHA-256 over "sample"
+        let key =
+            decode("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721").unwrap();
+        let digest: [u8; 32] = Sha256::digest(b"sample").into();
+        let sig = r1_sign_digest(&key, &digest).unwrap();
+        assert_eq!(
+            encode(sig.r),
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716"
+        );
+        assert_eq!(
+            encode(sig.s),
+            "f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"
+        );
+        let public_key = r1_public_key(&key).unwrap();
+        assert!(r1_verify_digest(&public_key, &digest, &sig.to_compact()));
+    }
+}
@@ -0,0 +1,311 @@
+use std::convert::TryInto;
+
+use crate::{keystore::KeySource, CKMError, CurveSign, SignRequest, SigningSignature};
+
+use super::k1::K1;
+use k256::{
+    elliptic_curve::{
+        group::ff::PrimeField,
+        sec1::{FromEncodedPoint, ToEncodedPoint},
+    },
+    AffinePoint, EncodedPoint, FieldBytes, ProjectivePoint, Scalar,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+
+/// secp256k1 field size p, big endian
+const FIELD_SIZE: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xfc, 0x2f,
+];
+
+/// BIP341 key tweak applied before signing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TapTweak {
+    /// merkle root of the script tree, `None` for a key-path only output
+    pub merkle_root: Option<[u8; 32]>,
+}
+
+/// options for BIP340 schnorr signing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SchnorrOptions {
+    /// auxiliary randomness, fresh random bytes are used when it is `None`
+    pub aux_rand: Option<[u8; 32]>,
+    /// tweak the key as a taproot output key before signing
+    pub tweak: Option<TapTweak>,
+}
+
+#[derive(Default)]
+pub(crate) struct Schnorr {
+    pub(crate) options: SchnorrOptions,
+}
+
+impl CurveSign for Schnorr {
+    fn derive_key(
+        &self,
+        request: &SignRequest,
+        password: &str,
+        store: &impl KeySource,
+    ) -> Result<Vec<u8>, CKMError> {
+        let key = K1 {}.derive_key(request, password, store)?;
+        match self.options.tweak {
+            Some(tweak) => tweak_secret_key(&key, tweak.merkle_root.as_ref()),
+            None => Ok(key),
+        }
+    }
+
+    fn sign(
+        &self,
+        request: &SignRequest,
+        password: &str,
+        store: &impl KeySource,
+    ) -> Result<SigningSignature, CKMError> {
+        let key = self.derive_key(request, password, store)?;
+        let sig = schnorr_sign(&key, &request.unsigend_data, self.options.aux_rand)?;
+        SigningSignature::from_compact(&sig)
+    }
+}
+
+/// get the 32 bytes x-only public key of a secp256k1 private key
+pub(crate) fn x_only_public_key(key_bytes: &[u8]) -> Result<[u8; 32], CKMError> {
+    let d = _secret_scalar(key_bytes)?;
+    let (_, p) = _even_y_point(&d);
+    Ok(p)
+}
+
+/// tweak a secp256k1 private key with BIP341 `taproot_tweak_seckey`
+pub(crate) fn tweak_secret_key(
+    key_bytes: &[u8],
+    merkle_root: Option<&[u8; 32]>,
+) -> Result<Vec<u8>, CKMError> {
+    let d = _secret_scalar(key_bytes)?;
+    let (d, p) = _even_y_point(&d);
+    let mut data = p.to_vec();
+    if let Some(root) = merkle_root {
+        data.extend_from_slice(root);
+    }
+    let t = tagged_hash("TapTweak", &data);
+    let t = Scalar::from_repr(t.into()).ok_or(CKMError::SigningError)?;
+    let tweaked = d + t;
+    if bool::from(tweaked.is_zero()) {
+        return Err(CKMError::SigningError);
+    }
+    Ok(tweaked.to_bytes().to_vec())
+}
+
+/// verify a BIP340 schnorr signature against a 32 bytes x-only public key
+pub fn schnorr_verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let (public_key, signature): (&[u8; 32], &[u8; 64]) =
+        match (public_key.try_into(), signature.try_into()) {
+            (Ok(p), Ok(s)) => (p, s),
+            _ => return false,
+        };
+    let p = match _lift_x(public_key) {
+        Some(p) => p,
+        None => return false,
+    };
+    let r: [u8; 32] = signature[0..32].try_into().unwrap();
+    if r >= FIELD_SIZE {
+        return false;
+    }
+    let s_bytes: [u8; 32] = signature[32..].try_into().unwrap();
+    let s = match Scalar::from_repr(s_bytes.into()) {
+        Some(s) => s,
+        None => return false,
+    };
+    let e = _challenge(&r, public_key, message);
+    let big_r = (ProjectivePoint::generator() * s - ProjectivePoint::from(p) * e).to_affine();
+    match _x_and_parity(&big_r) {
+        Some((x, false)) => x == r,
+        _ => false,
+    }
+}
+
+/// BIP340 sign the message, fresh auxiliary randomness is used when `aux_rand` is `None`
+pub(crate) fn schnorr_sign(
+    key_bytes: &[u8],
+    message: &[u8],
+    aux_rand: Option<[u8; 32]>,
+) -> Result<[u8; 64], CKMError> {
+    let aux_rand = match aux_rand {
+        Some(aux_rand) => aux_rand,
+        None => {
+            let mut aux_rand = [0u8; 32];
+            SystemRandom::new()
+                .fill(&mut aux_rand)
+                .map_err(|_e| CKMError::RandomError)?;
+            aux_rand
+        }
+    };
+    let d = _secret_scalar(key_bytes)?;
+    let (d, p) = _even_y_point(&d);
+
+    let mut t: [u8; 32] = tagged_hash("BIP0340/aux", &aux_rand);
+    for (t, d) in t.iter_mut().zip(d.to_bytes().iter()) {
+        *t ^= d;
+    }
+    let mut nonce_input = t.to_vec();
+    nonce_input.extend_from_slice(&p);
+    nonce_input.extend_from_slice(message);
+    let rand = tagged_hash("BIP0340/nonce", &nonce_input);
+    let k = Scalar::from_bytes_reduced(&rand.into());
+    if bool::from(k.is_zero()) {
+        return Err(CKMError::SigningError);
+    }
+    let (k, r) = _even_y_point(&k);
+    let e = _challenge(&r, &p, message);
+
+    let mut sig = [0u8; 64];
+    sig[0..32].copy_from_slice(&r);
+    sig[32..].copy_from_slice(&(k + e * d).to_bytes());
+    if !schnorr_verify(&p, message, &sig) {
+        return Err(CKMError::SigningError);
+    }
+    Ok(sig)
+}
+
+fn _secret_scalar(key_bytes: &[u8]) -> Result<Scalar, CKMError> {
+    let key: [u8; 32] = key_bytes.try_into().map_err(|_e| CKMError::SigningError)?;
+    let d = Scalar::from_repr(key.into()).ok_or(CKMError::SigningError)?;
+    if bool::from(d.is_zero()) {
+        return Err(CKMError::SigningError);
+    }
+    Ok(d)
+}
+
+/// negate the scalar if needed so that its point has an even y, returns the scalar and the point x
+fn _even_y_point(d: &Scalar) -> (Scalar, [u8; 32]) {
+    let point = (ProjectivePoint::generator() * d).to_affine();
+    // the scalar is never zero here, so the point is never the identity
+    let (x, odd) = _x_and_parity(&point).unwrap();
+    if odd {
+        (-d, x)
+    } else {
+        (*d, x)
+    }
+}
+
+fn _x_and_parity(point: &AffinePoint) -> Option<([u8; 32], bool)> {
+    let encoded = point.to_encoded_point(true);
+    let bytes = encoded.as_bytes();
+    if bytes.len() != 33 {
+        return None;
+    }
+    let x: [u8; 32] = bytes[1..].try_into().unwrap();
+    Some((x, bytes[0] == 0x03))
+}
+
+fn _lift_x(x: &[u8; 32]) -> Option<AffinePoint> {
+    let mut compressed = vec![0x02];
+    compressed.extend_from_slice(x);
+    let encoded = EncodedPoint::from_bytes(&compressed).ok()?;
+    AffinePoint::from_encoded_point(&encoded)
+}
+
+fn _challenge(r: &[u8; 32], p: &[u8; 32], message: &[u8]) -> Scalar {
+    let mut data = r.to_vec();
+    data.extend_from_slice(p);
+    data.extend_from_slice(message);
+    let e: FieldBytes = tagged_hash("BIP0340/challenge", &data).into();
+    Scalar::from_bytes_reduced(&e)
+}
+
+/// BIP340 tagged hash `sha256(sha256(tag) || sha256(tag) || data)`
+pub(crate) fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{keystore::fake::FakeKeystore, Curve};
+
+    use super::*;
+    use hex::{decode, encode};
+
+    const BIP340_VECTORS: &str = include_str!("test_vectors/bip340.csv");
+
+    #[test]
+    fn test_bip340_vectors() {
+        for line in BIP340_VECTORS.lines().skip(1) {
+            let fields: Vec<&str> = line.split(',').collect();
+            let index = fields[0];
+            let secret_key = decode(fields[1]).unwrap();
+            let public_key = decode(fields[2]).unwrap();
+            let aux_rand = decode(fields[3]).unwrap();
+            let message = decode(fields[4]).unwrap();
+            let signature = decode(fields[5]).unwrap();
+            let valid = fields[6] == "TRUE";
+
+            if !secret_key.is_empty() {
+                let pubkey = x_only_public_key(&secret_key).unwrap();
+                assert_eq!(
+                    pubkey.to_vec(),
+                    public_key,
+                    "public key of vector {}",
+                    index
+                );
+                let aux_rand: [u8; 32] = aux_rand.try_into().unwrap();
+                let sig = schnorr_sign(&secret_key, &message, Some(aux_rand)).unwrap();
+                assert_eq!(sig.to_vec(), signature, "signature of vector {}", index);
+            }
+            assert_eq!(
+                schnorr_verify(&public_key, &message, &signature),
+                valid,
+                "verification of vector {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn test_bip86_tweak() {
+        // BIP86 test vector, the fake store holds the "abandon ... about" seed
+        let fake_store = FakeKeystore {};
+        let request = SignRequest {
+            path: "m/86'/0'/0'/0/0",
+            unsigend_data: vec![],
+            key_id: "123456",
+            curve: Curve::Secp256k1Schnorr,
+        };
+        let key = K1 {}.derive_key(&request, "pass", &fake_store).unwrap();
+        assert_eq!(
+            encode(x_only_public_key(&key).unwrap()),
+            "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115"
+        );
+        let tweaked = tweak_secret_key(&key, None).unwrap();
+        assert_eq!(
+            encode(x_only_public_key(&tweaked).unwrap()),
+            "a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let fake_store = FakeKeystore {};
+
+        let schnorr = Schnorr {
+            options: SchnorrOptions {
+                aux_rand: None,
+                tweak: Some(TapTweak { merkle_root: None }),
+            },
+        };
+
+        let request = SignRequest {
+            path: "m/86'/0'/0'/0/0",
+            unsigend_data: [7u8; 32].to_vec(),
+            key_id: "123456",
+            curve: Curve::Secp256k1Schnorr,
+        };
+
+        let sig = schnorr.sign(&request, "pass", &fake_store).unwrap();
+        let sig_bytes = sig.to_compact();
+        let output_key =
+            decode("a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c").unwrap();
+        assert!(schnorr_verify(&output_key, &[7u8; 32], &sig_bytes));
+    }
+}
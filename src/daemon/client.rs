@@ -0,0 +1,168 @@
+use super::*;
+use serde::de::DeserializeOwned;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// client of a `DaemonServer`, with the signing API of `KeyMaster`. Requests share one
+/// connection and are sent one at a time, a connection idle for long is replaced before the
+/// daemon closes it
+pub struct DaemonClient {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+    last_used: Instant,
+}
+
+impl Connection {
+    fn open(path: &Path) -> Result<Self, CKMError> {
+        let writer = UnixStream::connect(path).map_err(|e| CKMError::io(path, e))?;
+        writer.set_read_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+        writer.set_write_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+        let reader = BufReader::new(writer.try_clone().map_err(_io_error)?);
+        Ok(Self {
+            reader,
+            writer,
+            next_id: 0,
+            last_used: Instant::now(),
+        })
+    }
+}
+
+impl DaemonClient {
+    /// connect to the daemon listening on the socket path
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, CKMError> {
+        let path = path.as_ref().to_path_buf();
+        let conn = Connection::open(&path)?;
+        Ok(Self {
+            path,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// sign the request, the password is ignored while the key is unlocked in the daemon
+    pub fn sign(
+        &self,
+        sign_request: SignRequest,
+        password: &str,
+    ) -> Result<SigningSignature, CKMError> {
+        self._call(
+            "sign",
+            SignParams {
+                key_id: sign_request.key_id.to_string(),
+                path: sign_request.path.to_string(),
+                curve: sign_request.curve,
+                data: sign_request.unsigend_data,
+                password: password.to_string(),
+            },
+        )
+    }
+
+    /// public key of the path, see `KeyMaster::get_public_key`
+    pub fn get_public_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<Vec<u8>, CKMError> {
+        let public_key: String = self._call(
+            "get_public_key",
+            PublicKeyParams {
+                key_id: key_id.to_string(),
+                path: path.to_string(),
+                curve,
+                password: password.to_string(),
+            },
+        )?;
+        hex::decode(public_key).map_err(|_e| _protocol_error("invalid response"))
+    }
+
+    /// ids of the keys in the store of the daemon
+    pub fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        self._call("list_keys", serde_json::Value::Null)
+    }
+
+    /// unlock the key in the daemon for `ttl`, every client can then sign with it without the
+    /// password
+    pub fn unlock(
+        &self,
+        key_id: &str,
+        password: &str,
+        ttl: Duration,
+    ) -> Result<UnlockSession, CKMError> {
+        let result: UnlockResult = self._call(
+            "unlock",
+            UnlockParams {
+                key_id: key_id.to_string(),
+                password: password.to_string(),
+                ttl_ms: ttl.as_millis() as u64,
+            },
+        )?;
+        let expires_at = Instant::now() + Duration::from_millis(result.expires_in_ms);
+        Ok(UnlockSession::new(&result.key_id, expires_at))
+    }
+
+    /// end the session of a key in the daemon, returns whether it was unlocked
+    pub fn lock(&self, key_id: &str) -> Result<bool, CKMError> {
+        self._call(
+            "lock",
+            LockParams {
+                key_id: key_id.to_string(),
+            },
+        )
+    }
+
+    fn _call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: impl Serialize,
+    ) -> Result<T, CKMError> {
+        let mut conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        if conn.last_used.elapsed() >= IDLE_TIMEOUT / 2 {
+            let next_id = conn.next_id;
+            *conn = Connection::open(&self.path)?;
+            conn.next_id = next_id;
+        }
+        conn.last_used = Instant::now();
+        conn.next_id += 1;
+        let id = conn.next_id;
+        let request = RpcRequest {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id: Some(id.into()),
+            method: method.to_string(),
+            params: serde_json::to_value(params).map_err(|_e| CKMError::SerializeError)?,
+        };
+        let mut line = serde_json::to_vec(&request).map_err(|_e| CKMError::SerializeError)?;
+        line.push(b'\n');
+        // a daemon refusing the peer answers and closes before reading, so the write can fail
+        // with the answer waiting
+        let written = conn.writer.write_all(&line).map_err(_io_error);
+        let response = match (read_message(&mut conn.reader), written) {
+            (Ok(Some(response)), _) => response,
+            (_, Err(e)) | (Err(e), Ok(())) => return Err(e),
+            (Ok(None), Ok(())) => return Err(_protocol_error("connection closed")),
+        };
+
+        let response: RpcResponse =
+            serde_json::from_slice(&response).map_err(|_e| _protocol_error("invalid response"))?;
+        if !response.id.is_null() && response.id != id {
+            return Err(_protocol_error("response id mismatch"));
+        }
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(error.into_error()),
+            (Some(result), None) => {
+                serde_json::from_value(result).map_err(|_e| _protocol_error("invalid response"))
+            }
+            (None, None) => Err(_protocol_error("empty response")),
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! local signing daemon, one process owns a `KeyMaster` and serves it to the apps of the host
+//! over newline delimited JSON-RPC 2.0 on a Unix domain socket. Connections are only served
+//! when the peer credentials of the socket show an allowed user
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+mod client;
+mod server;
+
+pub use client::DaemonClient;
+pub use server::DaemonServer;
+
+const JSONRPC_VERSION: &str = "2.0";
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+/// the server closes connections without a request for this long, clients reconnect after half
+/// of it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// JSON-RPC errors of requests that did not reach the key master, the others carry
+/// `CKMError::code`
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// the peer user is not allowed, sent before the connection is closed
+const PEER_NOT_ALLOWED: i64 = -32000;
+/// `unlock` and `lock` are only served to the user running the daemon
+const OWNER_ONLY: i64 = -32001;
+
+/// request line, notifications without `id` are not supported
+#[derive(Serialize, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RpcResponse {
+    jsonrpc: String,
+    id: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// the client side `CKMError`, variants without data keep their type and the rest become
+    /// `RemoteError`
+    fn into_error(self) -> CKMError {
+        match self.code {
+            100 => CKMError::NotFound(self.message.trim_start_matches("not found ").to_string()),
+            101 => CKMError::NotExist,
+            102 => CKMError::PasswordInvalid,
+            103 => CKMError::KeyNotExportable,
+            104 => CKMError::VaultLocked,
+            203 => CKMError::FileNotExit,
+            300 => CKMError::SigningError,
+            301 => CKMError::InvalidPath {
+                path: self
+                    .message
+                    .trim_start_matches("invalid derivation path ")
+                    .to_string(),
+                source: None,
+            },
+            302 => CKMError::UnsupportedCurve,
+            303 => CKMError::InternalSigningUnsupported,
+            _ => CKMError::RemoteError(format!("{}: {}", self.code, self.message)),
+        }
+    }
+}
+
+impl From<CKMError> for RpcError {
+    fn from(e: CKMError) -> Self {
+        Self {
+            code: i64::from(e.code()),
+            message: e.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignParams {
+    key_id: String,
+    path: String,
+    curve: Curve,
+    #[serde(with = "hex::serde")]
+    data: Vec<u8>,
+    /// not needed while the key is unlocked
+    #[serde(default)]
+    password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PublicKeyParams {
+    key_id: String,
+    path: String,
+    curve: Curve,
+    #[serde(default)]
+    password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnlockParams {
+    key_id: String,
+    password: String,
+    ttl_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnlockResult {
+    key_id: String,
+    expires_in_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LockParams {
+    key_id: String,
+}
+
+/// one message without the newline, `None` when the peer closed the connection
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, CKMError> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .map_err(_io_error)?;
+    match line.pop() {
+        None => Ok(None),
+        Some(b'\n') => Ok(Some(line)),
+        Some(_) if line.len() >= MAX_MESSAGE_SIZE => Err(_protocol_error("message too large")),
+        Some(_) => Err(_protocol_error("truncated message")),
+    }
+}
+
+fn _io_error(e: std::io::Error) -> CKMError {
+    CKMError::RemoteError(e.to_string())
+}
+
+fn _protocol_error(message: &str) -> CKMError {
+    CKMError::RemoteError(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::new_key_id;
+    use std::io::{BufReader, Write};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::keystore::fake::SEED;
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("ckm-{}.sock", new_key_id().unwrap()))
+    }
+
+    /// daemon over a memory keystore holding `SEED` with password "123"
+    fn start(allowed_uids: Option<&[u32]>) -> (PathBuf, String) {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        let path = socket_path();
+        let mut server = DaemonServer::bind(&path, key_master).unwrap();
+        if let Some(uids) = allowed_uids {
+            server = server.allowed_uids(uids);
+        }
+        thread::spawn(move || server.serve());
+        (path, key_id)
+    }
+
+    fn request(key_id: &str, curve: Curve) -> SignRequest<'_> {
+        SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id,
+            curve,
+        }
+    }
+
+    #[test]
+    fn test_daemon_loopback() {
+        let (path, key_id) = start(None);
+        let client = DaemonClient::connect(&path).unwrap();
+        assert_eq!(client.list_keys().unwrap(), vec![key_id.clone()]);
+
+        let local = KeyMaster::new(MemoryKeystore::new());
+        let local_id = local.write_seed("123", SEED.to_string()).unwrap();
+        for curve in [Curve::Secp256k1, Curve::Secp256R1, Curve::Ed25519] {
+            assert_eq!(
+                client
+                    .get_public_key(&key_id, "m/44'/0'/0'", curve, "123")
+                    .unwrap(),
+                local
+                    .get_public_key(&local_id, "m/44'/0'/0'", curve, "123")
+                    .unwrap()
+            );
+        }
+        let sig = client
+            .sign(request(&key_id, Curve::Secp256k1), "123")
+            .unwrap();
+        let expected = local
+            .sign(request(&local_id, Curve::Secp256k1), "123")
+            .unwrap();
+        assert_eq!((sig.r, sig.s, sig.v), (expected.r, expected.s, expected.v));
+
+        assert!(matches!(
+            client.sign(request(&key_id, Curve::Secp256k1), "456"),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(matches!(
+            client.get_public_key(&key_id, "m/x", Curve::Secp256k1, "123"),
+            Err(CKMError::InvalidPath { path, .. }) if path == "m/x"
+        ));
+        assert!(matches!(
+            client.get_public_key("unknown", "m/0", Curve::Secp256k1, "123"),
+            Err(CKMError::NotExist)
+        ));
+    }
+
+    #[test]
+    fn test_daemon_unlock() {
+        let (path, key_id) = start(None);
+        let first = DaemonClient::connect(&path).unwrap();
+        let second = DaemonClient::connect(&path).unwrap();
+
+        let session = first
+            .unlock(&key_id, "123", Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(session.key_id(), key_id);
+        assert!(!session.is_expired());
+        // the other app signs without the password while the key is unlocked
+        let sig = second
+            .sign(request(&key_id, Curve::Secp256k1Schnorr), "")
+            .unwrap();
+        let x_only = second
+            .get_public_key(&key_id, "m/44'/0'/0'/0/0", Curve::Secp256k1Schnorr, "")
+            .unwrap();
+        assert!(schnorr_verify(&x_only, b"hello", &sig.to_compact()));
+
+        assert!(second.lock(&key_id).unwrap());
+        assert!(!first.lock(&key_id).unwrap());
+        assert!(matches!(
+            first.sign(request(&key_id, Curve::Secp256k1), ""),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(matches!(
+            first.unlock(&key_id, "456", Duration::from_secs(60)),
+            Err(CKMError::PasswordInvalid)
+        ));
+    }
+
+    #[test]
+    fn test_daemon_protocol() {
+        let (path, _key_id) = start(None);
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut call = |line: &str| -> serde_json::Value {
+            writer.write_all(line.as_bytes()).unwrap();
+            writer.write_all(b"\n").unwrap();
+            serde_json::from_slice(&read_message(&mut reader).unwrap().unwrap()).unwrap()
+        };
+
+        let response = call("{not json");
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        assert_eq!(response["id"], serde_json::Value::Null);
+        let response = call(r#"{"jsonrpc":"2.0","method":"list_keys"}"#);
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        let response = call(r#"{"jsonrpc":"2.0","id":"a","method":"export"}"#);
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(response["id"], "a");
+        let response = call(r#"{"jsonrpc":"2.0","id":2,"method":"lock","params":{}}"#);
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        // the connection stays usable after errors
+        let response = call(r#"{"jsonrpc":"2.0","id":3,"method":"lock","params":{"key_id":"x"}}"#);
+        assert_eq!(response["result"], false);
+        assert_eq!(response["id"], 3);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let path = socket_path();
+        let server = DaemonServer::bind(&path, key_master)
+            .unwrap()
+            .with_max_connections(1);
+        thread::spawn(move || server.serve());
+
+        // an idle connection holds the only slot, the next client waits for it
+        let idle = UnixStream::connect(&path).unwrap();
+        let client = DaemonClient::connect(&path).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || sender.send(client.list_keys()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+        drop(idle);
+        let keys = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(keys.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_peer_not_allowed() {
+        let (path, _key_id) = start(Some(&[]));
+        let client = DaemonClient::connect(&path).unwrap();
+        match client.list_keys() {
+            Err(CKMError::RemoteError(message)) => assert!(message.contains("not allowed")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stale_socket() {
+        let path = socket_path();
+        let server = DaemonServer::bind(&path, KeyMaster::new(MemoryKeystore::new())).unwrap();
+        // a live daemon keeps its socket
+        assert!(DaemonServer::bind(&path, KeyMaster::new(MemoryKeystore::new())).is_err());
+        drop(server);
+        assert!(!path.exists());
+
+        // the socket of a daemon that is gone is replaced
+        let stale = socket_path();
+        drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+        assert!(stale.exists());
+        let server = DaemonServer::bind(&stale, KeyMaster::new(MemoryKeystore::new())).unwrap();
+        assert_eq!(server.path(), stale.as_path());
+
+        let file = socket_path();
+        std::fs::write(&file, b"").unwrap();
+        assert!(DaemonServer::bind(&file, KeyMaster::new(MemoryKeystore::new())).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+}
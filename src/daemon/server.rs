@@ -0,0 +1,259 @@
+use super::*;
+use crate::connection::{retry_accept, ConnectionLimit, MAX_CONNECTIONS};
+use crate::socket::{euid, peer_uid, remove_stale_socket};
+use std::fs;
+use std::io::{BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// signing daemon serving a `KeyMaster` on a Unix socket. Only the user running the daemon
+/// unlocks and locks keys, the sessions then serve all allowed clients. The socket file is
+/// removed when the server is dropped
+pub struct DaemonServer<Store> {
+    listener: UnixListener,
+    path: PathBuf,
+    owner: u32,
+    allowed_uids: Arc<Vec<u32>>,
+    key_master: Arc<KeyMaster<Store>>,
+    limit: ConnectionLimit,
+}
+
+impl<Store: Keystore + 'static> DaemonServer<Store> {
+    /// listen on the socket path with mode 0600, only the user running the daemon is allowed.
+    /// A socket left behind by a daemon that is gone is replaced
+    pub fn bind<P: AsRef<Path>>(path: P, key_master: KeyMaster<Store>) -> Result<Self, CKMError> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path).map_err(|e| CKMError::io(&path, e))?;
+        let server = Self {
+            listener,
+            path,
+            owner: euid(),
+            allowed_uids: Arc::new(vec![euid()]),
+            key_master: Arc::new(key_master),
+            limit: ConnectionLimit::new(MAX_CONNECTIONS),
+        };
+        server.set_mode(0o600)?;
+        Ok(server)
+    }
+
+    /// replace the users allowed to connect, other users also need write access to the socket
+    /// file, see `set_mode`
+    pub fn allowed_uids(mut self, uids: &[u32]) -> Self {
+        self.allowed_uids = Arc::new(uids.to_vec());
+        self
+    }
+
+    /// handle at most `max` connections at once, 64 by default, further clients wait in the
+    /// listen backlog until a connection ends
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limit = ConnectionLimit::new(max);
+        self
+    }
+
+    /// file mode of the socket, like 0660 to let a group connect
+    pub fn set_mode(&self, mode: u32) -> Result<(), CKMError> {
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(mode))
+            .map_err(|e| CKMError::io(&self.path, e))
+    }
+
+    /// path of the socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// serve connections until the listener fails, each connection runs on its own thread and
+    /// carries any number of requests. A connection is only accepted while fewer than the
+    /// maximum are handled, and closed when it is idle for 5 minutes. Failed accepts that only
+    /// concern one client or a passing lack of resources are logged to stderr
+    pub fn serve(self) -> Result<(), CKMError> {
+        loop {
+            let permit = self.limit.acquire();
+            let (stream, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if retry_accept("ckm daemon", &e) => continue,
+                Err(e) => return Err(CKMError::io(&self.path, e)),
+            };
+            let owner = self.owner;
+            let allowed_uids = self.allowed_uids.clone();
+            let key_master = self.key_master.clone();
+            thread::spawn(move || {
+                let _permit = permit;
+                // broken connections only affect their client
+                let _ = _handle(stream, owner, &allowed_uids, &key_master);
+            });
+        }
+    }
+}
+
+impl<Store> Drop for DaemonServer<Store> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn _handle<Store: Keystore>(
+    stream: UnixStream,
+    owner: u32,
+    allowed_uids: &[u32],
+    key_master: &KeyMaster<Store>,
+) -> Result<(), CKMError> {
+    stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .map_err(_io_error)?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+    let mut writer = stream.try_clone().map_err(_io_error)?;
+    let uid = peer_uid(&stream).map_err(_io_error)?;
+    if !allowed_uids.contains(&uid) {
+        let error = RpcError::new(PEER_NOT_ALLOWED, "peer not allowed");
+        return _respond(&mut writer, serde_json::Value::Null, Err(error));
+    }
+
+    let mut reader = BufReader::new(stream);
+    while let Some(message) = read_message(&mut reader)? {
+        let request = serde_json::from_slice::<serde_json::Value>(&message)
+            .map_err(|_e| RpcError::new(PARSE_ERROR, "parse error"))
+            .and_then(|value| {
+                serde_json::from_value::<RpcRequest>(value)
+                    .map_err(|_e| RpcError::new(INVALID_REQUEST, "invalid request"))
+            });
+        let (id, result) = match request {
+            Ok(RpcRequest {
+                jsonrpc,
+                id: Some(id),
+                method,
+                params,
+            }) if jsonrpc == JSONRPC_VERSION => {
+                (id, _dispatch(&method, params, uid == owner, key_master))
+            }
+            Ok(_) => (
+                serde_json::Value::Null,
+                Err(RpcError::new(INVALID_REQUEST, "invalid request")),
+            ),
+            Err(error) => (serde_json::Value::Null, Err(error)),
+        };
+        _respond(&mut writer, id, result)?;
+    }
+    Ok(())
+}
+
+fn _respond(
+    writer: &mut UnixStream,
+    id: serde_json::Value,
+    result: Result<serde_json::Value, RpcError>,
+) -> Result<(), CKMError> {
+    let (result, error) = match result {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    let response = RpcResponse {
+        jsonrpc: JSONRPC_VERSION.to_string(),
+        id,
+        result,
+        error,
+    };
+    let mut line = serde_json::to_vec(&response).map_err(|_e| CKMError::SerializeError)?;
+    line.push(b'\n');
+    writer.write_all(&line).map_err(_io_error)
+}
+
+fn _dispatch<Store: Keystore>(
+    method: &str,
+    params: serde_json::Value,
+    is_owner: bool,
+    key_master: &KeyMaster<Store>,
+) -> Result<serde_json::Value, RpcError> {
+    match method {
+        "unlock" | "lock" if !is_owner => Err(RpcError::new(
+            OWNER_ONLY,
+            "only the daemon owner unlocks and locks keys",
+        )),
+        "sign" => {
+            let params: SignParams = _params(params)?;
+            let request = SignRequest {
+                path: &params.path,
+                unsigend_data: params.data,
+                key_id: &params.key_id,
+                curve: params.curve,
+            };
+            _value(key_master.sign(request, &params.password)?)
+        }
+        "get_public_key" => {
+            let params: PublicKeyParams = _params(params)?;
+            let public_key = key_master.get_public_key(
+                &params.key_id,
+                &params.path,
+                params.curve,
+                &params.password,
+            )?;
+            _value(hex::encode(public_key))
+        }
+        "list_keys" => _value(key_master.list_keys()?),
+        "unlock" => {
+            let params: UnlockParams = _params(params)?;
+            let ttl = Duration::from_millis(params.ttl_ms);
+            let session = key_master.unlock(&params.key_id, &params.password, ttl)?;
+            let expires_in = session
+                .expires_at()
+                .saturating_duration_since(Instant::now());
+            _value(UnlockResult {
+                key_id: params.key_id,
+                expires_in_ms: expires_in.as_millis() as u64,
+            })
+        }
+        "lock" => {
+            let params: LockParams = _params(params)?;
+            _value(key_master.lock(&params.key_id))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+    }
+}
+
+fn _params<T: serde::de::DeserializeOwned>(params: serde_json::Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, &e.to_string()))
+}
+
+fn _value<T: Serialize>(value: T) -> Result<serde_json::Value, RpcError> {
+    serde_json::to_value(value).map_err(|_e| RpcError::from(CKMError::SerializeError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::fake::SEED;
+    use serde_json::json;
+
+    #[test]
+    fn test_owner_only() {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        let unlock = json!({ "key_id": key_id, "password": "123", "ttl_ms": 60000 });
+        let lock = json!({ "key_id": key_id });
+        let sign = json!({
+            "key_id": key_id,
+            "path": "m/0",
+            "curve": Curve::Secp256k1,
+            "data": "00",
+        });
+
+        // another allowed user can neither unlock nor end the session of the owner
+        let error = _dispatch("unlock", unlock.clone(), false, &key_master).unwrap_err();
+        assert_eq!(error.code, OWNER_ONLY);
+        assert!(!key_master.is_unlocked(&key_id));
+        _dispatch("unlock", unlock, true, &key_master).unwrap();
+        let error = _dispatch("lock", lock.clone(), false, &key_master).unwrap_err();
+        assert_eq!(error.code, OWNER_ONLY);
+        // but signs with the key the owner unlocked
+        _dispatch("sign", sign, false, &key_master).unwrap();
+        assert_eq!(
+            _dispatch("lock", lock, true, &key_master).unwrap(),
+            json!(true)
+        );
+    }
+}
@@ -1,7 +1,13 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
 
-/// Crypto key master error defination
-#[derive(Debug, Error)]
+/// underlying error of a `CKMError`, shared so the error stays `Clone`
+pub type ErrorSource = Arc<dyn std::error::Error + Send + Sync>;
+
+/// Crypto key master error defination, `code` gives a stable number for every variant
+#[derive(Debug, Clone, Error)]
+#[non_exhaustive]
 pub enum CKMError {
     #[error("not found {0}")]
     NotFound(String),
@@ -35,4 +41,219 @@ pub enum CKMError {
 
     #[error("Signing issue")]
     SigningError,
+
+    #[error("psbt error {0}")]
+    PsbtError(String),
+
+    #[error("bitcoin message error {0}")]
+    MessageError(String),
+
+    #[error("signature format error {0}")]
+    SignatureFormatError(String),
+
+    #[error("ecdh error {0}")]
+    EcdhError(String),
+
+    #[error("ecies error {0}")]
+    EciesError(String),
+
+    #[error("database error {reason}")]
+    DatabaseError {
+        reason: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+
+    #[error("vault is locked")]
+    VaultLocked,
+
+    #[error("pkcs11 error {reason}")]
+    Pkcs11Error {
+        reason: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+
+    #[error("key can not be exported")]
+    KeyNotExportable,
+
+    #[error("curve is not supported")]
+    UnsupportedCurve,
+
+    #[error("keystore does not sign internally")]
+    InternalSigningUnsupported,
+
+    #[error("remote signer error {0}")]
+    RemoteError(String),
+
+    #[error("hashicorp vault error {0}")]
+    HashiCorpError(String),
+
+    #[error("aws kms error {0}")]
+    KmsError(String),
+
+    #[error("ssh error {0}")]
+    SshError(String),
+
+    #[error("jose error {0}")]
+    JoseError(String),
+
+    #[error("memory lock error {0}")]
+    MemoryLockError(String),
+
+    #[error("invalid derivation path {path}")]
+    InvalidPath {
+        path: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+
+    #[error("corrupt keystore, {reason}")]
+    CorruptKeystore {
+        reason: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+
+    #[error("invalid kdf parameters {reason}")]
+    KdfParams {
+        reason: String,
+        #[source]
+        source: Option<ErrorSource>,
+    },
+
+    #[error("io error on {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: Arc<std::io::Error>,
+    },
+
+    #[error("{context}")]
+    Crypto {
+        context: &'static str,
+        #[source]
+        source: ErrorSource,
+    },
+}
+
+impl CKMError {
+    /// stable number of the variant for FFI and RPC consumers, grouped as 1xx keys and
+    /// passwords, 2xx storage, 3xx signing and keys, 4xx encodings and 5xx backends. Numbers
+    /// are never reused, new variants get new ones
+    pub fn code(&self) -> u32 {
+        match self {
+            CKMError::Unknown => 1,
+            CKMError::NotFound(_) => 100,
+            CKMError::NotExist => 101,
+            CKMError::PasswordInvalid => 102,
+            CKMError::KeyNotExportable => 103,
+            CKMError::VaultLocked => 104,
+            CKMError::CorruptKeystore { .. } => 105,
+            CKMError::KdfParams { .. } => 106,
+            CKMError::Io { .. } => 200,
+            CKMError::FileGenerationError => 201,
+            CKMError::FileError => 202,
+            CKMError::FileNotExit => 203,
+            CKMError::FileReadError => 204,
+            CKMError::DatabaseError { .. } => 205,
+            CKMError::MemoryLockError(_) => 206,
+            CKMError::SigningError => 300,
+            CKMError::InvalidPath { .. } => 301,
+            CKMError::UnsupportedCurve => 302,
+            CKMError::InternalSigningUnsupported => 303,
+            CKMError::RandomError => 304,
+            CKMError::Crypto { .. } => 305,
+            CKMError::SignatureFormatError(_) => 306,
+            CKMError::EcdhError(_) => 307,
+            CKMError::EciesError(_) => 308,
+            CKMError::SerializeError => 400,
+            CKMError::PsbtError(_) => 401,
+            CKMError::MessageError(_) => 402,
+            CKMError::SshError(_) => 403,
+            CKMError::JoseError(_) => 404,
+            CKMError::RemoteError(_) => 500,
+            CKMError::HashiCorpError(_) => 501,
+            CKMError::KmsError(_) => 502,
+            CKMError::Pkcs11Error { .. } => 503,
+        }
+    }
+
+    /// io error on a file
+    pub(crate) fn io(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        CKMError::Io {
+            path: path.as_ref().to_path_buf(),
+            source: Arc::new(source),
+        }
+    }
+
+    /// error of a crypto library, the context says what failed
+    pub(crate) fn crypto(
+        context: &'static str,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        CKMError::Crypto {
+            context,
+            source: Arc::new(source),
+        }
+    }
+
+    /// keystore content that can not be read back
+    pub(crate) fn corrupt(
+        reason: &str,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        CKMError::CorruptKeystore {
+            reason: reason.to_string(),
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    /// kdf parameters the kdf library rejects, the reason names the kdf
+    pub(crate) fn kdf_params(
+        reason: &str,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        CKMError::KdfParams {
+            reason: reason.to_string(),
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    /// keystore content without the expected field
+    pub(crate) fn missing(field: &str) -> Self {
+        CKMError::CorruptKeystore {
+            reason: format!("missing {}", field),
+            source: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_error_sources() {
+        let e = CKMError::io(
+            "keys/abc",
+            std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied"),
+        );
+        assert_eq!(e.to_string(), "io error on keys/abc");
+        assert_eq!(e.source().unwrap().to_string(), "denied");
+        assert_eq!(e.clone().code(), 200);
+
+        let e = CKMError::InvalidPath {
+            path: "m/x".to_string(),
+            source: None,
+        };
+        assert!(e.source().is_none());
+        assert_eq!(e.code(), 301);
+
+        let e = CKMError::kdf_params("scrypt", std::fmt::Error);
+        assert_eq!(e.to_string(), "invalid kdf parameters scrypt");
+        assert!(e.source().is_some());
+        assert_eq!(e.code(), 106);
+    }
 }
@@ -0,0 +1,213 @@
+//! JWK of RFC 7517 and RFC 8037 for the public keys of a `KeyMaster`
+use super::algorithm;
+use crate::curve::k1::k1_uncompressed;
+use crate::curve::r1::r1_uncompressed;
+use crate::{CKMError, Curve};
+use bitcoin::base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// public JWK, `EC` for P-256 and secp256k1 and `OKP` for Ed25519
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<String>,
+}
+
+impl Jwk {
+    /// signing JWK of a public key of `KeyMaster::get_public_key`, the `kid` is its thumbprint
+    pub fn from_public_key(curve: Curve, public_key: &[u8]) -> Result<Self, CKMError> {
+        let invalid = || CKMError::JoseError("invalid public key".to_string());
+        let (kty, crv, x, y) = match curve {
+            Curve::Ed25519 if public_key.len() == 32 => {
+                ("OKP", "Ed25519", public_key.to_vec(), None)
+            }
+            Curve::Ed25519 => return Err(invalid()),
+            Curve::Secp256R1 | Curve::Secp256k1 => {
+                let (crv, point) = match curve {
+                    Curve::Secp256R1 => ("P-256", r1_uncompressed(public_key)),
+                    _ => ("secp256k1", k1_uncompressed(public_key)),
+                };
+                let point = point.ok_or_else(invalid)?;
+                ("EC", crv, point[1..33].to_vec(), Some(point[33..].to_vec()))
+            }
+            Curve::Secp256k1Schnorr => return Err(CKMError::UnsupportedCurve),
+        };
+        let mut jwk = Self {
+            kty: kty.to_string(),
+            crv: crv.to_string(),
+            x: BASE64_URL_SAFE_NO_PAD.encode(x),
+            y: y.map(|y| BASE64_URL_SAFE_NO_PAD.encode(y)),
+            kid: None,
+            alg: Some(algorithm(curve)?.to_string()),
+            use_: Some("sig".to_string()),
+        };
+        jwk.kid = Some(jwk.thumbprint()?);
+        Ok(jwk)
+    }
+
+    /// curve and public key, uncompressed SEC1 for the `EC` keys
+    pub fn public_key(&self) -> Result<(Curve, Vec<u8>), CKMError> {
+        let invalid = || CKMError::JoseError("invalid public key".to_string());
+        let x = _decode(&self.x)?;
+        match (self.kty.as_str(), self.crv.as_str()) {
+            ("OKP", "Ed25519") if x.len() == 32 => Ok((Curve::Ed25519, x)),
+            ("EC", crv @ "P-256") | ("EC", crv @ "secp256k1") => {
+                let y = _decode(self.y.as_deref().ok_or_else(invalid)?)?;
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(invalid());
+                }
+                let point = [&[4u8][..], &x, &y].concat();
+                match crv {
+                    "P-256" => Ok((
+                        Curve::Secp256R1,
+                        r1_uncompressed(&point).ok_or_else(invalid)?,
+                    )),
+                    _ => Ok((
+                        Curve::Secp256k1,
+                        k1_uncompressed(&point).ok_or_else(invalid)?,
+                    )),
+                }
+            }
+            ("OKP", "Ed25519") => Err(invalid()),
+            _ => Err(CKMError::UnsupportedCurve),
+        }
+    }
+
+    /// RFC 7638 thumbprint, the base64url SHA-256 of the required members in order
+    pub fn thumbprint(&self) -> Result<String, CKMError> {
+        let string =
+            |value: &str| serde_json::to_string(value).map_err(|_e| CKMError::SerializeError);
+        let members = match self.kty.as_str() {
+            "EC" => {
+                let y = self
+                    .y
+                    .as_deref()
+                    .ok_or_else(|| CKMError::JoseError("EC key without y".to_string()))?;
+                format!(
+                    r#"{{"crv":{},"kty":"EC","x":{},"y":{}}}"#,
+                    string(&self.crv)?,
+                    string(&self.x)?,
+                    string(y)?
+                )
+            }
+            "OKP" => format!(
+                r#"{{"crv":{},"kty":"OKP","x":{}}}"#,
+                string(&self.crv)?,
+                string(&self.x)?
+            ),
+            _ => return Err(CKMError::UnsupportedCurve),
+        };
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(members.as_bytes())))
+    }
+}
+
+/// JWK set, what `/.well-known/jwks.json` serves
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+impl Jwks {
+    /// the key with the `kid`
+    pub fn find(&self, kid: &str) -> Option<&Jwk> {
+        self.keys.iter().find(|jwk| jwk.kid.as_deref() == Some(kid))
+    }
+}
+
+fn _decode(value: &str) -> Result<Vec<u8>, CKMError> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|e| CKMError::JoseError(format!("invalid base64url: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::decode;
+
+    #[test]
+    fn test_thumbprint() {
+        // RFC 8037 A.1 and A.3
+        let x = _decode("11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo").unwrap();
+        let jwk = Jwk::from_public_key(Curve::Ed25519, &x).unwrap();
+        assert_eq!(
+            jwk.kid.as_deref(),
+            Some("kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k")
+        );
+        assert_eq!(
+            serde_json::to_string(&jwk).unwrap(),
+            r#"{"kty":"OKP","crv":"Ed25519","x":"11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo","kid":"kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k","alg":"EdDSA","use":"sig"}"#
+        );
+        assert_eq!(jwk.public_key().unwrap(), (Curve::Ed25519, x));
+
+        // optional members do not change the thumbprint
+        let bare = Jwk {
+            kid: None,
+            alg: None,
+            use_: None,
+            ..jwk.clone()
+        };
+        assert_eq!(bare.thumbprint().unwrap(), jwk.thumbprint().unwrap());
+    }
+
+    #[test]
+    fn test_ec_jwk() {
+        // the generators of P-256 and secp256k1
+        let keys = [
+            (
+                Curve::Secp256R1,
+                "036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+            ),
+            (
+                Curve::Secp256k1,
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            ),
+        ];
+        let mut jwks = Jwks::default();
+        for (curve, public_key) in keys {
+            let public_key = decode(public_key).unwrap();
+            let jwk = Jwk::from_public_key(curve, &public_key).unwrap();
+            assert_eq!(jwk.kty, "EC");
+            let (jwk_curve, point) = jwk.public_key().unwrap();
+            assert_eq!(
+                (jwk_curve, point.len(), &point[1..33]),
+                (curve, 65, &public_key[1..])
+            );
+
+            // a point off the curve is rejected
+            let mut off_curve = jwk.clone();
+            off_curve.y = Some(jwk.x.clone());
+            assert!(matches!(
+                off_curve.public_key(),
+                Err(CKMError::JoseError(_))
+            ));
+            jwks.keys.push(jwk);
+        }
+        assert_eq!(jwks.keys[0].crv, "P-256");
+        assert_eq!(jwks.keys[1].crv, "secp256k1");
+        assert_ne!(jwks.keys[0].kid, jwks.keys[1].kid);
+
+        let json = serde_json::to_string(&jwks).unwrap();
+        let parsed: Jwks = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, jwks);
+        let kid = jwks.keys[1].kid.clone().unwrap();
+        assert_eq!(parsed.find(&kid), Some(&jwks.keys[1]));
+        assert!(parsed.find("unknown").is_none());
+
+        assert!(matches!(
+            Jwk::from_public_key(Curve::Secp256k1Schnorr, &[2u8; 32]),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(Jwk::from_public_key(Curve::Secp256R1, &[5u8; 33]).is_err());
+    }
+}
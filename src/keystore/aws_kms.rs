@@ -0,0 +1,748 @@
+use crate::curve::k1::k1_recoverable_signature;
+use crate::*;
+use bitcoin::base64::{prelude::BASE64_STANDARD, Engine};
+use hmac::{Hmac, Mac, NewMac};
+use k256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const SERVICE: &str = "kms";
+const KEY_SPEC: &str = "ECC_SECG_P256K1";
+/// DER SubjectPublicKeyInfo header of an uncompressed secp256k1 key
+const K1_SPKI_PREFIX: &str = "3056301006072a8648ce3d020106052b8104000a034200";
+
+/// AWS credentials used to sign the KMS requests
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    /// long term access key
+    pub fn new(access_key_id: &str, secret_access_key: &str) -> Self {
+        Self {
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            session_token: None,
+        }
+    }
+
+    /// temporary credentials from STS carry a session token
+    pub fn with_session_token(mut self, session_token: &str) -> Self {
+        self.session_token = Some(session_token.to_string());
+        self
+    }
+
+    /// read `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+    pub fn from_env() -> Result<Self, CKMError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_e| CKMError::KmsError(format!("{} is not set", name)))
+        };
+        let credentials = Self::new(&var("AWS_ACCESS_KEY_ID")?, &var("AWS_SECRET_ACCESS_KEY")?);
+        Ok(match std::env::var("AWS_SESSION_TOKEN") {
+            Ok(token) if !token.is_empty() => credentials.with_session_token(&token),
+            _ => credentials,
+        })
+    }
+}
+
+/// keystore of asymmetric AWS KMS keys
+/// secp256k1 keys are `ECC_SECG_P256K1` KMS keys and sign in KMS, the key id is the KMS key id,
+/// ARN or alias and the password and path are not used
+pub struct AwsKmsKeystore {
+    credentials: AwsCredentials,
+    region: String,
+    endpoint: String,
+    agent: ureq::Agent,
+    public_keys: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl AwsKmsKeystore {
+    /// keystore on the KMS endpoint of the region, like `us-east-1`
+    pub fn new(credentials: AwsCredentials, region: &str) -> Self {
+        Self {
+            credentials,
+            region: region.to_string(),
+            endpoint: format!("https://kms.{}.amazonaws.com", region),
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            public_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// keystore configured by the `AWS_*` environment variables, `AWS_ENDPOINT_URL` overrides
+    /// the regional endpoint
+    pub fn from_env() -> Result<Self, CKMError> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_e| std::env::var("AWS_DEFAULT_REGION"))
+            .map_err(|_e| CKMError::KmsError("AWS_REGION is not set".to_string()))?;
+        let store = Self::new(AwsCredentials::from_env()?, &region);
+        Ok(match std::env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) if !endpoint.is_empty() => store.with_endpoint(&endpoint),
+            _ => store,
+        })
+    }
+
+    /// send the requests to another endpoint, like a VPC endpoint or LocalStack
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// create a `ECC_SECG_P256K1` signing key, returns its key id
+    pub fn create_key(&self, description: &str) -> Result<String, CKMError> {
+        let body = json!({
+            "KeySpec": KEY_SPEC,
+            "KeyUsage": "SIGN_VERIFY",
+            "Description": description,
+        });
+        let response = self.call("CreateKey", body)?;
+        response["KeyMetadata"]["KeyId"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(_invalid_response)
+    }
+
+    /// 65 bytes uncompressed public key of a KMS key
+    pub fn public_key(&self, key_id: &str) -> Result<Vec<u8>, CKMError> {
+        if let Some(public_key) = self._public_keys().get(key_id) {
+            return Ok(public_key.clone());
+        }
+        let response = self.call("GetPublicKey", json!({ "KeyId": key_id }))?;
+        if response["KeySpec"].as_str() != Some(KEY_SPEC) {
+            return Err(CKMError::UnsupportedCurve);
+        }
+        let der = response["PublicKey"]
+            .as_str()
+            .and_then(|s| BASE64_STANDARD.decode(s).ok())
+            .ok_or_else(_invalid_response)?;
+        let prefix = hex::decode(K1_SPKI_PREFIX).map_err(|_e| CKMError::SerializeError)?;
+        let public_key = match der.strip_prefix(prefix.as_slice()) {
+            Some(point) if point.len() == 65 => point.to_vec(),
+            _ => return Err(_invalid_response()),
+        };
+        self._public_keys()
+            .insert(key_id.to_string(), public_key.clone());
+        Ok(public_key)
+    }
+
+    /// sign a 32 bytes digest in KMS, the signature is low-S and carries the recovery id
+    pub fn sign(&self, key_id: &str, digest: &[u8; 32]) -> Result<SigningSignature, CKMError> {
+        let public_key = self.public_key(key_id)?;
+        let body = json!({
+            "KeyId": key_id,
+            "Message": BASE64_STANDARD.encode(digest),
+            "MessageType": "DIGEST",
+            "SigningAlgorithm": "ECDSA_SHA_256",
+        });
+        let response = self.call("Sign", body)?;
+        let der = response["Signature"]
+            .as_str()
+            .and_then(|s| BASE64_STANDARD.decode(s).ok())
+            .ok_or_else(_invalid_response)?;
+        // KMS returns DER without normalizing s or telling the recovery id
+        k1_recoverable_signature(digest, SigningSignature::from_der(&der)?, &public_key)
+    }
+
+    /// signed `TrentService` JSON request
+    fn call(&self, action: &str, body: Value) -> Result<Value, CKMError> {
+        let payload = body.to_string();
+        let amz_date = _amz_date(SystemTime::now());
+        let host = self
+            .endpoint
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let target = format!("TrentService.{}", action);
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1"),
+            ("host", host.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+            ("x-amz-target", target.as_str()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+        let authorization = sigv4_authorization(
+            &self.credentials,
+            &self.region,
+            SERVICE,
+            "POST",
+            "/",
+            "",
+            &headers,
+            payload.as_bytes(),
+            &amz_date,
+        );
+
+        let mut request = self
+            .agent
+            .post(&format!("{}/", self.endpoint))
+            .set("Authorization", &authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+        match request.send_string(&payload) {
+            Ok(response) => response.into_json().map_err(|_e| _invalid_response()),
+            Err(ureq::Error::Status(status, response)) => Err(_kms_error(
+                status,
+                &response.into_json().unwrap_or(Value::Null),
+            )),
+            Err(ureq::Error::Transport(e)) => Err(CKMError::KmsError(e.to_string())),
+        }
+    }
+
+    fn _public_keys(&self) -> MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.public_keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Keystore for AwsKmsKeystore {
+    /// entropy of 128 or 256 bits from KMS `GenerateRandom`
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        match length {
+            128 | 256 => {
+                let response =
+                    self.call("GenerateRandom", json!({ "NumberOfBytes": length / 8 }))?;
+                response["Plaintext"]
+                    .as_str()
+                    .and_then(|s| BASE64_STANDARD.decode(s).ok())
+                    .ok_or_else(_invalid_response)
+            }
+            _ => Err(CKMError::NotFound("length is not right".to_string())),
+        }
+    }
+
+    /// KMS keys never leave KMS
+    fn get_key(&self, _password: &str, _key_id: String) -> Result<Vec<u8>, CKMError> {
+        Err(CKMError::KeyNotExportable)
+    }
+
+    /// seeds can not be imported, KMS keys are created with `create_key`
+    fn write_key(&self, _password: &str, _key: String) -> Result<String, CKMError> {
+        Err(CKMError::KeyNotExportable)
+    }
+
+    /// every key of the account in the region, not only the secp256k1 ones
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let mut key_ids = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let body = match &marker {
+                Some(marker) => json!({ "Limit": 1000, "Marker": marker }),
+                None => json!({ "Limit": 1000 }),
+            };
+            let response = self.call("ListKeys", body)?;
+            if let Some(keys) = response["Keys"].as_array() {
+                key_ids.extend(
+                    keys.iter()
+                        .filter_map(|key| key["KeyId"].as_str().map(|s| s.to_string())),
+                );
+            }
+            marker = match (
+                response["Truncated"].as_bool(),
+                response["NextMarker"].as_str(),
+            ) {
+                (Some(true), Some(next)) => Some(next.to_string()),
+                _ => break,
+            };
+        }
+        key_ids.sort();
+        Ok(key_ids)
+    }
+
+    fn signs_internally(&self) -> bool {
+        true
+    }
+
+    fn sign_digest(
+        &self,
+        _password: &str,
+        key_id: &str,
+        _path: &str,
+        curve: &Curve,
+        digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        if *curve != Curve::Secp256k1 {
+            return Err(CKMError::UnsupportedCurve);
+        }
+        let digest: [u8; 32] = digest.try_into().map_err(|_e| CKMError::SigningError)?;
+        self.sign(key_id, &digest)
+    }
+
+    fn get_public_key(
+        &self,
+        _password: &str,
+        key_id: &str,
+        _path: &str,
+        curve: &Curve,
+    ) -> Result<Vec<u8>, CKMError> {
+        if *curve != Curve::Secp256k1 {
+            return Err(CKMError::UnsupportedCurve);
+        }
+        let public_key = self.public_key(key_id)?;
+        let encoded =
+            k256::EncodedPoint::from_bytes(&public_key).map_err(|_e| _invalid_response())?;
+        let point =
+            k256::AffinePoint::from_encoded_point(&encoded).ok_or_else(_invalid_response)?;
+        Ok(point.to_encoded_point(true).as_bytes().to_vec())
+    }
+}
+
+/// AWS Signature Version 4 `Authorization` header, header names are lowercase and the query
+/// is already canonical
+#[allow(clippy::too_many_arguments)]
+fn sigv4_authorization(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload: &[u8],
+    amz_date: &str,
+) -> String {
+    let mut headers = headers.to_vec();
+    headers.sort_by(|a, b| a.0.cmp(b.0));
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(payload))
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = _signing_key(&credentials.secret_access_key, date, region, service);
+    let signature = _hmac_sha256(&signing_key, string_to_sign.as_bytes());
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex::encode(signature)
+    )
+}
+
+fn _signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = format!("AWS4{}", secret_access_key);
+    let key = _hmac_sha256(key.as_bytes(), date.as_bytes());
+    let key = _hmac_sha256(&key, region.as_bytes());
+    let key = _hmac_sha256(&key, service.as_bytes());
+    _hmac_sha256(&key, b"aws4_request")
+}
+
+fn _hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `YYYYMMDDTHHMMSSZ` UTC timestamp
+fn _amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from days since the epoch, Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// map a KMS error response, unknown keys are `NotExist`
+fn _kms_error(status: u16, body: &Value) -> CKMError {
+    let kind = body["__type"]
+        .as_str()
+        .unwrap_or_default()
+        .rsplit('#')
+        .next()
+        .unwrap_or_default();
+    let message = body["message"]
+        .as_str()
+        .or_else(|| body["Message"].as_str())
+        .unwrap_or_default();
+    match kind {
+        "NotFoundException" => CKMError::NotExist,
+        "" => CKMError::KmsError(format!("{} {}", status, message)),
+        _ => CKMError::KmsError(format!("{} {}", kind, message)),
+    }
+}
+
+fn _invalid_response() -> CKMError {
+    CKMError::KmsError("invalid response".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::k1::{k1_recover_public_key, k1_verify_digest};
+    use crate::test_util::{serve_http, MockRequest};
+    use std::sync::Arc;
+
+    /// stand-in for the KMS endpoint of LocalStack or moto, it checks the request signatures
+    /// and can answer with high-S signatures like KMS does
+    #[derive(Default)]
+    struct MockKms {
+        keys: HashMap<String, Vec<u8>>,
+        high_s: bool,
+        signs: usize,
+        public_key_requests: usize,
+    }
+
+    impl MockKms {
+        fn start() -> (String, Arc<Mutex<MockKms>>) {
+            let kms = Arc::new(Mutex::new(MockKms::default()));
+            let shared = kms.clone();
+            let addr = serve_http("application/x-amz-json-1.1", move |request| {
+                _serve(request, &shared)
+            });
+            (addr, kms)
+        }
+
+        fn handle(&mut self, target: &str, body: Value) -> (u16, Value) {
+            let not_found = || {
+                let error = json!({ "__type": "NotFoundException", "message": "Key not found" });
+                (400, error)
+            };
+            match target {
+                "TrentService.CreateKey" => {
+                    assert_eq!(body["KeySpec"], KEY_SPEC);
+                    let mut secret = vec![0u8; 32];
+                    crate::keystore::local::random_fill(&mut secret).unwrap();
+                    let key_id = format!("key-{}", self.keys.len() + 1);
+                    self.keys.insert(key_id.clone(), secret);
+                    (200, json!({ "KeyMetadata": { "KeyId": key_id } }))
+                }
+                "TrentService.GetPublicKey" => match self.keys.get(body["KeyId"].as_str().unwrap())
+                {
+                    Some(secret) => {
+                        self.public_key_requests += 1;
+                        let mut der = hex::decode(K1_SPKI_PREFIX).unwrap();
+                        der.extend(_uncompressed(secret));
+                        let response = json!({
+                            "KeySpec": KEY_SPEC,
+                            "PublicKey": BASE64_STANDARD.encode(der),
+                        });
+                        (200, response)
+                    }
+                    None => not_found(),
+                },
+                "TrentService.Sign" => match self.keys.get(body["KeyId"].as_str().unwrap()) {
+                    Some(secret) => {
+                        assert_eq!(body["MessageType"], "DIGEST");
+                        assert_eq!(body["SigningAlgorithm"], "ECDSA_SHA_256");
+                        self.signs += 1;
+                        let digest = BASE64_STANDARD
+                            .decode(body["Message"].as_str().unwrap())
+                            .unwrap();
+                        let sig = _textbook_sign(secret, &digest, self.high_s);
+                        let signature = BASE64_STANDARD.encode(sig.to_der());
+                        (200, json!({ "Signature": signature }))
+                    }
+                    None => not_found(),
+                },
+                "TrentService.ListKeys" => {
+                    let mut key_ids: Vec<&String> = self.keys.keys().collect();
+                    key_ids.sort();
+                    // one key per page to exercise the markers
+                    let start = body["Marker"]
+                        .as_str()
+                        .map(|marker| marker.parse().unwrap())
+                        .unwrap_or(0);
+                    let page: Vec<Value> = key_ids
+                        .iter()
+                        .skip(start)
+                        .take(1)
+                        .map(|key_id| json!({ "KeyId": key_id }))
+                        .collect();
+                    let truncated = start + 1 < key_ids.len();
+                    let mut response = json!({ "Keys": page, "Truncated": truncated });
+                    if truncated {
+                        response["NextMarker"] = json!((start + 1).to_string());
+                    }
+                    (200, response)
+                }
+                "TrentService.GenerateRandom" => {
+                    let mut random = vec![0u8; body["NumberOfBytes"].as_u64().unwrap() as usize];
+                    crate::keystore::local::random_fill(&mut random).unwrap();
+                    (200, json!({ "Plaintext": BASE64_STANDARD.encode(random) }))
+                }
+                _ => (400, json!({ "__type": "UnknownOperationException" })),
+            }
+        }
+    }
+
+    fn _uncompressed(secret: &[u8]) -> Vec<u8> {
+        let d = k256::Scalar::from_bytes_reduced(secret.into());
+        let point = (k256::ProjectivePoint::generator() * d).to_affine();
+        point.to_encoded_point(false).as_bytes().to_vec()
+    }
+
+    /// ECDSA from the curve arithmetic with a random nonce, so the tests do not check the
+    /// signing code of the crate against itself; KMS does not normalize S either
+    fn _textbook_sign(secret: &[u8], digest: &[u8], high_s: bool) -> SigningSignature {
+        let d = k256::Scalar::from_bytes_reduced(secret.into());
+        let z = k256::Scalar::from_bytes_reduced(digest.into());
+        loop {
+            let mut k = [0u8; 32];
+            crate::keystore::local::random_fill(&mut k).unwrap();
+            let k = k256::Scalar::from_bytes_reduced(&k.into());
+            let k_inverse = match Option::<k256::Scalar>::from(k.invert()) {
+                Some(k_inverse) => k_inverse,
+                None => continue,
+            };
+            let point = (k256::ProjectivePoint::generator() * k).to_affine();
+            let encoded = point.to_encoded_point(false);
+            let r = k256::Scalar::from_bytes_reduced(encoded.as_bytes()[1..33].into());
+            let mut s = k_inverse * (z + r * d);
+            if bool::from(r.is_zero()) || bool::from(s.is_zero()) {
+                continue;
+            }
+            if bool::from(s.is_high()) != high_s {
+                s = -s;
+            }
+            let mut compact = r.to_bytes().to_vec();
+            compact.extend_from_slice(&s.to_bytes());
+            return SigningSignature::from_compact(&compact).unwrap();
+        }
+    }
+
+    /// check the request signature and answer the KMS operation
+    fn _serve(request: MockRequest, kms: &Mutex<MockKms>) -> (u16, String) {
+        let signed: Vec<(&str, &str)> = request
+            .headers
+            .iter()
+            .filter(|(key, _)| {
+                matches!(
+                    key.as_str(),
+                    "content-type"
+                        | "host"
+                        | "x-amz-date"
+                        | "x-amz-target"
+                        | "x-amz-security-token"
+                )
+            })
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let credentials = AwsCredentials::new("test", "test");
+        let expected = sigv4_authorization(
+            &credentials,
+            "us-east-1",
+            SERVICE,
+            &request.method,
+            &request.path,
+            "",
+            &signed,
+            &request.body,
+            request.header("x-amz-date"),
+        );
+        let (status, response) = if request.header("authorization") != expected {
+            (400, json!({ "__type": "InvalidSignatureException" }))
+        } else {
+            let body = serde_json::from_slice(&request.body).unwrap();
+            kms.lock()
+                .unwrap()
+                .handle(request.header("x-amz-target"), body)
+        };
+        (status, response.to_string())
+    }
+
+    #[test]
+    fn test_sigv4() {
+        // example request of the AWS Signature Version 4 documentation
+        let credentials =
+            AwsCredentials::new("AKIDEXAMPLE", "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY");
+        let authorization = sigv4_authorization(
+            &credentials,
+            "us-east-1",
+            "iam",
+            "GET",
+            "/",
+            "Action=ListUsers&Version=2010-05-08",
+            &[
+                (
+                    "content-type",
+                    "application/x-www-form-urlencoded; charset=utf-8",
+                ),
+                ("host", "iam.amazonaws.com"),
+                ("x-amz-date", "20150830T123600Z"),
+            ],
+            b"",
+            "20150830T123600Z",
+        );
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, SignedHeaders=content-type;host;x-amz-date, Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+        assert_eq!(
+            hex::encode(_signing_key(
+                &credentials.secret_access_key,
+                "20150830",
+                "us-east-1",
+                "iam"
+            )),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+
+        let time = UNIX_EPOCH + Duration::from_secs(1440938160);
+        assert_eq!(_amz_date(time), "20150830T123600Z");
+        let time = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(_amz_date(time), "20000229T000000Z");
+    }
+
+    #[test]
+    fn test_kms_sign() {
+        let (addr, kms) = MockKms::start();
+        let store = AwsKmsKeystore::new(AwsCredentials::new("test", "test"), "us-east-1")
+            .with_endpoint(&addr);
+        let key_id = store.create_key("ckm test").unwrap();
+        let other = store.create_key("ckm test").unwrap();
+        assert_eq!(store.list_keys().unwrap(), vec![key_id.clone(), other]);
+        assert_eq!(store.generate_entropy(128).unwrap().len(), 16);
+        assert_eq!(store.generate_entropy(256).unwrap().len(), 32);
+        assert!(store.generate_entropy(32).is_err());
+
+        let public_key = store.public_key(&key_id).unwrap();
+        assert_eq!(public_key.len(), 65);
+        let compressed = store
+            .get_public_key("", &key_id, "", &Curve::Secp256k1)
+            .unwrap();
+        assert_eq!(compressed.len(), 33);
+        assert_eq!(compressed[1..], public_key[1..33]);
+
+        for high_s in [false, true] {
+            kms.lock().unwrap().high_s = high_s;
+            for i in 0u8..4 {
+                let digest: [u8; 32] = Sha256::digest(&[i]).into();
+                let sig = store
+                    .sign_digest("", &key_id, "", &Curve::Secp256k1, &digest)
+                    .unwrap();
+                assert!(sig.is_low_s());
+                let compact = sig.to_compact();
+                assert!(k1_verify_digest(&public_key, &digest, &compact));
+                let recovered =
+                    k1_recover_public_key(&digest, &compact, sig.v.unwrap(), false).unwrap();
+                assert_eq!(recovered, public_key);
+            }
+        }
+        // the public key is fetched once
+        assert_eq!(kms.lock().unwrap().public_key_requests, 1);
+        assert_eq!(kms.lock().unwrap().signs, 8);
+
+        assert!(matches!(
+            store.sign_digest("", "missing", "", &Curve::Secp256k1, &[0u8; 32]),
+            Err(CKMError::NotExist)
+        ));
+        assert!(matches!(
+            store.sign_digest("", &key_id, "", &Curve::Secp256R1, &[0u8; 32]),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(matches!(
+            store.get_key("", key_id.clone()),
+            Err(CKMError::KeyNotExportable)
+        ));
+
+        let wrong = AwsKmsKeystore::new(AwsCredentials::new("test", "wrong"), "us-east-1")
+            .with_endpoint(&addr);
+        assert!(matches!(
+            wrong.public_key(&key_id),
+            Err(CKMError::KmsError(message)) if message.starts_with("InvalidSignatureException")
+        ));
+    }
+
+    #[test]
+    fn test_key_master_usage() {
+        let (addr, _kms) = MockKms::start();
+        let store = AwsKmsKeystore::new(AwsCredentials::new("test", "test"), "us-east-1")
+            .with_endpoint(&addr);
+        let key_id = store.create_key("ckm test").unwrap();
+        let key_master = KeyMaster::new(store);
+
+        let data = b"hello kms".to_vec();
+        let request = SignRequest {
+            key_id: &key_id,
+            path: "",
+            curve: Curve::Secp256k1,
+            unsigend_data: data.clone(),
+        };
+        let sig = key_master.sign(request, "").unwrap();
+        let public_key = key_master
+            .get_public_key(&key_id, "", Curve::Secp256k1, "")
+            .unwrap();
+        let digest: [u8; 32] = Sha256::digest(&data).into();
+        assert!(k1_verify_digest(&public_key, &digest, &sig.to_compact()));
+        assert_eq!(
+            k1_recover_public_key(&digest, &sig.to_compact(), sig.v.unwrap(), true).unwrap(),
+            public_key
+        );
+    }
+
+    /// runs against LocalStack or moto at `CKM_KMS_ENDPOINT`, like `http://127.0.0.1:4566`,
+    /// with the `test` credentials of the emulators
+    #[test]
+    #[ignore = "needs a KMS emulator, see CKM_KMS_ENDPOINT"]
+    fn test_kms_emulator() {
+        let endpoint = std::env::var("CKM_KMS_ENDPOINT").expect("CKM_KMS_ENDPOINT must be set");
+        let store = AwsKmsKeystore::new(AwsCredentials::new("test", "test"), "us-east-1")
+            .with_endpoint(&endpoint);
+        let key_id = store.create_key("ckm emulator test").unwrap();
+        assert!(store.list_keys().unwrap().contains(&key_id));
+        let public_key = store.public_key(&key_id).unwrap();
+        for i in 0u8..8 {
+            let digest: [u8; 32] = Sha256::digest(&[i]).into();
+            let sig = store.sign(&key_id, &digest).unwrap();
+            assert!(sig.is_low_s());
+            assert!(k1_verify_digest(&public_key, &digest, &sig.to_compact()));
+            assert_eq!(
+                k1_recover_public_key(&digest, &sig.to_compact(), sig.v.unwrap(), false).unwrap(),
+                public_key
+            );
+        }
+    }
+}
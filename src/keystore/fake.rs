@@ -1,22 +1,66 @@
-use crate::{CKMError, Keystore};
+use crate::curve::k1::{derive_from_seed, k1_sign_recoverable};
+use crate::{CKMError, Curve, Keystore, SigningSignature};
 use hex::decode;
+use std::convert::TryInto;
+
+/// BIP39 seed of the `abandon ... about` mnemonic, the seed the fake stores hold and the tests
+/// write to the real ones
+pub(crate) const SEED: &str = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
 
 #[derive(Debug, Clone, Default)]
 pub(crate) struct FakeKeystore {}
 
 impl Keystore for FakeKeystore {
-    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
-        let fakeBuffer = vec![0u8; 32];
-        Ok(fakeBuffer)
+    fn generate_entropy(&self, _length: u32) -> Result<Vec<u8>, CKMError> {
+        let fake_buffer = vec![0u8; 32];
+        Ok(fake_buffer)
     }
 
-    fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
-        let fakeSeed = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
-        let result = decode(fakeSeed).map_err(|_e| CKMError::SerializeError)?;
+    fn get_key(&self, _password: &str, _key_id: String) -> Result<Vec<u8>, CKMError> {
+        let result = decode(SEED).map_err(|_e| CKMError::SerializeError)?;
         Ok(result)
     }
 
-    fn write_key(&mut self, password: &str, key: String) -> Result<String, crate::CKMError> {
+    fn write_key(&self, _password: &str, _key: String) -> Result<String, crate::CKMError> {
         Ok("123456".to_string())
     }
 }
+
+/// keystore that signs internally with the fake seed and never hands it out
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FakeSigner {}
+
+impl Keystore for FakeSigner {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        FakeKeystore {}.generate_entropy(length)
+    }
+
+    fn get_key(&self, _password: &str, _key_id: String) -> Result<Vec<u8>, CKMError> {
+        Err(CKMError::KeyNotExportable)
+    }
+
+    fn write_key(&self, _password: &str, _key: String) -> Result<String, CKMError> {
+        Ok("123456".to_string())
+    }
+
+    fn signs_internally(&self) -> bool {
+        true
+    }
+
+    fn sign_digest(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+        curve: &Curve,
+        digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        if !matches!(curve, Curve::Secp256k1) {
+            return Err(CKMError::UnsupportedCurve);
+        }
+        let seed = FakeKeystore {}.get_key(password, key_id.to_string())?;
+        let key = derive_from_seed(&seed, path)?;
+        let digest: [u8; 32] = digest.try_into().map_err(|_e| CKMError::SigningError)?;
+        k1_sign_recoverable(&key, &digest)
+    }
+}
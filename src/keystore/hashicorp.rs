@@ -0,0 +1,803 @@
+use crate::*;
+use bitcoin::base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
+use k256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use serde_json::{json, Value};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::local::{new_key_id, open_envelope, random_bytes, seal_envelope};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// DER SubjectPublicKeyInfo header of an uncompressed P-256 key
+const P256_SPKI_PREFIX: &str = "3059301306072a8648ce3d020106082a8648ce3d030107034200";
+
+enum Auth {
+    Token,
+    AppRole {
+        mount: String,
+        role_id: String,
+        secret_id: String,
+    },
+}
+
+struct TokenState {
+    token: String,
+    renewable: bool,
+    lease_duration: Duration,
+    obtained_at: Instant,
+}
+
+/// HashiCorp Vault client shared by the KV and Transit keystores
+/// the token is renewed once two thirds of its lease passed, AppRole logins are repeated when the
+/// token can not be renewed anymore
+pub struct HashiCorpClient {
+    addr: String,
+    namespace: Option<String>,
+    auth: Auth,
+    agent: ureq::Agent,
+    state: Mutex<TokenState>,
+}
+
+impl HashiCorpClient {
+    /// client authenticated with a token, `addr` is like `http://127.0.0.1:8200`
+    pub fn with_token(addr: &str, token: &str) -> Result<Self, CKMError> {
+        let client = Self::_new(addr, Auth::Token, token);
+        let lookup = client._send("GET", "auth/token/lookup-self", None)?;
+        let mut state = client._state();
+        state.renewable = lookup["data"]["renewable"].as_bool().unwrap_or(false);
+        state.lease_duration = Duration::from_secs(lookup["data"]["ttl"].as_u64().unwrap_or(0));
+        drop(state);
+        Ok(client)
+    }
+
+    /// client logging in with AppRole on the `approle` mount
+    pub fn with_approle(addr: &str, role_id: &str, secret_id: &str) -> Result<Self, CKMError> {
+        let auth = Auth::AppRole {
+            mount: "approle".to_string(),
+            role_id: role_id.to_string(),
+            secret_id: secret_id.to_string(),
+        };
+        let client = Self::_new(addr, auth, "");
+        client._login()?;
+        Ok(client)
+    }
+
+    /// send requests to a Vault Enterprise namespace
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// renew the token lease now, AppRole clients log in again when the renewal fails
+    pub fn renew(&self) -> Result<(), CKMError> {
+        let renewed = self
+            ._send("POST", "auth/token/renew-self", Some(json!({})))
+            .and_then(|response| self._store_auth(&response));
+        match (renewed, &self.auth) {
+            (Ok(()), _) => Ok(()),
+            (Err(_e), Auth::AppRole { .. }) => self._login(),
+            (Err(e), Auth::Token) => Err(e),
+        }
+    }
+
+    fn _new(addr: &str, auth: Auth, token: &str) -> Self {
+        Self {
+            addr: addr.trim_end_matches('/').to_string(),
+            namespace: None,
+            auth,
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            state: Mutex::new(TokenState {
+                token: token.to_string(),
+                renewable: false,
+                lease_duration: Duration::from_secs(0),
+                obtained_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn _login(&self) -> Result<(), CKMError> {
+        if let Auth::AppRole {
+            mount,
+            role_id,
+            secret_id,
+        } = &self.auth
+        {
+            let body = json!({ "role_id": role_id, "secret_id": secret_id });
+            let response = self._send("POST", &format!("auth/{}/login", mount), Some(body))?;
+            self._store_auth(&response)?;
+        }
+        Ok(())
+    }
+
+    fn _store_auth(&self, response: &Value) -> Result<(), CKMError> {
+        let auth = &response["auth"];
+        let token = auth["client_token"]
+            .as_str()
+            .ok_or_else(|| CKMError::HashiCorpError("missing client token".to_string()))?;
+        let mut state = self._state();
+        state.token = token.to_string();
+        state.renewable = auth["renewable"].as_bool().unwrap_or(false);
+        state.lease_duration = Duration::from_secs(auth["lease_duration"].as_u64().unwrap_or(0));
+        state.obtained_at = Instant::now();
+        Ok(())
+    }
+
+    /// authenticated request, the lease is renewed before it runs out and AppRole clients log
+    /// in again once when the token was revoked
+    fn request(&self, method: &str, path: &str, body: Option<Value>) -> Result<Value, CKMError> {
+        let (due, renewable) = {
+            let state = self._state();
+            let due = !state.lease_duration.is_zero()
+                && state.obtained_at.elapsed() * 3 >= state.lease_duration * 2;
+            (due, state.renewable)
+        };
+        if due && renewable {
+            self.renew()?;
+        } else if due {
+            self._login()?;
+        }
+        match self._send_status(method, path, body.clone()) {
+            // a revoked or expired AppRole token is replaced once
+            Err((403, _)) if matches!(self.auth, Auth::AppRole { .. }) => {
+                self._login()?;
+                self._send(method, path, body)
+            }
+            result => result.map_err(|(status, body)| _vault_error(status, &body)),
+        }
+    }
+
+    fn _send(&self, method: &str, path: &str, body: Option<Value>) -> Result<Value, CKMError> {
+        self._send_status(method, path, body)
+            .map_err(|(status, body)| _vault_error(status, &body))
+    }
+
+    /// send the request, failures are the HTTP status and the error body
+    fn _send_status(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, (u16, Value)> {
+        let token = self._state().token.clone();
+        let mut request = self
+            .agent
+            .request(method, &format!("{}/v1/{}", self.addr, path));
+        if !token.is_empty() {
+            request = request.set("X-Vault-Token", &token);
+        }
+        if let Some(namespace) = &self.namespace {
+            request = request.set("X-Vault-Namespace", namespace);
+        }
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+        let invalid = || (0, json!({ "errors": ["invalid response"] }));
+        match response {
+            Ok(response) if response.status() == 204 => Ok(Value::Null),
+            Ok(response) => response.into_json().map_err(|_e| invalid()),
+            Err(ureq::Error::Status(status, response)) => {
+                Err((status, response.into_json().unwrap_or(Value::Null)))
+            }
+            Err(ureq::Error::Transport(e)) => Err((0, json!({ "errors": [e.to_string()] }))),
+        }
+    }
+
+    fn _state(&self) -> MutexGuard<'_, TokenState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// map a Vault error response, missing paths are `NotExist` and a sealed Vault is `VaultLocked`
+fn _vault_error(status: u16, body: &Value) -> CKMError {
+    let errors: Vec<&str> = body["errors"]
+        .as_array()
+        .map(|errors| errors.iter().filter_map(|e| e.as_str()).collect())
+        .unwrap_or_default();
+    let message = errors.join(", ");
+    match status {
+        0 => CKMError::HashiCorpError(message),
+        404 => CKMError::NotExist,
+        503 if message.contains("sealed") => CKMError::VaultLocked,
+        _ => CKMError::HashiCorpError(format!("{} {}", status, message)),
+    }
+}
+
+/// keystore on the KV version 2 secrets engine of HashiCorp Vault
+/// keys are sealed under their password with the same envelope as `LocalKeystore` before they
+/// are written, so Vault only stores ciphertext
+pub struct HashiCorpKvKeystore {
+    client: HashiCorpClient,
+    mount: String,
+    prefix: String,
+}
+
+impl HashiCorpKvKeystore {
+    /// keys under `<mount>/<prefix>/<key_id>`, like `secret` and `ckm`
+    pub fn new(client: HashiCorpClient, mount: &str, prefix: &str) -> Self {
+        Self {
+            client,
+            mount: mount.trim_matches('/').to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    /// delete every version of a key, returns whether it was stored. Vault answers 204 to the
+    /// delete of a missing key, so the metadata is read first
+    pub fn remove_key(&self, key_id: &str) -> Result<bool, CKMError> {
+        let path = self._path("metadata", key_id);
+        match self.client.request("GET", &path, None) {
+            Ok(_) => {}
+            Err(CKMError::NotExist) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        self.client.request("DELETE", &path, None)?;
+        Ok(true)
+    }
+
+    fn _path(&self, kind: &str, key_id: &str) -> String {
+        format!("{}/{}/{}/{}", self.mount, kind, self.prefix, key_id)
+    }
+}
+
+impl Keystore for HashiCorpKvKeystore {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        random_bytes(length)
+    }
+
+    fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
+        let response = self
+            .client
+            .request("GET", &self._path("data", &key_id), None)?;
+        let envelope = response["data"]["data"]["envelope"]
+            .as_str()
+            .ok_or(CKMError::NotExist)?;
+        open_envelope(envelope, password)
+    }
+
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let key_id = new_key_id()?;
+        let envelope = seal_envelope(password, key.as_bytes())?;
+        // check-and-set 0 refuses to overwrite an existing key
+        let body = json!({ "options": { "cas": 0 }, "data": { "envelope": envelope } });
+        self.client
+            .request("POST", &self._path("data", &key_id), Some(body))?;
+        Ok(key_id)
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let path = format!("{}/metadata/{}?list=true", self.mount, self.prefix);
+        match self.client.request("GET", &path, None) {
+            Ok(response) => {
+                let mut key_ids: Vec<String> = response["data"]["keys"]
+                    .as_array()
+                    .map(|keys| {
+                        keys.iter()
+                            .filter_map(|key| key.as_str().map(|s| s.to_string()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                key_ids.sort();
+                Ok(key_ids)
+            }
+            Err(CKMError::NotExist) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// keystore on the Transit secrets engine of HashiCorp Vault
+/// ECDSA-P256 and Ed25519 keys are created in the Transit engine and sign there, the key id is
+/// the Transit key name and the password and path are not used
+pub struct HashiCorpTransitKeystore {
+    client: HashiCorpClient,
+    mount: String,
+}
+
+impl HashiCorpTransitKeystore {
+    /// keystore on the Transit engine mounted at `mount`, like `transit`
+    pub fn new(client: HashiCorpClient, mount: &str) -> Self {
+        Self {
+            client,
+            mount: mount.trim_matches('/').to_string(),
+        }
+    }
+
+    /// create a non exportable Transit key, `Curve::Secp256R1` or `Curve::Ed25519`
+    pub fn create_key(&self, name: &str, curve: Curve) -> Result<(), CKMError> {
+        let key_type = match curve {
+            Curve::Secp256R1 => "ecdsa-p256",
+            Curve::Ed25519 => "ed25519",
+            _ => return Err(CKMError::UnsupportedCurve),
+        };
+        let body = json!({ "type": key_type, "exportable": false });
+        self.client
+            .request("POST", &format!("{}/keys/{}", self.mount, name), Some(body))?;
+        Ok(())
+    }
+
+    fn _key(&self, name: &str, curve: &Curve) -> Result<Value, CKMError> {
+        let response =
+            self.client
+                .request("GET", &format!("{}/keys/{}", self.mount, name), None)?;
+        let expected = match curve {
+            Curve::Secp256R1 => "ecdsa-p256",
+            Curve::Ed25519 => "ed25519",
+            _ => return Err(CKMError::UnsupportedCurve),
+        };
+        if response["data"]["type"].as_str() != Some(expected) {
+            return Err(CKMError::UnsupportedCurve);
+        }
+        Ok(response["data"].clone())
+    }
+}
+
+impl Keystore for HashiCorpTransitKeystore {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        random_bytes(length)
+    }
+
+    /// Transit keys never leave Vault
+    fn get_key(&self, _password: &str, _key_id: String) -> Result<Vec<u8>, CKMError> {
+        Err(CKMError::KeyNotExportable)
+    }
+
+    /// Transit keys are created in Vault with `create_key`
+    fn write_key(&self, _password: &str, _key: String) -> Result<String, CKMError> {
+        Err(CKMError::HashiCorpError(
+            "transit keys are created with create_key".to_string(),
+        ))
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let path = format!("{}/keys?list=true", self.mount);
+        match self.client.request("GET", &path, None) {
+            Ok(response) => Ok(response["data"]["keys"]
+                .as_array()
+                .map(|keys| {
+                    keys.iter()
+                        .filter_map(|key| key.as_str().map(|s| s.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default()),
+            Err(CKMError::NotExist) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn signs_internally(&self) -> bool {
+        true
+    }
+
+    /// ECDSA-P256 signs the prehashed SHA-256 digest, Ed25519 signs the data
+    fn sign_digest(
+        &self,
+        _password: &str,
+        key_id: &str,
+        _path: &str,
+        curve: &Curve,
+        digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        let body = match curve {
+            Curve::Secp256R1 if digest.len() == 32 => json!({
+                "input": BASE64_STANDARD.encode(digest),
+                "prehashed": true,
+                "hash_algorithm": "sha2-256",
+                "marshaling_algorithm": "jws",
+            }),
+            Curve::Ed25519 => json!({ "input": BASE64_STANDARD.encode(digest) }),
+            Curve::Secp256R1 => return Err(CKMError::SigningError),
+            _ => return Err(CKMError::UnsupportedCurve),
+        };
+        let response = self.client.request(
+            "POST",
+            &format!("{}/sign/{}", self.mount, key_id),
+            Some(body),
+        )?;
+        // signatures look like `vault:v1:<base64>`, raw r || s for both key types
+        let signature = response["data"]["signature"]
+            .as_str()
+            .and_then(|s| s.rsplit(':').next())
+            .ok_or(CKMError::SigningError)?;
+        let raw = match curve {
+            Curve::Secp256R1 => BASE64_URL_SAFE_NO_PAD.decode(signature),
+            _ => BASE64_STANDARD.decode(signature),
+        }
+        .map_err(|_e| CKMError::SigningError)?;
+        SigningSignature::from_compact(&raw)
+    }
+
+    /// compressed SEC1 for ECDSA-P256 and 32 bytes for Ed25519, from the latest key version
+    fn get_public_key(
+        &self,
+        _password: &str,
+        key_id: &str,
+        _path: &str,
+        curve: &Curve,
+    ) -> Result<Vec<u8>, CKMError> {
+        let key = self._key(key_id, curve)?;
+        let version = key["latest_version"].as_u64().unwrap_or(1).to_string();
+        let public_key = key["keys"][version]["public_key"]
+            .as_str()
+            .ok_or_else(|| CKMError::HashiCorpError("missing public key".to_string()))?;
+        let invalid = || CKMError::HashiCorpError("invalid public key".to_string());
+        match curve {
+            Curve::Secp256R1 => {
+                let der = _pem_body(public_key).ok_or_else(invalid)?;
+                let prefix = hex::decode(P256_SPKI_PREFIX).unwrap();
+                let point = der.strip_prefix(prefix.as_slice()).ok_or_else(invalid)?;
+                let encoded = p256::EncodedPoint::from_bytes(point).map_err(|_e| invalid())?;
+                let point = p256::AffinePoint::from_encoded_point(&encoded).ok_or_else(invalid)?;
+                Ok(point.to_encoded_point(true).as_bytes().to_vec())
+            }
+            _ => BASE64_STANDARD.decode(public_key).map_err(|_e| invalid()),
+        }
+    }
+}
+
+fn _pem_body(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    BASE64_STANDARD.decode(body.trim()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ecdsa::hazmat::{SignPrimitive, VerifyPrimitive};
+    use p256::elliptic_curve::group::ff::PrimeField;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::keystore::fake::SEED;
+    use crate::test_util::serve_http;
+
+    /// stand-in for `vault server -dev` with the token, AppRole, KV v2 and Transit endpoints
+    /// the keystores use
+    #[derive(Default)]
+    struct MockVault {
+        tokens: Vec<String>,
+        sealed: bool,
+        logins: usize,
+        renewals: usize,
+        kv: HashMap<String, Value>,
+        transit: HashMap<String, (String, Vec<u8>)>,
+    }
+
+    impl MockVault {
+        fn start() -> (String, Arc<Mutex<MockVault>>) {
+            let vault = Arc::new(Mutex::new(MockVault {
+                tokens: vec!["root".to_string()],
+                ..Default::default()
+            }));
+            let shared = vault.clone();
+            let addr = serve_http("application/json", move |request| {
+                let path = request.path.trim_start_matches("/v1/");
+                let token = Some(request.header("x-vault-token")).filter(|t| !t.is_empty());
+                let body = serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+                let (status, response) =
+                    shared
+                        .lock()
+                        .unwrap()
+                        .handle(&request.method, path, token, body);
+                match response {
+                    Value::Null => (status, String::new()),
+                    response => (status, response.to_string()),
+                }
+            });
+            (addr, vault)
+        }
+
+        fn handle(
+            &mut self,
+            method: &str,
+            path: &str,
+            token: Option<&str>,
+            body: Value,
+        ) -> (u16, Value) {
+            let error = |status, message: &str| (status, json!({ "errors": [message] }));
+            if self.sealed {
+                return error(503, "Vault is sealed");
+            }
+            let (path, list) = match path.strip_suffix("?list=true") {
+                Some(path) => (path, true),
+                None => (path, false),
+            };
+            if path == "auth/approle/login" {
+                if body["role_id"] != "role" || body["secret_id"] != "secret" {
+                    return error(400, "invalid role or secret ID");
+                }
+                self.logins += 1;
+                let token = format!("s.approle{}", self.logins);
+                self.tokens.push(token.clone());
+                return (
+                    200,
+                    json!({ "auth": { "client_token": token, "lease_duration": 1, "renewable": true } }),
+                );
+            }
+            match token {
+                Some(token) if self.tokens.iter().any(|t| t == token) => {}
+                _ => return error(403, "permission denied"),
+            }
+            let segments: Vec<&str> = path.split('/').collect();
+            match (method, segments.as_slice()) {
+                ("GET", ["auth", "token", "lookup-self"]) => {
+                    (200, json!({ "data": { "ttl": 0, "renewable": false } }))
+                }
+                ("POST", ["auth", "token", "renew-self"]) => {
+                    self.renewals += 1;
+                    let auth =
+                        json!({ "client_token": token, "lease_duration": 1, "renewable": true });
+                    (200, json!({ "auth": auth }))
+                }
+                ("POST", ["secret", "data", "ckm", key_id]) => {
+                    if body["options"]["cas"] == 0 && self.kv.contains_key(*key_id) {
+                        return error(
+                            400,
+                            "check-and-set parameter did not match the current version",
+                        );
+                    }
+                    self.kv.insert(key_id.to_string(), body["data"].clone());
+                    (200, json!({ "data": { "version": 1 } }))
+                }
+                ("GET", ["secret", "data", "ckm", key_id]) => match self.kv.get(*key_id) {
+                    Some(data) => (200, json!({ "data": { "data": data } })),
+                    None => error(404, ""),
+                },
+                ("GET", ["secret", "metadata", "ckm", key_id]) => match self.kv.get(*key_id) {
+                    Some(_) => (200, json!({ "data": { "current_version": 1 } })),
+                    None => error(404, ""),
+                },
+                // like Vault, deleting the metadata of a missing key succeeds
+                ("DELETE", ["secret", "metadata", "ckm", key_id]) => {
+                    self.kv.remove(*key_id);
+                    (204, Value::Null)
+                }
+                ("GET", ["secret", "metadata", "ckm"]) if list && !self.kv.is_empty() => {
+                    let keys: Vec<&String> = self.kv.keys().collect();
+                    (200, json!({ "data": { "keys": keys } }))
+                }
+                ("POST", ["transit", "keys", name]) => {
+                    let mut secret = vec![0u8; 32];
+                    crate::keystore::local::random_fill(&mut secret).unwrap();
+                    let key_type = body["type"].as_str().unwrap().to_string();
+                    self.transit.insert(name.to_string(), (key_type, secret));
+                    (204, Value::Null)
+                }
+                ("GET", ["transit", "keys"]) if list => {
+                    let keys: Vec<&String> = self.transit.keys().collect();
+                    (200, json!({ "data": { "keys": keys } }))
+                }
+                ("GET", ["transit", "keys", name]) => match self.transit.get(*name) {
+                    Some((key_type, secret)) => {
+                        let public_key = match key_type.as_str() {
+                            "ecdsa-p256" => {
+                                let point =
+                                    curve::ecdh::public_key(secret, &Curve::Secp256R1).unwrap();
+                                let encoded = p256::EncodedPoint::from_bytes(&point).unwrap();
+                                let point =
+                                    p256::AffinePoint::from_encoded_point(&encoded).unwrap();
+                                let mut der = hex::decode(P256_SPKI_PREFIX).unwrap();
+                                der.extend_from_slice(point.to_encoded_point(false).as_bytes());
+                                format!(
+                                    "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+                                    BASE64_STANDARD.encode(der)
+                                )
+                            }
+                            _ => {
+                                let key_pair = Ed25519KeyPair::from_seed_unchecked(secret).unwrap();
+                                BASE64_STANDARD.encode(key_pair.public_key())
+                            }
+                        };
+                        let data = json!({
+                            "type": key_type,
+                            "latest_version": 1,
+                            "keys": { "1": { "public_key": public_key } },
+                        });
+                        (200, json!({ "data": data }))
+                    }
+                    None => error(404, ""),
+                },
+                ("POST", ["transit", "sign", name]) => {
+                    let (key_type, secret) = match self.transit.get(*name) {
+                        Some(key) => key,
+                        None => return error(400, "signing key not found"),
+                    };
+                    let input = BASE64_STANDARD
+                        .decode(body["input"].as_str().unwrap())
+                        .unwrap();
+                    let signature = match key_type.as_str() {
+                        "ecdsa-p256" => {
+                            assert_eq!(body["prehashed"], true);
+                            assert_eq!(body["marshaling_algorithm"], "jws");
+                            let d = curve::r1::secret_scalar(secret).unwrap();
+                            let z = p256::Scalar::from_bytes_reduced(input.as_slice().into());
+                            let k = loop {
+                                let mut k = [0u8; 32];
+                                crate::keystore::local::random_fill(&mut k).unwrap();
+                                if let Some(k) = p256::Scalar::from_repr(k.into()) {
+                                    break k;
+                                }
+                            };
+                            let sig = d.try_sign_prehashed(&k, &z).unwrap();
+                            BASE64_URL_SAFE_NO_PAD.encode(sig.as_ref())
+                        }
+                        _ => {
+                            let key_pair = Ed25519KeyPair::from_seed_unchecked(secret).unwrap();
+                            BASE64_STANDARD.encode(key_pair.sign(&input))
+                        }
+                    };
+                    let signature = format!("vault:v1:{}", signature);
+                    (200, json!({ "data": { "signature": signature } }))
+                }
+                _ => error(404, ""),
+            }
+        }
+    }
+
+    #[test]
+    fn test_kv() {
+        let (addr, vault) = MockVault::start();
+        let client = HashiCorpClient::with_token(&addr, "root").unwrap();
+        let store = HashiCorpKvKeystore::new(client, "secret", "ckm");
+        assert!(store.list_keys().unwrap().is_empty());
+
+        let seed = crate::keystore::encode_seed(SEED).unwrap();
+        let key_id = store.write_key("123", seed.clone()).unwrap();
+        assert_eq!(
+            store.get_key("123", key_id.clone()).unwrap(),
+            seed.as_bytes()
+        );
+        assert!(matches!(
+            store.get_key("124", key_id.clone()),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert_eq!(store.list_keys().unwrap(), vec![key_id.clone()]);
+        // Vault only sees the sealed envelope
+        let stored = vault.lock().unwrap().kv[&key_id]["envelope"].to_string();
+        assert!(!stored.contains(SEED));
+
+        // the seed signs like a local one
+        let key_master = KeyMaster::new(store);
+        let request = SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: b"hello".to_vec(),
+            key_id: &key_id,
+            curve: Curve::Secp256k1,
+        };
+        let sig = key_master.sign(request, "123").unwrap();
+        assert_eq!(
+            hex::encode(sig.r),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+        );
+
+        let client = HashiCorpClient::with_token(&addr, "root").unwrap();
+        let store = HashiCorpKvKeystore::new(client, "secret", "ckm");
+        assert!(store.remove_key(&key_id).unwrap());
+        assert!(!store.remove_key(&key_id).unwrap());
+        assert!(key_master.list_keys().unwrap().is_empty());
+
+        vault.lock().unwrap().sealed = true;
+        assert!(matches!(key_master.list_keys(), Err(CKMError::VaultLocked)));
+    }
+
+    #[test]
+    fn test_transit() {
+        let (addr, _vault) = MockVault::start();
+        let client = HashiCorpClient::with_token(&addr, "root").unwrap();
+        let store = HashiCorpTransitKeystore::new(client, "transit");
+        store.create_key("p256", Curve::Secp256R1).unwrap();
+        store.create_key("ed", Curve::Ed25519).unwrap();
+        assert!(matches!(
+            store.create_key("k1", Curve::Secp256k1),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        let mut keys = store.list_keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["ed", "p256"]);
+
+        let key_master = KeyMaster::new(store);
+        let request = |key_id, curve| SignRequest {
+            path: "m",
+            unsigend_data: b"hello".to_vec(),
+            key_id,
+            curve,
+        };
+
+        let public_key = key_master
+            .get_public_key("p256", "m", Curve::Secp256R1, "")
+            .unwrap();
+        let sig = key_master
+            .sign(request("p256", Curve::Secp256R1), "")
+            .unwrap();
+        let encoded = p256::EncodedPoint::from_bytes(&public_key).unwrap();
+        let point = p256::AffinePoint::from_encoded_point(&encoded).unwrap();
+        let digest = Sha256::digest(b"hello");
+        let z = p256::Scalar::from_bytes_reduced(&digest);
+        let signature = p256::ecdsa::Signature::from_scalars(sig.r, sig.s).unwrap();
+        assert!(point.verify_prehashed(&z, &signature).is_ok());
+
+        let public_key = key_master
+            .get_public_key("ed", "m", Curve::Ed25519, "")
+            .unwrap();
+        let sig = key_master.sign(request("ed", Curve::Ed25519), "").unwrap();
+        let verifier =
+            ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key);
+        assert!(verifier.verify(b"hello", &sig.to_compact()).is_ok());
+
+        // the key type is checked and Transit keys do not leave Vault
+        assert!(matches!(
+            key_master.get_public_key("ed", "m", Curve::Secp256R1, ""),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(matches!(
+            key_master.get_public_key("missing", "m", Curve::Ed25519, ""),
+            Err(CKMError::NotExist)
+        ));
+        assert!(matches!(
+            key_master.sign_psbt(&[], "p256", ""),
+            Err(CKMError::KeyNotExportable)
+        ));
+    }
+
+    #[test]
+    fn test_approle_lease() {
+        let (addr, vault) = MockVault::start();
+        assert!(matches!(
+            HashiCorpClient::with_approle(&addr, "role", "wrong"),
+            Err(CKMError::HashiCorpError(_))
+        ));
+        let client = HashiCorpClient::with_approle(&addr, "role", "secret").unwrap();
+        let store = HashiCorpKvKeystore::new(client, "secret", "ckm");
+        assert!(store.list_keys().unwrap().is_empty());
+
+        // the one second lease is renewed once two thirds of it passed
+        std::thread::sleep(Duration::from_millis(700));
+        assert!(store.list_keys().unwrap().is_empty());
+        assert_eq!(vault.lock().unwrap().renewals, 1);
+
+        // a revoked token is replaced by a new login
+        vault.lock().unwrap().tokens.retain(|t| t == "root");
+        assert!(store.list_keys().unwrap().is_empty());
+        assert_eq!(vault.lock().unwrap().logins, 2);
+    }
+
+    /// runs against `vault server -dev` with `vault secrets enable transit`, at
+    /// `CKM_VAULT_ADDR` with the root token `CKM_VAULT_TOKEN`
+    #[test]
+    #[ignore = "needs a Vault dev server, see CKM_VAULT_ADDR"]
+    fn test_dev_server() {
+        let addr = std::env::var("CKM_VAULT_ADDR").expect("CKM_VAULT_ADDR must be set");
+        let token = std::env::var("CKM_VAULT_TOKEN").expect("CKM_VAULT_TOKEN must be set");
+        let client = HashiCorpClient::with_token(&addr, &token).unwrap();
+        let store = HashiCorpKvKeystore::new(client, "secret", "ckm-test");
+        let key_id = store.write_key("123", SEED.to_string()).unwrap();
+        assert_eq!(
+            store.get_key("123", key_id.clone()).unwrap(),
+            SEED.as_bytes()
+        );
+        assert!(store.remove_key(&key_id).unwrap());
+        assert!(!store.remove_key(&key_id).unwrap());
+
+        let client = HashiCorpClient::with_token(&addr, &token).unwrap();
+        let store = HashiCorpTransitKeystore::new(client, "transit");
+        let name = format!("ckm-test-{}", new_key_id().unwrap());
+        store.create_key(&name, Curve::Ed25519).unwrap();
+        let public_key = store
+            .get_public_key("", &name, "m", &Curve::Ed25519)
+            .unwrap();
+        let sig = store
+            .sign_digest("", &name, "m", &Curve::Ed25519, b"hello")
+            .unwrap();
+        let verifier =
+            ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key);
+        assert!(verifier.verify(b"hello", &sig.to_compact()).is_ok());
+    }
+}
@@ -1,7 +1,7 @@
+use super::{encode_key, web3};
 use crate::*;
-use aes::cipher::generic_array::GenericArray;
-use aes::cipher::{StreamCipher, StreamCipherSeek};
-use aes::{cipher::FromBlockCipher, Aes128, Aes128Ctr, NewBlockCipher};
+use aes::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
+use aes::Aes128Ctr;
 use hex::{decode, encode};
 use ring::rand::{SecureRandom, SystemRandom};
 use scrypt::{scrypt, ScryptParams};
@@ -9,7 +9,7 @@ use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde_json::Value;
 use sha3::{Digest, Sha3_256};
 use std::convert::TryInto;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::path::Path;
 use std::str;
@@ -25,6 +25,51 @@ impl LocalKeystore {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// encrypt the key under a new password, the file is replaced atomically
+    pub fn change_password(
+        &self,
+        key_id: &str,
+        password: &str,
+        new_password: &str,
+    ) -> Result<(), CKMError> {
+        _check_key_id(key_id)?;
+        let _lock = _lock_writers()?;
+        let content = _read_file(Path::new(key_id))?;
+        let key = Zeroizing::new(open_envelope(&content, password)?);
+        let serialized = seal_envelope(new_password, &key)?;
+        _replace_file(Path::new(key_id), &serialized)
+    }
+
+    /// store the key of a keystore file written by `LocalKeystore` on another host, or of a Web3
+    /// Secret Storage V3 file, under a new id and password. The private key of a V3 file is the
+    /// secp256k1 key of path `m`
+    pub fn import_keystore(
+        &self,
+        content: &str,
+        password: &str,
+        new_password: &str,
+    ) -> Result<String, CKMError> {
+        let value: Value =
+            serde_json::from_str(content).map_err(|e| CKMError::corrupt("invalid json", e))?;
+        let key = match web3::is_web3(&value) {
+            true => Zeroizing::new(encode_key(&web3::open_web3(&value, password)?).into_bytes()),
+            false => Zeroizing::new(open_envelope(content, password)?),
+        };
+        let serialized = seal_envelope(new_password, &key)?;
+        _write_keystore_file(new_key_id()?, serialized)
+    }
+
+    /// remove the key file
+    pub fn delete_key(&self, key_id: &str) -> Result<(), CKMError> {
+        _check_key_id(key_id)?;
+        let _lock = _lock_writers()?;
+        let path = Path::new(key_id);
+        if !path.is_file() {
+            return Err(CKMError::FileNotExit);
+        }
+        std::fs::remove_file(path).map_err(|e| CKMError::io(path, e))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -129,12 +174,13 @@ impl Serialize for Kdfparams {
     }
 }
 
-fn random_bytes(length: u32) -> Result<Vec<u8>, CKMError> {
+/// random entropy of 128 or 256 bits
+pub(crate) fn random_bytes(length: u32) -> Result<Vec<u8>, CKMError> {
     match length {
         128 | 256 => {
             let size = length / 8;
             let mut key = vec![0u8; size.try_into().unwrap()];
-            _random_generator(&mut key);
+            _random_generator(&mut key)?;
             Ok(key)
         }
         _ => Err(CKMError::NotFound("length is not right".to_string())),
@@ -150,87 +196,118 @@ impl Keystore for LocalKeystore {
     }
 
     fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
-        let value = _read_keystore_file(key_id)?;
-        match _verify_password(&value.mac, &password.to_string(), &value.ciphertext) {
-            true => {
-                let mut password_hash = vec![0; value.kdfparams.dklen.try_into().unwrap()];
-                let password_bytes = password.as_bytes();
-                let salt = &value.kdfparams.salt;
-                let params =
-                    ScryptParams::new(value.kdfparams.log_n, value.kdfparams.r, value.kdfparams.p)
-                        .unwrap();
-                scrypt(password_bytes, salt, &params, &mut password_hash);
-                Ok(_decrypt(
-                    &value.ciphertext,
-                    &password_hash,
-                    &value.cipherparams.iv,
-                ))
-            }
-            false => Err(CKMError::PasswordInvalid),
-        }
+        _check_key_id(&key_id)?;
+        let content = _read_keystore_file(key_id)?;
+        open_envelope(&content, password)
     }
 
-    fn write_key(&mut self, password: &str, key: String) -> Result<String, CKMError> {
-        let mut store_id = [0u8; 16];
-        _random_generator(&mut store_id);
-        let (password_hash, salt) = _password_hash(password);
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let file_name = new_key_id()?;
+        let serialized = seal_envelope(password, key.as_bytes())?;
+        _write_keystore_file(file_name, serialized)
+    }
 
-        let mut encrypted_key_bytes = key.as_bytes().to_vec();
-        let (_, iv) = _encrypt(&password_hash, &mut encrypted_key_bytes);
+    /// keystore files in the working directory
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let entries = std::fs::read_dir(".").map_err(|e| CKMError::io(".", e))?;
+        let mut key_ids: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.len() == 32 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+            .collect();
+        key_ids.sort();
+        Ok(key_ids)
+    }
+}
 
-        let mut mac = password.as_bytes().to_vec();
-        mac.extend(&encrypted_key_bytes);
+/// random 16 bytes hex key id
+pub(crate) fn new_key_id() -> Result<String, CKMError> {
+    let mut store_id = [0u8; 16];
+    _random_generator(&mut store_id)?;
+    Ok(encode(store_id))
+}
 
-        let mut hasher = Sha3_256::default();
-        hasher.input(&mac);
-        let mac_bytes = hasher.result();
-        let cipherparams = Cipherparams::new(iv);
-        let kdf_params = Kdfparams {
-            salt: salt.to_vec(),
-            ..Default::default()
-        };
-        let keystore_obj = KeystoreObj::new(
-            encrypted_key_bytes,
-            cipherparams,
-            kdf_params,
-            mac_bytes.to_vec(),
-        );
-        let serialized =
-            serde_json::to_string(&keystore_obj).map_err(|_e| CKMError::SerializeError)?;
-        let file_name = encode(store_id);
-        _write_keystore_file(file_name, serialized)
+/// encrypt the key under the password into the JSON keystore envelope
+pub(crate) fn seal_envelope(password: &str, key: &[u8]) -> Result<String, CKMError> {
+    let (password_hash, salt) = _password_hash(password)?;
+
+    let mut encrypted_key_bytes = key.to_vec();
+    let (_, iv) = _encrypt(&password_hash, &mut encrypted_key_bytes)?;
+
+    let mut mac = password.as_bytes().to_vec();
+    mac.extend(&encrypted_key_bytes);
+
+    let mut hasher = Sha3_256::default();
+    hasher.input(&mac);
+    let mac_bytes = hasher.result();
+    let cipherparams = Cipherparams::new(iv);
+    let kdf_params = Kdfparams {
+        salt: salt.to_vec(),
+        ..Default::default()
+    };
+    let keystore_obj = KeystoreObj::new(
+        encrypted_key_bytes,
+        cipherparams,
+        kdf_params,
+        mac_bytes.to_vec(),
+    );
+    serde_json::to_string(&keystore_obj)
+        .map_err(|e| CKMError::crypto("keystore encoding failed", e))
+}
+
+/// decrypt the key of a JSON keystore envelope with the password
+pub(crate) fn open_envelope(content: &str, password: &str) -> Result<Vec<u8>, CKMError> {
+    let value = _parse_keystore(content)?;
+    match _verify_password(&value.mac, &password.to_string(), &value.ciphertext) {
+        true => {
+            let mut password_hash = vec![0; value.kdfparams.dklen.try_into().unwrap()];
+            let password_bytes = password.as_bytes();
+            let salt = &value.kdfparams.salt;
+            let params =
+                ScryptParams::new(value.kdfparams.log_n, value.kdfparams.r, value.kdfparams.p)
+                    .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+            scrypt(password_bytes, salt, &params, &mut password_hash)
+                .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+            _decrypt(&value.ciphertext, &password_hash, &value.cipherparams.iv)
+        }
+        false => Err(CKMError::PasswordInvalid),
     }
 }
 
-fn _encrypt<'a>(key: &[u8; 16], data: &'a mut Vec<u8>) -> (&'a mut Vec<u8>, Vec<u8>) {
+fn _encrypt<'a>(
+    key: &[u8; 16],
+    data: &'a mut Vec<u8>,
+) -> Result<(&'a mut Vec<u8>, Vec<u8>), CKMError> {
     let mut nonce = [0u8; 16].to_vec();
-    _random_generator(&mut nonce);
-    let mut wrap_key = GenericArray::from_slice(key);
-    let iv = GenericArray::from_slice(&nonce);
-    let mut cipher = Aes128Ctr::from_block_cipher(Aes128::new(&wrap_key), iv);
+    _random_generator(&mut nonce)?;
+    let mut cipher = Aes128Ctr::new_from_slices(key, &nonce)
+        .map_err(|e| CKMError::crypto("aes128-ctr key failed", e))?;
     cipher.apply_keystream(data);
-    (data, nonce)
+    Ok((data, nonce))
 }
 
-fn _decrypt(ciphertext: &Vec<u8>, password: &Vec<u8>, iv: &Vec<u8>) -> Vec<u8> {
-    let passord_bytes = GenericArray::from_slice(password);
-    let iv_bytes = GenericArray::from_slice(iv);
-
-    let mut cipher = Aes128Ctr::from_block_cipher(Aes128::new(&passord_bytes), iv_bytes);
-    let mut ciphertext_bytes = ciphertext.clone();
+fn _decrypt(ciphertext: &[u8], password: &[u8], iv: &[u8]) -> Result<Vec<u8>, CKMError> {
+    let mut cipher = Aes128Ctr::new_from_slices(password, iv)
+        .map_err(|e| CKMError::corrupt(&format!("cipher iv of {} bytes", iv.len()), e))?;
+    let mut ciphertext_bytes = ciphertext.to_vec();
     cipher.seek(0);
     cipher.apply_keystream(&mut ciphertext_bytes);
-    ciphertext_bytes
+    Ok(ciphertext_bytes)
 }
 
-fn _password_hash(password: &str) -> ([u8; 16], [u8; 16]) {
+fn _password_hash(password: &str) -> Result<([u8; 16], [u8; 16]), CKMError> {
     let password_bytes = password.as_bytes();
     let mut salt = [0u8; 16];
     let mut password_hash = [0u8; 16];
-    _random_generator(&mut salt);
+    _random_generator(&mut salt)?;
     let params = ScryptParams::new(13, 8, 1).unwrap();
-    scrypt(&password_bytes, &salt, &params, &mut password_hash);
-    (password_hash, salt)
+    scrypt(password_bytes, &salt, &params, &mut password_hash)
+        .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+    Ok((password_hash, salt))
+}
+
+/// fill the buffer with random bytes
+pub(crate) fn random_fill(data: &mut [u8]) -> Result<(), CKMError> {
+    _random_generator(data).map(|_| ())
 }
 
 fn _random_generator(data: &mut [u8]) -> Result<&mut [u8], CKMError> {
@@ -252,57 +329,96 @@ fn _verify_password(mac: &Vec<u8>, password: &String, ciphertext: &Vec<u8>) -> b
     mac == &mac_bytes.to_vec()
 }
 
+/// hidden lock file of the directory, it is never removed so all writers lock the same file
+const WRITERS_LOCK: &str = ".ckm.lock";
+
+/// lock the directory for one writer at a time. Readers take no lock, files are only replaced
+/// by rename so they see the old or the new content. The lock is advisory and also keeps
+/// threads of one process apart
+fn _lock_writers() -> Result<File, CKMError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(WRITERS_LOCK)
+        .map_err(|e| CKMError::io(WRITERS_LOCK, e))?;
+    file.lock().map_err(|e| CKMError::io(WRITERS_LOCK, e))?;
+    Ok(file)
+}
+
 fn _write_keystore_file(file_name: String, content: String) -> Result<String, CKMError> {
+    let _lock = _lock_writers()?;
     let path = Path::new(&file_name);
-    let mut file = File::create(&path).map_err(|_e| CKMError::FileGenerationError)?;
-    file.write_all(content.as_bytes())
-        .map_err(|_e| CKMError::FileError)?;
+    if path.exists() {
+        return Err(CKMError::FileGenerationError);
+    }
+    _replace_file(path, &content)?;
     Ok(file_name)
 }
 
-fn _read_keystore_file(file_name: String) -> Result<KeystoreObj, CKMError> {
-    let path = Path::new(&file_name);
-    let mut file = File::open(&path).map_err(|_e| CKMError::FileNotExit)?;
-    let mut s = String::new();
-    let _ = file
-        .read_to_string(&mut s)
-        .map_err(|_e| CKMError::FileReadError);
+/// write the content next to the file and rename it over, readers never see a partly
+/// written file. The caller holds the exclusive lock
+fn _replace_file(path: &Path, content: &str) -> Result<(), CKMError> {
+    let tmp = format!(".{}.tmp", path.display());
+    let mut file = File::create(&tmp).map_err(|e| CKMError::io(&tmp, e))?;
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| CKMError::io(&tmp, e))?;
+    std::fs::rename(&tmp, path).map_err(|e| CKMError::io(path, e))
+}
 
-    let v: Value = serde_json::from_str(s.as_str()).map_err(|_e| CKMError::FileReadError)?;
+/// key ids are the 32 hex characters of `new_key_id`, anything else is not a key file
+fn _check_key_id(key_id: &str) -> Result<(), CKMError> {
+    match key_id.len() == 32 && key_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Ok(()),
+        false => Err(CKMError::NotExist),
+    }
+}
 
-    let ciphertext = v.get("ciphertext").ok_or(CKMError::FileReadError)?;
+fn _read_keystore_file(file_name: String) -> Result<String, CKMError> {
+    _check_key_id(&file_name)?;
+    let path = Path::new(&file_name);
+    if !path.is_file() {
+        return Err(CKMError::FileNotExit);
+    }
+    _read_file(path)
+}
 
-    let ciphertext = ciphertext.as_str().ok_or(CKMError::FileReadError)?;
+fn _read_file(path: &Path) -> Result<String, CKMError> {
+    let mut file = File::open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => CKMError::FileNotExit,
+        _ => CKMError::io(path, e),
+    })?;
+    let mut s = String::new();
+    file.read_to_string(&mut s)
+        .map_err(|e| CKMError::io(path, e))?;
+    Ok(s)
+}
 
-    let ciphertext_bytes = decode(ciphertext).map_err(|_e| CKMError::FileReadError)?;
+fn _parse_keystore(s: &str) -> Result<KeystoreObj, CKMError> {
+    let v: Value = serde_json::from_str(s).map_err(|e| CKMError::corrupt("invalid json", e))?;
 
-    let iv = v["cipherparams"]["iv"]
-        .as_str()
-        .ok_or(CKMError::FileReadError)?;
+    let ciphertext = _hex_field(&v["ciphertext"], "ciphertext")?;
 
-    let iv = decode(iv).map_err(|_e| CKMError::FileReadError)?;
+    let iv = _hex_field(&v["cipherparams"]["iv"], "cipherparams.iv")?;
     let cipherparams = Cipherparams::new(iv);
 
-    let salt = v["kdfparams"]["salt"]
-        .as_str()
-        .ok_or(CKMError::FileReadError)?;
+    let salt = _hex_field(&v["kdfparams"]["salt"], "kdfparams.salt")?;
 
-    let salt = decode(salt).map_err(|_e| CKMError::FileReadError)?;
-
-    let mac = v["mac"].as_str().ok_or(CKMError::FileReadError)?;
-    let mac = decode(mac).map_err(|_e| CKMError::FileReadError)?;
+    let mac = _hex_field(&v["mac"], "mac")?;
 
     let kdfparams = Kdfparams {
         salt,
         ..Default::default()
     };
 
-    Ok(KeystoreObj::new(
-        ciphertext_bytes,
-        cipherparams,
-        kdfparams,
-        mac,
-    ))
+    Ok(KeystoreObj::new(ciphertext, cipherparams, kdfparams, mac))
+}
+
+/// hex encoded field of the keystore JSON
+fn _hex_field(value: &Value, name: &str) -> Result<Vec<u8>, CKMError> {
+    let field = value.as_str().ok_or_else(|| CKMError::missing(name))?;
+    decode(field).map_err(|e| CKMError::corrupt(&format!("{} is not hex", name), e))
 }
 
 #[cfg(test)]
@@ -318,14 +434,14 @@ mod tests {
             &"123".to_string(),
             &"456".as_bytes().to_vec(),
         );
-        assert_eq!(result, true);
+        assert!(result);
         let mac_hash = "d7190eb194ff9494625514b6d178c87f99c5973e28c398969d2233f2960a573d";
         let result = _verify_password(
             &decode(mac_hash).unwrap(),
             &"123".to_string(),
             &"456".as_bytes().to_vec(),
         );
-        assert_eq!(result, false);
+        assert!(!result);
     }
 
     #[test]
@@ -337,7 +453,109 @@ mod tests {
             &decode(c).unwrap(),
             &decode(a).unwrap(),
             &decode(iv).unwrap(),
-        );
+        )
+        .unwrap();
         assert_eq!(str::from_utf8(&a).unwrap(), "456")
     }
+
+    #[test]
+    fn test_corrupt_keystore() {
+        let envelope = seal_envelope("123", b"seed").unwrap();
+        let mut value: Value = serde_json::from_str(&envelope).unwrap();
+        value["mac"] = Value::String("xyz".to_string());
+        let e = open_envelope(&value.to_string(), "123").unwrap_err();
+        assert!(matches!(
+            e,
+            CKMError::CorruptKeystore {
+                source: Some(_),
+                ..
+            }
+        ));
+        assert_eq!(e.to_string(), "corrupt keystore, mac is not hex");
+
+        value.as_object_mut().unwrap().remove("ciphertext");
+        let e = open_envelope(&value.to_string(), "123").unwrap_err();
+        assert_eq!(e.to_string(), "corrupt keystore, missing ciphertext");
+
+        let e = _read_keystore_file("0".repeat(32)).unwrap_err();
+        assert!(matches!(e, CKMError::FileNotExit));
+
+        // key ids are never paths
+        let store = LocalKeystore::new();
+        for key_id in ["../Cargo.toml", "/etc/passwd", "Cargo.toml", ""] {
+            assert!(matches!(
+                store.get_key("123", key_id.to_string()),
+                Err(CKMError::NotExist)
+            ));
+            assert!(matches!(
+                _read_keystore_file(key_id.to_string()),
+                Err(CKMError::NotExist)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_change_password_and_delete() {
+        let store = LocalKeystore::new();
+        let key_id = store.write_key("123", "00".repeat(64)).unwrap();
+        store.change_password(&key_id, "123", "456").unwrap();
+        assert!(matches!(
+            store.get_key("123", key_id.clone()),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert_eq!(
+            store.get_key("456", key_id.clone()).unwrap(),
+            "00".repeat(64).as_bytes()
+        );
+        assert!(matches!(
+            store.change_password(&key_id, "123", "789"),
+            Err(CKMError::PasswordInvalid)
+        ));
+
+        // readers do not wait for writers
+        let writing = _lock_writers().unwrap();
+        assert_eq!(
+            store.get_key("456", key_id.clone()).unwrap(),
+            "00".repeat(64).as_bytes()
+        );
+        drop(writing);
+
+        store.delete_key(&key_id).unwrap();
+        assert!(!Path::new(&key_id).exists());
+        assert!(matches!(
+            store.delete_key(&key_id),
+            Err(CKMError::FileNotExit)
+        ));
+        assert!(matches!(
+            store.delete_key("../etc"),
+            Err(CKMError::NotExist)
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let key_master = KeyMaster::new(LocalKeystore::new());
+        let key_ids: Vec<String> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|i| {
+                    let key_master = &key_master;
+                    scope.spawn(move || {
+                        let seed = format!("{:02x}", i).repeat(64);
+                        let key_id = key_master.write_seed("123", seed.clone()).unwrap();
+                        let store = LocalKeystore::new();
+                        assert_eq!(
+                            store.get_key("123", key_id.clone()).unwrap(),
+                            encode_seed(&seed).unwrap().as_bytes()
+                        );
+                        key_id
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        for key_id in key_ids {
+            assert!(!Path::new(&format!(".{}.tmp", key_id)).exists());
+            std::fs::remove_file(&key_id).unwrap();
+        }
+    }
 }
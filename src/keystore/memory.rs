@@ -0,0 +1,146 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use super::local::{new_key_id, open_envelope, random_bytes, seal_envelope};
+
+#[derive(Debug, Clone, Default)]
+/// keystore that never touches the disk, for tests and short lived processes
+/// keys are encrypted under their password with the same envelope as `LocalKeystore`,
+/// clones share the same entries and can be used across threads
+pub struct MemoryKeystore {
+    entries: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl MemoryKeystore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ids of the stored keys
+    pub fn key_ids(&self) -> Vec<String> {
+        let entries = self.entries.read().unwrap();
+        let mut ids: Vec<String> = entries.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// remove a key, returns whether it was stored
+    pub fn remove_key(&self, key_id: &str) -> bool {
+        self.entries.write().unwrap().remove(key_id).is_some()
+    }
+
+    /// JSON snapshot of the encrypted entries, keys stay encrypted under their passwords
+    pub fn snapshot(&self) -> Result<String, CKMError> {
+        let entries = self.entries.read().unwrap();
+        let mut snapshot = serde_json::Map::new();
+        for (key_id, envelope) in entries.iter() {
+            let value: serde_json::Value =
+                serde_json::from_str(envelope).map_err(|_e| CKMError::SerializeError)?;
+            snapshot.insert(key_id.clone(), value);
+        }
+        serde_json::to_string(&snapshot).map_err(|_e| CKMError::SerializeError)
+    }
+
+    /// restore a keystore from a snapshot
+    pub fn restore(snapshot: &str) -> Result<Self, CKMError> {
+        let snapshot: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(snapshot).map_err(|_e| CKMError::SerializeError)?;
+        let entries = snapshot
+            .into_iter()
+            .map(|(key_id, value)| (key_id, value.to_string()))
+            .collect();
+        Ok(Self {
+            entries: Arc::new(RwLock::new(entries)),
+        })
+    }
+}
+
+impl Keystore for MemoryKeystore {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        random_bytes(length)
+    }
+
+    fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
+        let envelope = self
+            .entries
+            .read()
+            .unwrap()
+            .get(&key_id)
+            .cloned()
+            .ok_or(CKMError::NotExist)?;
+        open_envelope(&envelope, password)
+    }
+
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let envelope = seal_envelope(password, key.as_bytes())?;
+        let mut entries = self.entries.write().unwrap();
+        let mut key_id = new_key_id()?;
+        while entries.contains_key(&key_id) {
+            key_id = new_key_id()?;
+        }
+        entries.insert(key_id.clone(), envelope);
+        Ok(key_id)
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        Ok(self.key_ids())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_get_write() {
+        let store = MemoryKeystore::new();
+        let key_id = store.write_key("123", "456".to_string()).unwrap();
+        assert_eq!(key_id.len(), 32);
+        assert_eq!(store.get_key("123", key_id.clone()).unwrap(), b"456");
+        assert!(matches!(
+            store.get_key("124", key_id.clone()),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(matches!(
+            store.get_key("123", "missing".to_string()),
+            Err(CKMError::NotExist)
+        ));
+        let envelope: serde_json::Value =
+            serde_json::from_str(&store.entries.read().unwrap()[&key_id]).unwrap();
+        assert_ne!(envelope["ciphertext"], hex::encode("456"));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let store = MemoryKeystore::new();
+        let key_id = store.write_key("123", "456".to_string()).unwrap();
+        let snapshot = store.snapshot().unwrap();
+
+        let restored = MemoryKeystore::restore(&snapshot).unwrap();
+        assert_eq!(restored.key_ids(), vec![key_id.clone()]);
+        assert_eq!(restored.get_key("123", key_id.clone()).unwrap(), b"456");
+        assert!(restored.remove_key(&key_id));
+        assert!(restored.key_ids().is_empty());
+    }
+
+    #[test]
+    fn test_threads() {
+        let store = MemoryKeystore::new();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || store.write_key("123", i.to_string()).unwrap())
+            })
+            .collect();
+        let key_ids: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(store.key_ids().len(), 4);
+        for (i, key_id) in key_ids.into_iter().enumerate() {
+            assert_eq!(
+                store.get_key("123", key_id).unwrap(),
+                i.to_string().as_bytes()
+            );
+        }
+    }
+}
@@ -1,11 +1,43 @@
+#[cfg(feature = "aws-kms")]
+mod aws_kms;
+#[cfg(test)]
 pub(crate) mod fake;
+#[cfg(feature = "hashicorp")]
+mod hashicorp;
 mod local;
+mod memory;
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod vault;
+mod web3;
 
-use crate::CKMError;
+use crate::{CKMError, Curve, SigningSignature};
+#[cfg(feature = "aws-kms")]
+pub use aws_kms::{AwsCredentials, AwsKmsKeystore};
+#[cfg(feature = "hashicorp")]
+pub use hashicorp::{HashiCorpClient, HashiCorpKvKeystore, HashiCorpTransitKeystore};
+#[cfg(any(
+    feature = "remote",
+    all(test, unix, any(feature = "daemon", feature = "ssh"))
+))]
+pub(crate) use local::new_key_id;
 pub use local::LocalKeystore;
+pub use memory::MemoryKeystore;
+#[cfg(feature = "pkcs11")]
+pub use pkcs11::Pkcs11Keystore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteKeystore;
+pub use vault::VaultKeystore;
+use zeroize::Zeroizing;
+
+#[cfg(any(feature = "ssh", feature = "jose"))]
+use std::cell::OnceCell;
 
 /// Keystore trait for storing keys, it can be local file or secure element etc.
-pub trait Keystore {
+/// stores are shared between threads, writes go through `&self`
+pub trait Keystore: Send + Sync {
     /// generate the entropy to provide to outside world
     fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError>;
 
@@ -13,7 +45,153 @@ pub trait Keystore {
     fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError>;
 
     /// write key to store
-    fn write_key(&mut self, password: &str, key: String) -> Result<String, CKMError>;
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError>;
+
+    /// ids of the stored keys
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        Err(CKMError::NotFound("key listing".to_string()))
+    }
+
+    /// whether the store signs with its keys itself instead of handing them out,
+    /// `KeyMaster::sign` routes the requests of such stores to `sign_digest`
+    fn signs_internally(&self) -> bool {
+        false
+    }
+
+    /// sign inside the store, the digest is the SHA-256 of the data for ECDSA and the
+    /// data itself for schnorr and Ed25519
+    fn sign_digest(
+        &self,
+        _password: &str,
+        _key_id: &str,
+        _path: &str,
+        _curve: &Curve,
+        _digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        Err(CKMError::InternalSigningUnsupported)
+    }
+
+    /// public key of a key held by a store that signs internally, in the encoding of
+    /// `KeyMaster::get_public_key`
+    fn get_public_key(
+        &self,
+        _password: &str,
+        _key_id: &str,
+        _path: &str,
+        _curve: &Curve,
+    ) -> Result<Vec<u8>, CKMError> {
+        Err(CKMError::InternalSigningUnsupported)
+    }
+}
+
+/// where the key master reads seeds and derives keys, every keystore and the unlock sessions
+pub(crate) trait KeySource {
+    /// seed written by `KeyMaster::write_seed`
+    fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError>;
+
+    /// secp256k1 private key of a BIP32 path
+    fn derive_k1(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        let seed = self.read_seed(password, key_id)?;
+        Ok(Zeroizing::new(crate::curve::k1::derive_from_seed(
+            &seed, path,
+        )?))
+    }
+}
+
+impl<Store: Keystore> KeySource for Store {
+    fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        let key = Zeroizing::new(self.get_key(password, key_id.to_string())?);
+        decode_seed(key)
+    }
+}
+
+/// keys of one call that needs the seed of a key twice, like a signature and its public key.
+/// The seed is decrypted on the first read and wiped when the call ends
+#[cfg(any(feature = "ssh", feature = "jose"))]
+pub(crate) struct SeedOnce<'a, Keys> {
+    keys: &'a Keys,
+    key_id: &'a str,
+    seed: OnceCell<Result<Zeroizing<Vec<u8>>, CKMError>>,
+}
+
+#[cfg(any(feature = "ssh", feature = "jose"))]
+impl<'a, Keys: KeySource> SeedOnce<'a, Keys> {
+    pub(crate) fn new(keys: &'a Keys, key_id: &'a str) -> Self {
+        Self {
+            keys,
+            key_id,
+            seed: OnceCell::new(),
+        }
+    }
+}
+
+#[cfg(any(feature = "ssh", feature = "jose"))]
+impl<'a, Keys: KeySource> KeySource for SeedOnce<'a, Keys> {
+    fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        if key_id != self.key_id {
+            return self.keys.read_seed(password, key_id);
+        }
+        self.seed
+            .get_or_init(|| self.keys.read_seed(password, key_id))
+            .clone()
+    }
+}
+
+/// tag of the seeds written by `KeyMaster::write_seed`, the hex encoded seed follows it
+const SEED_TAG: &[u8] = b"ckm-seed:hex:";
+
+/// tag of imported secp256k1 private keys, like the ones of Web3 Secret Storage files, the hex
+/// encoded key follows it. `decode_seed` keeps the tag so derivations see an imported key
+const KEY_TAG: &[u8] = b"ckm-key:secp256k1:hex:";
+
+/// read a seed written by `KeyMaster::write_seed`
+pub(crate) fn read_seed(
+    store: &impl KeySource,
+    password: &str,
+    key_id: &str,
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    store.read_seed(password, key_id)
+}
+
+/// what `KeyMaster::write_seed` stores for a hex encoded seed
+pub(crate) fn encode_seed(seed: &str) -> Result<String, CKMError> {
+    let bytes = Zeroizing::new(hex::decode(seed).map_err(|_e| CKMError::SerializeError)?);
+    let mut stored = String::from_utf8(SEED_TAG.to_vec()).map_err(|_e| CKMError::SerializeError)?;
+    stored.push_str(&hex::encode(&*bytes));
+    Ok(stored)
+}
+
+/// what is stored for an imported secp256k1 private key
+pub(crate) fn encode_key(key: &[u8]) -> String {
+    format!("{}{}", String::from_utf8_lossy(KEY_TAG), hex::encode(key))
+}
+
+/// the private key of an imported key, `None` for seeds
+pub(crate) fn imported_key(seed: &[u8]) -> Option<Result<Zeroizing<Vec<u8>>, CKMError>> {
+    let key = seed.strip_prefix(KEY_TAG)?;
+    Some(
+        hex::decode(key)
+            .map(Zeroizing::new)
+            .map_err(|e| CKMError::corrupt("tagged key is not hex", e)),
+    )
+}
+
+/// seed bytes of a stored key, tagged seeds of `KeyMaster::write_seed` are decoded and keys
+/// written to the store directly are the seed bytes themselves
+pub(crate) fn decode_seed(key: Zeroizing<Vec<u8>>) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    match key.strip_prefix(SEED_TAG) {
+        Some(seed) => {
+            Ok(Zeroizing::new(hex::decode(seed).map_err(|e| {
+                CKMError::corrupt("tagged seed is not hex", e)
+            })?))
+        }
+        None => Ok(key),
+    }
 }
 
 #[cfg(test)]
@@ -22,6 +200,40 @@ mod tests {
     use super::*;
     use std::str;
 
+    /// counts the reads of the fake seed
+    #[cfg(any(feature = "ssh", feature = "jose"))]
+    struct CountingKeys {
+        reads: std::cell::Cell<usize>,
+    }
+
+    #[cfg(any(feature = "ssh", feature = "jose"))]
+    impl KeySource for CountingKeys {
+        fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+            self.reads.set(self.reads.get() + 1);
+            fake::FakeKeystore {}.read_seed(password, key_id)
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "ssh", feature = "jose"))]
+    fn test_seed_once() {
+        let keys = CountingKeys {
+            reads: std::cell::Cell::new(0),
+        };
+        let once = SeedOnce::new(&keys, "123456");
+        let seed = once.read_seed("pass", "123456").unwrap();
+        let key = once.derive_k1("pass", "123456", "m/0").unwrap();
+        assert_eq!(
+            *key,
+            crate::curve::k1::derive_from_seed(&seed, "m/0").unwrap()
+        );
+        assert_eq!(keys.reads.get(), 1);
+        // other keys are read as they are
+        once.read_seed("pass", "654321").unwrap();
+        once.read_seed("pass", "654321").unwrap();
+        assert_eq!(keys.reads.get(), 3);
+    }
+
     #[test]
     fn test_generate_entropy() {
         let local_keystore = LocalKeystore::new();
@@ -30,12 +242,29 @@ mod tests {
 
     #[test]
     fn test_get_write() {
-        let mut local_keystore = LocalKeystore::new();
+        let local_keystore = LocalKeystore::new();
         let v = local_keystore.write_key("123", "456".to_string()).unwrap();
         let c = local_keystore.get_key("123", v).unwrap();
         assert_eq!(str::from_utf8(&c).unwrap(), "456");
     }
 
+    #[test]
+    fn test_seed_encoding() {
+        let stored = encode_seed("00FF").unwrap();
+        assert_eq!(stored, "ckm-seed:hex:00ff");
+        let seed = decode_seed(Zeroizing::new(stored.into_bytes())).unwrap();
+        assert_eq!(&seed[..], &[0x00, 0xff]);
+        assert!(encode_seed("not hex").is_err());
+
+        // untagged keys are raw seeds even when they look like hex
+        let seed = decode_seed(Zeroizing::new(b"00ff".to_vec())).unwrap();
+        assert_eq!(&seed[..], b"00ff");
+        assert!(matches!(
+            decode_seed(Zeroizing::new(b"ckm-seed:hex:zz".to_vec())),
+            Err(CKMError::CorruptKeystore { .. })
+        ));
+    }
+
     fn keystore_test_entropy(keystore: impl Keystore) {
         let a = keystore.generate_entropy(128).unwrap();
         assert_eq!(a.len(), 16);
@@ -0,0 +1,533 @@
+use crate::curve::k1::k1_recoverable_signature;
+use crate::keystore::imported_key;
+use crate::*;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::error::{Error as Pkcs11Error, RvError};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::slot::Slot;
+use cryptoki::types::AuthPin;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::local::new_key_id;
+
+/// DER encoded `namedCurve` OIDs
+const SECP256K1_PARAMS: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x0a];
+const P256_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ED25519_PARAMS: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+/// the `edwards25519` printable string some tokens use for Ed25519
+const EDWARDS25519_PARAMS: &[u8] = b"\x13\x0cedwards25519";
+
+/// keystore on a PKCS#11 token such as an HSM, a smart card or SoftHSM
+/// keys are generated or imported on the token as non extractable keys and signing happens on
+/// the device, the password is the user PIN. Token keys are not derived, so request paths are ignored
+pub struct Pkcs11Keystore {
+    pkcs11: Pkcs11,
+    slot: Slot,
+}
+
+impl Pkcs11Keystore {
+    /// load the PKCS#11 module and use the token with the label, or the first token
+    pub fn open<P: AsRef<Path>>(module: P, token_label: Option<&str>) -> Result<Self, CKMError> {
+        let pkcs11 = Pkcs11::new(module.as_ref()).map_err(_pkcs11_error)?;
+        match pkcs11.initialize(CInitializeArgs::OsThreads) {
+            Ok(()) | Err(Pkcs11Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+            Err(e) => return Err(_pkcs11_error(e)),
+        }
+        let mut slot = None;
+        for candidate in pkcs11.get_slots_with_token().map_err(_pkcs11_error)? {
+            let info = pkcs11.get_token_info(candidate).map_err(_pkcs11_error)?;
+            if token_label.is_none_or(|label| info.label().trim_end() == label) {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| CKMError::NotFound("PKCS#11 token".to_string()))?;
+        Ok(Self { pkcs11, slot })
+    }
+
+    /// generate a key pair on the token, returns the key id
+    pub fn generate_key(
+        &self,
+        pin: &str,
+        curve: Curve,
+        label: Option<&str>,
+    ) -> Result<String, CKMError> {
+        let session = self._session(pin)?;
+        let key_id = new_key_id()?;
+        let (mechanism, key_type, params) = _curve_params(&curve)?;
+        let mut public_template = vec![
+            Attribute::Token(true),
+            Attribute::Verify(true),
+            Attribute::KeyType(key_type),
+            Attribute::EcParams(params.to_vec()),
+            Attribute::Id(hex::decode(&key_id).unwrap()),
+        ];
+        let mut private_template = vec![
+            Attribute::Token(true),
+            Attribute::Private(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+            Attribute::Sign(true),
+            Attribute::KeyType(key_type),
+            Attribute::Id(hex::decode(&key_id).unwrap()),
+        ];
+        if let Some(label) = label {
+            public_template.push(Attribute::Label(label.as_bytes().to_vec()));
+            private_template.push(Attribute::Label(label.as_bytes().to_vec()));
+        }
+        session
+            .generate_key_pair(&mechanism, &public_template, &private_template)
+            .map_err(_pkcs11_error)?;
+        Ok(key_id)
+    }
+
+    /// import a raw private key to the token as a non extractable key, returns the key id
+    pub fn import_key(
+        &self,
+        pin: &str,
+        curve: Curve,
+        private_key: &[u8],
+        label: Option<&str>,
+    ) -> Result<String, CKMError> {
+        let session = self._session(pin)?;
+        let key_id = new_key_id()?;
+        let (_, key_type, params) = _curve_params(&curve)?;
+        let public_point = match curve {
+            Curve::Secp256k1 | Curve::Secp256k1Schnorr => {
+                curve::ecdh::public_key(private_key, &curve)?
+            }
+            Curve::Secp256R1 => curve::ecdh::public_key(private_key, &curve)?,
+            Curve::Ed25519 => return Err(CKMError::UnsupportedCurve),
+        };
+        let mut private_template = vec![
+            Attribute::Class(ObjectClass::PRIVATE_KEY),
+            Attribute::Token(true),
+            Attribute::Private(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+            Attribute::Sign(true),
+            Attribute::KeyType(key_type),
+            Attribute::EcParams(params.to_vec()),
+            Attribute::Value(private_key.to_vec()),
+            Attribute::Id(hex::decode(&key_id).unwrap()),
+        ];
+        let mut public_template = vec![
+            Attribute::Class(ObjectClass::PUBLIC_KEY),
+            Attribute::Token(true),
+            Attribute::Verify(true),
+            Attribute::KeyType(key_type),
+            Attribute::EcParams(params.to_vec()),
+            Attribute::EcPoint(_der_octet_string(&_uncompressed(&public_point, &curve)?)),
+            Attribute::Id(hex::decode(&key_id).unwrap()),
+        ];
+        if let Some(label) = label {
+            private_template.push(Attribute::Label(label.as_bytes().to_vec()));
+            public_template.push(Attribute::Label(label.as_bytes().to_vec()));
+        }
+        session
+            .create_object(&private_template)
+            .map_err(_pkcs11_error)?;
+        session
+            .create_object(&public_template)
+            .map_err(_pkcs11_error)?;
+        Ok(key_id)
+    }
+
+    /// public key of a token key, uncompressed SEC1 for ECDSA keys and 32 bytes for Ed25519
+    pub fn public_key(&self, pin: &str, key_id: &str) -> Result<Vec<u8>, CKMError> {
+        let session = self._session(pin)?;
+        let public = _find(&session, ObjectClass::PUBLIC_KEY, key_id)?;
+        _public_key(&session, public)
+    }
+
+    /// remove both halves of a token key, returns whether it was stored
+    pub fn delete_key(&self, pin: &str, key_id: &str) -> Result<bool, CKMError> {
+        let session = self._session(pin)?;
+        let mut removed = false;
+        for class in [ObjectClass::PRIVATE_KEY, ObjectClass::PUBLIC_KEY] {
+            if let Ok(object) = _find(&session, class, key_id) {
+                session.destroy_object(object).map_err(_pkcs11_error)?;
+                removed = true;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn _sign(
+        &self,
+        pin: &str,
+        key_id: &str,
+        curve: &Curve,
+        data: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        let session = self._session(pin)?;
+        let private = _find(&session, ObjectClass::PRIVATE_KEY, key_id)?;
+        let key_params = _ec_params(&session, private)?;
+        let (_, _, params) = _curve_params(curve)?;
+        if key_params != params && !(params == ED25519_PARAMS && key_params == EDWARDS25519_PARAMS)
+        {
+            return Err(CKMError::UnsupportedCurve);
+        }
+        let mechanism = match curve {
+            Curve::Ed25519 => Mechanism::Eddsa,
+            Curve::Secp256k1 | Curve::Secp256R1 if data.len() == 32 => Mechanism::Ecdsa,
+            _ => return Err(CKMError::SigningError),
+        };
+        let raw = session
+            .sign(&mechanism, private, data)
+            .map_err(_pkcs11_error)?;
+        let sig = SigningSignature::from_compact(&raw)?;
+        if let Curve::Secp256k1 = curve {
+            // tokens do not normalize secp256k1 signatures or return the recovery id
+            let public = _find(&session, ObjectClass::PUBLIC_KEY, key_id)?;
+            let public_key = _public_key(&session, public)?;
+            let digest: [u8; 32] = data.try_into().map_err(|_e| CKMError::SigningError)?;
+            return k1_recoverable_signature(&digest, sig, &public_key);
+        }
+        Ok(sig)
+    }
+
+    fn _session(&self, pin: &str) -> Result<Session, CKMError> {
+        let session = self
+            .pkcs11
+            .open_rw_session(self.slot)
+            .map_err(_pkcs11_error)?;
+        match session.login(UserType::User, Some(&AuthPin::new(pin.to_string()))) {
+            Ok(()) | Err(Pkcs11Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => Ok(session),
+            Err(Pkcs11Error::Pkcs11(RvError::PinIncorrect, _)) => Err(CKMError::PasswordInvalid),
+            Err(e) => Err(_pkcs11_error(e)),
+        }
+    }
+}
+
+impl Keystore for Pkcs11Keystore {
+    /// entropy from the token random generator
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        match length {
+            128 | 256 => {
+                let session = self
+                    .pkcs11
+                    .open_ro_session(self.slot)
+                    .map_err(_pkcs11_error)?;
+                session
+                    .generate_random_vec(length / 8)
+                    .map_err(_pkcs11_error)
+            }
+            _ => Err(CKMError::NotFound("length is not right".to_string())),
+        }
+    }
+
+    /// token keys never leave the device
+    fn get_key(&self, _password: &str, _key_id: String) -> Result<Vec<u8>, CKMError> {
+        Err(CKMError::KeyNotExportable)
+    }
+
+    /// import a secp256k1 private key stored as `ckm-key:secp256k1:hex:<key>` to the token. A
+    /// token holds keys and not seeds to derive them from, so the seeds of
+    /// `KeyMaster::write_seed` and untagged keys are `UnsupportedCurve`
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let private_key =
+            imported_key(key.trim().as_bytes()).ok_or(CKMError::UnsupportedCurve)??;
+        self.import_key(password, Curve::Secp256k1, &private_key, None)
+    }
+
+    /// ids of the private keys on the token
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let session = self
+            .pkcs11
+            .open_ro_session(self.slot)
+            .map_err(_pkcs11_error)?;
+        let objects = session
+            .find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY)])
+            .map_err(_pkcs11_error)?;
+        let mut key_ids = Vec::new();
+        for object in objects {
+            let attributes = session
+                .get_attributes(object, &[AttributeType::Id])
+                .map_err(_pkcs11_error)?;
+            if let Some(Attribute::Id(id)) = attributes.into_iter().next() {
+                key_ids.push(hex::encode(id));
+            }
+        }
+        key_ids.sort();
+        Ok(key_ids)
+    }
+
+    fn signs_internally(&self) -> bool {
+        true
+    }
+
+    fn get_public_key(
+        &self,
+        password: &str,
+        key_id: &str,
+        _path: &str,
+        curve: &Curve,
+    ) -> Result<Vec<u8>, CKMError> {
+        let public_key = self.public_key(password, key_id)?;
+        match curve {
+            Curve::Ed25519 => Ok(public_key),
+            Curve::Secp256k1 | Curve::Secp256R1 => _compressed(&public_key, curve),
+            Curve::Secp256k1Schnorr => Err(CKMError::UnsupportedCurve),
+        }
+    }
+
+    /// sign on the token, secp256k1 signatures are low-S with a recovery id
+    fn sign_digest(
+        &self,
+        password: &str,
+        key_id: &str,
+        _path: &str,
+        curve: &Curve,
+        digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        self._sign(password, key_id, curve, digest)
+    }
+}
+
+fn _curve_params(curve: &Curve) -> Result<(Mechanism<'static>, KeyType, &'static [u8]), CKMError> {
+    match curve {
+        Curve::Secp256k1 => Ok((Mechanism::EccKeyPairGen, KeyType::EC, SECP256K1_PARAMS)),
+        Curve::Secp256R1 => Ok((Mechanism::EccKeyPairGen, KeyType::EC, P256_PARAMS)),
+        Curve::Ed25519 => Ok((
+            Mechanism::EccEdwardsKeyPairGen,
+            KeyType::EC_EDWARDS,
+            ED25519_PARAMS,
+        )),
+        Curve::Secp256k1Schnorr => Err(CKMError::UnsupportedCurve),
+    }
+}
+
+fn _find(session: &Session, class: ObjectClass, key_id: &str) -> Result<ObjectHandle, CKMError> {
+    let id = hex::decode(key_id).map_err(|_e| CKMError::NotExist)?;
+    session
+        .find_objects(&[Attribute::Class(class), Attribute::Id(id)])
+        .map_err(_pkcs11_error)?
+        .into_iter()
+        .next()
+        .ok_or(CKMError::NotExist)
+}
+
+fn _ec_params(session: &Session, object: ObjectHandle) -> Result<Vec<u8>, CKMError> {
+    match session
+        .get_attributes(object, &[AttributeType::EcParams])
+        .map_err(_pkcs11_error)?
+        .pop()
+    {
+        Some(Attribute::EcParams(params)) => Ok(params),
+        _ => Err(CKMError::Pkcs11Error {
+            reason: "key has no EC params".to_string(),
+            source: None,
+        }),
+    }
+}
+
+fn _public_key(session: &Session, object: ObjectHandle) -> Result<Vec<u8>, CKMError> {
+    match session
+        .get_attributes(object, &[AttributeType::EcPoint])
+        .map_err(_pkcs11_error)?
+        .pop()
+    {
+        Some(Attribute::EcPoint(point)) => _ec_point(&point),
+        _ => Err(CKMError::Pkcs11Error {
+            reason: "key has no EC point".to_string(),
+            source: None,
+        }),
+    }
+}
+
+/// `CKA_EC_POINT` is a DER OCTET STRING of the point, some tokens return the bare uncompressed
+/// or compressed point instead
+fn _ec_point(value: &[u8]) -> Result<Vec<u8>, CKMError> {
+    match _parse_octet_string(value) {
+        Some(point) if matches!(point.len(), 65 | 33 | 32) => Ok(point.to_vec()),
+        _ if matches!(value.len(), 65 | 33) => Ok(value.to_vec()),
+        _ => Err(CKMError::Pkcs11Error {
+            reason: "invalid EC point".to_string(),
+            source: None,
+        }),
+    }
+}
+
+/// content of a DER OCTET STRING spanning the whole value
+fn _parse_octet_string(value: &[u8]) -> Option<&[u8]> {
+    let (&tag, rest) = value.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    if tag != 0x04 {
+        return None;
+    }
+    let (len, content) = match first {
+        0..=0x7f => (first as usize, rest),
+        0x81 => {
+            let (&len, content) = rest.split_first()?;
+            // DER uses the short form below 0x80
+            if len < 0x80 {
+                return None;
+            }
+            (len as usize, content)
+        }
+        _ => return None,
+    };
+    if content.len() == len {
+        Some(content)
+    } else {
+        None
+    }
+}
+
+fn _der_octet_string(value: &[u8]) -> Vec<u8> {
+    let mut der = vec![0x04];
+    if value.len() >= 0x80 {
+        der.push(0x81);
+    }
+    der.push(value.len() as u8);
+    der.extend_from_slice(value);
+    der
+}
+
+fn _compressed(public_key: &[u8], curve: &Curve) -> Result<Vec<u8>, CKMError> {
+    let uncompressed = _uncompressed(public_key, curve)?;
+    let mut compressed = vec![0x02 | (uncompressed[64] & 1)];
+    compressed.extend_from_slice(&uncompressed[1..33]);
+    Ok(compressed)
+}
+
+fn _uncompressed(public_key: &[u8], curve: &Curve) -> Result<Vec<u8>, CKMError> {
+    use k256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+    let invalid = || CKMError::Pkcs11Error {
+        reason: "invalid public key".to_string(),
+        source: None,
+    };
+    match curve {
+        Curve::Secp256R1 => {
+            let encoded = p256::EncodedPoint::from_bytes(public_key).map_err(|_e| invalid())?;
+            let point = p256::AffinePoint::from_encoded_point(&encoded).ok_or_else(invalid)?;
+            Ok(point.to_encoded_point(false).as_bytes().to_vec())
+        }
+        _ => {
+            let encoded = k256::EncodedPoint::from_bytes(public_key).map_err(|_e| invalid())?;
+            let point = k256::AffinePoint::from_encoded_point(&encoded).ok_or_else(invalid)?;
+            Ok(point.to_encoded_point(false).as_bytes().to_vec())
+        }
+    }
+}
+
+fn _pkcs11_error(e: Pkcs11Error) -> CKMError {
+    CKMError::Pkcs11Error {
+        reason: "token call failed".to_string(),
+        source: Some(Arc::new(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::k1::{k1_recover_public_key, k1_verify_digest};
+    use crate::curve::r1::r1_verify_digest;
+    use crate::keystore::{encode_key, encode_seed};
+    use sha2::{Digest, Sha256};
+
+    /// the token tests are ignored by default, `scripts/softhsm.sh` sets up a SoftHSM token and
+    /// `CKM_PKCS11_MODULE` for `cargo test --features pkcs11 -- --ignored`
+    fn token() -> (Pkcs11Keystore, String) {
+        let module = std::env::var("CKM_PKCS11_MODULE")
+            .expect("CKM_PKCS11_MODULE must point to the PKCS#11 module, see scripts/softhsm.sh");
+        let label = std::env::var("CKM_PKCS11_TOKEN").unwrap_or_else(|_e| "ckm".to_string());
+        let pin = std::env::var("CKM_PKCS11_PIN").unwrap_or_else(|_e| "1234".to_string());
+        (Pkcs11Keystore::open(module, Some(&label)).unwrap(), pin)
+    }
+
+    #[test]
+    fn test_ec_point() {
+        for point in [vec![4u8; 65], vec![2u8; 33], vec![7u8; 32]] {
+            assert_eq!(_ec_point(&_der_octet_string(&point)).unwrap(), point);
+        }
+
+        // bare points whose first coordinate byte looks like a DER length
+        for first in [0x3f, 0x41, 0x81] {
+            let mut point = vec![0x04, first];
+            point.extend_from_slice(&[9u8; 63]);
+            assert_eq!(_ec_point(&point).unwrap(), point);
+        }
+        let mut compressed = vec![0x03, 0x1f];
+        compressed.extend_from_slice(&[9u8; 31]);
+        assert_eq!(_ec_point(&compressed).unwrap(), compressed);
+
+        assert!(_ec_point(&[0x04, 0x03, 1, 2, 3]).is_err());
+        assert!(_ec_point(&[0x04, 0x81, 0x20]).is_err());
+        assert!(_ec_point(&[7u8; 64]).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a PKCS#11 token, see scripts/softhsm.sh"]
+    fn test_generate_sign() {
+        let (store, pin) = token();
+        assert_eq!(store.generate_entropy(256).unwrap().len(), 32);
+        for curve in [Curve::Secp256k1, Curve::Secp256R1, Curve::Ed25519] {
+            let key_id = store.generate_key(&pin, curve, Some("ckm-test")).unwrap();
+            let request = SignRequest {
+                path: "m",
+                unsigend_data: b"hello".to_vec(),
+                key_id: &key_id,
+                curve,
+            };
+            let data = match request.curve {
+                Curve::Ed25519 => request.unsigend_data.clone(),
+                _ => Sha256::digest(&request.unsigend_data).to_vec(),
+            };
+            let sig = store
+                .sign_digest(&pin, &key_id, request.path, &request.curve, &data)
+                .unwrap();
+            let public_key = store.public_key(&pin, &key_id).unwrap();
+            let digest: [u8; 32] = Sha256::digest(b"hello").into();
+            match request.curve {
+                Curve::Secp256k1 => {
+                    assert!(sig.is_low_s());
+                    assert!(sig.v.is_some());
+                    assert!(k1_verify_digest(&public_key, &digest, &sig.to_compact()));
+                }
+                Curve::Secp256R1 => {
+                    assert!(r1_verify_digest(&public_key, &digest, &sig.to_compact()));
+                }
+                _ => assert!(verify_signature(curve, &public_key, b"hello", &sig).unwrap()),
+            }
+            assert!(matches!(
+                store.get_key(&pin, key_id.clone()),
+                Err(CKMError::KeyNotExportable)
+            ));
+            assert!(store.delete_key(&pin, &key_id).unwrap());
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a PKCS#11 token, see scripts/softhsm.sh"]
+    fn test_import() {
+        let (store, pin) = token();
+        let private_key = "e284129cc0922579a535bbf4d1a3b25773090d28c909bc0fed73b5e0222cc372";
+        let stored = encode_key(&hex::decode(private_key).unwrap());
+        let key_id = store.write_key(&pin, stored).unwrap();
+        let digest: [u8; 32] = Sha256::digest(b"hello").into();
+        let sig = store
+            .sign_digest(&pin, &key_id, "m", &Curve::Secp256k1, &digest)
+            .unwrap();
+        // RFC6979 is not required on tokens, so only the key is compared
+        let expected = curve::k1::k1_public_key(&hex::decode(private_key).unwrap()).unwrap();
+        let recovered =
+            k1_recover_public_key(&digest, &sig.to_compact(), sig.v.unwrap(), true).unwrap();
+        assert_eq!(recovered, expected.to_vec());
+        assert!(store.delete_key(&pin, &key_id).unwrap());
+
+        // seeds do not import to a token
+        let seed = encode_seed(crate::keystore::fake::SEED).unwrap();
+        for key in [seed, private_key.to_string()] {
+            assert!(matches!(
+                store.write_key(&pin, key),
+                Err(CKMError::UnsupportedCurve)
+            ));
+        }
+    }
+}
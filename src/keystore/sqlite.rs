@@ -0,0 +1,279 @@
+use crate::*;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::local::{new_key_id, open_envelope, random_bytes, seal_envelope};
+
+/// schema migrations, `PRAGMA user_version` records how many have been applied
+const MIGRATIONS: &[&str] = &["CREATE TABLE keys (
+        key_id TEXT PRIMARY KEY NOT NULL,
+        label TEXT,
+        envelope TEXT NOT NULL,
+        created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+    );
+    CREATE INDEX keys_label ON keys (label);"];
+
+#[derive(Debug)]
+/// keystore in a single SQLite database file
+/// keys are encrypted with the same envelope as `LocalKeystore` and stored with their
+/// label in one database, writes run in transactions
+pub struct SqliteKeystore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteKeystore {
+    /// open or create the database at the path and apply pending migrations
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CKMError> {
+        let conn = Connection::open(path).map_err(_db_error)?;
+        Self::from_connection(conn)
+    }
+
+    /// open a database living in memory only
+    pub fn open_in_memory() -> Result<Self, CKMError> {
+        let conn = Connection::open_in_memory().map_err(_db_error)?;
+        Self::from_connection(conn)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self, CKMError> {
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(_db_error)?;
+        _migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// schema version of the database
+    pub fn schema_version(&self) -> Result<usize, CKMError> {
+        _user_version(&self._conn())
+    }
+
+    /// write a key with a label for later lookup
+    pub fn write_key_with_label(
+        &self,
+        password: &str,
+        key: String,
+        label: &str,
+    ) -> Result<String, CKMError> {
+        let mut key_ids = self.write_keys(password, vec![(key, Some(label.to_string()))])?;
+        Ok(key_ids.remove(0))
+    }
+
+    /// write keys with their optional labels in one transaction, either all of them are
+    /// stored or none is
+    pub fn write_keys(
+        &self,
+        password: &str,
+        keys: Vec<(String, Option<String>)>,
+    ) -> Result<Vec<String>, CKMError> {
+        let mut rows = Vec::with_capacity(keys.len());
+        for (key, label) in keys {
+            rows.push((
+                new_key_id()?,
+                label,
+                seal_envelope(password, key.as_bytes())?,
+            ));
+        }
+        let mut conn = self._conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(_db_error)?;
+        for (key_id, label, envelope) in rows.iter() {
+            tx.execute(
+                "INSERT INTO keys (key_id, label, envelope) VALUES (?1, ?2, ?3)",
+                params![key_id, label, envelope],
+            )
+            .map_err(_db_error)?;
+        }
+        tx.commit().map_err(_db_error)?;
+        Ok(rows.into_iter().map(|(key_id, _, _)| key_id).collect())
+    }
+
+    /// ids of the keys with the label
+    pub fn find_by_label(&self, label: &str) -> Result<Vec<String>, CKMError> {
+        let conn = self._conn();
+        let mut stmt = conn
+            .prepare("SELECT key_id FROM keys WHERE label = ?1 ORDER BY created_at, key_id")
+            .map_err(_db_error)?;
+        let rows = stmt
+            .query_map(params![label], |row| row.get(0))
+            .map_err(_db_error)?;
+        rows.collect::<Result<Vec<String>, _>>().map_err(_db_error)
+    }
+
+    /// label of the key
+    pub fn label(&self, key_id: &str) -> Result<Option<String>, CKMError> {
+        self._conn()
+            .query_row(
+                "SELECT label FROM keys WHERE key_id = ?1",
+                params![key_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(_db_error)?
+            .ok_or(CKMError::NotExist)
+    }
+
+    /// ids of all stored keys
+    pub fn key_ids(&self) -> Result<Vec<String>, CKMError> {
+        let conn = self._conn();
+        let mut stmt = conn
+            .prepare("SELECT key_id FROM keys ORDER BY created_at, key_id")
+            .map_err(_db_error)?;
+        let rows = stmt.query_map([], |row| row.get(0)).map_err(_db_error)?;
+        rows.collect::<Result<Vec<String>, _>>().map_err(_db_error)
+    }
+
+    /// remove a key, returns whether it was stored
+    pub fn remove_key(&self, key_id: &str) -> Result<bool, CKMError> {
+        let removed = self
+            ._conn()
+            .execute("DELETE FROM keys WHERE key_id = ?1", params![key_id])
+            .map_err(_db_error)?;
+        Ok(removed > 0)
+    }
+
+    fn _conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Keystore for SqliteKeystore {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        random_bytes(length)
+    }
+
+    fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
+        let envelope: String = self
+            ._conn()
+            .query_row(
+                "SELECT envelope FROM keys WHERE key_id = ?1",
+                params![key_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(_db_error)?
+            .ok_or(CKMError::NotExist)?;
+        open_envelope(&envelope, password)
+    }
+
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let mut key_ids = self.write_keys(password, vec![(key, None)])?;
+        Ok(key_ids.remove(0))
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        self.key_ids()
+    }
+}
+
+fn _migrate(conn: &mut Connection) -> Result<(), CKMError> {
+    let tx = conn
+        .transaction_with_behavior(TransactionBehavior::Exclusive)
+        .map_err(_db_error)?;
+    let version = _user_version(&tx)?;
+    if version > MIGRATIONS.len() {
+        return Err(CKMError::DatabaseError {
+            reason: format!(
+                "database schema version {} is newer than supported {}",
+                version,
+                MIGRATIONS.len()
+            ),
+            source: None,
+        });
+    }
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration).map_err(_db_error)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64)
+        .map_err(_db_error)?;
+    tx.commit().map_err(_db_error)
+}
+
+fn _user_version(conn: &Connection) -> Result<usize, CKMError> {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(_db_error)?;
+    Ok(version as usize)
+}
+
+fn _db_error(e: rusqlite::Error) -> CKMError {
+    CKMError::DatabaseError {
+        reason: "sqlite failed".to_string(),
+        source: Some(Arc::new(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_write() {
+        let store = SqliteKeystore::open_in_memory().unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+
+        let key_id = store.write_key("123", "456".to_string()).unwrap();
+        assert_eq!(store.get_key("123", key_id.clone()).unwrap(), b"456");
+        assert!(matches!(
+            store.get_key("124", key_id.clone()),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(matches!(
+            store.get_key("123", "missing".to_string()),
+            Err(CKMError::NotExist)
+        ));
+        assert!(store.remove_key(&key_id).unwrap());
+        assert!(store.key_ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_labels() {
+        let store = SqliteKeystore::open_in_memory().unwrap();
+        let a = store
+            .write_key_with_label("123", "a".to_string(), "alice")
+            .unwrap();
+        let key_ids = store
+            .write_keys(
+                "123",
+                vec![
+                    ("b".to_string(), Some("bob".to_string())),
+                    ("c".to_string(), Some("alice".to_string())),
+                    ("d".to_string(), None),
+                ],
+            )
+            .unwrap();
+        let mut alice = store.find_by_label("alice").unwrap();
+        alice.sort();
+        let mut expected = vec![a, key_ids[1].clone()];
+        expected.sort();
+        assert_eq!(alice, expected);
+        assert_eq!(store.label(&key_ids[0]).unwrap(), Some("bob".to_string()));
+        assert_eq!(store.label(&key_ids[2]).unwrap(), None);
+        assert_eq!(store.key_ids().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = std::env::temp_dir().join(format!("ckm-{}.sqlite", new_key_id().unwrap()));
+        let key_id = SqliteKeystore::open(&path)
+            .unwrap()
+            .write_key_with_label("123", "456".to_string(), "label")
+            .unwrap();
+        let store = SqliteKeystore::open(&path).unwrap();
+        assert_eq!(store.get_key("123", key_id).unwrap(), b"456");
+
+        // a database from a newer release is refused
+        store
+            ._conn()
+            .pragma_update(None, "user_version", 99)
+            .unwrap();
+        drop(store);
+        assert!(matches!(
+            SqliteKeystore::open(&path),
+            Err(CKMError::DatabaseError { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,476 @@
+use crate::*;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use ring::constant_time::verify_slices_are_equal;
+use scrypt::{scrypt, ScryptParams};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use super::local::{new_key_id, random_bytes, random_fill};
+
+const VAULT_VERSION: u32 = 1;
+const INDEX_AAD: &[u8] = b"crypto_key_master vault index";
+
+#[derive(Debug)]
+/// keystore where all keys live in one file unlocked by a master password
+/// the KDF runs once per unlock and every entry is encrypted with its own data key, the index
+/// of key ids is encrypted too
+pub struct VaultKeystore {
+    path: PathBuf,
+    state: Mutex<VaultState>,
+}
+
+#[derive(Debug)]
+struct VaultState {
+    file: VaultFile,
+    unlocked: Option<Unlocked>,
+}
+
+#[derive(Debug)]
+struct Unlocked {
+    master_key: Zeroizing<Vec<u8>>,
+    password_digest: Zeroizing<Vec<u8>>,
+    index: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    kdf: String,
+    kdfparams: KdfParams,
+    index: Sealed,
+    /// entries by opaque slot id, the key ids are only in the encrypted index
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    #[serde(with = "hex::serde")]
+    salt: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sealed {
+    #[serde(with = "hex::serde")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// data key encrypted under the master key
+    data_key: Sealed,
+    /// key encrypted under the data key
+    key: Sealed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    slot: String,
+    created_at: u64,
+}
+
+impl VaultKeystore {
+    /// create a new vault file protected by the master password, the vault is unlocked
+    pub fn create<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, CKMError> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            return Err(CKMError::FileGenerationError);
+        }
+        let mut salt = vec![0u8; 16];
+        random_fill(&mut salt)?;
+        let kdfparams = KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+            salt,
+        };
+        let master_key = _master_key(password, &kdfparams)?;
+        let index = BTreeMap::new();
+        let file = VaultFile {
+            version: VAULT_VERSION,
+            kdf: "scrypt".to_string(),
+            kdfparams,
+            index: _seal_index(&master_key, &index)?,
+            entries: BTreeMap::new(),
+        };
+        _write_vault(&path, &file)?;
+        let unlocked = Unlocked {
+            master_key,
+            password_digest: _password_digest(password),
+            index,
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(VaultState {
+                file,
+                unlocked: Some(unlocked),
+            }),
+        })
+    }
+
+    /// open an existing vault file, the vault starts locked
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CKMError> {
+        let path = path.as_ref().to_path_buf();
+        let file = _read_vault(&path)?;
+        Ok(Self {
+            path,
+            state: Mutex::new(VaultState {
+                file,
+                unlocked: None,
+            }),
+        })
+    }
+
+    /// derive the master key from the password and decrypt the index
+    pub fn unlock(&self, password: &str) -> Result<(), CKMError> {
+        let mut state = self._state();
+        state.file = _read_vault(&self.path)?;
+        let master_key = _master_key(password, &state.file.kdfparams)?;
+        let index = _open(&master_key, &state.file.index, INDEX_AAD)
+            .map_err(|_e| CKMError::PasswordInvalid)?;
+        let index = serde_json::from_slice(&index)
+            .map_err(|e| CKMError::corrupt("invalid vault index", e))?;
+        state.unlocked = Some(Unlocked {
+            master_key,
+            password_digest: _password_digest(password),
+            index,
+        });
+        Ok(())
+    }
+
+    /// forget the master key and the index
+    pub fn lock(&self) {
+        self._state().unlocked = None;
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self._state().unlocked.is_some()
+    }
+
+    /// ids of the stored keys, the vault must be unlocked
+    pub fn key_ids(&self) -> Result<Vec<String>, CKMError> {
+        let state = self._state();
+        let unlocked = state.unlocked.as_ref().ok_or(CKMError::VaultLocked)?;
+        Ok(unlocked.index.keys().cloned().collect())
+    }
+
+    /// remove a key, returns whether it was stored
+    pub fn remove_key(&self, password: &str, key_id: &str) -> Result<bool, CKMError> {
+        let mut state = self._state();
+        _check_password(&state, password)?;
+        let VaultState { file, unlocked } = &mut *state;
+        let unlocked = unlocked.as_mut().unwrap();
+        let mut index = unlocked.index.clone();
+        let removed = match index.remove(key_id) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let mut new_file = file.clone();
+        new_file.entries.remove(&removed.slot);
+        new_file.index = _seal_index(&unlocked.master_key, &index)?;
+        _write_vault(&self.path, &new_file)?;
+        *file = new_file;
+        unlocked.index = index;
+        Ok(true)
+    }
+
+    fn _state(&self) -> MutexGuard<'_, VaultState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Keystore for VaultKeystore {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        random_bytes(length)
+    }
+
+    fn get_key(&self, password: &str, key_id: String) -> Result<Vec<u8>, CKMError> {
+        let state = self._state();
+        _check_password(&state, password)?;
+        let unlocked = state.unlocked.as_ref().unwrap();
+        let slot = &unlocked.index.get(&key_id).ok_or(CKMError::NotExist)?.slot;
+        let entry = state
+            .file
+            .entries
+            .get(slot)
+            .ok_or_else(|| CKMError::missing("vault entry"))?;
+        let aad = _entry_aad(&key_id);
+        let data_key = Zeroizing::new(_open(&unlocked.master_key, &entry.data_key, &aad)?);
+        _open(&data_key, &entry.key, &aad)
+    }
+
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let mut state = self._state();
+        _check_password(&state, password)?;
+        let VaultState { file, unlocked } = &mut *state;
+        let unlocked = unlocked.as_mut().unwrap();
+
+        let mut key_id = new_key_id()?;
+        while unlocked.index.contains_key(&key_id) {
+            key_id = new_key_id()?;
+        }
+        let mut slot = new_key_id()?;
+        while file.entries.contains_key(&slot) {
+            slot = new_key_id()?;
+        }
+        let aad = _entry_aad(&key_id);
+        let mut data_key = Zeroizing::new(vec![0u8; 32]);
+        random_fill(&mut data_key)?;
+        let entry = Entry {
+            data_key: _seal(&unlocked.master_key, &data_key, &aad)?,
+            key: _seal(&data_key, key.as_bytes(), &aad)?,
+        };
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut index = unlocked.index.clone();
+        index.insert(
+            key_id.clone(),
+            IndexEntry {
+                slot: slot.clone(),
+                created_at,
+            },
+        );
+
+        let mut new_file = file.clone();
+        new_file.entries.insert(slot, entry);
+        new_file.index = _seal_index(&unlocked.master_key, &index)?;
+        _write_vault(&self.path, &new_file)?;
+        *file = new_file;
+        unlocked.index = index;
+        Ok(key_id)
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        self.key_ids()
+    }
+}
+
+fn _check_password(state: &VaultState, password: &str) -> Result<(), CKMError> {
+    let unlocked = state.unlocked.as_ref().ok_or(CKMError::VaultLocked)?;
+    verify_slices_are_equal(&unlocked.password_digest, &_password_digest(password))
+        .map_err(|_e| CKMError::PasswordInvalid)
+}
+
+fn _password_digest(password: &str) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(Sha256::digest(password.as_bytes()).to_vec())
+}
+
+fn _master_key(password: &str, params: &KdfParams) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let scrypt_params = ScryptParams::new(params.log_n, params.r, params.p)
+        .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+    let mut master_key = Zeroizing::new(vec![0u8; 32]);
+    scrypt(
+        password.as_bytes(),
+        &params.salt,
+        &scrypt_params,
+        &mut master_key,
+    )
+    .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+    Ok(master_key)
+}
+
+fn _entry_aad(key_id: &str) -> Vec<u8> {
+    let mut aad = b"crypto_key_master vault entry ".to_vec();
+    aad.extend_from_slice(key_id.as_bytes());
+    aad
+}
+
+fn _seal_index(
+    master_key: &[u8],
+    index: &BTreeMap<String, IndexEntry>,
+) -> Result<Sealed, CKMError> {
+    let plaintext =
+        Zeroizing::new(serde_json::to_vec(index).map_err(|_e| CKMError::SerializeError)?);
+    _seal(master_key, &plaintext, INDEX_AAD)
+}
+
+fn _seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Sealed, CKMError> {
+    let mut nonce = vec![0u8; 12];
+    random_fill(&mut nonce)?;
+    let nonce_bytes: [u8; 12] = nonce.as_slice().try_into().unwrap();
+    let ciphertext = Aes256Gcm::new_from_slice(key)
+        .map_err(|_e| CKMError::SerializeError)?
+        .encrypt(
+            &nonce_bytes.into(),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|e| CKMError::crypto("aes-256-gcm sealing failed", e))?;
+    Ok(Sealed { nonce, ciphertext })
+}
+
+fn _open(key: &[u8], sealed: &Sealed, aad: &[u8]) -> Result<Vec<u8>, CKMError> {
+    let nonce: [u8; 12] = sealed
+        .nonce
+        .as_slice()
+        .try_into()
+        .map_err(|e| CKMError::corrupt(&format!("nonce of {} bytes", sealed.nonce.len()), e))?;
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|e| CKMError::crypto("aes-256-gcm key failed", e))?
+        .decrypt(
+            &nonce.into(),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad,
+            },
+        )
+        .map_err(|e| CKMError::corrupt("entry does not authenticate", e))
+}
+
+fn _read_vault(path: &Path) -> Result<VaultFile, CKMError> {
+    let content = fs::read(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => CKMError::FileNotExit,
+        _ => CKMError::io(path, e),
+    })?;
+    let file: VaultFile =
+        serde_json::from_slice(&content).map_err(|e| CKMError::corrupt("invalid vault json", e))?;
+    if file.version != VAULT_VERSION {
+        return Err(CKMError::CorruptKeystore {
+            reason: format!("unsupported vault version {}", file.version),
+            source: None,
+        });
+    }
+    if file.kdf != "scrypt" {
+        return Err(CKMError::KdfParams {
+            reason: format!("unsupported kdf {}", file.kdf),
+            source: None,
+        });
+    }
+    Ok(file)
+}
+
+/// replace the vault file atomically, the new content is synced to a temporary file in the
+/// same directory and renamed over the old one
+fn _write_vault(path: &Path, file: &VaultFile) -> Result<(), CKMError> {
+    let content = serde_json::to_vec_pretty(file).map_err(|_e| CKMError::SerializeError)?;
+    let mut tmp_name = path.file_name().ok_or(CKMError::FileError)?.to_os_string();
+    tmp_name.push(format!(".{}.tmp", new_key_id()?));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = (|| {
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .map_err(|e| CKMError::io(&tmp_path, e))?;
+        tmp.write_all(&content)
+            .and_then(|_| tmp.sync_all())
+            .map_err(|e| CKMError::io(&tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| CKMError::io(path, e))?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            // persist the rename, not every platform can open a directory
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vault_path() -> PathBuf {
+        std::env::temp_dir().join(format!("ckm-vault-{}.json", new_key_id().unwrap()))
+    }
+
+    #[test]
+    fn test_get_write() {
+        let path = vault_path();
+        let vault = VaultKeystore::create(&path, "123").unwrap();
+        let a = vault.write_key("123", "456".to_string()).unwrap();
+        let b = vault.write_key("123", "789".to_string()).unwrap();
+        assert_eq!(vault.get_key("123", a.clone()).unwrap(), b"456");
+        assert!(matches!(
+            vault.get_key("124", a.clone()),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(matches!(
+            vault.write_key("124", "0".to_string()),
+            Err(CKMError::PasswordInvalid)
+        ));
+
+        // key ids and keys do not appear in the file
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains(&a));
+        assert!(!content.contains(&hex::encode("456")));
+
+        let vault = VaultKeystore::open(&path).unwrap();
+        assert!(!vault.is_unlocked());
+        assert!(matches!(
+            vault.get_key("123", a.clone()),
+            Err(CKMError::VaultLocked)
+        ));
+        assert!(matches!(
+            vault.unlock("124"),
+            Err(CKMError::PasswordInvalid)
+        ));
+        vault.unlock("123").unwrap();
+        let mut key_ids = vec![a.clone(), b.clone()];
+        key_ids.sort();
+        assert_eq!(vault.key_ids().unwrap(), key_ids);
+        assert_eq!(vault.get_key("123", b.clone()).unwrap(), b"789");
+
+        assert!(vault.remove_key("123", &a).unwrap());
+        assert!(!vault.remove_key("123", &a).unwrap());
+        vault.lock();
+        assert!(matches!(vault.key_ids(), Err(CKMError::VaultLocked)));
+
+        let vault = VaultKeystore::open(&path).unwrap();
+        vault.unlock("123").unwrap();
+        assert_eq!(vault.key_ids().unwrap(), vec![b]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tampered_entry() {
+        let path = vault_path();
+        let vault = VaultKeystore::create(&path, "123").unwrap();
+        let a = vault.write_key("123", "456".to_string()).unwrap();
+        let b = vault.write_key("123", "789".to_string()).unwrap();
+
+        // swapping two entries is detected by the key id bound into each entry
+        let mut file = _read_vault(&path).unwrap();
+        let slots: Vec<String> = file.entries.keys().cloned().collect();
+        let first = file.entries[&slots[0]].clone();
+        let second = file.entries[&slots[1]].clone();
+        file.entries.insert(slots[0].clone(), second);
+        file.entries.insert(slots[1].clone(), first);
+        _write_vault(&path, &file).unwrap();
+
+        let vault = VaultKeystore::open(&path).unwrap();
+        vault.unlock("123").unwrap();
+        for key_id in [a, b] {
+            assert!(matches!(
+                vault.get_key("123", key_id),
+                Err(CKMError::CorruptKeystore {
+                    source: Some(_),
+                    ..
+                })
+            ));
+        }
+        assert!(VaultKeystore::create(&path, "123").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
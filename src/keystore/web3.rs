@@ -0,0 +1,204 @@
+//! Web3 Secret Storage V3 files of Ethereum wallets like geth, decrypted for import
+use crate::{CKMError, Zeroizing};
+use aes::cipher::{NewCipher, StreamCipher};
+use aes::Aes128Ctr;
+use hex::decode;
+use scrypt::{scrypt, ScryptParams};
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+
+/// largest scrypt `n` accepted, 2^20 takes 1 GiB with `r` = 8
+const MAX_LOG_N: u32 = 20;
+
+/// whether the JSON is a V3 file, geth writes `crypto` and older clients `Crypto`
+pub(crate) fn is_web3(value: &Value) -> bool {
+    value["version"] == 3 && _crypto(value).is_object()
+}
+
+/// the private key of the V3 file, the MAC is checked before decrypting
+pub(crate) fn open_web3(value: &Value, password: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let crypto = _crypto(value);
+    if crypto["cipher"] != "aes-128-ctr" {
+        return Err(CKMError::CorruptKeystore {
+            reason: format!("unsupported cipher {}", crypto["cipher"]),
+            source: None,
+        });
+    }
+    let ciphertext = _hex_field(&crypto["ciphertext"], "crypto.ciphertext")?;
+    let iv = _hex_field(&crypto["cipherparams"]["iv"], "crypto.cipherparams.iv")?;
+    let mac = _hex_field(&crypto["mac"], "crypto.mac")?;
+    let derived = _derive_key(&crypto["kdf"], &crypto["kdfparams"], password)?;
+
+    let mut hasher = Keccak256::default();
+    hasher.input(&derived[16..32]);
+    hasher.input(&ciphertext);
+    if hasher.result().as_slice() != mac.as_slice() {
+        return Err(CKMError::PasswordInvalid);
+    }
+    let mut cipher = Aes128Ctr::new_from_slices(&derived[..16], &iv).map_err(|_e| {
+        CKMError::CorruptKeystore {
+            reason: format!("cipher iv of {} bytes", iv.len()),
+            source: None,
+        }
+    })?;
+    let mut key = Zeroizing::new(ciphertext);
+    cipher.apply_keystream(&mut key);
+    Ok(key)
+}
+
+fn _crypto(value: &Value) -> &Value {
+    match &value["crypto"] {
+        Value::Null => &value["Crypto"],
+        crypto => crypto,
+    }
+}
+
+/// key of the scrypt or pbkdf2 parameters, its first half decrypts and the second one is
+/// hashed into the MAC
+fn _derive_key(
+    kdf: &Value,
+    params: &Value,
+    password: &str,
+) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+    let salt = _hex_field(&params["salt"], "crypto.kdfparams.salt")?;
+    let dklen = _number(params, "dklen")?;
+    if !(32..=64).contains(&dklen) {
+        return Err(CKMError::KdfParams {
+            reason: format!("dklen {}", dklen),
+            source: None,
+        });
+    }
+    let mut derived = Zeroizing::new(vec![0u8; dklen as usize]);
+    match kdf.as_str() {
+        Some("scrypt") => {
+            let n = _number(params, "n")?;
+            let (r, p) = (_number(params, "r")?, _number(params, "p")?);
+            if !n.is_power_of_two() || n < 2 || n.trailing_zeros() > MAX_LOG_N {
+                return Err(CKMError::KdfParams {
+                    reason: format!("scrypt n {}", n),
+                    source: None,
+                });
+            }
+            let params = ScryptParams::new(n.trailing_zeros() as u8, r, p)
+                .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+            scrypt(password.as_bytes(), &salt, &params, &mut derived)
+                .map_err(|e| CKMError::kdf_params("scrypt", e))?;
+        }
+        Some("pbkdf2") => {
+            if params["prf"] != "hmac-sha256" {
+                return Err(CKMError::KdfParams {
+                    reason: format!("pbkdf2 prf {}", params["prf"]),
+                    source: None,
+                });
+            }
+            let c = NonZeroU32::new(_number(params, "c")?).ok_or_else(|| CKMError::KdfParams {
+                reason: "pbkdf2 c 0".to_string(),
+                source: None,
+            })?;
+            ring::pbkdf2::derive(
+                ring::pbkdf2::PBKDF2_HMAC_SHA256,
+                c,
+                &salt,
+                password.as_bytes(),
+                &mut derived,
+            );
+        }
+        _ => {
+            return Err(CKMError::KdfParams {
+                reason: format!("unsupported kdf {}", kdf),
+                source: None,
+            })
+        }
+    }
+    Ok(derived)
+}
+
+fn _number(params: &Value, name: &str) -> Result<u32, CKMError> {
+    params[name]
+        .as_u64()
+        .and_then(|number| u32::try_from(number).ok())
+        .ok_or_else(|| CKMError::missing(&format!("crypto.kdfparams.{}", name)))
+}
+
+fn _hex_field(value: &Value, name: &str) -> Result<Vec<u8>, CKMError> {
+    let field = value.as_str().ok_or_else(|| CKMError::missing(name))?;
+    decode(field).map_err(|e| CKMError::corrupt(&format!("{} is not hex", name), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PRIVATE_KEY: &str = "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d";
+
+    /// the pbkdf2 test vector of the Web3 Secret Storage definition and a scrypt one of the same
+    /// key, password "testpassword"
+    fn vectors() -> Vec<Value> {
+        vec![
+            json!({
+                "version": 3,
+                "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+                "crypto": {
+                    "cipher": "aes-128-ctr",
+                    "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+                    "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+                    "kdf": "pbkdf2",
+                    "kdfparams": {
+                        "c": 262144,
+                        "dklen": 32,
+                        "prf": "hmac-sha256",
+                        "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+                    },
+                    "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+                }
+            }),
+            // geth light scrypt parameters, made with OpenSSL and Python hashlib
+            json!({
+                "version": 3,
+                "Crypto": {
+                    "cipher": "aes-128-ctr",
+                    "cipherparams": { "iv": "69c3fec79525ebb81591bf22c7f49358" },
+                    "ciphertext": "51bd56ca03bc610d29f0e4797d404cf54911ba35631a2c68e4a65a1d9fd0fb10",
+                    "kdf": "scrypt",
+                    "kdfparams": {
+                        "dklen": 32,
+                        "n": 4096,
+                        "p": 6,
+                        "r": 8,
+                        "salt": "5dd01f746edb467e998c77200aa45e4d93d81e85701fca65470a19e047649023"
+                    },
+                    "mac": "87e919fb0a5dce040b85f9823ab4b9af77978694e72d5841c0109678cb4ec721"
+                }
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_open_web3() {
+        for vector in vectors() {
+            assert!(is_web3(&vector));
+            let key = open_web3(&vector, "testpassword").unwrap();
+            assert_eq!(hex::encode(&*key), PRIVATE_KEY);
+            assert!(matches!(
+                open_web3(&vector, "wrong"),
+                Err(CKMError::PasswordInvalid)
+            ));
+        }
+        assert!(!is_web3(&json!({ "version": 3 })));
+
+        let mut vector = vectors().remove(1);
+        vector["Crypto"]["kdfparams"]["n"] = json!(1u64 << 30);
+        assert!(matches!(
+            open_web3(&vector, "testpassword"),
+            Err(CKMError::KdfParams { .. })
+        ));
+        vector["Crypto"]["kdf"] = json!("argon2");
+        assert!(matches!(
+            open_web3(&vector, "testpassword"),
+            Err(CKMError::KdfParams { .. })
+        ));
+    }
+}
@@ -1,35 +1,120 @@
 //! # Crypto Key Master
 //!
 //! `crypto_key_master` is the rust library to help manage keys in crypto world. it can help to generate the entropy and store keys in
-//! and sign data. currently it support three signing algorithem, Secp256k1, Secp256R1 and Ed25519
-//! 
+//! and sign data. currently it support three signing algorithem, Secp256k1, Secp256R1 and Ed25519,
+//! and BIP340 schnorr signatures on Secp256k1
+//!
 //! # Examples
-//! ```
-//!   
-//!   let mut key_master = KeyMaster {};
-//!   let entropy = key_master.generate_entropy(32).unwrap();
+//! ```no_run
+//!   use crypto_key_master::{Curve, KeyMaster, LocalKeystore, SignRequest};
+//!
+//!   let mut key_master = KeyMaster::new(LocalKeystore::new());
+//!   let entropy = key_master.generate_entropy(256).unwrap();
 //!   let key_id = key_master.write_seed("123", "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4".to_string()).unwrap();
-//!   let request = SignRequest { path: "m/44'/0'/0'/0/0", unsigend_data: "hello".as_bytes().to_vec(), key_id: "123456", curve: Curve::Secp256k1};
+//!   let request = SignRequest { path: "m/44'/0'/0'/0/0", unsigend_data: "hello".as_bytes().to_vec(), key_id: &key_id, curve: Curve::Secp256k1};
 //!   let sig = key_master.sign(request, "123").unwrap();
-//! 
+//!
 //! ```
 
+#[cfg(feature = "async")]
+mod asynchronous;
+mod batch;
+mod btc;
+#[cfg(any(
+    feature = "remote",
+    all(unix, any(feature = "daemon", feature = "ssh"))
+))]
+mod connection;
 mod curve;
+#[cfg(all(unix, feature = "daemon"))]
+mod daemon;
 mod error;
+#[cfg(feature = "jose")]
+mod jose;
 mod keystore;
+#[cfg(feature = "remote")]
+mod remote;
+mod session;
+#[cfg(all(unix, any(feature = "daemon", feature = "ssh")))]
+mod socket;
+#[cfg(feature = "ssh")]
+mod ssh;
+#[cfg(all(test, any(feature = "hashicorp", feature = "aws-kms")))]
+mod test_util;
 
-use curve::{k1::K1, CurveSign};
-pub use curve::SigningSignature;
-pub use error::CKMError;
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncKeyMaster, AsyncKeystore, BlockingKeystore};
+use bitcoin::base64::{prelude::BASE64_STANDARD, Engine};
+pub use bitcoin::Network;
+pub use btc::message::{verify_message as verify_bitcoin_message, AddressType, MessageFormat};
+pub use curve::ecdh::hkdf_sha256;
+pub use curve::ecies::{ecies_encrypt, EciesFormat};
+pub use curve::schnorr::{schnorr_verify, SchnorrOptions, TapTweak};
+use curve::{ed25519::Ed25519, k1::K1, r1::R1, schnorr::Schnorr, CurveSign};
+pub use curve::{verify_digest, verify_signature, SigningSignature};
+#[cfg(all(unix, feature = "daemon"))]
+pub use daemon::{DaemonClient, DaemonServer};
+pub use error::{CKMError, ErrorSource};
+#[cfg(feature = "jose")]
+pub use jose::{jws_payload, verify_jws, verify_jwt, Jwk, Jwks, JwtClaims};
+#[cfg(any(feature = "ssh", feature = "jose"))]
+use keystore::SeedOnce;
 pub use keystore::*;
+use keystore::{read_seed, KeySource};
+#[cfg(feature = "remote")]
+pub use remote::{RemoteKeystore, RemoteSignerServer, RemoteTlsConfig};
+use serde::{Deserialize, Serialize};
+pub use session::UnlockSession;
+use session::{SessionStore, Sessions};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "ssh")]
+pub use ssh::verify_sshsig;
+#[cfg(all(unix, feature = "ssh"))]
+pub use ssh::SshAgent;
+use std::convert::TryInto;
+use std::time::Duration;
+pub use zeroize::Zeroizing;
 
 /// Curve defination for supported signing Curve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Curve {
+    #[serde(rename = "secp256k1")]
     Secp256k1,
+    /// BIP340 schnorr signature on Secp256k1
+    #[serde(rename = "secp256k1-schnorr")]
+    Secp256k1Schnorr,
+    #[serde(rename = "secp256r1")]
     Secp256R1,
+    #[serde(rename = "ed25519")]
     Ed25519,
 }
 
+impl Curve {
+    /// name of the curve, `secp256k1`, `secp256k1-schnorr`, `secp256r1` or `ed25519`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Curve::Secp256k1 => "secp256k1",
+            Curve::Secp256k1Schnorr => "secp256k1-schnorr",
+            Curve::Secp256R1 => "secp256r1",
+            Curve::Ed25519 => "ed25519",
+        }
+    }
+}
+
+impl std::str::FromStr for Curve {
+    type Err = CKMError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "secp256k1" => Ok(Curve::Secp256k1),
+            "secp256k1-schnorr" | "schnorr" => Ok(Curve::Secp256k1Schnorr),
+            "secp256r1" | "p256" | "p-256" => Ok(Curve::Secp256R1),
+            "ed25519" => Ok(Curve::Ed25519),
+            _ => Err(CKMError::UnsupportedCurve),
+        }
+    }
+}
+
 /// SignRequest defination for Sign data
 pub struct SignRequest<'a> {
     pub path: &'a str,
@@ -44,22 +129,393 @@ pub struct KeyMaster<Store = LocalKeystore> {
 
 struct KeyMasterInner<Store> {
     store: Store,
+    sessions: Sessions,
 }
 
 impl<Store: Keystore> KeyMaster<Store> {
     pub fn new(store: Store) -> Self {
         Self {
-            inner: KeyMasterInner { store },
+            inner: KeyMasterInner {
+                store,
+                sessions: Sessions::default(),
+            },
         }
     }
 
+    /// keep up to `capacity` intermediate BIP32 nodes per unlocked key, so keys under the same
+    /// parent like `m/84'/0'/0'/0/*` are derived with one step. The nodes live as long as the
+    /// unlock session
+    pub fn with_derivation_cache(mut self, capacity: usize) -> Self {
+        self.inner.sessions.cache_capacity = capacity;
+        self
+    }
+
+    /// decrypt the key once and keep it in locked memory for `ttl`, until then it is used
+    /// without the password and any password passed for it is ignored. A `ttl` is at most a
+    /// year, longer ones like `Duration::MAX` are cut to it
+    pub fn unlock(
+        &self,
+        key_id: &str,
+        password: &str,
+        ttl: Duration,
+    ) -> Result<UnlockSession, CKMError> {
+        let key = Zeroizing::new(self.inner.store.get_key(password, key_id.to_string())?);
+        self.inner.sessions.unlock(key_id, &key, ttl)
+    }
+
+    /// end the session of a key and wipe it, returns whether it was unlocked
+    pub fn lock(&self, key_id: &str) -> bool {
+        self.inner.sessions.lock(key_id)
+    }
+
+    /// end every session
+    pub fn lock_all(&self) {
+        self.inner.sessions.lock_all()
+    }
+
+    /// whether the key has a live session
+    pub fn is_unlocked(&self, key_id: &str) -> bool {
+        self.inner.sessions.is_unlocked(key_id)
+    }
+
     /// access private keys to sign data
     pub fn sign(
         &self,
         sign_request: SignRequest,
         password: &str,
     ) -> Result<SigningSignature, CKMError> {
-        dispatch(sign_request, password, &self.inner.store)
+        dispatch(sign_request, password, &self.inner.store, &self._store())
+    }
+
+    /// sign many requests, each key is decrypted once and the shared BIP32 parents are derived
+    /// once. The results are in the order of the requests, a failed request does not stop the
+    /// others. With the `rayon` feature the requests are signed in parallel
+    pub fn sign_batch(
+        &self,
+        sign_requests: Vec<SignRequest>,
+        password: &str,
+    ) -> Vec<Result<SigningSignature, CKMError>> {
+        batch::sign_batch(sign_requests, password, &self.inner.store, &self._store())
+    }
+
+    /// sign data with a BIP340 schnorr signature, the options control the
+    /// auxiliary randomness and the BIP341 taproot tweak
+    pub fn sign_schnorr(
+        &self,
+        sign_request: SignRequest,
+        password: &str,
+        options: SchnorrOptions,
+    ) -> Result<SigningSignature, CKMError> {
+        let schnorr = Schnorr { options };
+        schnorr.sign(&sign_request, password, &self._store())
+    }
+
+    /// get the 32 bytes x-only public key for BIP340, tweaked as a taproot output key if the tweak is set
+    pub fn get_x_only_public_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        password: &str,
+        tweak: Option<TapTweak>,
+    ) -> Result<Vec<u8>, CKMError> {
+        let request = SignRequest {
+            path,
+            unsigend_data: Vec::new(),
+            key_id,
+            curve: Curve::Secp256k1Schnorr,
+        };
+        let schnorr = Schnorr {
+            options: SchnorrOptions {
+                aux_rand: None,
+                tweak,
+            },
+        };
+        let key = schnorr.derive_key(&request, password, &self._store())?;
+        Ok(curve::schnorr::x_only_public_key(&key)?.to_vec())
+    }
+
+    /// sign the inputs of a binary PSBT (BIP174 version 0 or BIP370 version 2), the inputs are
+    /// matched to the key by the master fingerprint and the BIP32 derivation path, inputs of
+    /// other keys are left unsigned
+    pub fn sign_psbt(
+        &self,
+        psbt: &[u8],
+        key_id: &str,
+        password: &str,
+    ) -> Result<Vec<u8>, CKMError> {
+        let seed = read_seed(&self._store(), password, key_id)?;
+        btc::psbt::sign_psbt(psbt, &seed)
+    }
+
+    /// sign the inputs of a base64 encoded PSBT
+    pub fn sign_psbt_base64(
+        &self,
+        psbt: &str,
+        key_id: &str,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        let psbt = BASE64_STANDARD
+            .decode(psbt.trim())
+            .map_err(|_e| CKMError::PsbtError("invalid base64".to_string()))?;
+        let signed = self.sign_psbt(&psbt, key_id, password)?;
+        Ok(BASE64_STANDARD.encode(signed))
+    }
+
+    /// sign a bitcoin message (BIP137 or BIP322 simple) for the address of the secp256k1 key,
+    /// returns the base64 signature
+    pub fn sign_bitcoin_message(
+        &self,
+        sign_request: SignRequest,
+        address_type: AddressType,
+        format: MessageFormat,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        if !matches!(sign_request.curve, Curve::Secp256k1) {
+            return Err(CKMError::MessageError(
+                "bitcoin messages are signed with Secp256k1".to_string(),
+            ));
+        }
+        let key = K1 {}.derive_key(&sign_request, password, &self._store())?;
+        btc::message::sign_message(&key, &sign_request.unsigend_data, address_type, format)
+    }
+
+    /// get the bitcoin address of the secp256k1 key at the path
+    pub fn get_bitcoin_address(
+        &self,
+        key_id: &str,
+        path: &str,
+        address_type: AddressType,
+        network: Network,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        let request = SignRequest {
+            path,
+            unsigend_data: Vec::new(),
+            key_id,
+            curve: Curve::Secp256k1,
+        };
+        let key = K1 {}.derive_key(&request, password, &self._store())?;
+        btc::message::address(&key, address_type, network)
+    }
+
+    /// ECDH shared secret between the key of the path and the peer public key, the key never
+    /// leaves the key master. Secp256k1 and Secp256R1 take a SEC1 peer key and return the x
+    /// coordinate of the shared point, Ed25519 runs X25519 with the key converted to Curve25519.
+    /// Pass the secret through `hkdf_sha256` to get symmetric keys
+    pub fn ecdh(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        peer_public_key: &[u8],
+        password: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        let seed = read_seed(&self._store(), password, key_id)?;
+        let key = curve::ecdh::derive_from_seed(&seed, path, &curve)?;
+        curve::ecdh::shared_secret(&key, &curve, peer_public_key)
+    }
+
+    /// decrypt an ECIES ciphertext (`EciesFormat::V1` or `EciesFormat::Eciespy`) sent to the
+    /// secp256k1 public key of the path
+    pub fn ecies_decrypt(
+        &self,
+        key_id: &str,
+        path: &str,
+        ciphertext: &[u8],
+        password: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        let seed = read_seed(&self._store(), password, key_id)?;
+        let key = curve::ecdh::derive_from_seed(&seed, path, &Curve::Secp256k1)?;
+        curve::ecies::ecies_decrypt(&key, ciphertext)
+    }
+
+    /// public key to hand to peers for ECDH, compressed SEC1 for Secp256k1 and Secp256R1,
+    /// X25519 for Ed25519
+    pub fn get_ecdh_public_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<Vec<u8>, CKMError> {
+        let seed = read_seed(&self._store(), password, key_id)?;
+        let key = curve::ecdh::derive_from_seed(&seed, path, &curve)?;
+        curve::ecdh::public_key(&key, &curve)
+    }
+
+    /// sign a prepared digest, the SHA-256 of the data for ECDSA, the 32 bytes message for
+    /// schnorr and the message itself for Ed25519. Stores that sign internally get the digest
+    /// as it is
+    pub fn sign_digest(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        digest: &[u8],
+        password: &str,
+    ) -> Result<SigningSignature, CKMError> {
+        let store = &self.inner.store;
+        if store.signs_internally() {
+            return store.sign_digest(password, key_id, path, &curve, digest);
+        }
+        match curve {
+            Curve::Secp256k1 => {
+                let key = self._store().derive_k1(password, key_id, path)?;
+                let digest: [u8; 32] = digest.try_into().map_err(|_e| CKMError::SigningError)?;
+                curve::k1::k1_sign_recoverable(&key, &digest)
+            }
+            Curve::Secp256k1Schnorr => {
+                let key = self._store().derive_k1(password, key_id, path)?;
+                let sig = curve::schnorr::schnorr_sign(&key, digest, None)?;
+                SigningSignature::from_compact(&sig)
+            }
+            Curve::Secp256R1 => {
+                let seed = read_seed(&self._store(), password, key_id)?;
+                let key = Zeroizing::new(curve::r1::derive_from_seed(&seed, path)?);
+                let digest: [u8; 32] = digest.try_into().map_err(|_e| CKMError::SigningError)?;
+                curve::r1::r1_sign_digest(&key, &digest)
+            }
+            Curve::Ed25519 => {
+                let seed = read_seed(&self._store(), password, key_id)?;
+                let key = Zeroizing::new(curve::ed25519::derive_from_seed(&seed, path)?);
+                let sig = curve::ed25519::ed25519_sign(&key, digest)?;
+                SigningSignature::from_compact(&sig)
+            }
+        }
+    }
+
+    /// public key of the path, compressed SEC1 for Secp256k1 and Secp256R1, x-only for
+    /// schnorr and 32 bytes for Ed25519
+    pub fn get_public_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<Vec<u8>, CKMError> {
+        self._public_key(&self._store(), key_id, path, curve, password)
+    }
+
+    /// BIP32 extended public key of the secp256k1 path, like `xpub6BosfCnifzxc...`
+    pub fn get_xpub(&self, key_id: &str, path: &str, password: &str) -> Result<String, CKMError> {
+        let seed = read_seed(&self._store(), password, key_id)?;
+        let xprv = curve::k1::derive_xprv(&seed, path)?;
+        Ok(xprv.public_key().to_string(bip32::Prefix::XPUB))
+    }
+
+    /// BIP32 fingerprint of the master key, as used in PSBT key origins
+    pub fn get_master_fingerprint(
+        &self,
+        key_id: &str,
+        password: &str,
+    ) -> Result<[u8; 4], CKMError> {
+        let seed = read_seed(&self._store(), password, key_id)?;
+        curve::k1::master_fingerprint(&seed)
+    }
+
+    /// OpenSSH public key of the Ed25519 or Secp256R1 path as an `authorized_keys` line with
+    /// the comment `<key_id>:<path>`
+    #[cfg(feature = "ssh")]
+    pub fn get_ssh_public_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        ssh::key_type(curve)?;
+        let public_key = self.get_public_key(key_id, path, curve, password)?;
+        ssh::authorized_key(curve, &public_key, &format!("{}:{}", key_id, path))
+    }
+
+    /// OpenSSH private key file of the Ed25519 or Secp256R1 path, encrypted with aes256-ctr
+    /// and bcrypt-pbkdf under the passphrase like `ssh-keygen`, unencrypted when it is empty
+    #[cfg(feature = "ssh")]
+    pub fn export_ssh_private_key(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+        passphrase: &str,
+    ) -> Result<Zeroizing<String>, CKMError> {
+        ssh::key_type(curve)?;
+        let seed = read_seed(&self._store(), password, key_id)?;
+        let key = Zeroizing::new(match curve {
+            Curve::Ed25519 => curve::ed25519::derive_from_seed(&seed, path)?,
+            _ => curve::r1::derive_from_seed(&seed, path)?,
+        });
+        let comment = format!("{}:{}", key_id, path);
+        ssh::key::private_key_file(curve, &key, &comment, passphrase)
+    }
+
+    /// armored SSHSIG signature of the data in the namespace with the Ed25519 or Secp256R1 key,
+    /// what `ssh-keygen -Y sign -n <namespace>` writes. git signs commits in the `git` namespace
+    #[cfg(feature = "ssh")]
+    pub fn sign_sshsig(
+        &self,
+        sign_request: SignRequest,
+        namespace: &str,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        let (key_id, path, curve) = (sign_request.key_id, sign_request.path, sign_request.curve);
+        ssh::key_type(curve)?;
+        let request = SignRequest {
+            unsigend_data: ssh::sshsig::signed_data(namespace, &sign_request.unsigend_data)?,
+            ..sign_request
+        };
+        // the seed is decrypted once for the signature and the public key
+        let store = self._store();
+        let keys = SeedOnce::new(&store, key_id);
+        let public_key = self._public_key(&keys, key_id, path, curve, password)?;
+        let sig = dispatch(request, password, &self.inner.store, &keys)?;
+        ssh::sshsig::signature(curve, &public_key, namespace, &sig)
+    }
+
+    /// JWK of the Secp256R1, Secp256k1 or Ed25519 public key of the path, its `kid` is the
+    /// RFC 7638 thumbprint
+    #[cfg(feature = "jose")]
+    pub fn get_jwk(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<Jwk, CKMError> {
+        jose::algorithm(curve)?;
+        let public_key = self.get_public_key(key_id, path, curve, password)?;
+        Jwk::from_public_key(curve, &public_key)
+    }
+
+    /// compact JWS of the data, ES256 with Secp256R1, ES256K with Secp256k1 and EdDSA with
+    /// Ed25519. The header names the key by its JWK thumbprint in `kid`
+    #[cfg(feature = "jose")]
+    pub fn sign_jws(&self, sign_request: SignRequest, password: &str) -> Result<String, CKMError> {
+        self._sign_jws(sign_request, None, password)
+    }
+
+    /// JWT of the claims, a JWS with `typ` JWT like `sign_jws`
+    #[cfg(feature = "jose")]
+    pub fn sign_jwt(
+        &self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        claims: &JwtClaims,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        let request = SignRequest {
+            path,
+            unsigend_data: serde_json::to_vec(claims).map_err(|_e| CKMError::SerializeError)?,
+            key_id,
+            curve,
+        };
+        self._sign_jws(request, Some("JWT"), password)
+    }
+
+    /// ids of the keys in the store
+    pub fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        self.inner.store.list_keys()
     }
 
     /// generate entropy for seed
@@ -67,9 +523,65 @@ impl<Store: Keystore> KeyMaster<Store> {
         self.inner.store.generate_entropy(length)
     }
 
-    /// write seed to storage
-    pub fn write_seed(&mut self, password: &str, seed: String) -> Result<String, CKMError> {
-        self.inner.store.write_key(password, seed)
+    /// write a hex encoded seed to storage, tagged so reading it back does not depend on
+    /// guessing its encoding
+    pub fn write_seed(&self, password: &str, seed: String) -> Result<String, CKMError> {
+        let stored = Zeroizing::new(keystore::encode_seed(&seed)?);
+        self.inner.store.write_key(password, stored.to_string())
+    }
+
+    /// write a key to storage as it is, the remote signer stores what the `write_seed` of its
+    /// clients already tagged
+    #[cfg(feature = "remote")]
+    pub(crate) fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        self.inner.store.write_key(password, key)
+    }
+
+    #[cfg(feature = "jose")]
+    fn _sign_jws(
+        &self,
+        sign_request: SignRequest,
+        typ: Option<&str>,
+        password: &str,
+    ) -> Result<String, CKMError> {
+        let (key_id, path, curve) = (sign_request.key_id, sign_request.path, sign_request.curve);
+        jose::algorithm(curve)?;
+        // the seed is decrypted once for the `kid` and the signature
+        let store = self._store();
+        let keys = SeedOnce::new(&store, key_id);
+        let public_key = self._public_key(&keys, key_id, path, curve, password)?;
+        let jwk = Jwk::from_public_key(curve, &public_key)?;
+        let input =
+            jose::signing_input(curve, jwk.kid.as_deref(), typ, &sign_request.unsigend_data)?;
+        let request = SignRequest {
+            unsigend_data: input.as_bytes().to_vec(),
+            ..sign_request
+        };
+        let sig = dispatch(request, password, &self.inner.store, &keys)?;
+        Ok(jose::compact(input, &sig))
+    }
+
+    /// public key of the path, from the store when it signs internally
+    fn _public_key(
+        &self,
+        keys: &impl KeySource,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<Vec<u8>, CKMError> {
+        let store = &self.inner.store;
+        if store.signs_internally() {
+            return store.get_public_key(password, key_id, path, &curve);
+        }
+        public_key(keys, password, key_id, path, curve)
+    }
+
+    fn _store(&self) -> SessionStore<'_, Store> {
+        SessionStore {
+            store: &self.inner.store,
+            sessions: &self.inner.sessions,
+        }
     }
 }
 
@@ -77,27 +589,97 @@ fn dispatch(
     sign_request: SignRequest,
     password: &str,
     store: &impl Keystore,
+    keys: &impl KeySource,
+) -> Result<SigningSignature, CKMError> {
+    if store.signs_internally() {
+        return store.sign_digest(
+            password,
+            sign_request.key_id,
+            sign_request.path,
+            &sign_request.curve,
+            &signed_input(&sign_request),
+        );
+    }
+    sign_with_keys(sign_request, password, keys)
+}
+
+/// what stores that sign internally get, the SHA-256 digest for ECDSA and the data for the others
+fn signed_input(sign_request: &SignRequest) -> Vec<u8> {
+    match sign_request.curve {
+        Curve::Secp256k1 | Curve::Secp256R1 => Sha256::digest(&sign_request.unsigend_data).to_vec(),
+        Curve::Secp256k1Schnorr | Curve::Ed25519 => sign_request.unsigend_data.clone(),
+    }
+}
+
+/// sign with a key derived in process
+fn sign_with_keys(
+    sign_request: SignRequest,
+    password: &str,
+    keys: &impl KeySource,
 ) -> Result<SigningSignature, CKMError> {
     match sign_request.curve {
         Curve::Secp256k1 => {
             let k1 = K1 {};
-            k1.sign(&sign_request, password, store)
+            k1.sign(&sign_request, password, keys)
+        }
+        Curve::Secp256k1Schnorr => {
+            let schnorr = Schnorr::default();
+            schnorr.sign(&sign_request, password, keys)
+        }
+        Curve::Secp256R1 => {
+            let r1 = R1 {};
+            r1.sign(&sign_request, password, keys)
+        }
+        Curve::Ed25519 => {
+            let ed25519 = Ed25519 {};
+            ed25519.sign(&sign_request, password, keys)
         }
-        _ => todo!(),
     }
 }
 
+/// public key of a key derived in process
+fn public_key(
+    keys: &impl KeySource,
+    password: &str,
+    key_id: &str,
+    path: &str,
+    curve: Curve,
+) -> Result<Vec<u8>, CKMError> {
+    match curve {
+        Curve::Secp256k1 => {
+            let key = keys.derive_k1(password, key_id, path)?;
+            Ok(curve::k1::k1_public_key(&key)?.to_vec())
+        }
+        Curve::Secp256k1Schnorr => {
+            let key = keys.derive_k1(password, key_id, path)?;
+            Ok(curve::schnorr::x_only_public_key(&key)?.to_vec())
+        }
+        Curve::Secp256R1 => {
+            let seed = read_seed(keys, password, key_id)?;
+            let key = Zeroizing::new(curve::r1::derive_from_seed(&seed, path)?);
+            Ok(curve::r1::r1_public_key(&key)?.to_vec())
+        }
+        Curve::Ed25519 => {
+            let seed = read_seed(keys, password, key_id)?;
+            let key = Zeroizing::new(curve::ed25519::derive_from_seed(&seed, path)?);
+            Ok(curve::ed25519::ed25519_public_key(&key)?.to_vec())
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::keystore::fake::FakeKeystore;
+    use crate::keystore::fake::{FakeKeystore, FakeSigner, SEED};
     #[test]
     fn sample_usage() {
         let fake_store = FakeKeystore {};
 
-        let mut key_master = KeyMaster {
-            inner: KeyMasterInner { store: fake_store },
+        let key_master = KeyMaster {
+            inner: KeyMasterInner {
+                store: fake_store,
+                sessions: Sessions::default(),
+            },
         };
 
         let entropy = key_master.generate_entropy(32).unwrap();
@@ -116,11 +698,332 @@ mod tests {
 
         let sig = key_master.sign(request, "123").unwrap();
 
-        let sig_expect = SigningSignature {
-            r: "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d".to_string(),
-            s: "0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d".to_string(),
-            v: None,
+        assert_eq!(
+            hex::encode(sig.r),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+        );
+        assert_eq!(
+            hex::encode(sig.s),
+            "0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d"
+        );
+        assert!(sig.v.is_some());
+    }
+
+    #[test]
+    fn xpub_and_verify_usage() {
+        let key_master = KeyMaster::new(FakeKeystore {});
+        // BIP44 account of the `abandon ... about` mnemonic
+        assert_eq!(
+            key_master.get_xpub("123456", "m/44'/0'/0'", "123").unwrap(),
+            "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj"
+        );
+        assert_eq!(
+            key_master.get_master_fingerprint("123456", "123").unwrap(),
+            [0x73, 0xc5, 0xda, 0x0a]
+        );
+
+        let curves = [
+            Curve::Secp256k1,
+            Curve::Secp256k1Schnorr,
+            Curve::Secp256R1,
+            Curve::Ed25519,
+        ];
+        for curve in curves {
+            // hardened for SLIP-10 Ed25519
+            let request = SignRequest {
+                path: "m/44'/0'/0'/0'/0'",
+                unsigend_data: b"hello".to_vec(),
+                key_id: "123456",
+                curve,
+            };
+            let sig = key_master.sign(request, "123").unwrap();
+            let public_key = key_master
+                .get_public_key("123456", "m/44'/0'/0'/0'/0'", curve, "123")
+                .unwrap();
+            assert!(verify_signature(curve, &public_key, b"hello", &sig).unwrap());
+            assert!(!verify_signature(curve, &public_key, b"hellx", &sig).unwrap());
+        }
+        let sig = SigningSignature::default();
+        assert!(verify_digest(Curve::Secp256k1, &[2u8; 33], &[0u8; 31], &sig).is_err());
+    }
+
+    #[test]
+    fn internal_signing_usage() {
+        let key_master = KeyMaster::new(FakeSigner {});
+        let request = SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: "123456",
+            curve: Curve::Secp256k1,
+        };
+        // same signature as the derive in process path of `sample_usage`
+        let sig = key_master.sign(request, "123").unwrap();
+        assert_eq!(
+            hex::encode(sig.r),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+        );
+        assert_eq!(
+            hex::encode(sig.s),
+            "0a6e719280a0503794715ae4403d09aec3664629f94435581a45a446d7c7ad2d"
+        );
+
+        // software stores do not sign internally
+        assert!(!FakeKeystore {}.signs_internally());
+        assert!(matches!(
+            FakeKeystore {}.sign_digest("123", "123456", "m", &Curve::Secp256k1, &[0u8; 32]),
+            Err(CKMError::InternalSigningUnsupported)
+        ));
+    }
+
+    #[test]
+    fn memory_keystore_usage() {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+
+        let request = SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: &key_id,
+            curve: Curve::Secp256k1,
+        };
+        let sig = key_master.sign(request, "123").unwrap();
+        assert_eq!(
+            hex::encode(sig.r),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+        );
+
+        let request = SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: &key_id,
+            curve: Curve::Secp256k1,
+        };
+        assert!(matches!(
+            key_master.sign(request, "456"),
+            Err(CKMError::PasswordInvalid)
+        ));
+    }
+
+    #[test]
+    fn unlock_session_usage() {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        let request = || SignRequest {
+            path: "m/44'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: &key_id,
+            curve: Curve::Secp256k1,
+        };
+
+        assert!(matches!(
+            key_master.unlock(&key_id, "456", Duration::from_secs(60)),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(!key_master.is_unlocked(&key_id));
+
+        let session = key_master
+            .unlock(&key_id, "123", Duration::from_millis(200))
+            .unwrap();
+        assert_eq!(session.key_id(), key_id);
+        let sig = key_master.sign(request(), "").unwrap();
+        assert_eq!(
+            hex::encode(sig.r),
+            "38a047f20caca5618cc56b0947939372a4c9c34cc05dd59dd75ef31f2323839d"
+        );
+        assert!(key_master
+            .get_public_key(&key_id, "m/44'/0'/0'/0/0", Curve::Secp256k1, "")
+            .is_ok());
+
+        // the session expires on its own
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(session.is_expired());
+        assert!(matches!(
+            key_master.sign(request(), ""),
+            Err(CKMError::PasswordInvalid)
+        ));
+
+        key_master
+            .unlock(&key_id, "123", Duration::from_secs(60))
+            .unwrap();
+        assert!(key_master.lock(&key_id));
+        assert!(key_master.sign(request(), "").is_err());
+        key_master
+            .unlock(&key_id, "123", Duration::from_secs(60))
+            .unwrap();
+        key_master.lock_all();
+        assert!(!key_master.is_unlocked(&key_id));
+        assert!(key_master.sign(request(), "123").is_ok());
+    }
+
+    #[test]
+    fn sign_batch_usage() {
+        let seed = SEED;
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let first = key_master.write_seed("123", seed.to_string()).unwrap();
+        let second = key_master.write_seed("123", seed.to_string()).unwrap();
+
+        let paths: Vec<String> = (0..8).map(|i| format!("m/84'/0'/0'/0/{}", i)).collect();
+        let mut requests: Vec<SignRequest> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| SignRequest {
+                path,
+                unsigend_data: format!("input {}", i).into_bytes(),
+                key_id: if i % 2 == 0 { &first } else { &second },
+                curve: if i % 3 == 0 {
+                    Curve::Secp256k1Schnorr
+                } else {
+                    Curve::Secp256k1
+                },
+            })
+            .collect();
+        // failing requests do not stop the batch
+        requests.insert(
+            2,
+            SignRequest {
+                path: "m/x",
+                unsigend_data: Vec::new(),
+                key_id: &first,
+                curve: Curve::Secp256k1,
+            },
+        );
+        requests.insert(
+            4,
+            SignRequest {
+                path: "m/0",
+                unsigend_data: Vec::new(),
+                key_id: "missing",
+                curve: Curve::Secp256k1,
+            },
+        );
+        requests.insert(
+            6,
+            SignRequest {
+                path: "m/0",
+                unsigend_data: Vec::new(),
+                key_id: &first,
+                curve: Curve::Ed25519,
+            },
+        );
+
+        let expected: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                let request = SignRequest {
+                    path: request.path,
+                    unsigend_data: request.unsigend_data.clone(),
+                    key_id: request.key_id,
+                    curve: request.curve,
+                };
+                let schnorr = request.curve == Curve::Secp256k1Schnorr;
+                key_master.sign(request, "123").map(|sig| (sig, schnorr))
+            })
+            .collect();
+        let results = key_master.sign_batch(requests, "123");
+        assert_eq!(results.len(), 11);
+        for (result, expected) in results.iter().zip(expected) {
+            match (result, expected) {
+                // schnorr signatures use random auxiliary data
+                (Ok(sig), Ok((expected, true))) => assert_eq!(sig.r.len(), expected.r.len()),
+                (Ok(sig), Ok((expected, false))) => assert_eq!(*sig, expected),
+                (Err(e), Err(expected)) => assert_eq!(e.to_string(), expected.to_string()),
+                _ => panic!("batch and single results differ"),
+            }
+        }
+        assert!(matches!(results[2], Err(CKMError::InvalidPath { .. })));
+        assert!(matches!(results[4], Err(CKMError::NotExist)));
+        assert!(matches!(results[6], Err(CKMError::InvalidPath { .. })));
+
+        let request = SignRequest {
+            path: "m/0",
+            unsigend_data: Vec::new(),
+            key_id: &first,
+            curve: Curve::Secp256k1,
+        };
+        assert!(matches!(
+            key_master.sign_batch(vec![request], "456")[0],
+            Err(CKMError::PasswordInvalid)
+        ));
+    }
+
+    #[test]
+    fn shared_key_master() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<KeyMaster<LocalKeystore>>();
+        assert_send_sync::<KeyMaster<MemoryKeystore>>();
+
+        let key_master = std::sync::Arc::new(KeyMaster::new(MemoryKeystore::new()));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let key_master = key_master.clone();
+                std::thread::spawn(move || key_master.write_seed("123", SEED.to_string()).unwrap())
+            })
+            .collect();
+        let mut key_ids: Vec<String> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        key_ids.sort();
+        assert_eq!(key_master.list_keys().unwrap(), key_ids);
+    }
+
+    #[test]
+    fn schnorr_usage() {
+        let key_master = KeyMaster::new(FakeKeystore {});
+
+        let tweak = Some(TapTweak { merkle_root: None });
+        let output_key = key_master
+            .get_x_only_public_key("123456", "m/86'/0'/0'/0/0", "123", tweak)
+            .unwrap();
+        assert_eq!(
+            hex::encode(&output_key),
+            "a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c"
+        );
+
+        let request = SignRequest {
+            path: "m/86'/0'/0'/0/0",
+            unsigend_data: [1u8; 32].to_vec(),
+            key_id: "123456",
+            curve: Curve::Secp256k1Schnorr,
+        };
+        let options = SchnorrOptions {
+            aux_rand: Some([0u8; 32]),
+            tweak,
+        };
+        let sig = key_master.sign_schnorr(request, "123", options).unwrap();
+        let sig_bytes = sig.to_compact();
+        assert!(schnorr_verify(&output_key, &[1u8; 32], &sig_bytes));
+    }
+
+    #[test]
+    fn bitcoin_message_usage() {
+        let key_master = KeyMaster::new(FakeKeystore {});
+
+        let address = key_master
+            .get_bitcoin_address(
+                "123456",
+                "m/86'/0'/0'/0/0",
+                AddressType::P2tr,
+                Network::Bitcoin,
+                "123",
+            )
+            .unwrap();
+        assert_eq!(
+            address,
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+
+        let request = SignRequest {
+            path: "m/86'/0'/0'/0/0",
+            unsigend_data: "hello".as_bytes().to_vec(),
+            key_id: "123456",
+            curve: Curve::Secp256k1,
         };
-        assert_eq!(sig_expect, sig);
+        let sig = key_master
+            .sign_bitcoin_message(
+                request,
+                AddressType::P2tr,
+                MessageFormat::Bip322Simple,
+                "123",
+            )
+            .unwrap();
+        assert!(verify_bitcoin_message(&address, b"hello", &sig).unwrap());
     }
 }
@@ -0,0 +1,146 @@
+use super::*;
+use crate::keystore::new_key_id;
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+use std::convert::TryFrom;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// client of a `RemoteSignerServer` used as a keystore
+/// keys live in the keystore of the server and are used through requests over mutually
+/// authenticated TLS, they never leave the server
+pub struct RemoteKeystore {
+    addr: String,
+    server_name: ServerName,
+    tls: Arc<ClientConfig>,
+}
+
+impl RemoteKeystore {
+    /// client of the server at `addr` (`host:port`), the server certificate must be valid for
+    /// `server_name`
+    pub fn new(addr: &str, server_name: &str, tls: RemoteTlsConfig) -> Result<Self, CKMError> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_e| CKMError::RemoteError("invalid server name".to_string()))?;
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(tls.roots)
+            .with_client_auth_cert(tls.cert_chain, tls.key)
+            .map_err(|e| CKMError::RemoteError(e.to_string()))?;
+        Ok(Self {
+            addr: addr.to_string(),
+            server_name,
+            tls: Arc::new(config),
+        })
+    }
+
+    fn _call(&self, request: &RemoteRequest) -> Result<serde_json::Value, CKMError> {
+        let request_id = new_key_id()?;
+        let body = serde_json::to_vec(request).map_err(|_e| CKMError::SerializeError)?;
+        let message = HttpMessage {
+            start_line: format!("POST {} HTTP/1.1", ENDPOINT),
+            headers: vec![
+                ("Host".to_string(), self.addr.clone()),
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("X-Request-Id".to_string(), request_id.clone()),
+            ],
+            body,
+        };
+
+        let socket = TcpStream::connect(&self.addr).map_err(_io_error)?;
+        socket.set_read_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+        socket.set_write_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+        let conn = ClientConnection::new(self.tls.clone(), self.server_name.clone())
+            .map_err(|e| CKMError::RemoteError(e.to_string()))?;
+        let mut stream = StreamOwned::new(conn, socket);
+        message.write(&mut stream)?;
+        let response = HttpMessage::read(&mut BufReader::new(stream))?;
+
+        if response.header(REQUEST_ID_HEADER) != Some(request_id.as_str()) {
+            return Err(_protocol_error("request id mismatch"));
+        }
+        let response: RemoteResponse = serde_json::from_slice(&response.body)
+            .map_err(|_e| _protocol_error("invalid response"))?;
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(error.into_error()),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(_protocol_error("empty response")),
+        }
+    }
+}
+
+impl Keystore for RemoteKeystore {
+    fn generate_entropy(&self, length: u32) -> Result<Vec<u8>, CKMError> {
+        let result = self._call(&RemoteRequest::GenerateEntropy { length })?;
+        _hex_result(result)
+    }
+
+    /// remote keys never leave the server
+    fn get_key(&self, _password: &str, _key_id: String) -> Result<Vec<u8>, CKMError> {
+        Err(CKMError::KeyNotExportable)
+    }
+
+    fn write_key(&self, password: &str, key: String) -> Result<String, CKMError> {
+        let result = self._call(&RemoteRequest::WriteKey {
+            password: password.to_string(),
+            key,
+        })?;
+        serde_json::from_value(result).map_err(|_e| _protocol_error("invalid response"))
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>, CKMError> {
+        let result = self._call(&RemoteRequest::ListKeys)?;
+        serde_json::from_value(result).map_err(|_e| _protocol_error("invalid response"))
+    }
+
+    fn signs_internally(&self) -> bool {
+        true
+    }
+
+    fn sign_digest(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+        curve: &Curve,
+        digest: &[u8],
+    ) -> Result<SigningSignature, CKMError> {
+        let result = self._call(&RemoteRequest::SignDigest {
+            password: password.to_string(),
+            key_id: key_id.to_string(),
+            path: path.to_string(),
+            curve: *curve,
+            digest: digest.to_vec(),
+        })?;
+        serde_json::from_value(result).map_err(|_e| _protocol_error("invalid response"))
+    }
+
+    fn get_public_key(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+        curve: &Curve,
+    ) -> Result<Vec<u8>, CKMError> {
+        let result = self._call(&RemoteRequest::GetPublicKey {
+            password: password.to_string(),
+            key_id: key_id.to_string(),
+            path: path.to_string(),
+            curve: *curve,
+        })?;
+        _hex_result(result)
+    }
+}
+
+fn _hex_result(result: serde_json::Value) -> Result<Vec<u8>, CKMError> {
+    result
+        .as_str()
+        .and_then(|s| hex::decode(s).ok())
+        .ok_or_else(|| _protocol_error("invalid response"))
+}
+
+fn _io_error(e: std::io::Error) -> CKMError {
+    CKMError::RemoteError(e.to_string())
+}
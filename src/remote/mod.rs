@@ -0,0 +1,431 @@
+//! remote signer, a `Keystore` client and a reference server talking JSON over HTTP/1.1 with
+//! mutually authenticated TLS
+use crate::*;
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+mod client;
+mod server;
+
+pub use client::RemoteKeystore;
+pub use server::RemoteSignerServer;
+
+/// endpoint of the keystore requests
+const ENDPOINT: &str = "/v1/keystore";
+/// request id header, echoed by the server
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_BODY_SIZE: usize = 1 << 20;
+const MAX_HEADERS: usize = 64;
+
+/// certificates and key for mutually authenticated TLS, the CA verifies the peer and the
+/// certificate chain and key identify this side
+pub struct RemoteTlsConfig {
+    roots: RootCertStore,
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl RemoteTlsConfig {
+    /// load PEM encoded CA certificates, certificate chain and PKCS#8 or SEC1 private key
+    pub fn from_pem(ca_pem: &[u8], cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, CKMError> {
+        let mut roots = RootCertStore::empty();
+        for der in _pem_certs(ca_pem)? {
+            roots
+                .add(&der)
+                .map_err(|e| CKMError::RemoteError(format!("invalid CA certificate: {}", e)))?;
+        }
+        let cert_chain = _pem_certs(cert_pem)?;
+        if roots.is_empty() || cert_chain.is_empty() {
+            return Err(CKMError::RemoteError("missing certificate".to_string()));
+        }
+        let key = rustls_pemfile::read_all(&mut &key_pem[..])
+            .map_err(|_e| CKMError::RemoteError("invalid private key".to_string()))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key)
+                | rustls_pemfile::Item::RSAKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| CKMError::RemoteError("missing private key".to_string()))?;
+        Ok(Self {
+            roots,
+            cert_chain,
+            key,
+        })
+    }
+
+    /// load the PEM files
+    pub fn from_files<P: AsRef<Path>>(ca: P, cert: P, key: P) -> Result<Self, CKMError> {
+        let read = |path: P| std::fs::read(&path).map_err(|e| CKMError::io(path, e));
+        Self::from_pem(&read(ca)?, &read(cert)?, &read(key)?)
+    }
+}
+
+fn _pem_certs(pem: &[u8]) -> Result<Vec<Certificate>, CKMError> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .map_err(|_e| CKMError::RemoteError("invalid certificate".to_string()))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// keystore request, the password unlocks the key in the keystore of the server
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum RemoteRequest {
+    GenerateEntropy {
+        length: u32,
+    },
+    WriteKey {
+        password: String,
+        key: String,
+    },
+    ListKeys,
+    GetPublicKey {
+        password: String,
+        key_id: String,
+        path: String,
+        curve: Curve,
+    },
+    SignDigest {
+        password: String,
+        key_id: String,
+        path: String,
+        curve: Curve,
+        #[serde(with = "hex::serde")]
+        digest: Vec<u8>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct RemoteResponse {
+    request_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RemoteErrorBody>,
+}
+
+/// structured error, `code` is mapped back to the `CKMError` variant by the client
+#[derive(Serialize, Deserialize)]
+struct RemoteErrorBody {
+    code: String,
+    message: String,
+}
+
+impl RemoteErrorBody {
+    fn from_error(e: &CKMError) -> Self {
+        let code = match e {
+            CKMError::NotExist | CKMError::FileNotExit => "not_exist",
+            CKMError::PasswordInvalid => "password_invalid",
+            CKMError::KeyNotExportable => "key_not_exportable",
+            CKMError::UnsupportedCurve => "unsupported_curve",
+            CKMError::InternalSigningUnsupported => "internal_signing_unsupported",
+            CKMError::VaultLocked => "vault_locked",
+            CKMError::SigningError => "signing_error",
+            CKMError::InvalidPath { .. } => "invalid_path",
+            CKMError::NotFound(_) => "not_found",
+            _ => "internal",
+        };
+        Self {
+            code: code.to_string(),
+            message: e.to_string(),
+        }
+    }
+
+    fn bad_request(message: &str) -> Self {
+        Self {
+            code: "bad_request".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match self.code.as_str() {
+            "bad_request" => "400 Bad Request",
+            "password_invalid" => "401 Unauthorized",
+            "not_exist" | "not_found" => "404 Not Found",
+            "internal" => "500 Internal Server Error",
+            _ => "422 Unprocessable Entity",
+        }
+    }
+
+    fn into_error(self) -> CKMError {
+        match self.code.as_str() {
+            "not_exist" => CKMError::NotExist,
+            "password_invalid" => CKMError::PasswordInvalid,
+            "key_not_exportable" => CKMError::KeyNotExportable,
+            "unsupported_curve" => CKMError::UnsupportedCurve,
+            "internal_signing_unsupported" => CKMError::InternalSigningUnsupported,
+            "vault_locked" => CKMError::VaultLocked,
+            "signing_error" => CKMError::SigningError,
+            "invalid_path" => CKMError::InvalidPath {
+                path: self
+                    .message
+                    .trim_start_matches("invalid derivation path ")
+                    .to_string(),
+                source: None,
+            },
+            "not_found" => CKMError::NotFound(self.message),
+            _ => CKMError::RemoteError(format!("{}: {}", self.code, self.message)),
+        }
+    }
+}
+
+/// HTTP/1.1 request or response with a `Content-Length` body
+struct HttpMessage {
+    start_line: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpMessage {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn read(reader: &mut impl BufRead) -> Result<Self, CKMError> {
+        let start_line = _read_line(reader)?;
+        let mut headers = Vec::new();
+        loop {
+            let line = _read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(_protocol_error("too many headers"));
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| _protocol_error("invalid header"))?;
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+        let mut message = Self {
+            start_line,
+            headers,
+            body: Vec::new(),
+        };
+        let length: usize = message
+            .header("content-length")
+            .unwrap_or("0")
+            .parse()
+            .map_err(|_e| _protocol_error("invalid content length"))?;
+        if length > MAX_BODY_SIZE {
+            return Err(_protocol_error("body too large"));
+        }
+        message.body = vec![0u8; length];
+        reader
+            .read_exact(&mut message.body)
+            .map_err(|e| CKMError::RemoteError(e.to_string()))?;
+        Ok(message)
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), CKMError> {
+        let mut head = format!("{}\r\n", self.start_line);
+        for (key, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        writer
+            .write_all(head.as_bytes())
+            .and_then(|_| writer.write_all(&self.body))
+            .and_then(|_| writer.flush())
+            .map_err(|e| CKMError::RemoteError(e.to_string()))
+    }
+}
+
+fn _read_line(reader: &mut impl BufRead) -> Result<String, CKMError> {
+    let mut line = Vec::new();
+    reader
+        .take(8192)
+        .read_until(b'\n', &mut line)
+        .map_err(|e| CKMError::RemoteError(e.to_string()))?;
+    if line.last() != Some(&b'\n') {
+        return Err(_protocol_error("truncated message"));
+    }
+    let line = String::from_utf8(line).map_err(|_e| _protocol_error("invalid header"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// request ids are 1 to 64 characters of `[A-Za-z0-9_-]`
+fn _valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 64
+        && request_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn _protocol_error(message: &str) -> CKMError {
+    CKMError::RemoteError(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate as RcgenCertificate, CertificateParams, IsCa};
+    use std::thread;
+
+    use crate::keystore::fake::SEED;
+
+    struct Pki {
+        ca: RcgenCertificate,
+        ca_pem: String,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = RcgenCertificate::from_params(params).unwrap();
+            let ca_pem = ca.serialize_pem().unwrap();
+            Self { ca, ca_pem }
+        }
+
+        fn tls(&self, trusted_ca_pem: &str) -> RemoteTlsConfig {
+            let cert =
+                RcgenCertificate::from_params(CertificateParams::new(vec!["localhost".into()]))
+                    .unwrap();
+            RemoteTlsConfig::from_pem(
+                trusted_ca_pem.as_bytes(),
+                cert.serialize_pem_with_signer(&self.ca).unwrap().as_bytes(),
+                cert.serialize_private_key_pem().as_bytes(),
+            )
+            .unwrap()
+        }
+    }
+
+    fn start(pki: &Pki) -> String {
+        let server =
+            RemoteSignerServer::bind("127.0.0.1:0", pki.tls(&pki.ca_pem), MemoryKeystore::new())
+                .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+        addr
+    }
+
+    #[test]
+    fn test_loopback() {
+        let pki = Pki::new();
+        let addr = start(&pki);
+        let client = RemoteKeystore::new(&addr, "localhost", pki.tls(&pki.ca_pem)).unwrap();
+        let key_master = KeyMaster::new(client);
+
+        assert_eq!(key_master.generate_entropy(256).unwrap().len(), 32);
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        assert_eq!(key_master.list_keys().unwrap(), vec![key_id.clone()]);
+
+        // the remote key signs like the same seed in a local store
+        let local = KeyMaster::new(MemoryKeystore::new());
+        let local_id = local.write_seed("123", SEED.to_string()).unwrap();
+        let path = "m/44'/0'/0'/0/0";
+        for curve in [Curve::Secp256k1, Curve::Secp256k1Schnorr, Curve::Secp256R1] {
+            assert_eq!(
+                key_master
+                    .get_public_key(&key_id, path, curve, "123")
+                    .unwrap(),
+                local.get_public_key(&local_id, path, curve, "123").unwrap()
+            );
+        }
+        let request = |key_id| SignRequest {
+            path,
+            unsigend_data: b"hello".to_vec(),
+            key_id,
+            curve: Curve::Secp256k1,
+        };
+        assert_eq!(
+            key_master.sign(request(&key_id), "123").unwrap(),
+            local.sign(request(&local_id), "123").unwrap()
+        );
+
+        // errors come back as their variants
+        assert!(matches!(
+            key_master.sign(request(&key_id), "124"),
+            Err(CKMError::PasswordInvalid)
+        ));
+        assert!(matches!(
+            key_master.get_public_key("missing", path, Curve::Secp256k1, "123"),
+            Err(CKMError::NotExist)
+        ));
+
+        // P-256 and Ed25519 keys of the seed sign on the server like local ones
+        for curve in [Curve::Secp256R1, Curve::Ed25519] {
+            let path = "m/44'/60'/0'";
+            let curve_request = |key_id| SignRequest {
+                path,
+                curve,
+                ..request(key_id)
+            };
+            let sig = key_master.sign(curve_request(&key_id), "123").unwrap();
+            assert_eq!(sig, local.sign(curve_request(&local_id), "123").unwrap());
+            let public_key = key_master
+                .get_public_key(&key_id, path, curve, "123")
+                .unwrap();
+            assert!(verify_signature(curve, &public_key, b"hello", &sig).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let pki = Pki::new();
+        let server =
+            RemoteSignerServer::bind("127.0.0.1:0", pki.tls(&pki.ca_pem), MemoryKeystore::new())
+                .unwrap()
+                .with_max_connections(1);
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve());
+
+        // an idle connection holds the only slot, the next client waits for it
+        let idle = std::net::TcpStream::connect(&addr).unwrap();
+        let client = RemoteKeystore::new(&addr, "localhost", pki.tls(&pki.ca_pem)).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || sender.send(client.list_keys()).unwrap());
+        assert!(receiver
+            .recv_timeout(std::time::Duration::from_millis(300))
+            .is_err());
+        drop(idle);
+        let keys = receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(keys.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_untrusted_client() {
+        let pki = Pki::new();
+        let addr = start(&pki);
+        // a client certificate from another CA is refused by the server
+        let other = Pki::new();
+        let client = RemoteKeystore::new(&addr, "localhost", other.tls(&pki.ca_pem)).unwrap();
+        assert!(matches!(client.list_keys(), Err(CKMError::RemoteError(_))));
+        // and a server from another CA is refused by the client
+        let client = RemoteKeystore::new(&addr, "localhost", pki.tls(&other.ca_pem)).unwrap();
+        assert!(matches!(client.list_keys(), Err(CKMError::RemoteError(_))));
+    }
+
+    #[test]
+    fn test_http_message() {
+        let message = HttpMessage {
+            start_line: "POST /v1/keystore HTTP/1.1".to_string(),
+            headers: vec![("X-Request-Id".to_string(), "abc".to_string())],
+            body: b"{}".to_vec(),
+        };
+        let mut encoded = Vec::new();
+        message.write(&mut encoded).unwrap();
+        let decoded = HttpMessage::read(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded.start_line, message.start_line);
+        assert_eq!(decoded.header("x-request-id"), Some("abc"));
+        assert_eq!(decoded.body, b"{}");
+
+        assert!(_valid_request_id("0f-A_"));
+        assert!(!_valid_request_id("a b"));
+        assert!(!_valid_request_id(&"a".repeat(65)));
+    }
+}
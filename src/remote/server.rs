@@ -0,0 +1,171 @@
+use super::*;
+use crate::connection::{retry_accept, ConnectionLimit, MAX_CONNECTIONS};
+use crate::keystore::new_key_id;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// reference remote signer server wrapping any keystore, clients must present a certificate
+/// issued by the configured CA. Every connection carries one request
+pub struct RemoteSignerServer<Store> {
+    listener: TcpListener,
+    tls: Arc<ServerConfig>,
+    key_master: Arc<KeyMaster<Store>>,
+    limit: ConnectionLimit,
+}
+
+impl<Store: Keystore + 'static> RemoteSignerServer<Store> {
+    /// listen on the address, the connections are served by `serve`
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        tls: RemoteTlsConfig,
+        store: Store,
+    ) -> Result<Self, CKMError> {
+        let verifier = AllowAnyAuthenticatedClient::new(tls.roots).boxed();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(tls.cert_chain, tls.key)
+            .map_err(|e| CKMError::RemoteError(e.to_string()))?;
+        let listener = TcpListener::bind(addr).map_err(_io_error)?;
+        Ok(Self {
+            listener,
+            tls: Arc::new(config),
+            key_master: Arc::new(KeyMaster::new(store)),
+            limit: ConnectionLimit::new(MAX_CONNECTIONS),
+        })
+    }
+
+    /// handle at most `max` connections at once, 64 by default, further clients wait in the
+    /// listen backlog until a connection ends
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limit = ConnectionLimit::new(max);
+        self
+    }
+
+    /// address the server listens on
+    pub fn local_addr(&self) -> Result<SocketAddr, CKMError> {
+        self.listener.local_addr().map_err(_io_error)
+    }
+
+    /// serve connections until the listener fails, each connection runs on its own thread
+    /// and a connection is only accepted while fewer than the maximum are handled. Aborted
+    /// connections and a lack of file descriptors are logged to stderr and do not stop it
+    pub fn serve(self) -> Result<(), CKMError> {
+        loop {
+            let permit = self.limit.acquire();
+            let (socket, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if retry_accept("ckm remote signer", &e) => continue,
+                Err(e) => return Err(_io_error(e)),
+            };
+            let tls = self.tls.clone();
+            let key_master = self.key_master.clone();
+            thread::spawn(move || {
+                let _permit = permit;
+                // failed handshakes and broken connections only affect their client
+                let _ = _handle(socket, tls, &key_master);
+            });
+        }
+    }
+}
+
+fn _handle<Store: Keystore>(
+    socket: TcpStream,
+    tls: Arc<ServerConfig>,
+    key_master: &KeyMaster<Store>,
+) -> Result<(), CKMError> {
+    socket.set_read_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+    socket.set_write_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+    let conn = ServerConnection::new(tls).map_err(|e| CKMError::RemoteError(e.to_string()))?;
+    let mut reader = BufReader::new(StreamOwned::new(conn, socket));
+    let request = HttpMessage::read(&mut reader)?;
+
+    let request_id = match request.header(REQUEST_ID_HEADER) {
+        Some(request_id) if _valid_request_id(request_id) => request_id.to_string(),
+        _ => new_key_id()?,
+    };
+    let outcome = if request.start_line != format!("POST {} HTTP/1.1", ENDPOINT) {
+        Err(RemoteErrorBody::bad_request("unknown endpoint"))
+    } else {
+        match serde_json::from_slice::<RemoteRequest>(&request.body) {
+            Ok(request) => {
+                _dispatch(request, key_master).map_err(|e| RemoteErrorBody::from_error(&e))
+            }
+            Err(_e) => Err(RemoteErrorBody::bad_request("invalid request")),
+        }
+    };
+    let (status, response) = match outcome {
+        Ok(result) => (
+            "200 OK",
+            RemoteResponse {
+                request_id: request_id.clone(),
+                result: Some(result),
+                error: None,
+            },
+        ),
+        Err(error) => (
+            error.status(),
+            RemoteResponse {
+                request_id: request_id.clone(),
+                result: None,
+                error: Some(error),
+            },
+        ),
+    };
+    let response = HttpMessage {
+        start_line: format!("HTTP/1.1 {}", status),
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("X-Request-Id".to_string(), request_id),
+        ],
+        body: serde_json::to_vec(&response).map_err(|_e| CKMError::SerializeError)?,
+    };
+    let stream = reader.get_mut();
+    response.write(stream)?;
+    stream.conn.send_close_notify();
+    stream.flush().map_err(_io_error)
+}
+
+fn _dispatch<Store: Keystore>(
+    request: RemoteRequest,
+    key_master: &KeyMaster<Store>,
+) -> Result<serde_json::Value, CKMError> {
+    match request {
+        RemoteRequest::GenerateEntropy { length } => {
+            _value(hex::encode(key_master.generate_entropy(length)?))
+        }
+        RemoteRequest::WriteKey { password, key } => _value(key_master.write_key(&password, key)?),
+        RemoteRequest::ListKeys => _value(key_master.list_keys()?),
+        RemoteRequest::GetPublicKey {
+            password,
+            key_id,
+            path,
+            curve,
+        } => {
+            let public_key = key_master.get_public_key(&key_id, &path, curve, &password)?;
+            _value(hex::encode(public_key))
+        }
+        RemoteRequest::SignDigest {
+            password,
+            key_id,
+            path,
+            curve,
+            digest,
+        } => _value(key_master.sign_digest(&key_id, &path, curve, &digest, &password)?),
+    }
+}
+
+fn _value<T: Serialize>(value: T) -> Result<serde_json::Value, CKMError> {
+    serde_json::to_value(value).map_err(|_e| CKMError::SerializeError)
+}
+
+fn _io_error(e: std::io::Error) -> CKMError {
+    CKMError::RemoteError(e.to_string())
+}
@@ -0,0 +1,525 @@
+//! unlock sessions keeping decrypted keys in memory so signing does not need the password
+use crate::curve::{k1::master_node, parse_path};
+use crate::keystore::{decode_seed, KeySource};
+use crate::{CKMError, Keystore};
+use bip32::{ChildNumber, ExtendedKey, Prefix, XPrv};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use zeroize::{Zeroize, Zeroizing};
+
+/// longest unlock session, longer ttls like `Duration::MAX` are cut to it
+const MAX_TTL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// handle of an unlocked key, returned by `KeyMaster::unlock`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnlockSession {
+    key_id: String,
+    expires_at: Instant,
+}
+
+impl UnlockSession {
+    pub(crate) fn new(key_id: &str, expires_at: Instant) -> Self {
+        Self {
+            key_id: key_id.to_string(),
+            expires_at,
+        }
+    }
+
+    /// id of the unlocked key
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// when the key is locked again
+    pub fn expires_at(&self) -> Instant {
+        self.expires_at
+    }
+
+    /// whether the session ran out, `KeyMaster::lock` can end it earlier
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
+
+/// page aligned buffer locked into RAM, it is wiped before it is freed
+struct LockedBytes {
+    ptr: *mut u8,
+    len: usize,
+    layout: Layout,
+}
+
+// the buffer is owned and only read through `&self`
+unsafe impl Send for LockedBytes {}
+unsafe impl Sync for LockedBytes {}
+
+impl LockedBytes {
+    fn new(bytes: &[u8]) -> Result<Self, CKMError> {
+        // whole pages so unlocking one buffer never unlocks another one sharing its page
+        let page = _page_size();
+        let size = bytes.len().max(1).div_ceil(page) * page;
+        let layout = Layout::from_size_align(size, page)
+            .map_err(|e| CKMError::MemoryLockError(e.to_string()))?;
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(CKMError::MemoryLockError("allocation failed".to_string()));
+        }
+        let locked = Self {
+            ptr,
+            len: bytes.len(),
+            layout,
+        };
+        _mlock(ptr, size)?;
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+        Ok(locked)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for LockedBytes {
+    fn drop(&mut self) {
+        unsafe {
+            std::slice::from_raw_parts_mut(self.ptr, self.layout.size()).zeroize();
+            _munlock(self.ptr, self.layout.size());
+            dealloc(self.ptr, self.layout);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn _page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as usize
+    } else {
+        4096
+    }
+}
+
+#[cfg(not(unix))]
+fn _page_size() -> usize {
+    4096
+}
+
+#[cfg(unix)]
+fn _mlock(ptr: *mut u8, size: usize) -> Result<(), CKMError> {
+    if unsafe { libc::mlock(ptr as *const libc::c_void, size) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(CKMError::MemoryLockError(e.to_string()));
+    }
+    Ok(())
+}
+
+/// memory locking is only available on unix, elsewhere the buffer is still wiped
+#[cfg(not(unix))]
+fn _mlock(_ptr: *mut u8, _size: usize) -> Result<(), CKMError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+unsafe fn _munlock(ptr: *mut u8, size: usize) {
+    libc::munlock(ptr as *const libc::c_void, size);
+}
+
+#[cfg(not(unix))]
+unsafe fn _munlock(_ptr: *mut u8, _size: usize) {}
+
+struct Unlocked {
+    key: LockedBytes,
+    expires_at: Instant,
+    generation: u64,
+    nodes: NodeCache,
+}
+
+/// least recently used intermediate BIP32 nodes of an unlocked key, keyed by their path,
+/// `ExtendedKey` wipes the private key when a node is evicted or the session ends
+#[derive(Default)]
+struct NodeCache {
+    nodes: BTreeMap<Vec<ChildNumber>, (ExtendedKey, u64)>,
+    tick: u64,
+}
+
+impl NodeCache {
+    fn get(&mut self, path: &[ChildNumber]) -> Option<ExtendedKey> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.nodes.get_mut(path).map(|(node, used)| {
+            *used = tick;
+            node.clone()
+        })
+    }
+
+    fn insert(&mut self, path: &[ChildNumber], node: &XPrv, capacity: usize) {
+        if !self.nodes.contains_key(path) && self.nodes.len() >= capacity {
+            let oldest = self
+                .nodes
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.nodes.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        let node = node.to_extended_key(Prefix::XPRV);
+        self.nodes.insert(path.to_vec(), (node, self.tick));
+    }
+}
+
+/// where a derivation starts, a cached node at a depth of the path or the seed
+enum DeriveFrom {
+    Node(usize, ExtendedKey),
+    Seed(Zeroizing<Vec<u8>>),
+}
+
+#[derive(Default)]
+struct SessionMap {
+    keys: HashMap<String, Unlocked>,
+    generation: u64,
+    reaping: bool,
+}
+
+/// unlocked keys of a key master, one reaper thread drops the keys when they expire and
+/// stops when no key is left. With a cache capacity the parents of the derived BIP32 keys
+/// are kept per session, so siblings only cost one child derivation
+#[derive(Default)]
+pub(crate) struct Sessions {
+    inner: Arc<(Mutex<SessionMap>, Condvar)>,
+    pub(crate) cache_capacity: usize,
+}
+
+impl Sessions {
+    pub(crate) fn unlock(
+        &self,
+        key_id: &str,
+        key: &[u8],
+        ttl: Duration,
+    ) -> Result<UnlockSession, CKMError> {
+        let expires_at = Instant::now() + ttl.min(MAX_TTL);
+        let key = LockedBytes::new(key)?;
+        let mut map = self._map();
+        map.generation += 1;
+        let unlocked = Unlocked {
+            key,
+            expires_at,
+            generation: map.generation,
+            nodes: NodeCache::default(),
+        };
+        map.keys.insert(key_id.to_string(), unlocked);
+        if map.reaping {
+            // the new session may expire before the one the reaper waits for
+            self.inner.1.notify_one();
+        } else {
+            map.reaping = true;
+            let inner = self.inner.clone();
+            thread::spawn(move || _reap(&inner));
+        }
+        Ok(UnlockSession::new(key_id, expires_at))
+    }
+
+    /// secp256k1 key of the path from the cached parent nodes, `None` when the key is not
+    /// unlocked or the cache is off
+    fn derive_k1(&self, key_id: &str, path: &str) -> Option<Result<Zeroizing<Vec<u8>>, CKMError>> {
+        if self.cache_capacity == 0 {
+            return None;
+        }
+        let path = match parse_path(path) {
+            Ok(path) => path,
+            Err(e) => return Some(Err(e)),
+        };
+        let children: Vec<ChildNumber> = path.iter().collect();
+        let parent = &children[..children.len().saturating_sub(1)];
+
+        // the deepest cached ancestor of the parent, only read under the lock
+        let (generation, from) = {
+            let mut map = self._map();
+            let unlocked = match map.keys.get_mut(key_id) {
+                Some(unlocked) if Instant::now() < unlocked.expires_at => unlocked,
+                _ => return None,
+            };
+            let cached = (0..=parent.len()).rev().find_map(|depth| {
+                unlocked
+                    .nodes
+                    .get(&parent[..depth])
+                    .map(|node| DeriveFrom::Node(depth, node))
+            });
+            let from = cached.unwrap_or_else(|| {
+                DeriveFrom::Seed(Zeroizing::new(unlocked.key.as_slice().to_vec()))
+            });
+            (unlocked.generation, from)
+        };
+
+        Some(self._derive_k1(key_id, generation, from, &children))
+    }
+
+    fn _derive_k1(
+        &self,
+        key_id: &str,
+        generation: u64,
+        from: DeriveFrom,
+        children: &[ChildNumber],
+    ) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        let parent = &children[..children.len().saturating_sub(1)];
+        let (depth, mut node) = match from {
+            DeriveFrom::Node(depth, node) => (
+                depth,
+                XPrv::try_from(node).map_err(|e| CKMError::crypto("bip32 node failed", e))?,
+            ),
+            DeriveFrom::Seed(key) => (0, master_node(&decode_seed(key)?)?),
+        };
+        for child in &parent[depth..] {
+            node = node
+                .derive_child(*child)
+                .map_err(|e| CKMError::crypto("bip32 derivation failed", e))?;
+        }
+        if depth < parent.len() {
+            let mut map = self._map();
+            if let Some(unlocked) = map.keys.get_mut(key_id) {
+                if unlocked.generation == generation {
+                    unlocked.nodes.insert(parent, &node, self.cache_capacity);
+                }
+            }
+        }
+        if let Some(child) = children.last() {
+            node = node
+                .derive_child(*child)
+                .map_err(|e| CKMError::crypto("bip32 derivation failed", e))?;
+        }
+        Ok(Zeroizing::new(node.private_key().to_bytes().to_vec()))
+    }
+
+    pub(crate) fn lock(&self, key_id: &str) -> bool {
+        self._map().keys.remove(key_id).is_some()
+    }
+
+    pub(crate) fn lock_all(&self) {
+        self._map().keys.clear();
+    }
+
+    pub(crate) fn is_unlocked(&self, key_id: &str) -> bool {
+        self._key(key_id, |_key| ()).is_some()
+    }
+
+    /// run `f` on the key of a live session
+    fn _key<T>(&self, key_id: &str, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        let mut map = self._map();
+        match map.keys.get(key_id) {
+            Some(unlocked) if Instant::now() < unlocked.expires_at => {
+                Some(f(unlocked.key.as_slice()))
+            }
+            Some(_) => {
+                map.keys.remove(key_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn _map(&self) -> MutexGuard<'_, SessionMap> {
+        self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Sessions {
+    /// wipe the keys now and let the reaper stop
+    fn drop(&mut self) {
+        self.lock_all();
+        self.inner.1.notify_one();
+    }
+}
+
+/// drop the expired keys until none is left, sleeping until the next expiry or unlock
+fn _reap(inner: &(Mutex<SessionMap>, Condvar)) {
+    let (map, changed) = inner;
+    let mut map = map.lock().unwrap_or_else(|e| e.into_inner());
+    loop {
+        let now = Instant::now();
+        map.keys.retain(|_, unlocked| now < unlocked.expires_at);
+        let next = match map.keys.values().map(|unlocked| unlocked.expires_at).min() {
+            Some(next) => next,
+            None => {
+                map.reaping = false;
+                return;
+            }
+        };
+        map = changed
+            .wait_timeout(map, next - now)
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+    }
+}
+
+/// the store as seen through the sessions, unlocked keys are read without the password
+pub(crate) struct SessionStore<'a, Store> {
+    pub(crate) store: &'a Store,
+    pub(crate) sessions: &'a Sessions,
+}
+
+impl<'a, Store: Keystore> KeySource for SessionStore<'a, Store> {
+    fn read_seed(&self, password: &str, key_id: &str) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        match self
+            .sessions
+            ._key(key_id, |key| Zeroizing::new(key.to_vec()))
+        {
+            Some(key) => decode_seed(key),
+            None => self.store.read_seed(password, key_id),
+        }
+    }
+
+    fn derive_k1(
+        &self,
+        password: &str,
+        key_id: &str,
+        path: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, CKMError> {
+        match self.sessions.derive_k1(key_id, path) {
+            Some(key) => key,
+            None => {
+                let seed = self.read_seed(password, key_id)?;
+                Ok(Zeroizing::new(crate::curve::k1::derive_from_seed(
+                    &seed, path,
+                )?))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::fake::SEED;
+    use std::sync::Weak;
+
+    #[test]
+    fn test_locked_bytes() {
+        let key = LockedBytes::new(b"secret seed").unwrap();
+        assert_eq!(key.as_slice(), b"secret seed");
+        assert_eq!(key.ptr as usize % _page_size(), 0);
+        assert_eq!(key.layout.size() % _page_size(), 0);
+        let empty = LockedBytes::new(b"").unwrap();
+        assert!(empty.as_slice().is_empty());
+    }
+
+    #[test]
+    fn test_derivation_cache() {
+        let seed = SEED;
+        let seed_bytes = hex::decode(seed).unwrap();
+        let mut sessions = Sessions::default();
+        sessions
+            .unlock("a", &seed_bytes, Duration::from_secs(60))
+            .unwrap();
+        // off by default
+        assert!(sessions.derive_k1("a", "m/84'/0'/0'/0/0").is_none());
+
+        sessions.cache_capacity = 2;
+        assert!(sessions.derive_k1("b", "m/84'/0'/0'/0/0").is_none());
+        let paths = [
+            "m/84'/0'/0'/0/0",
+            "m/84'/0'/0'/0/1",
+            "m/84'/0'/0'/1/7",
+            "m/84'/0'/0'/0/2",
+            "m/44'/0'/0'/0/0",
+            "m/84'/0'/0'/0/3",
+            "m",
+            "m/0",
+        ];
+        for path in paths.iter() {
+            let key = sessions.derive_k1("a", path).unwrap().unwrap();
+            let expected = crate::curve::k1::derive_from_seed(&seed_bytes, path).unwrap();
+            assert_eq!(*key, expected, "{}", path);
+        }
+        let cached = |sessions: &Sessions| -> Vec<String> {
+            let map = sessions._map();
+            map.keys["a"]
+                .nodes
+                .nodes
+                .keys()
+                .map(|path| {
+                    path.iter()
+                        .map(|c| c.to_string())
+                        .collect::<Vec<_>>()
+                        .join("/")
+                })
+                .collect()
+        };
+        // bounded, the least recently used parent `84'/0'/0'/1` was evicted
+        assert_eq!(cached(&sessions), vec!["44'/0'/0'/0", "84'/0'/0'/0"]);
+        assert!(matches!(
+            sessions.derive_k1("a", "m/x"),
+            Some(Err(CKMError::InvalidPath { .. }))
+        ));
+
+        sessions.lock("a");
+        assert!(sessions.derive_k1("a", "m/84'/0'/0'/0/0").is_none());
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let sessions = Sessions::default();
+        let session = sessions
+            .unlock("a", b"seed a", Duration::from_millis(100))
+            .unwrap();
+        sessions
+            .unlock("b", b"seed b", Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(session.key_id(), "a");
+        assert!(!session.is_expired());
+        assert!(sessions.is_unlocked("a"));
+
+        thread::sleep(Duration::from_millis(300));
+        assert!(session.is_expired());
+        // the reaper dropped the key without any access
+        assert!(!sessions._map().keys.contains_key("a"));
+        assert!(sessions.is_unlocked("b"));
+
+        // unlocking again outlives the expiry of the first session
+        sessions
+            .unlock("a", b"seed a", Duration::from_millis(100))
+            .unwrap();
+        sessions
+            .unlock("a", b"seed a", Duration::from_secs(60))
+            .unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(sessions.is_unlocked("a"));
+
+        assert!(sessions.lock("a"));
+        assert!(!sessions.lock("a"));
+        sessions.lock_all();
+        assert!(!sessions.is_unlocked("b"));
+    }
+
+    #[test]
+    fn test_sessions_reaper() {
+        let sessions = Sessions::default();
+        let session = sessions.unlock("a", b"seed a", Duration::MAX).unwrap();
+        assert!(session.expires_at() <= Instant::now() + MAX_TTL);
+        assert!(session.expires_at() > Instant::now() + MAX_TTL - Duration::from_secs(60));
+        for key_id in ["a", "b", "c"].iter() {
+            sessions
+                .unlock(key_id, b"seed", Duration::from_secs(3600))
+                .unwrap();
+        }
+        // one reaper for all the sessions
+        assert!(sessions._map().reaping);
+        assert_eq!(Arc::strong_count(&sessions.inner), 2);
+
+        // a shorter session wakes the reaper up
+        sessions
+            .unlock("d", b"seed d", Duration::from_millis(100))
+            .unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!sessions._map().keys.contains_key("d"));
+
+        // it stops with the sessions instead of sleeping out the ttl
+        let inner: Weak<_> = Arc::downgrade(&sessions.inner);
+        drop(sessions);
+        thread::sleep(Duration::from_millis(200));
+        assert!(inner.upgrade().is_none());
+    }
+}
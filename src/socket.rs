@@ -0,0 +1,93 @@
+//! helpers of the servers listening on Unix sockets
+use crate::CKMError;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// effective user id of this process
+pub(crate) fn euid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+/// remove the socket of a server that is gone, a live socket or another file is an error
+pub(crate) fn remove_stale_socket(path: &Path) -> Result<(), CKMError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(CKMError::io(path, e)),
+    };
+    let in_use = |message: &str| {
+        CKMError::io(
+            path,
+            std::io::Error::new(std::io::ErrorKind::AddrInUse, message.to_string()),
+        )
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(in_use("not a socket"));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(in_use("another server is listening"));
+    }
+    fs::remove_file(path).map_err(|e| CKMError::io(path, e))
+}
+
+/// user id of the process on the other end of the socket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// user id of the process on the other end of the socket
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// without peer credentials every connection is refused
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+pub(crate) fn peer_uid(_stream: &UnixStream) -> std::io::Result<u32> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "peer credentials are not supported",
+    ))
+}
@@ -0,0 +1,390 @@
+use super::*;
+use crate::connection::{retry_accept, ConnectionLimit, MAX_CONNECTIONS};
+use crate::socket::{euid, peer_uid, remove_stale_socket};
+use crate::{KeyMaster, Keystore, SignRequest};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// message numbers of draft-miller-ssh-agent
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+/// longest message read, like OpenSSH
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+/// connections without a request for this long are closed, OpenSSH opens one per use
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Identity {
+    key_id: String,
+    path: String,
+    curve: Curve,
+    blob: Vec<u8>,
+    comment: String,
+}
+
+/// ssh-agent on a Unix socket serving keys derived by a `KeyMaster`, only the user running
+/// the agent and root may connect. The keys sign while they are unlocked in the key master,
+/// sign requests of a locked key fail like those of a locked agent
+pub struct SshAgent<Store> {
+    listener: UnixListener,
+    path: PathBuf,
+    key_master: Arc<KeyMaster<Store>>,
+    identities: Vec<Identity>,
+    limit: ConnectionLimit,
+}
+
+impl<Store: Keystore + 'static> SshAgent<Store> {
+    /// listen on the socket path with mode 0600, a socket left behind by an agent that is gone
+    /// is replaced
+    pub fn bind<P: AsRef<Path>>(path: P, key_master: KeyMaster<Store>) -> Result<Self, CKMError> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path).map_err(|e| CKMError::io(&path, e))?;
+        let agent = Self {
+            listener,
+            path,
+            key_master: Arc::new(key_master),
+            identities: Vec::new(),
+            limit: ConnectionLimit::new(MAX_CONNECTIONS),
+        };
+        fs::set_permissions(&agent.path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| CKMError::io(&agent.path, e))?;
+        Ok(agent)
+    }
+
+    /// serve the Ed25519 or Secp256R1 key of the path, the password reads the public key. The
+    /// comment of the identity is `<key_id>:<path>`
+    pub fn add_identity(
+        &mut self,
+        key_id: &str,
+        path: &str,
+        curve: Curve,
+        password: &str,
+    ) -> Result<(), CKMError> {
+        key_type(curve)?;
+        let public_key = self
+            .key_master
+            .get_public_key(key_id, path, curve, password)?;
+        self.identities.push(Identity {
+            key_id: key_id.to_string(),
+            path: path.to_string(),
+            curve,
+            blob: public_key_blob(curve, &public_key)?,
+            comment: format!("{}:{}", key_id, path),
+        });
+        Ok(())
+    }
+
+    /// handle at most `max` connections at once, 64 by default, further clients wait in the
+    /// listen backlog until a connection ends
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limit = ConnectionLimit::new(max);
+        self
+    }
+
+    /// the key master, to unlock and lock the keys of the identities
+    pub fn key_master(&self) -> &KeyMaster<Store> {
+        &self.key_master
+    }
+
+    /// path of the socket, the value of `SSH_AUTH_SOCK`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// serve connections until the listener fails, each connection runs on its own thread. A
+    /// connection is only accepted while fewer than the maximum are handled, and closed when it
+    /// is idle for 5 minutes. Like the daemon, it logs transient accept errors and keeps going
+    pub fn serve(mut self) -> Result<(), CKMError> {
+        let identities = Arc::new(std::mem::take(&mut self.identities));
+        loop {
+            let permit = self.limit.acquire();
+            let (stream, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if retry_accept("ckm ssh agent", &e) => continue,
+                Err(e) => return Err(CKMError::io(&self.path, e)),
+            };
+            let identities = identities.clone();
+            let key_master = self.key_master.clone();
+            thread::spawn(move || {
+                let _permit = permit;
+                // broken connections only affect their client
+                let _ = _handle(stream, &identities, &key_master);
+            });
+        }
+    }
+}
+
+impl<Store> Drop for SshAgent<Store> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn _handle<Store: Keystore>(
+    mut stream: UnixStream,
+    identities: &[Identity],
+    key_master: &KeyMaster<Store>,
+) -> Result<(), CKMError> {
+    stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .map_err(_io_error)?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(_io_error)?;
+    let uid = peer_uid(&stream).map_err(_io_error)?;
+    if uid != euid() && uid != 0 {
+        return Ok(());
+    }
+    loop {
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result.map_err(_io_error)?,
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_MESSAGE_SIZE {
+            return Err(CKMError::SshError("invalid message length".to_string()));
+        }
+        let mut message = vec![0u8; len];
+        stream.read_exact(&mut message).map_err(_io_error)?;
+
+        let response =
+            _respond(&message, identities, key_master).unwrap_or_else(|_e| vec![SSH_AGENT_FAILURE]);
+        let mut framed = SshWriter::default();
+        framed.string(&response);
+        stream.write_all(&framed.into_bytes()).map_err(_io_error)?;
+    }
+}
+
+fn _respond<Store: Keystore>(
+    message: &[u8],
+    identities: &[Identity],
+    key_master: &KeyMaster<Store>,
+) -> Result<Vec<u8>, CKMError> {
+    let mut reader = SshReader::new(message);
+    let mut response = SshWriter::default();
+    match reader.u8()? {
+        SSH_AGENTC_REQUEST_IDENTITIES => {
+            response.u8(SSH_AGENT_IDENTITIES_ANSWER);
+            response.u32(identities.len() as u32);
+            for identity in identities {
+                response.string(&identity.blob);
+                response.string(identity.comment.as_bytes());
+            }
+        }
+        SSH_AGENTC_SIGN_REQUEST => {
+            let blob = reader.string()?;
+            let data = reader.string()?;
+            // the flags only select RSA hashes
+            let _flags = reader.u32()?;
+            let identity = identities
+                .iter()
+                .find(|identity| identity.blob == blob)
+                .ok_or(CKMError::NotExist)?;
+            let request = SignRequest {
+                path: &identity.path,
+                unsigend_data: data.to_vec(),
+                key_id: &identity.key_id,
+                curve: identity.curve,
+            };
+            let sig = key_master.sign(request, "")?;
+            response.u8(SSH_AGENT_SIGN_RESPONSE);
+            response.string(&signature_blob(identity.curve, &sig)?);
+        }
+        _ => response.u8(SSH_AGENT_FAILURE),
+    }
+    Ok(response.into_bytes())
+}
+
+fn _io_error(e: std::io::Error) -> CKMError {
+    CKMError::SshError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::new_key_id;
+    use crate::{verify_signature, MemoryKeystore};
+    use bitcoin::base64::{prelude::BASE64_STANDARD, Engine};
+    use std::process::Command;
+
+    use crate::keystore::fake::SEED;
+    const PATH: &str = "m/44'/22'/0'/0'/0'";
+    const CURVES: [Curve; 2] = [Curve::Ed25519, Curve::Secp256R1];
+
+    /// agent serving the Ed25519 and P-256 keys of `SEED`, returns the socket and the
+    /// `authorized_keys` lines of the identities
+    fn start(unlock: bool) -> (PathBuf, Vec<String>) {
+        start_with(unlock, MAX_CONNECTIONS)
+    }
+
+    fn start_with(unlock: bool, max_connections: usize) -> (PathBuf, Vec<String>) {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        let path = std::env::temp_dir().join(format!("ckm-{}.sock", new_key_id().unwrap()));
+        let mut agent = SshAgent::bind(&path, key_master).unwrap();
+        let mut lines = Vec::new();
+        for curve in CURVES {
+            agent.add_identity(&key_id, PATH, curve, "123").unwrap();
+            let line = agent
+                .key_master()
+                .get_ssh_public_key(&key_id, PATH, curve, "123")
+                .unwrap();
+            lines.push(line);
+        }
+        assert!(matches!(
+            agent.add_identity(&key_id, PATH, Curve::Secp256k1, "123"),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        if unlock {
+            agent
+                .key_master()
+                .unlock(&key_id, "123", Duration::from_secs(60))
+                .unwrap();
+        }
+        let agent = agent.with_max_connections(max_connections);
+        thread::spawn(move || agent.serve());
+        (path, lines)
+    }
+
+    fn call(stream: &mut UnixStream, message: &[u8]) -> Vec<u8> {
+        let mut framed = SshWriter::default();
+        framed.string(message);
+        stream.write_all(&framed.into_bytes()).unwrap();
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut response = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response).unwrap();
+        response
+    }
+
+    fn sign_request(blob: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut request = SshWriter::default();
+        request.u8(SSH_AGENTC_SIGN_REQUEST);
+        request.string(blob);
+        request.string(data);
+        request.u32(0);
+        request.into_bytes()
+    }
+
+    #[test]
+    fn test_agent_protocol() {
+        let (path, lines) = start(true);
+        let mut stream = UnixStream::connect(&path).unwrap();
+
+        let response = call(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]);
+        let mut reader = SshReader::new(&response);
+        assert_eq!(reader.u8().unwrap(), SSH_AGENT_IDENTITIES_ANSWER);
+        assert_eq!(reader.u32().unwrap(), 2);
+        for (curve, line) in CURVES.iter().zip(&lines) {
+            let blob = reader.string().unwrap();
+            let comment = std::str::from_utf8(reader.string().unwrap()).unwrap();
+            let encoded = BASE64_STANDARD.encode(blob);
+            assert!(line.contains(&encoded) && line.ends_with(comment));
+
+            let response = call(&mut stream, &sign_request(blob, b"hello"));
+            let mut sign_response = SshReader::new(&response);
+            assert_eq!(sign_response.u8().unwrap(), SSH_AGENT_SIGN_RESPONSE);
+            let sig = parse_signature_blob(*curve, sign_response.string().unwrap()).unwrap();
+            let (key_curve, public_key) = parse_public_key_blob(blob).unwrap();
+            assert_eq!(key_curve, *curve);
+            assert!(verify_signature(*curve, &public_key, b"hello", &sig).unwrap());
+        }
+
+        // unknown keys and requests fail, the connection stays usable
+        let response = call(&mut stream, &sign_request(b"unknown", b"hello"));
+        assert_eq!(response, [SSH_AGENT_FAILURE]);
+        assert_eq!(call(&mut stream, &[17]), [SSH_AGENT_FAILURE]);
+        let response = call(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]);
+        assert_eq!(response[0], SSH_AGENT_IDENTITIES_ANSWER);
+    }
+
+    #[test]
+    fn test_locked_agent() {
+        let (path, _lines) = start(false);
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let response = call(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]);
+        let mut reader = SshReader::new(&response);
+        reader.u8().unwrap();
+        reader.u32().unwrap();
+        let blob = reader.string().unwrap();
+        let response = call(&mut stream, &sign_request(blob, b"hello"));
+        assert_eq!(response, [SSH_AGENT_FAILURE]);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let (path, _lines) = start_with(true, 1);
+        // an idle connection holds the only slot, the next client waits for it
+        let idle = UnixStream::connect(&path).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            sender
+                .send(call(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]))
+                .unwrap()
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+        drop(idle);
+        let response = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(response[0], SSH_AGENT_IDENTITIES_ANSWER);
+    }
+
+    /// `ssh-add -L` and `ssh-keygen -Y sign` of OpenSSH, skipped when they are not installed
+    #[test]
+    fn test_openssh_client() {
+        if Command::new("ssh-add").arg("-h").output().is_err() {
+            return;
+        }
+        let (path, lines) = start(true);
+        let output = Command::new("ssh-add")
+            .arg("-L")
+            .env("SSH_AUTH_SOCK", &path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            lines.join("\n") + "\n"
+        );
+
+        let dir = std::env::temp_dir().join(format!("ckm-ssh-{}", new_key_id().unwrap()));
+        fs::create_dir(&dir).unwrap();
+        let data = dir.join("data");
+        fs::write(&data, b"hello").unwrap();
+        let allowed_signers = dir.join("allowed_signers");
+        for line in &lines {
+            let key = dir.join("id.pub");
+            fs::write(&key, format!("{}\n", line)).unwrap();
+            let status = Command::new("ssh-keygen")
+                .args(["-q", "-Y", "sign", "-n", "file", "-f"])
+                .arg(&key)
+                .arg(&data)
+                .env("SSH_AUTH_SOCK", &path)
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            fs::write(&allowed_signers, format!("ckm {}\n", line)).unwrap();
+            let output = Command::new("ssh-keygen")
+                .args(["-Y", "verify", "-n", "file", "-I", "ckm", "-f"])
+                .arg(&allowed_signers)
+                .arg("-s")
+                .arg(dir.join("data.sig"))
+                .stdin(fs::File::open(&data).unwrap())
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            fs::remove_file(dir.join("data.sig")).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
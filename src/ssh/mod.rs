@@ -0,0 +1,317 @@
+//! OpenSSH support for the Ed25519 and ecdsa-sha2-nistp256 keys derived by a `KeyMaster`: an
+//! ssh-agent serving them on a Unix socket, `authorized_keys` and private key files, SSHSIG
+use crate::curve::r1::r1_uncompressed;
+use crate::{CKMError, Curve, SigningSignature};
+use bitcoin::base64::{prelude::BASE64_STANDARD, Engine};
+
+#[cfg(unix)]
+mod agent;
+pub(crate) mod key;
+pub(crate) mod sshsig;
+
+#[cfg(unix)]
+pub use agent::SshAgent;
+pub use sshsig::verify_sshsig;
+
+/// line width of the base64 in the PEM-like files of OpenSSH
+const ARMOR_WIDTH: usize = 70;
+
+/// writer of the SSH wire encoding of RFC 4251
+#[derive(Default)]
+pub(crate) struct SshWriter {
+    buf: Vec<u8>,
+}
+
+impl SshWriter {
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn string(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+    }
+
+    /// non-negative mpint of big endian bytes
+    pub(crate) fn mpint(&mut self, value: &[u8]) {
+        let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+        let value = &value[start..];
+        if matches!(value.first(), Some(b) if b & 0x80 != 0) {
+            self.u32(value.len() as u32 + 1);
+            self.buf.push(0);
+            self.buf.extend_from_slice(value);
+        } else {
+            self.string(value);
+        }
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl zeroize::Zeroize for SshWriter {
+    fn zeroize(&mut self) {
+        self.buf.zeroize();
+    }
+}
+
+/// reader of the SSH wire encoding of RFC 4251
+pub(crate) struct SshReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SshReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn _take(&mut self, len: usize) -> Result<&'a [u8], CKMError> {
+        if self.data.len() < len {
+            return Err(CKMError::SshError("truncated message".to_string()));
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, CKMError> {
+        Ok(self._take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, CKMError> {
+        let bytes = self._take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<&'a [u8], CKMError> {
+        let len = self.u32()? as usize;
+        self._take(len)
+    }
+}
+
+/// SSH name of the key algorithm of the curve
+pub(crate) fn key_type(curve: Curve) -> Result<&'static str, CKMError> {
+    match curve {
+        Curve::Ed25519 => Ok("ssh-ed25519"),
+        Curve::Secp256R1 => Ok("ecdsa-sha2-nistp256"),
+        _ => Err(CKMError::UnsupportedCurve),
+    }
+}
+
+/// public key blob of a key of `KeyMaster::get_public_key`
+pub(crate) fn public_key_blob(curve: Curve, public_key: &[u8]) -> Result<Vec<u8>, CKMError> {
+    let mut blob = SshWriter::default();
+    blob.string(key_type(curve)?.as_bytes());
+    if curve == Curve::Secp256R1 {
+        let point = r1_uncompressed(public_key)
+            .ok_or_else(|| CKMError::SshError("invalid P-256 public key".to_string()))?;
+        blob.string(b"nistp256");
+        blob.string(&point);
+    } else {
+        blob.string(public_key);
+    }
+    Ok(blob.into_bytes())
+}
+
+/// curve and public key of a public key blob, the P-256 point stays uncompressed
+pub(crate) fn parse_public_key_blob(blob: &[u8]) -> Result<(Curve, Vec<u8>), CKMError> {
+    let mut reader = SshReader::new(blob);
+    let curve = match reader.string()? {
+        b"ssh-ed25519" => Curve::Ed25519,
+        b"ecdsa-sha2-nistp256" => {
+            if reader.string()? != b"nistp256" {
+                return Err(CKMError::SshError("unknown ECDSA curve".to_string()));
+            }
+            Curve::Secp256R1
+        }
+        _ => return Err(CKMError::UnsupportedCurve),
+    };
+    Ok((curve, reader.string()?.to_vec()))
+}
+
+/// `authorized_keys` line of a public key, without a comment when it is empty
+pub(crate) fn authorized_key(
+    curve: Curve,
+    public_key: &[u8],
+    comment: &str,
+) -> Result<String, CKMError> {
+    let line = format!(
+        "{} {}",
+        key_type(curve)?,
+        BASE64_STANDARD.encode(public_key_blob(curve, public_key)?)
+    );
+    match comment {
+        "" => Ok(line),
+        _ => Ok(format!("{} {}", line, comment)),
+    }
+}
+
+/// public key blob of an `authorized_keys` line, options before the key type are not supported
+pub(crate) fn parse_authorized_key(line: &str) -> Result<Vec<u8>, CKMError> {
+    let mut fields = line.split_whitespace();
+    let (key_type, encoded) = match (fields.next(), fields.next()) {
+        (Some(key_type), Some(encoded)) => (key_type, encoded),
+        _ => return Err(CKMError::SshError("invalid public key line".to_string())),
+    };
+    let blob = BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|e| CKMError::SshError(format!("public key: {}", e)))?;
+    if SshReader::new(&blob).string()? != key_type.as_bytes() {
+        return Err(CKMError::SshError(
+            "key type does not match the key".to_string(),
+        ));
+    }
+    Ok(blob)
+}
+
+/// signature blob of a signature of `KeyMaster::sign`, ECDSA signs the SHA-256 of the data
+pub(crate) fn signature_blob(curve: Curve, sig: &SigningSignature) -> Result<Vec<u8>, CKMError> {
+    let mut blob = SshWriter::default();
+    blob.string(key_type(curve)?.as_bytes());
+    if curve == Curve::Secp256R1 {
+        let mut rs = SshWriter::default();
+        rs.mpint(&sig.r);
+        rs.mpint(&sig.s);
+        blob.string(&rs.into_bytes());
+    } else {
+        blob.string(&sig.to_compact());
+    }
+    Ok(blob.into_bytes())
+}
+
+/// signature of a signature blob of the curve
+pub(crate) fn parse_signature_blob(
+    curve: Curve,
+    blob: &[u8],
+) -> Result<SigningSignature, CKMError> {
+    let mut reader = SshReader::new(blob);
+    if reader.string()? != key_type(curve)?.as_bytes() {
+        return Err(CKMError::SshError(
+            "signature of another key type".to_string(),
+        ));
+    }
+    let sig = reader.string()?;
+    if curve == Curve::Ed25519 {
+        return SigningSignature::from_compact(sig);
+    }
+    let mut rs = SshReader::new(sig);
+    let mut compact = [0u8; 64];
+    for half in compact.chunks_mut(32) {
+        let value = rs.string()?;
+        let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+        let value = &value[start..];
+        if value.len() > 32 {
+            return Err(CKMError::SshError(
+                "ECDSA signature value too long".to_string(),
+            ));
+        }
+        half[32 - value.len()..].copy_from_slice(value);
+    }
+    SigningSignature::from_compact(&compact)
+}
+
+/// `-----BEGIN <label>-----` armor of the bytes, as written by OpenSSH
+pub(crate) fn armor(label: &str, bytes: &[u8]) -> String {
+    // private key files go through here, leave no copy behind
+    let encoded = zeroize::Zeroizing::new(BASE64_STANDARD.encode(bytes));
+    let mut armored = String::with_capacity(encoded.len() * 72 / 70 + 2 * label.len() + 40);
+    armored.push_str(&format!("-----BEGIN {}-----\n", label));
+    for line in encoded.as_bytes().chunks(ARMOR_WIDTH) {
+        armored.push_str(std::str::from_utf8(line).unwrap());
+        armored.push('\n');
+    }
+    armored.push_str(&format!("-----END {}-----\n", label));
+    armored
+}
+
+/// bytes of an `armor`
+pub(crate) fn dearmor(label: &str, armored: &str) -> Result<Vec<u8>, CKMError> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let body = armored
+        .trim()
+        .strip_prefix(&begin)
+        .and_then(|body| body.strip_suffix(&end))
+        .ok_or_else(|| CKMError::SshError(format!("not an armored {}", label)))?;
+    let encoded: String = body.split_whitespace().collect();
+    BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|e| CKMError::SshError(format!("{}: {}", label, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_encoding() {
+        // RFC 4251 section 5 examples
+        let mut writer = SshWriter::default();
+        writer.mpint(&[0, 0]);
+        writer.mpint(&[0x80]);
+        writer.mpint(&[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]);
+        writer.string(b"testing");
+        let bytes = writer.into_bytes();
+        assert_eq!(
+            hex::encode(&bytes),
+            concat!(
+                "00000000",
+                "000000020080",
+                "0000000809a378f9b2e332a7",
+                "0000000774657374696e67"
+            )
+        );
+
+        let mut reader = SshReader::new(&bytes);
+        assert_eq!(reader.u32().unwrap(), 0);
+        assert_eq!(reader.string().unwrap(), [0, 0x80]);
+        assert_eq!(reader.string().unwrap().len(), 8);
+        assert_eq!(reader.string().unwrap(), b"testing");
+        assert!(matches!(reader.u8(), Err(CKMError::SshError(_))));
+    }
+
+    #[test]
+    fn test_key_blobs() {
+        assert!(matches!(
+            public_key_blob(Curve::Secp256k1, &[2u8; 33]),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(public_key_blob(Curve::Secp256R1, &[5u8; 33]).is_err());
+
+        let blob = public_key_blob(Curve::Ed25519, &[7u8; 32]).unwrap();
+        let mut reader = SshReader::new(&blob);
+        assert_eq!(reader.string().unwrap(), b"ssh-ed25519");
+        assert_eq!(reader.string().unwrap(), [7u8; 32]);
+
+        // the generator of P-256
+        let generator =
+            hex::decode("036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296")
+                .unwrap();
+        let blob = public_key_blob(Curve::Secp256R1, &generator).unwrap();
+        let mut reader = SshReader::new(&blob);
+        assert_eq!(reader.string().unwrap(), b"ecdsa-sha2-nistp256");
+        assert_eq!(reader.string().unwrap(), b"nistp256");
+        let point = reader.string().unwrap();
+        assert_eq!((point.len(), point[0]), (65, 4));
+        assert_eq!(point[1..33], generator[1..]);
+        let (curve, public_key) = parse_public_key_blob(&blob).unwrap();
+        assert_eq!((curve, public_key.as_slice()), (Curve::Secp256R1, point));
+
+        let line = authorized_key(Curve::Secp256R1, &generator, "ckm").unwrap();
+        assert!(
+            line.starts_with("ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTY")
+                && line.ends_with(" ckm")
+        );
+        assert_eq!(parse_authorized_key(&line).unwrap(), blob);
+        let line = line.replacen("ecdsa-sha2-nistp256", "ssh-ed25519", 1);
+        assert!(matches!(
+            parse_authorized_key(&line),
+            Err(CKMError::SshError(_))
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! SSHSIG signatures of OpenSSH's PROTOCOL.sshsig, as made by `ssh-keygen -Y sign` and used by
+//! git with `gpg.format=ssh`
+use super::{
+    armor, dearmor, parse_authorized_key, parse_public_key_blob, parse_signature_blob,
+    public_key_blob, signature_blob, SshReader, SshWriter,
+};
+use crate::{verify_signature, CKMError, Curve, SigningSignature};
+use sha2::{Digest, Sha256, Sha512};
+
+const MAGIC: &[u8] = b"SSHSIG";
+const VERSION: u32 = 1;
+const LABEL: &str = "SSH SIGNATURE";
+/// hash algorithm of `ssh-keygen`
+const HASH_ALGORITHM: &str = "sha512";
+
+/// what the key signs for the data in the namespace
+pub(crate) fn signed_data(namespace: &str, data: &[u8]) -> Result<Vec<u8>, CKMError> {
+    _signed_data(namespace, HASH_ALGORITHM, data)
+}
+
+fn _signed_data(namespace: &str, hash_algorithm: &str, data: &[u8]) -> Result<Vec<u8>, CKMError> {
+    if namespace.is_empty() {
+        return Err(CKMError::SshError("empty namespace".to_string()));
+    }
+    let hash = match hash_algorithm {
+        "sha512" => Sha512::digest(data).to_vec(),
+        "sha256" => Sha256::digest(data).to_vec(),
+        _ => {
+            return Err(CKMError::SshError(format!(
+                "unknown hash algorithm {}",
+                hash_algorithm
+            )))
+        }
+    };
+    let mut signed = SshWriter::default();
+    signed.buf.extend_from_slice(MAGIC);
+    signed.string(namespace.as_bytes());
+    signed.string(b"");
+    signed.string(hash_algorithm.as_bytes());
+    signed.string(&hash);
+    Ok(signed.into_bytes())
+}
+
+/// armored signature of a signature of the `signed_data`
+pub(crate) fn signature(
+    curve: Curve,
+    public_key: &[u8],
+    namespace: &str,
+    sig: &SigningSignature,
+) -> Result<String, CKMError> {
+    let mut blob = SshWriter::default();
+    blob.buf.extend_from_slice(MAGIC);
+    blob.u32(VERSION);
+    blob.string(&public_key_blob(curve, public_key)?);
+    blob.string(namespace.as_bytes());
+    blob.string(b"");
+    blob.string(HASH_ALGORITHM.as_bytes());
+    blob.string(&signature_blob(curve, sig)?);
+    Ok(armor(LABEL, &blob.into_bytes()))
+}
+
+/// verify an armored SSHSIG signature of the data in the namespace for the public key of an
+/// `authorized_keys` line, like `ssh-keygen -Y verify`
+pub fn verify_sshsig(
+    public_key: &str,
+    namespace: &str,
+    data: &[u8],
+    signature: &str,
+) -> Result<bool, CKMError> {
+    let expected_blob = parse_authorized_key(public_key)?;
+    let blob = dearmor(LABEL, signature)?;
+    let mut reader = SshReader::new(&blob);
+    if reader._take(MAGIC.len())? != MAGIC || reader.u32()? != VERSION {
+        return Err(CKMError::SshError("not an SSHSIG signature".to_string()));
+    }
+    let key_blob = reader.string()?;
+    let signed_namespace = reader.string()?;
+    reader.string()?;
+    let hash_algorithm = std::str::from_utf8(reader.string()?)
+        .map_err(|_e| CKMError::SshError("invalid hash algorithm".to_string()))?;
+    let sig_blob = reader.string()?;
+    if key_blob != expected_blob.as_slice() || signed_namespace != namespace.as_bytes() {
+        return Ok(false);
+    }
+
+    let (curve, key) = parse_public_key_blob(key_blob)?;
+    let sig = parse_signature_blob(curve, sig_blob)?;
+    let signed = _signed_data(namespace, hash_algorithm, data)?;
+    verify_signature(curve, &key, &signed, &sig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyMaster, MemoryKeystore, SignRequest};
+    use std::process::Command;
+
+    use crate::keystore::fake::SEED;
+    const PATH: &str = "m/44'/22'/0'/0'/0'";
+    const CURVES: [Curve; 2] = [Curve::Ed25519, Curve::Secp256R1];
+
+    fn request<'a>(key_id: &'a str, curve: Curve, data: &[u8]) -> SignRequest<'a> {
+        SignRequest {
+            path: PATH,
+            unsigend_data: data.to_vec(),
+            key_id,
+            curve,
+        }
+    }
+
+    #[test]
+    fn test_sshsig() {
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        let lines: Vec<String> = CURVES
+            .iter()
+            .map(|curve| {
+                key_master
+                    .get_ssh_public_key(&key_id, PATH, *curve, "123")
+                    .unwrap()
+            })
+            .collect();
+        for (curve, line) in CURVES.iter().zip(&lines) {
+            let sig = key_master
+                .sign_sshsig(request(&key_id, *curve, b"hello"), "git", "123")
+                .unwrap();
+            assert!(sig.starts_with("-----BEGIN SSH SIGNATURE-----\n"));
+            assert!(verify_sshsig(line, "git", b"hello", &sig).unwrap());
+            assert!(!verify_sshsig(line, "file", b"hello", &sig).unwrap());
+            assert!(!verify_sshsig(line, "git", b"hellx", &sig).unwrap());
+            let other = lines.iter().find(|other| *other != line).unwrap();
+            assert!(!verify_sshsig(other, "git", b"hello", &sig).unwrap());
+            assert!(verify_sshsig(line, "git", b"hello", "garbage").is_err());
+        }
+
+        assert!(matches!(
+            key_master.sign_sshsig(request(&key_id, Curve::Secp256k1, b"hello"), "git", "123"),
+            Err(CKMError::UnsupportedCurve)
+        ));
+        assert!(matches!(
+            key_master.sign_sshsig(request(&key_id, Curve::Ed25519, b"hello"), "", "123"),
+            Err(CKMError::SshError(_))
+        ));
+    }
+
+    /// signatures of `ssh-keygen -Y sign` with the exported private keys verify here and the
+    /// key master's verify with `ssh-keygen -Y verify`, skipped when it is not installed
+    #[cfg(unix)]
+    #[test]
+    fn test_openssh_sshsig() {
+        use std::os::unix::fs::PermissionsExt;
+        if Command::new("ssh-keygen").arg("-?").output().is_err() {
+            return;
+        }
+        let key_master = KeyMaster::new(MemoryKeystore::new());
+        let key_id = key_master.write_seed("123", SEED.to_string()).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "ckm-sshsig-{}",
+            crate::keystore::new_key_id().unwrap()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        let (data, key, allowed_signers) = (dir.join("data"), dir.join("id"), dir.join("signers"));
+        std::fs::write(&data, b"hello").unwrap();
+        for curve in CURVES {
+            let line = key_master
+                .get_ssh_public_key(&key_id, PATH, curve, "123")
+                .unwrap();
+            std::fs::write(&allowed_signers, format!("ckm {}\n", line)).unwrap();
+
+            let sig = key_master
+                .sign_sshsig(request(&key_id, curve, b"hello"), "git", "123")
+                .unwrap();
+            std::fs::write(dir.join("data.sig"), &sig).unwrap();
+            let output = Command::new("ssh-keygen")
+                .args(["-Y", "verify", "-n", "git", "-I", "ckm", "-f"])
+                .arg(&allowed_signers)
+                .arg("-s")
+                .arg(dir.join("data.sig"))
+                .stdin(std::fs::File::open(&data).unwrap())
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            std::fs::remove_file(dir.join("data.sig")).unwrap();
+
+            let file = key_master
+                .export_ssh_private_key(&key_id, PATH, curve, "123", "")
+                .unwrap();
+            std::fs::write(&key, file.as_bytes()).unwrap();
+            std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600)).unwrap();
+            let status = Command::new("ssh-keygen")
+                .args(["-q", "-Y", "sign", "-n", "git", "-f"])
+                .arg(&key)
+                .arg(&data)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            let sig = std::fs::read_to_string(dir.join("data.sig")).unwrap();
+            assert!(verify_sshsig(&line, "git", b"hello", &sig).unwrap());
+            std::fs::remove_file(dir.join("data.sig")).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
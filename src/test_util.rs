@@ -0,0 +1,86 @@
+//! stand-in HTTP server of the tests of the keystores on HTTP APIs
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+/// request received by `serve_http`, the header names are lowercase
+pub(crate) struct MockRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl MockRequest {
+    /// value of the header, empty when it is missing
+    pub(crate) fn header(&self, name: &str) -> &str {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map_or("", |(_, value)| value.as_str())
+    }
+}
+
+/// serve one request per connection on a local port with the handler, which returns the status
+/// and body of the response, and return the `http://` address
+pub(crate) fn serve_http<H>(content_type: &'static str, handler: H) -> String
+where
+    H: Fn(MockRequest) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handler = handler.clone();
+            thread::spawn(move || _serve(stream.unwrap(), content_type, &*handler));
+        }
+    });
+    addr
+}
+
+fn _serve(
+    stream: TcpStream,
+    content_type: &str,
+    handler: &(dyn Fn(MockRequest) -> (u16, String) + Send + Sync),
+) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap().to_string();
+    let path = parts.next().unwrap().to_string();
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (key, value) = header.split_once(':').unwrap();
+        headers.push((key.to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let mut request = MockRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length = request.header("content-length").parse().unwrap_or(0);
+    request.body = vec![0u8; length];
+    reader.read_exact(&mut request.body).unwrap();
+
+    let (status, response) = handler(request);
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        response.len(),
+        response
+    )
+    .unwrap();
+}
@@ -0,0 +1,267 @@
+//! runs the `ckm` binary on a keystore directory with the `--json` output
+use crypto_key_master::*;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const SEED: &str = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
+
+/// the pbkdf2 test vector of the Web3 Secret Storage definition, password "testpassword"
+const WEB3_KEYSTORE: &str = r#"{
+    "version": 3,
+    "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+    "address": "008aeeda4d805471df9b2a5b0f38a0c3bcba786b",
+    "crypto": {
+        "cipher": "aes-128-ctr",
+        "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+        "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+        "kdf": "pbkdf2",
+        "kdfparams": {
+            "c": 262144,
+            "dklen": 32,
+            "prf": "hmac-sha256",
+            "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+        },
+        "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+    }
+}"#;
+
+/// keystore directory of one test with the password files `pw` ("123") and `web3-pw`
+struct Ckm {
+    dir: PathBuf,
+}
+
+impl Ckm {
+    fn new(name: &str) -> Self {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+            "ckm-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("pw"), "123\n").unwrap();
+        fs::write(dir.join("web3-pw"), "testpassword\n").unwrap();
+        Self { dir }
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).display().to_string()
+    }
+
+    /// exit code and JSON output of the command, fed `stdin`
+    fn run(&self, args: &[&str], stdin: &str) -> (i32, Value) {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ckm"))
+            .arg("--dir")
+            .arg(&self.dir)
+            .arg("--json")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stdin.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let value = serde_json::from_str(&stdout).unwrap_or(Value::Null);
+        (output.status.code().unwrap(), value)
+    }
+
+    /// run a command that must succeed
+    fn ok(&self, args: &[&str], stdin: &str) -> Value {
+        let (code, value) = self.run(args, stdin);
+        assert_eq!(code, 0, "ckm {:?}: {}", args, value);
+        value
+    }
+}
+
+impl Drop for Ckm {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn test_ckm_seed() {
+    let ckm = Ckm::new("seed");
+    let pw = ckm.path("pw");
+    let imported = ckm.ok(&["import", "--seed", "--password-file", &pw], SEED);
+    let key_id = imported["key_id"].as_str().unwrap().to_string();
+    assert_eq!(ckm.ok(&["list"], "")["keys"], json!([key_id]));
+
+    let local = KeyMaster::new(MemoryKeystore::new());
+    let local_id = local.write_seed("123", SEED.to_string()).unwrap();
+    let shown = ckm.ok(&["show", &key_id, "--password-file", &pw], "");
+    let fingerprint = local.get_master_fingerprint(&local_id, "123").unwrap();
+    assert_eq!(shown["fingerprint"], json!(hex::encode(fingerprint)));
+    assert_eq!(shown["kdf"], json!("scrypt"));
+
+    let path = "m/44'/0'/0'/0/0";
+    let signed = ckm.ok(
+        &["sign", &key_id, "--path", path, "--password-file", &pw],
+        "hello",
+    );
+    let request = SignRequest {
+        path,
+        unsigend_data: b"hello".to_vec(),
+        key_id: &local_id,
+        curve: Curve::Secp256k1,
+    };
+    let expected = local.sign(request, "123").unwrap();
+    assert_eq!(
+        signed["signature"],
+        json!(hex::encode(expected.to_compact()))
+    );
+    let public_key = signed["public_key"].as_str().unwrap();
+    let signature = signed["signature"].as_str().unwrap();
+    let verify = [
+        "verify",
+        "--public-key",
+        public_key,
+        "--signature",
+        signature,
+    ];
+    assert_eq!(ckm.ok(&verify, "hello")["valid"], json!(true));
+    assert_eq!(ckm.run(&verify, "other").0, 1);
+
+    // P-256 and Ed25519 keys of the seed sign and verify too
+    for curve in ["secp256r1", "ed25519"].iter() {
+        let path = "m/44'/0'/0'";
+        let signed = ckm.ok(
+            &[
+                "sign",
+                &key_id,
+                "--path",
+                path,
+                "--curve",
+                curve,
+                "--password-file",
+                &pw,
+            ],
+            "hello",
+        );
+        let parsed: Curve = curve.parse().unwrap();
+        let public_key = local
+            .get_public_key(&local_id, path, parsed, "123")
+            .unwrap();
+        assert_eq!(signed["public_key"], json!(hex::encode(&public_key)));
+        let verify = [
+            "verify",
+            "--curve",
+            curve,
+            "--public-key",
+            signed["public_key"].as_str().unwrap(),
+            "--signature",
+            signed["signature"].as_str().unwrap(),
+        ];
+        assert_eq!(ckm.ok(&verify, "hello")["valid"], json!(true), "{}", curve);
+        assert_eq!(ckm.run(&verify, "other").0, 1);
+    }
+
+    // a wrong password is an error with the code of the variant
+    fs::write(ckm.path("wrong"), "456\n").unwrap();
+    let (code, error) = ckm.run(
+        &["show", &key_id, "--password-file", &ckm.path("wrong")],
+        "",
+    );
+    assert_eq!(code, 2);
+    assert_eq!(error["code"], json!(CKMError::PasswordInvalid.code()));
+
+    ckm.ok(
+        &[
+            "change-password",
+            &key_id,
+            "--password-file",
+            &pw,
+            "--new-password-file",
+            &ckm.path("wrong"),
+        ],
+        "",
+    );
+    ckm.ok(
+        &["show", &key_id, "--password-file", &ckm.path("wrong")],
+        "",
+    );
+    ckm.ok(&["delete", &key_id, "--force"], "");
+    assert_eq!(ckm.ok(&["list"], "")["keys"], json!([]));
+}
+
+#[test]
+fn test_ckm_import_web3_keystore() {
+    let ckm = Ckm::new("web3");
+    fs::write(ckm.path("UTC--web3.json"), WEB3_KEYSTORE).unwrap();
+    let import = |from_password: &str| {
+        ckm.run(
+            &[
+                "import",
+                "--keystore",
+                &ckm.path("UTC--web3.json"),
+                "--from-password-file",
+                &ckm.path(from_password),
+                "--password-file",
+                &ckm.path("pw"),
+            ],
+            "",
+        )
+    };
+    let (code, error) = import("pw");
+    assert_eq!(code, 2);
+    assert_eq!(error["code"], json!(CKMError::PasswordInvalid.code()));
+
+    let (code, imported) = import("web3-pw");
+    assert_eq!(code, 0, "{}", imported);
+    let key_id = imported["key_id"].as_str().unwrap();
+
+    // the private key of the file signs at path m, it has the address of the file
+    fs::write(ckm.path("message"), "hello").unwrap();
+    let signed = ckm.ok(
+        &[
+            "sign",
+            key_id,
+            "--path",
+            "m",
+            "--input",
+            &ckm.path("message"),
+            "--password-file",
+            &ckm.path("pw"),
+        ],
+        "",
+    );
+    let public_key = hex::decode(signed["public_key"].as_str().unwrap()).unwrap();
+    let public_key = k256::PublicKey::from_sec1_bytes(&public_key).unwrap();
+    let uncompressed = public_key.to_encoded_point(false);
+    let hash = Keccak256::digest(&uncompressed.as_bytes()[1..]);
+    assert_eq!(
+        hex::encode(&hash[12..]),
+        "008aeeda4d805471df9b2a5b0f38a0c3bcba786b"
+    );
+    let shown = ckm.ok(&["show", key_id, "--password-file", &ckm.path("pw")], "");
+    assert_eq!(shown["path"], json!("m"));
+}
+
+#[test]
+fn test_ckm_usage_errors() {
+    let ckm = Ckm::new("usage");
+    for args in [
+        &["list", "--verbose"][..],
+        &["sign", "abc", "--pth", "m/0"][..],
+        &["frobnicate"][..],
+        &[][..],
+    ]
+    .iter()
+    {
+        let (code, output) = ckm.run(args, "");
+        assert_eq!(code, 2, "{:?}", args);
+        // usage errors go to stderr only
+        assert_eq!(output, Value::Null);
+    }
+}
@@ -0,0 +1,148 @@
+//! spawns `ckm-daemon` on a keystore directory and signs through `DaemonClient`
+#![cfg(unix)]
+use crypto_key_master::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SEED: &str = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
+
+/// keystore directory and socket of one test, removed with the daemon
+struct Daemon {
+    dir: PathBuf,
+    socket: PathBuf,
+    child: Option<Child>,
+}
+
+impl Daemon {
+    /// directory holding `SEED` with password "123", returns the key id too
+    fn new(name: &str) -> (Self, String) {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+            "daemon-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        // `LocalKeystore` writes to the working directory, move the file over
+        let key_id = KeyMaster::new(LocalKeystore::new())
+            .write_seed("123", SEED.to_string())
+            .unwrap();
+        fs::copy(&key_id, dir.join(&key_id)).unwrap();
+        LocalKeystore::new().delete_key(&key_id).unwrap();
+
+        let socket =
+            std::env::temp_dir().join(format!("ckm-daemon-{}-{}.sock", name, std::process::id()));
+        let daemon = Self {
+            dir,
+            socket,
+            child: None,
+        };
+        (daemon, key_id)
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_ckm-daemon"));
+        command
+            .arg("--socket")
+            .arg(&self.socket)
+            .arg("--dir")
+            .arg(&self.dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+    }
+
+    /// spawn the daemon and wait for its socket
+    fn start(&mut self) {
+        self.child = Some(self.command().spawn().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while DaemonClient::connect(&self.socket).is_err() {
+            assert!(Instant::now() < deadline, "daemon did not start");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// SIGKILL, the socket file stays behind
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    fn client(&self) -> DaemonClient {
+        DaemonClient::connect(&self.socket).unwrap()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.kill();
+        let _ = fs::remove_file(&self.socket);
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn request(key_id: &str, curve: Curve) -> SignRequest<'_> {
+    SignRequest {
+        path: "m/44'/0'/0'/0/0",
+        unsigend_data: "hello".as_bytes().to_vec(),
+        key_id,
+        curve,
+    }
+}
+
+#[test]
+fn test_daemon_process() {
+    let (mut daemon, key_id) = Daemon::new("process");
+    daemon.start();
+    let wallet = daemon.client();
+    let app = daemon.client();
+
+    assert_eq!(app.list_keys().unwrap(), vec![key_id.clone()]);
+    let local = KeyMaster::new(MemoryKeystore::new());
+    let local_id = local.write_seed("123", SEED.to_string()).unwrap();
+    let expected = local
+        .sign(request(&local_id, Curve::Secp256k1), "123")
+        .unwrap();
+
+    assert!(matches!(
+        app.sign(request(&key_id, Curve::Secp256k1), ""),
+        Err(CKMError::PasswordInvalid)
+    ));
+    let session = wallet
+        .unlock(&key_id, "123", Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(session.key_id(), key_id);
+    let sig = app.sign(request(&key_id, Curve::Secp256k1), "").unwrap();
+    assert_eq!((sig.r, sig.s, sig.v), (expected.r, expected.s, expected.v));
+    let public_key = app
+        .get_public_key(&key_id, "m/44'/0'/0'/0/0", Curve::Secp256k1, "")
+        .unwrap();
+    assert!(verify_signature(Curve::Secp256k1, &public_key, b"hello", &sig).unwrap());
+
+    assert!(wallet.lock(&key_id).unwrap());
+    assert!(matches!(
+        app.sign(request(&key_id, Curve::Secp256k1), ""),
+        Err(CKMError::PasswordInvalid)
+    ));
+}
+
+#[test]
+fn test_daemon_socket_reuse() {
+    let (mut daemon, key_id) = Daemon::new("reuse");
+    daemon.start();
+
+    // a second daemon does not take over a live socket
+    let status: ExitStatus = daemon.command().status().unwrap();
+    assert_eq!(status.code(), Some(2));
+    assert_eq!(daemon.client().list_keys().unwrap(), vec![key_id.clone()]);
+
+    // the socket of a killed daemon is replaced on restart
+    daemon.kill();
+    assert!(daemon.socket.exists());
+    daemon.start();
+    assert_eq!(daemon.client().list_keys().unwrap(), vec![key_id]);
+}